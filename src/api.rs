@@ -1,61 +1,251 @@
-//! Prototype of the Aves system calls.
-//! 
+//! Aves system calls.
+//!
 //! The goal of Aves is to provide a "everything is a file", or
 //! exactly, everything is a virtual file. This is more advanced
 //! than linux because sockets are also represented in the file
-//! system. This allows to generalize the concept of "block 
+//! system. This allows to generalize the concept of "block
 //! device" and virtual file systems.
+//!
+//! System calls are made with the `ecall` instruction, the number
+//! of the system call is given in `a7` and up to six arguments are
+//! given in `a0` to `a5`. The result is returned in `a0`, negative
+//! values are errors codes, see [`Error`].
+//!
+//! This module is shared between the kernel, that dispatches the
+//! calls, and the processes, that use the wrappers functions.
+//!
+//! Paths under `/sys` are served by kernel drivers, that register
+//! virtual files and directories in the system filesystem, see the
+//! [`sysfs`](crate::filesystem::sysfs) module.
+//!
+//! ```ignore
+//! // Open a TCP socket connected to 215.98.166.36:9832
+//! // The two following calls are equivalent, the second
+//! //  can be used for libraries to avoid encoding the
+//! //  ip address into a decimal numbers or variable
+//! //  length.
+//! open("/sys/ip4/126.98.166.36/tcp/9832", "r");
+//! open("/sys/ip4/x7E62A624/tcp/x2668", "r"); // -> 0x00000005
+//! // This would be implemented by multiple "fs drivers",
+//! //  registering directories resolving their own entries.
+//! // In fact, the IPv4 driver will provide an abstraction
+//! // for the TCP and UDP drivers and will need lower-level
+//! // drivers, typically to talk to network hardware.
+//!
+//! // The returned handle can be accessed later.
+//! open("/proc/self/io/x00000005/ip", "");  // -> ip4
+//!
+//! // Listen on a port.
+//! open("/sys/ip4/0.0.0.0/tcp/22", "l");
+//!
+//! // In the following snippet, we typically use the
+//! // "network" fs driver (providing /dev/net/ directory).
+//! // This driver uses lower-level drivers specific to
+//! // hardware cards to register the interfaces.
+//! open("/sys/net/eth0/ratelimit", "rw");
+//!
+//! // Obvious...
+//! open("/home/me/ok.txt", "w");
+//!
+//! // Open the random device.
+//! open("/sys/rand", "r");
+//!
+//! // Resolve the hostname and return a handle that will
+//! // just return the ip and its type (v4 or v6).
+//! open("/sys/hostname/google.fr", "r");
+//!
+//! // Create a custom host that can later be resolved.
+//! // The issue here is that it's not persistent, we could
+//! // later add a file like /conf/kernel for example that
+//! // can automatically configure the whole system at startup.
+//! open("/sys/hostname/customhost", "w");
+//! ```
 
+use core::arch::asm;
 
-extern {
-    fn open(path: &str, options: &str) -> usize;
-    fn free(handle: usize);
+
+/// Open a resource from its path: `(path_ptr, path_len, options_ptr, options_len) -> handle`.
+pub const SYS_OPEN: usize = 1;
+/// Free a handle: `(handle) -> 0`.
+pub const SYS_FREE: usize = 2;
+/// Read from a handle: `(handle, buf_ptr, buf_len) -> len`.
+pub const SYS_READ: usize = 3;
+/// Write to a handle: `(handle, buf_ptr, buf_len) -> len`.
+pub const SYS_WRITE: usize = 4;
+/// Move the cursor of a handle: `(handle, offset, whence) -> offset`.
+pub const SYS_SEEK: usize = 5;
+/// Exit the calling process: `(code) -> !`.
+pub const SYS_EXIT: usize = 6;
+/// Yield the hart to another process: `() -> 0`.
+pub const SYS_YIELD: usize = 7;
+/// Spawn a process: `(name_ptr, name_len) -> pid`.
+pub const SYS_SPAWN: usize = 8;
+/// Get the PID of the calling process: `() -> pid`.
+pub const SYS_GETPID: usize = 9;
+
+
+/// Seek relative to the start of the resource.
+pub const SEEK_SET: usize = 0;
+/// Seek relative to the current offset.
+pub const SEEK_CUR: usize = 1;
+/// Seek relative to the end of the resource.
+pub const SEEK_END: usize = 2;
+
+
+/// Error codes returned by system calls, negated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(isize)]
+pub enum Error {
+    /// The system call number is unknown.
+    InvalidSyscall = 1,
+    /// A pointer argument is not accessible by the process.
+    InvalidPointer = 2,
+    /// The handle is not opened by the process.
+    InvalidHandle = 3,
+    /// An argument is invalid, like a non UTF-8 string.
+    InvalidArgument = 4,
+    /// The path or the name doesn't exist.
+    NotFound = 5,
+    /// The process has too many opened handles.
+    TooManyHandles = 6,
+    /// The handle has not been opened for this operation.
+    PermissionDenied = 7,
+    /// The operation is not supported by the resource.
+    Unsupported = 8,
+    /// The kernel is out of memory.
+    OutOfMemory = 9,
+    /// The underlying device failed.
+    Io = 10,
+    /// A component of the path is not a directory.
+    NotDirectory = 11,
+    /// The operation is not allowed on a directory.
+    IsDirectory = 12,
+    /// The path already exists.
+    AlreadyExists = 13,
+    /// The directory is not empty.
+    NotEmpty = 14,
+    /// The resource is used, like a mount point.
+    Busy = 15,
+    /// The filesystem is read-only.
+    ReadOnly = 16,
+    /// The filesystem is full.
+    NoSpace = 17,
 }
 
+impl Error {
+
+    /// Get the error from its code, the positive one.
+    pub fn from_code(code: isize) -> Option<Self> {
+        Some(match code {
+            1 => Self::InvalidSyscall,
+            2 => Self::InvalidPointer,
+            3 => Self::InvalidHandle,
+            4 => Self::InvalidArgument,
+            5 => Self::NotFound,
+            6 => Self::TooManyHandles,
+            7 => Self::PermissionDenied,
+            8 => Self::Unsupported,
+            9 => Self::OutOfMemory,
+            10 => Self::Io,
+            11 => Self::NotDirectory,
+            12 => Self::IsDirectory,
+            13 => Self::AlreadyExists,
+            14 => Self::NotEmpty,
+            15 => Self::Busy,
+            16 => Self::ReadOnly,
+            17 => Self::NoSpace,
+            _ => return None
+        })
+    }
+
+}
+
+
+/// Type alias for results of system calls.
+pub type Result<T> = core::result::Result<T, Error>;
+
 
-unsafe fn test() {
-    
-    // Open a TCP socket connected to 215.98.166.36:9832
-    // The two following calls are equivalent, the second
-    //  can be used for libraries to avoid encoding the
-    //  ip address into a decimal numbers or variable
-    //  length.
-    open("/sys/ip4/126.98.166.36/tcp/9832", "r");
-    open("/sys/ip4/x7E62A624/tcp/x2668", "r"); // -> 0x00000005
-    // This would be implemented by multiple "fs drivers".
-    // In fact, the IPv4 driver will provide an abstraction
-    // for the TCP and UDP drivers and will need lower-level
-    // drivers, typically to talk to network hardware.
+/// Raw system call with the given number and arguments.
+///
+/// *This function is unsafe because pointers given as arguments
+/// must be valid for the system call.*
+#[inline(always)]
+pub unsafe fn syscall(num: usize, args: [usize; 6]) -> isize {
+    let ret: isize;
+    asm!("ecall",
+        inlateout("a0") args[0] => ret,
+        in("a1") args[1],
+        in("a2") args[2],
+        in("a3") args[3],
+        in("a4") args[4],
+        in("a5") args[5],
+        in("a7") num,
+    );
+    ret
+}
 
-    // The returned handle can be accessed later.
-    open("/proc/self/io/x00000005/ip", "");  // -> ip4
+/// Internal function to convert the raw return value to a result.
+#[inline]
+fn result(ret: isize) -> Result<usize> {
+    if ret < 0 {
+        Err(Error::from_code(-ret).unwrap_or(Error::InvalidSyscall))
+    } else {
+        Ok(ret as usize)
+    }
+}
 
-    // Listen on a port.
-    open("/sys/ip4/0.0.0.0/tcp/22", "l");
 
-    // In the following snippet, we typically use the 
-    // "network" fs driver (providing /dev/net/ directory).
-    // This driver uses lower-level drivers specific to
-    // hardware cards to register the interfaces.
-    open("/sys/net/eth0/ratelimit", "rw");
+/// Open the resource at the given path, options are a combination of
+/// `r` (read), `w` (write), `c` (create the file if it doesn't exist)
+/// and `t` (truncate the file when writing). Return the handle of the
+/// resource.
+pub fn open(path: &str, options: &str) -> Result<usize> {
+    result(unsafe { syscall(SYS_OPEN, [path.as_ptr().addr(), path.len(), options.as_ptr().addr(), options.len(), 0, 0]) })
+}
 
-    // Obvious...
-    open("/home/me/ok.txt", "w");
+/// Free the given handle.
+pub fn free(handle: usize) -> Result<()> {
+    result(unsafe { syscall(SYS_FREE, [handle, 0, 0, 0, 0, 0]) }).map(|_| ())
+}
 
-    // Open the random device.
-    open("/sys/rand", "r");
+/// Read from the handle into the given buffer, return the number of
+/// bytes read. Reading the TTY blocks until some input is available, a
+/// whole line in canonical mode, zero is returned at the end of input.
+pub fn read(handle: usize, buf: &mut [u8]) -> Result<usize> {
+    result(unsafe { syscall(SYS_READ, [handle, buf.as_mut_ptr().addr(), buf.len(), 0, 0, 0]) })
+}
 
-    // Resolve the hostname and return a handle that will 
-    // just return the ip and its type (v4 or v6).
-    open("/sys/hostname/google.fr", "r");
+/// Write the given buffer to the handle, return the number of bytes
+/// written.
+pub fn write(handle: usize, buf: &[u8]) -> Result<usize> {
+    result(unsafe { syscall(SYS_WRITE, [handle, buf.as_ptr().addr(), buf.len(), 0, 0, 0]) })
+}
 
-    // Create a custom host that can later be resolved.
-    // The issue here is that it's not persistent, we could 
-    // later add a file like /conf/kernel for example that 
-    // can automatically configure the whole system at startup.
-    open("/sys/hostname/customhost", "w");
+/// Move the cursor of the handle, `whence` is one of the `SEEK_*`
+/// constants. Return the new offset from the start.
+pub fn seek(handle: usize, offset: isize, whence: usize) -> Result<usize> {
+    result(unsafe { syscall(SYS_SEEK, [handle, offset as usize, whence, 0, 0, 0]) })
+}
 
+/// Exit the calling process with the given code.
+pub fn exit(code: usize) -> ! {
+    unsafe { syscall(SYS_EXIT, [code, 0, 0, 0, 0, 0]); }
+    unreachable!("process exit returned")
 }
 
+/// Yield the hart to another process.
+pub fn yield_now() {
+    unsafe { syscall(SYS_YIELD, [0; 6]); }
+}
 
-// Suchz
\ No newline at end of file
+/// Spawn the process with the given name, return its PID. Names that
+/// start with `/` are absolute paths of ELF executables, spawned as
+/// user processes, other names are built-in processes.
+pub fn spawn(name: &str) -> Result<usize> {
+    result(unsafe { syscall(SYS_SPAWN, [name.as_ptr().addr(), name.len(), 0, 0, 0, 0]) })
+}
+
+/// Get the PID of the calling process.
+pub fn getpid() -> usize {
+    unsafe { syscall(SYS_GETPID, [0; 6]) as usize }
+}
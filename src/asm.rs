@@ -9,7 +9,7 @@ use core::arch::global_asm;
 global_asm!(include_str!("asm/boot.asm"));
 global_asm!(include_str!("asm/trap.asm"));
 global_asm!(include_str!("asm/sym.asm"));
-global_asm!(include_str!("asm/proc.asm"));
+global_asm!(include_str!("asm/user.asm"));
 
 extern "C" {
 
@@ -31,10 +31,27 @@ extern "C" {
     pub static LD_MEMORY_END: *mut u8;
     pub static LD_MEMORY_SIZE: usize;
     
+    pub static LD_TEXT_START: *mut u8;
+    pub static LD_TEXT_END: *mut u8;
+
+    pub static LD_RODATA_START: *mut u8;
+    pub static LD_RODATA_END: *mut u8;
+
+    pub static LD_DATA_START: *mut u8;
+    pub static LD_DATA_END: *mut u8;
+
+    pub static LD_BSS_START: *mut u8;
+    pub static LD_BSS_END: *mut u8;
+
     pub static LD_KSTACK_START: *mut u8;
     pub static LD_KSTACK_END: *mut u8;
 
-    pub static LD_HEAP_START: *mut u8;
-    pub static LD_HEAP_SIZE: usize;
+    /// Value of the global pointer of the kernel, set at boot.
+    pub static LD_GLOBAL_POINTER: *mut u8;
+
+    /// Bounds of the built-in user program that writes a greeting
+    /// to the console, see `user.asm`.
+    pub static USER_HELLO_START: u8;
+    pub static USER_HELLO_END: u8;
 
 }
@@ -3,6 +3,12 @@
 .option norvc
 .section .text.init
 
+# Maximum number of harts supported, see 'smp::HART_MAX_COUNT'.
+.equ HART_MAX_COUNT, 8
+# Size of the kernel stack slice of each hart, the whole kernel stack
+# is shared between the maximum number of harts.
+.equ HART_KSTACK_SIZE, 0x10000
+
 .global _start
 _start:
 
@@ -19,30 +25,43 @@ _start:
     # Should be zero.
     csrw satp, zero
 
+    # Disable all interrupt for startup.
+    csrw mie, zero
+
+    # Physical Memory Protection, the whole physical memory is
+    # accessible from lower privileges (NAPOT range with all 
+    # address bits set), user processes are isolated by their 
+    # own page tables instead. PMP registers are per hart.
+    li t0, -1
+    srli t0, t0, 10
+    csrw pmpaddr0, t0
+    li t0, (0b11 << 3) | 0b111
+    csrw pmpcfg0, t0
+
     # Load the hard id to t0.
     csrr t0, mhartid
-    # If the hard id is not 0 (our bootstrapping hart), wait for interrupt.
+    # If the hard id is not 0 (our bootstrapping hart), wait to be woken up.
     bnez t0, work_hart
 
 # This section is only entered by the kernel hart #0.
 kernel_hart:
 
     # Here we want to init all the bss section to zero.
-    la a0, _ld_bss_start
-    la a1, _ld_bss_end
-    bgeu a0, a1, clear_bss_end
+    # Registers a0 (hart id) and a1 (device tree pointer) are kept
+    # untouched because they are given to kmain.
+    la t1, _ld_bss_start
+    la t2, _ld_bss_end
+    bgeu t1, t2, clear_bss_end
 clear_bss_loop:
-    sd zero, (a0)
-    addi a0, a0, 8
-    bltu a0, a1, clear_bss_loop
+    sd zero, (t1)
+    addi t1, t1, 8
+    bltu t1, t2, clear_bss_loop
 clear_bss_end:
 
-    # Setup stack, the stack grows from bottom to top.
+    # Setup stack, the stack grows from bottom to top. The hart #0 
+    # uses the first slice at the end of the kernel stack.
     la sp, _ld_kstack_end
 
-    # Disable all interrupt for startup.
-    csrw mie, zero
-
     # Machine Previous Privilege (MPP) = 0b11 (M)
     # Machine Previous Interrupt Enable (MPIE) = 1
     # Machine Interrupt Enable (MIE) = 1
@@ -63,12 +82,59 @@ clear_bss_end:
     # Actually go to kmain (jump to address into mepc).
     mret
 
-# This section is entered by any hart that is not #0.
+# This section is entered by any hart that is not #0, the hart waits
+# for a software interrupt sent by hart #0 once the kernel is ready.
 work_hart:
-    j asm_abort
+
+    # Harts with an id too high for the kernel are parked.
+    li t1, HART_MAX_COUNT
+    bgeu t0, t1, asm_abort
+
+    # Each hart has its own slice of the kernel stack.
+    la sp, _ld_kstack_end
+    li t1, HART_KSTACK_SIZE
+    mul t1, t1, t0
+    sub sp, sp, t1
+
+    # Only wake up on Machine Software Interrupt (MSIE), the interrupt
+    # is not taken because it's disabled in mstatus.
+    li t1, 0b1 << 3
+    csrw mie, t1
+work_hart_wait:
+    wfi
+    csrr t1, mip
+    andi t1, t1, 0b1 << 3
+    beqz t1, work_hart_wait
+
+    # The software interrupt is cleared by 'kmain_hart', it must not be
+    # taken before the trap frame of the hart is initialized.
+    csrw mie, zero
+
+    # Same state as hart #0 when entering kmain.
+    li t1, (0b11 << 11) | (0b1 << 7) | (0b1 << 3)
+    csrw mstatus, t1
+
+    la t1, kmain_hart
+    csrw mepc, t1
+
+    la t1, asm_trap_vector
+    csrw mtvec, t1
+
+    la ra, asm_abort
+
+    # Go to kmain_hart with the hart id as argument.
+    mv a0, t0
+    mret
 
 # Define a global abort function, also used to park unused harts and for panics.
 .global asm_abort
 asm_abort:
     wfi
     j asm_abort
+
+# Define a global idle function, used by the scheduler when no process can 
+# run, unlike 'asm_abort' interrupts are enabled when this is running.
+.global asm_idle
+asm_idle:
+    wfi
+    j asm_idle
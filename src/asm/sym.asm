@@ -9,12 +9,31 @@ LD_MEMORY_START: .dword _ld_memory_start
 LD_MEMORY_END: .dword _ld_memory_end
 LD_MEMORY_SIZE: .dword _ld_memory_size
 
+.global LD_TEXT_START
+.global LD_TEXT_END
+LD_TEXT_START: .dword _ld_text_start
+LD_TEXT_END: .dword _ld_text_end
+
+.global LD_RODATA_START
+.global LD_RODATA_END
+LD_RODATA_START: .dword _ld_rodata_start
+LD_RODATA_END: .dword _ld_rodata_end
+
+.global LD_DATA_START
+.global LD_DATA_END
+LD_DATA_START: .dword _ld_data_start
+LD_DATA_END: .dword _ld_data_end
+
+.global LD_BSS_START
+.global LD_BSS_END
+LD_BSS_START: .dword _ld_bss_start
+LD_BSS_END: .dword _ld_bss_end
+
 .global LD_KSTACK_START
 .global LD_KSTACK_END
 LD_KSTACK_START: .dword _ld_kstack_start
 LD_KSTACK_END: .dword _ld_kstack_end
 
-.global LD_HEAP_START
-.global LD_HEAP_SIZE
-LD_HEAP_START: .dword _ld_heap_start
-LD_HEAP_SIZE: .dword _ld_heap_size
+.global LD_GLOBAL_POINTER
+LD_GLOBAL_POINTER: .dword _ld_global_pointer
+
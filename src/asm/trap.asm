@@ -4,66 +4,117 @@
 .section .text
 
 .global asm_trap_vector
+.global asm_trap_return
+
 .align 4
 asm_trap_vector:
 
-#     # Atomically swap values between t0 and mscratch.
-#     # The scratch CSR must contain a pointer to a 'TrapFrame'
-#     # structure.
-#     csrrw t0, mscratch, t0
-
-#     # Here, t0 is a pointer to a 'TrapFrame', so lets save
-#     # t1 and t2 in the frame.
-#     sd 8(t0), t1
-#     sd 16(t0), t2
-
-#     # Copy t0 (pointer to 'TrapFrame') to t2.
-#     mv t2, t0
-#     # Restore old t0 from mscratch, and set mscratch to t2 
-#     # (pointer to 'TrapFrame').
-#     csrrw t0, mscratch, t2
-#     sd 0(t2), t0
+    # Atomically swap values between t6 and mscratch.
+    # The scratch CSR must contain a pointer to a 'TrapFrame'
+    # structure.
+    csrrw t6, mscratch, t6
 
-#     # From here, we are free to use t0 and t2 as we want.
-#     # t2 is used for the 'TrapFrame'.
+    # Here, t6 is a pointer to a 'TrapFrame', so lets save
+    # all registers but t6 (x31) in 'TrapFrame.regs'.
+    # x0 is hardwired to zero and doesn't need to be saved.
+    sd x1, 8(t6)
+    sd x2, 16(t6)
+    sd x3, 24(t6)
+    sd x4, 32(t6)
+    sd x5, 40(t6)
+    sd x6, 48(t6)
+    sd x7, 56(t6)
+    sd x8, 64(t6)
+    sd x9, 72(t6)
+    sd x10, 80(t6)
+    sd x11, 88(t6)
+    sd x12, 96(t6)
+    sd x13, 104(t6)
+    sd x14, 112(t6)
+    sd x15, 120(t6)
+    sd x16, 128(t6)
+    sd x17, 136(t6)
+    sd x18, 144(t6)
+    sd x19, 152(t6)
+    sd x20, 160(t6)
+    sd x21, 168(t6)
+    sd x22, 176(t6)
+    sd x23, 184(t6)
+    sd x24, 192(t6)
+    sd x25, 200(t6)
+    sd x26, 208(t6)
+    sd x27, 216(t6)
+    sd x28, 224(t6)
+    sd x29, 232(t6)
+    sd x30, 240(t6)
 
-#     # Load 'TrapFrame.traps_count' to t0.
-#     ld t0, 24(t2)
+    # Copy t6 (pointer to 'TrapFrame') to t5.
+    # Restore old t6 from mscratch, and set mscratch to t5
+    # (pointer to 'TrapFrame'), then save old t6.
+    mv t5, t6
+    csrrw t6, mscratch, t5
+    sd x31, 248(t5)
 
-#     # Saves 'TrapFrame.traps_count += 1'.
-#     addi t0, t0, 1
-#     sd 24(t2), t0
-    
-#     # Shift left to multiply the trap count by 24 (16 + 8, sizeof 'Trap').
-#     slli t0, t0, 4
-#     slli t1, t0, 3
-#     add t0, t0, t1
+    # Save the trap CSRs in 'TrapFrame.trap' and 'TrapFrame.mstatus'.
+    csrr t0, mcause
+    sd t0, 256(t5)
+    csrr t0, mtval
+    sd t0, 264(t5)
+    csrr t0, mepc
+    sd t0, 272(t5)
+    csrr t0, mstatus
+    sd t0, 280(t5)
 
-#     # Now pointing t1 to 'TrapFrame.traps[t0]' - 32 (offset of the array).
-#     addi t1, t2, t0
+    # Switch to the trap stack of the hart, 'TrapFrame.trap_stack'.
+    ld sp, 288(t5)
 
-#     # Store values in the 'Trap' object.
-#     csrr t0, mcause
-#     sd 32(t1), t0
-#     csrr t0, mtval
-#     sd 40(t1), t0
-#     csrr t0, mepc
-#     sd 48(t1), t0
+    # Call 'ktrap(frame: &mut TrapFrame)', the handler is allowed
+    # to modify the frame, especially 'TrapFrame.trap.mepc' which
+    # is the program counter that will be restored.
+    mv a0, t5
+    call ktrap
 
-#     andi t1, t2, 1 << 63
-#     bnez t1, is_interrupt
-    
-# is_exception:
+# Restore the state saved in the 'TrapFrame' pointed by mscratch
+# and return from the trap to 'TrapFrame.trap.mepc'.
+asm_trap_return:
 
-#     # We increment mepc by 4 in order to skip the exception instruction.
-#     addi t0, t0, 4
-#     csrw mepc, t0
+    csrr t6, mscratch
 
-# is_interrupt:
+    ld t0, 272(t6)
+    csrw mepc, t0
+    ld t0, 280(t6)
+    csrw mstatus, t0
 
-#     # Restore registers.
-#     ld t0, 0(t2)
-#     ld t1, 8(t2)
-#     ld t2, 16(t2)
+    ld x1, 8(t6)
+    ld x2, 16(t6)
+    ld x3, 24(t6)
+    ld x4, 32(t6)
+    ld x5, 40(t6)
+    ld x6, 48(t6)
+    ld x7, 56(t6)
+    ld x8, 64(t6)
+    ld x9, 72(t6)
+    ld x10, 80(t6)
+    ld x11, 88(t6)
+    ld x12, 96(t6)
+    ld x13, 104(t6)
+    ld x14, 112(t6)
+    ld x15, 120(t6)
+    ld x16, 128(t6)
+    ld x17, 136(t6)
+    ld x18, 144(t6)
+    ld x19, 152(t6)
+    ld x20, 160(t6)
+    ld x21, 168(t6)
+    ld x22, 176(t6)
+    ld x23, 184(t6)
+    ld x24, 192(t6)
+    ld x25, 200(t6)
+    ld x26, 208(t6)
+    ld x27, 216(t6)
+    ld x28, 224(t6)
+    ld x29, 232(t6)
+    ld x30, 240(t6)
+    ld x31, 248(x31)
 
     mret
@@ -0,0 +1,47 @@
+# Definition of built-in user programs, these are flat images copied
+# in the address space of user processes, so they must only use
+# pc-relative addressing.
+
+.section .rodata
+.option push
+.option norvc
+.option norelax
+
+# Write a greeting to the console and exit.
+.global USER_HELLO_START
+.global USER_HELLO_END
+.align 4
+USER_HELLO_START:
+
+    # open("/sys/console", "w")
+    la a0, user_hello_path
+    li a1, 12
+    la a2, user_hello_options
+    li a3, 1
+    li a7, 1
+    ecall
+    bltz a0, user_hello_fail
+
+    # write(handle, msg)
+    la a1, user_hello_msg
+    li a2, 22
+    li a7, 4
+    ecall
+
+    # exit(0)
+    li a0, 0
+    li a7, 6
+    ecall
+
+user_hello_fail:
+    # exit(1)
+    li a0, 1
+    li a7, 6
+    ecall
+
+user_hello_path: .ascii "/sys/console"
+user_hello_options: .ascii "w"
+user_hello_msg: .ascii "Hello from user mode!\n"
+USER_HELLO_END:
+
+.option pop
@@ -21,5 +21,8 @@ macro_rules! drivers {
 
 drivers! {
     BLOCK: BlockDriver = BlockDriver::new();
-    VIRTIO: VirtioDriver<0x1000_1000, 0x1000, 8> = VirtioDriver::new().with_block(&BLOCK);
+    VIRTIO: VirtioDriver<8> = VirtioDriver::new().with_block(&BLOCK);
+    FS: FsDriver = FsDriver::new().with_block(&BLOCK);
+    FAT: FatDriver = FatDriver::new(&FS);
+    EXT2: Ext2Driver = Ext2Driver::new(&FS);
 }
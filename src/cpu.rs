@@ -10,6 +10,16 @@ pub mod mhardid {
     }
 }
 
+pub mod mcycle {
+    /// Get the number of cycles executed by the hart executing this function.
+    #[inline(always)]
+    pub fn get() -> u64 {
+        let cycles;
+        unsafe { core::arch::asm!("csrr {0}, mcycle", out(reg) cycles); }
+        cycles
+    }
+}
+
 pub mod misa {
 
     bitflags::bitflags! {
@@ -99,6 +109,77 @@ pub mod mscratch {
         unsafe { core::arch::asm!("csrw mscratch, {0}", in(reg) value); }
     }
 
+    /// Get the `mscratch` register for the hart executing this function.
+    #[inline(always)]
+    pub fn get() -> usize {
+        let value;
+        unsafe { core::arch::asm!("csrr {0}, mscratch", out(reg) value); }
+        value
+    }
+
+}
+
+pub mod mstatus {
+
+    bitflags::bitflags! {
+
+        pub struct MstatusFlags: usize {
+            /// Supervisor Interrupt Enable
+            const SIE           = 1 << 1;
+            /// Machine Interrupt Enable
+            const MIE           = 1 << 3;
+            /// Supervisor Previous Interrupt Enable
+            const SPIE          = 1 << 5;
+            /// Machine Previous Interrupt Enable
+            const MPIE          = 1 << 7;
+            /// Supervisor Previous Privilege
+            const SPP           = 1 << 8;
+            /// Machine Previous Privilege (mask of the two bits)
+            const MPP           = 0b11 << 11;
+            /// Machine Previous Privilege = Supervisor
+            const MPP_SUPERVISOR = 0b01 << 11;
+            /// Machine Previous Privilege = Machine
+            const MPP_MACHINE   = 0b11 << 11;
+            /// Modify PRiVilege
+            const MPRV          = 1 << 17;
+            /// Permit Supervisor User Memory access
+            const SUM           = 1 << 18;
+        }
+
+    }
+
+    #[inline(always)]
+    pub fn get() -> MstatusFlags {
+        let raw: usize;
+        unsafe { core::arch::asm!("csrr {0}, mstatus", out(reg) raw); }
+        MstatusFlags::from_bits_truncate(raw)
+    }
+
+    /// Set the given flags, leaving other ones untouched.
+    #[inline(always)]
+    pub fn set(flags: MstatusFlags) {
+        unsafe { core::arch::asm!("csrs mstatus, {0}", in(reg) flags.bits); }
+    }
+
+    /// Clear the given flags, leaving other ones untouched.
+    #[inline(always)]
+    pub fn clear(flags: MstatusFlags) {
+        unsafe { core::arch::asm!("csrc mstatus, {0}", in(reg) flags.bits); }
+    }
+
+    /// Run the given function with machine interrupts disabled,
+    /// the previous interrupt enable state is restored afterward.
+    #[inline]
+    pub fn without_interrupts<R>(func: impl FnOnce() -> R) -> R {
+        let enabled = get().contains(MstatusFlags::MIE);
+        clear(MstatusFlags::MIE);
+        let ret = func();
+        if enabled {
+            set(MstatusFlags::MIE);
+        }
+        ret
+    }
+
 }
 
 pub mod mie {
@@ -127,4 +208,50 @@ pub mod mie {
         unsafe { core::arch::asm!("csrw mie, {0}", in(reg) flags.bits as u32); }
     }
 
+    #[inline(always)]
+    pub fn get() -> MieFlags {
+        let raw: usize;
+        unsafe { core::arch::asm!("csrr {0}, mie", out(reg) raw); }
+        MieFlags::from_bits_truncate(raw as u16)
+    }
+
+}
+
+pub mod satp {
+
+    /// Address translation mode of the `satp` register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(u8)]
+    pub enum Mode {
+        /// No translation or protection.
+        Bare = 0,
+        /// Page-based 39-bit virtual addressing.
+        Sv39 = 8,
+        /// Page-based 48-bit virtual addressing.
+        Sv48 = 9,
+    }
+
+    /// Set the `satp` register for the hart executing this function, 
+    /// the given physical page number is the one of the root table.
+    #[inline(always)]
+    pub fn set(mode: Mode, asid: u16, ppn: usize) {
+        let value = ((mode as usize) << 60) | ((asid as usize) << 44) | (ppn & ((1 << 44) - 1));
+        unsafe { core::arch::asm!("csrw satp, {0}", in(reg) value); }
+    }
+
+    /// Get the raw `satp` register for the hart executing this function.
+    #[inline(always)]
+    pub fn get() -> usize {
+        let value;
+        unsafe { core::arch::asm!("csrr {0}, satp", out(reg) value); }
+        value
+    }
+
+    /// Flush the whole address translation cache of the hart executing
+    /// this function, must be called after modifying the page tables.
+    #[inline(always)]
+    pub fn sfence_vma() {
+        unsafe { core::arch::asm!("sfence.vma zero, zero"); }
+    }
+
 }
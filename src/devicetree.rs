@@ -0,0 +1,466 @@
+//! Flattened device tree (FDT) parsing, used for hardware discovery.
+//!
+//! The bootloader (or QEMU) gives the physical address of the device
+//! tree blob in the `a1` register of every hart, it's parsed in-place
+//! without any allocation.
+//!
+//! The implementation follow the [`devicetree specification`].
+//!
+//! [`devicetree specification`]: https://github.com/devicetree-org/devicetree-specification/releases/download/v0.4/devicetree-specification-v0.4.pdf
+
+
+/// Magic number at the start of the header, big-endian.
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+
+/// The last compatible version we support.
+const FDT_VERSION: u32 = 17;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Maximum depth of nodes supported while iterating.
+const MAX_DEPTH: usize = 16;
+
+/// Default `#address-cells` value if not specified by the parent.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+
+/// Default `#size-cells` value if not specified by the parent.
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+
+/// The device tree given at boot, if valid.
+static mut DEVICE_TREE: Option<DeviceTree> = None;
+
+
+/// Initialize the global device tree from the pointer given at boot.
+///
+/// *This function is unsafe because it must be called once on boot,
+/// before any call to [`get`], and the given pointer must be either
+/// null or pointing to a device tree blob that stay valid forever.*
+pub unsafe fn init(ptr: *const u8) -> Result<(), DeviceTreeError> {
+    DEVICE_TREE = Some(DeviceTree::from_ptr(ptr)?);
+    Ok(())
+}
+
+/// Get the global device tree, if it has been successfully initialized.
+#[inline]
+pub fn get() -> Option<&'static DeviceTree> {
+    unsafe { DEVICE_TREE.as_ref() }
+}
+
+/// Shortcut to find the first node compatible with one of the given
+/// compatible strings in the global device tree.
+pub fn find_compatible(compatibles: &[&str]) -> Option<Node<'static>> {
+    let tree = get()?;
+    compatibles.iter().find_map(|compatible| tree.find_compatible(compatible).next())
+}
+
+
+/// A flattened device tree blob.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceTree {
+    /// The whole blob, including the header.
+    data: &'static [u8],
+    /// Offset of the structure block.
+    struct_offset: usize,
+    /// Offset of the strings block.
+    strings_offset: usize,
+    /// Offset of the memory reservation block.
+    reservations_offset: usize,
+}
+
+impl DeviceTree {
+
+    /// Parse the header of the device tree blob at the given address.
+    ///
+    /// *This function is unsafe because the pointer must be either null
+    /// or pointing to a valid device tree blob that is never modified.*
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, DeviceTreeError> {
+
+        if ptr.is_null() {
+            return Err(DeviceTreeError::Null);
+        }
+
+        let header = core::slice::from_raw_parts(ptr, 40);
+        if read_u32(header, 0) != Some(FDT_MAGIC) {
+            return Err(DeviceTreeError::InvalidMagic);
+        }
+
+        let total_size = read_u32(header, 4).unwrap() as usize;
+        let data = core::slice::from_raw_parts(ptr, total_size);
+
+        let last_compatible_version = read_u32(header, 24).unwrap();
+        if last_compatible_version > FDT_VERSION {
+            return Err(DeviceTreeError::UnsupportedVersion);
+        }
+
+        let tree = Self {
+            data,
+            struct_offset: read_u32(header, 8).unwrap() as usize,
+            strings_offset: read_u32(header, 12).unwrap() as usize,
+            reservations_offset: read_u32(header, 16).unwrap() as usize,
+        };
+
+        if tree.struct_offset >= total_size || tree.strings_offset >= total_size || tree.reservations_offset >= total_size {
+            return Err(DeviceTreeError::InvalidHeader);
+        }
+
+        Ok(tree)
+
+    }
+
+    /// Address of the device tree blob in memory.
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    /// Total size of the device tree blob in memory.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Iterate over the memory reservations `(address, size)` of the
+    /// memory reservation block.
+    pub fn reservations(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let mut offset = self.reservations_offset;
+        core::iter::from_fn(move || {
+            let addr = read_u64(self.data, offset)?;
+            let size = read_u64(self.data, offset + 8)?;
+            offset += 16;
+            (addr != 0 || size != 0).then_some((addr, size))
+        })
+    }
+
+    /// Iterate over all nodes of the tree, depth-first.
+    pub fn nodes(&self) -> NodeIter<'_> {
+        NodeIter {
+            tree: self,
+            offset: self.struct_offset,
+            depth: 0,
+            cells: [(DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS); MAX_DEPTH + 1],
+        }
+    }
+
+    /// Iterate over all nodes that have the given string in their
+    /// `compatible` property.
+    pub fn find_compatible<'a: 'c, 'c>(&'a self, compatible: &'c str) -> impl Iterator<Item = Node<'a>> + 'c {
+        self.nodes().filter(move |node| node.is_compatible(compatible))
+    }
+
+    /// Find a node from its absolute path, like `/chosen` or `/cpus`.
+    /// The unit address of each component can be omitted if unique.
+    pub fn find_path(&self, path: &str) -> Option<Node<'_>> {
+
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut expected = components.next();
+        let mut depth = 1;
+
+        for node in self.nodes() {
+            if node.depth == 0 {
+                if expected.is_none() {
+                    return Some(node);
+                }
+            } else if node.depth == depth {
+                let component = expected?;
+                let name = node.name();
+                if name == component || name.split('@').next() == Some(component) {
+                    expected = components.next();
+                    if expected.is_none() {
+                        return Some(node);
+                    }
+                    depth += 1;
+                }
+            } else if node.depth < depth {
+                // We left the parent node without finding the component.
+                return None;
+            }
+        }
+
+        None
+
+    }
+
+    /// Internal function to get a nul-terminated string from the strings block.
+    fn string(&self, offset: usize) -> Option<&'static str> {
+        read_str(self.data, self.strings_offset + offset).map(|(s, _)| s)
+    }
+
+}
+
+
+/// An iterator over nodes of a device tree, depth-first.
+pub struct NodeIter<'a> {
+    tree: &'a DeviceTree,
+    /// Offset of the next token in the blob.
+    offset: usize,
+    /// Current depth, 0 before the root node.
+    depth: usize,
+    /// For each depth, the `#address-cells` and `#size-cells` that
+    /// apply to children of the node at this depth.
+    cells: [(u32, u32); MAX_DEPTH + 1],
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+
+        let data = self.tree.data;
+
+        loop {
+
+            let token = read_u32(data, self.offset)?;
+            self.offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+
+                    let (name, name_len) = read_str(data, self.offset)?;
+                    let props_offset = align4(self.offset + name_len + 1);
+
+                    if self.depth >= MAX_DEPTH {
+                        return None;
+                    }
+
+                    let node = Node {
+                        tree: self.tree,
+                        name,
+                        depth: self.depth,
+                        props_offset,
+                        address_cells: self.cells[self.depth].0,
+                        size_cells: self.cells[self.depth].1,
+                    };
+
+                    self.depth += 1;
+                    self.offset = props_offset;
+
+                    // Cells of children of this node, default values if not given.
+                    let address_cells = node.property("#address-cells").and_then(|p| p.u32()).unwrap_or(DEFAULT_ADDRESS_CELLS);
+                    let size_cells = node.property("#size-cells").and_then(|p| p.u32()).unwrap_or(DEFAULT_SIZE_CELLS);
+                    self.cells[self.depth] = (address_cells, size_cells);
+
+                    return Some(node);
+
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let len = read_u32(data, self.offset)? as usize;
+                    self.offset = align4(self.offset + 8 + len);
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                // Invalid token, stop iterating.
+                _ => return None,
+            }
+
+        }
+
+    }
+
+}
+
+
+/// A node of the device tree.
+#[derive(Debug, Clone, Copy)]
+pub struct Node<'a> {
+    tree: &'a DeviceTree,
+    /// Name of the node, including the unit address.
+    name: &'static str,
+    /// Depth of the node, 0 for the root node.
+    depth: usize,
+    /// Offset of the first token after the name of the node.
+    props_offset: usize,
+    /// The `#address-cells` of the parent node, used for `reg`.
+    address_cells: u32,
+    /// The `#size-cells` of the parent node, used for `reg`.
+    size_cells: u32,
+}
+
+impl<'a> Node<'a> {
+
+    /// Name of the node, including the unit address (`name@address`).
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Depth of the node, 0 for the root node.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Iterate over the children of this node, recursively (depth-first).
+    pub fn descendants(&self) -> impl Iterator<Item = Node<'a>> {
+        let props_offset = self.props_offset;
+        let depth = self.depth;
+        self.tree.nodes()
+            .skip_while(move |node| node.props_offset != props_offset)
+            .skip(1)
+            .take_while(move |node| node.depth > depth)
+    }
+
+    /// Iterate over all the properties of this node.
+    pub fn properties(&self) -> impl Iterator<Item = Property> + 'a {
+        let tree = self.tree;
+        let mut offset = self.props_offset;
+        core::iter::from_fn(move || {
+            loop {
+                let token = read_u32(tree.data, offset)?;
+                offset += 4;
+                match token {
+                    FDT_PROP => {
+                        let len = read_u32(tree.data, offset)? as usize;
+                        let name_offset = read_u32(tree.data, offset + 4)? as usize;
+                        let value = tree.data.get(offset + 8..offset + 8 + len)?;
+                        offset = align4(offset + 8 + len);
+                        return Some(Property {
+                            name: tree.string(name_offset)?,
+                            value,
+                        });
+                    }
+                    FDT_NOP => {}
+                    // Properties are always before children nodes.
+                    _ => return None,
+                }
+            }
+        })
+    }
+
+    /// Find a property of this node from its name.
+    pub fn property(&self, name: &str) -> Option<Property> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    /// Iterate over the strings of the `compatible` property.
+    pub fn compatible(&self) -> impl Iterator<Item = &'static str> {
+        self.property("compatible").into_iter().flat_map(|prop| prop.strings())
+    }
+
+    /// Return true if the given string is in the `compatible` property.
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    /// Return the `device_type` property, if any.
+    pub fn device_type(&self) -> Option<&'static str> {
+        self.property("device_type").and_then(|prop| prop.str())
+    }
+
+    /// Iterate over the `(address, size)` pairs of the `reg` property,
+    /// decoded with the cells defined by the parent node.
+    pub fn reg(&self) -> impl Iterator<Item = (u64, u64)> {
+        let address_cells = self.address_cells as usize;
+        let size_cells = self.size_cells as usize;
+        let value = self.property("reg").map(|prop| prop.value).unwrap_or(&[]);
+        let stride = (address_cells + size_cells) * 4;
+        value.chunks_exact(stride.max(4)).filter_map(move |chunk| {
+            Some((read_cells(chunk, address_cells)?, read_cells(&chunk[address_cells * 4..], size_cells)?))
+        })
+    }
+
+    /// Iterate over the cells of the `interrupts` property, for the
+    /// PLIC each cell is an interrupt source id.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> {
+        self.property("interrupts").into_iter().flat_map(|prop| prop.u32s())
+    }
+
+}
+
+
+/// A property of a node.
+#[derive(Debug, Clone, Copy)]
+pub struct Property {
+    /// Name of the property.
+    pub name: &'static str,
+    /// Raw value of the property.
+    pub value: &'static [u8],
+}
+
+impl Property {
+
+    /// Interpret the value as a single big-endian u32.
+    pub fn u32(&self) -> Option<u32> {
+        read_u32(self.value, 0)
+    }
+
+    /// Interpret the value as a single big-endian u64, or u32.
+    pub fn u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => self.u32().map(|v| v as u64),
+            _ => read_u64(self.value, 0),
+        }
+    }
+
+    /// Iterate over the value as big-endian u32 cells.
+    pub fn u32s(&self) -> impl Iterator<Item = u32> {
+        self.value.chunks_exact(4).map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+    }
+
+    /// Interpret the value as a single nul-terminated string.
+    pub fn str(&self) -> Option<&'static str> {
+        self.strings().next()
+    }
+
+    /// Interpret the value as a list of nul-terminated strings.
+    pub fn strings(&self) -> impl Iterator<Item = &'static str> {
+        let value = self.value.strip_suffix(&[0]).unwrap_or(self.value);
+        value.split(|b| *b == 0).filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+}
+
+
+/// Errors that can happen while parsing the device tree header.
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceTreeError {
+    /// The given pointer is null.
+    Null,
+    /// The magic number of the header is invalid.
+    InvalidMagic,
+    /// The device tree blob requires an unsupported version.
+    UnsupportedVersion,
+    /// The offsets of the header are out of the blob.
+    InvalidHeader,
+}
+
+
+#[inline]
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+#[inline]
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(offset..offset + 8)?.try_into().unwrap()))
+}
+
+/// Read a value encoded on the given number of cells (1 or 2).
+fn read_cells(data: &[u8], cells: usize) -> Option<u64> {
+    match cells {
+        0 => Some(0),
+        1 => read_u32(data, 0).map(|v| v as u64),
+        2 => read_u64(data, 0),
+        _ => None,
+    }
+}
+
+/// Read a nul-terminated string, returning it with its length.
+fn read_str(data: &'static [u8], offset: usize) -> Option<(&'static str, usize)> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+    Some((core::str::from_utf8(&bytes[..len]).ok()?, len))
+}
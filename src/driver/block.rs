@@ -1,67 +1,78 @@
 //! Core block device driver.
 
-use core::mem::{MaybeUninit, transmute};
+use core::mem::transmute;
 
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::filesystem::{FsError, FsResult};
+use crate::filesystem::sysfs::{self, SysFile};
 use crate::util::OpaqueCell;
 use crate::sync::Mutex;
+use crate::println;
 use super::Driver;
 
 
-/// Maximum number of block devices.
-/// 
-/// *This might not be the definitive form, but for now we have a fixed length.*
-pub const BLOCK_DEVICE_COUNT: usize = 256;
-
 /// Maximum length for the block device name.
 pub const BLOCK_DEVICE_NAME_SIZE: usize = 16;
 
 /// Allow 64 bytes of custom data for block devices.
 pub const BLOCK_DEVICE_DATA_SIZE: usize = 64;
 
+/// Maximum size of a sector supported when accessing bytes at any
+/// offset of block devices.
+pub const MAX_SECTOR_SIZE: u64 = 512;
+
 
 /// This driver must be used by other drivers to register block devices
 /// and their callbacks in order to provide a uniformized API to 
-/// higher-level storage drivers.
+/// higher-level storage drivers. Registered devices are exposed in the
+/// sysfs as `/sys/block/<name>`.
 pub struct BlockDriver {
-    /// Registered devices.
-    devices: Mutex<BlockDevices>,
-}
-
-/// Vector of block devices currently registered.
-struct BlockDevices {
-    devices: [MaybeUninit<BlockDevice>; BLOCK_DEVICE_COUNT],
-    len: usize,
+    /// Registered devices, they are never unregistered so they are
+    /// leaked to be shared with static lifetime.
+    devices: Mutex<Vec<&'static BlockDevice>>,
 }
 
 impl BlockDriver {
 
     pub const fn new() -> Self {
         Self {
-            devices: Mutex::new(BlockDevices {
-                devices: unsafe { MaybeUninit::uninit().assume_init() },
-                len: 0,
-            }),
+            devices: Mutex::new(Vec::new()),
         }
     }
 
     /// Register a new block device.
     pub fn register(&self, dev: BlockDevice) {
 
-        let mut borrow = self.devices.spin_lock();
+        let dev: &'static BlockDevice = Box::leak(Box::new(dev));
+        self.devices.lock().push(dev);
 
-        let len = borrow.len;
-        debug_assert_ne!(len, BLOCK_DEVICE_COUNT, "reached max number of block devices");
-        borrow.devices[len] = MaybeUninit::new(dev);
-        borrow.len = len + 1;
+        if let Err(e) = sysfs::register_file(&format!("block/{}", dev.name()), Arc::new(BlockFile(dev))) {
+            println!(" = Failed to expose block device {}: {:?}", dev.name(), e);
+        }
+
+    }
+
+    /// Get a registered block device from its name.
+    pub fn get(&self, name: &str) -> Option<&'static BlockDevice> {
+        self.devices.lock().iter().copied().find(|dev| dev.name() == name)
+    }
 
+    /// Get all the registered block devices, in registration order.
+    pub fn devices(&self) -> Vec<&'static BlockDevice> {
+        self.devices.lock().clone()
     }
 
 }
 
 impl Driver for BlockDriver {
 
-    fn load(&self) {
-        
+    fn load(&'static self) {
+        // Created even without devices, so it can be listed.
+        let _ = sysfs::create_dir("block");
     }
 
     fn unload(&self) {
@@ -85,6 +96,8 @@ pub struct BlockDevice {
     write: Option<fn(data: *const u8, src: &[u8], off: u64) -> BlockIoResult<()>>,
     /// The sector size of the device.
     sector_size: u64,
+    /// The size of the device in bytes.
+    size: u64,
 }
 
 impl BlockDevice {
@@ -92,7 +105,7 @@ impl BlockDevice {
     /// Construct a new block device with a custom data and 
     /// access callbacks. The custom data must be synchronizable
     /// between threads because read and writes can happen from
-    /// any thread.
+    /// any thread. The size of the device is given in bytes.
     /// 
     /// *The given name should not contains nul chars and 
     /// must be ascii.*
@@ -101,6 +114,7 @@ impl BlockDevice {
         read: fn(data: &D, dst: &mut [u8], off: u64) -> BlockIoResult<()>,
         write: Option<fn(data: &D, src: &[u8], off: u64) -> BlockIoResult<()>>,
         sector_size: u64,
+        size: u64,
     ) -> Self {
 
         // SAFETY: Here the transmutation is safe because &D as the same
@@ -111,7 +125,8 @@ impl BlockDevice {
             data: OpaqueCell::new(data),
             read: unsafe { transmute(read) },
             write: write.map(|write| unsafe { transmute(write) }),
-            sector_size
+            sector_size,
+            size,
         }
 
     }
@@ -131,6 +146,10 @@ impl BlockDevice {
         self.sector_size
     }
 
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
     pub fn read_only(&self) -> bool {
         self.write.is_none()
     }
@@ -147,6 +166,102 @@ impl BlockDevice {
         }
     }
 
+    /// Read bytes at any offset of the device, the sectors that are not
+    /// entirely read go through a buffer, others are directly read.
+    pub fn read_bytes(&self, mut dst: &mut [u8], mut off: u64) -> BlockIoResult<()> {
+        let sector_size = self.checked_sector_size()?;
+        while !dst.is_empty() {
+            let sector_offset = off % sector_size;
+            let len = if sector_offset == 0 && dst.len() as u64 >= sector_size {
+                let len = dst.len() - dst.len() % sector_size as usize;
+                self.read(&mut dst[..len], off)?;
+                len
+            } else {
+                let mut sector = [0; MAX_SECTOR_SIZE as usize];
+                let sector = &mut sector[..sector_size as usize];
+                self.read(sector, off - sector_offset)?;
+                let len = ((sector_size - sector_offset) as usize).min(dst.len());
+                dst[..len].copy_from_slice(&sector[sector_offset as usize..][..len]);
+                len
+            };
+            off += len as u64;
+            dst = &mut dst[len..];
+        }
+        Ok(())
+    }
+
+    /// Write bytes at any offset of the device, the sectors that are not
+    /// entirely written are read first.
+    pub fn write_bytes(&self, mut src: &[u8], mut off: u64) -> BlockIoResult<()> {
+        let sector_size = self.checked_sector_size()?;
+        while !src.is_empty() {
+            let sector_offset = off % sector_size;
+            let len = if sector_offset == 0 && src.len() as u64 >= sector_size {
+                let len = src.len() - src.len() % sector_size as usize;
+                self.write(&src[..len], off)?;
+                len
+            } else {
+                let mut sector = [0; MAX_SECTOR_SIZE as usize];
+                let sector = &mut sector[..sector_size as usize];
+                self.read(sector, off - sector_offset)?;
+                let len = ((sector_size - sector_offset) as usize).min(src.len());
+                sector[sector_offset as usize..][..len].copy_from_slice(&src[..len]);
+                self.write(sector, off - sector_offset)?;
+                len
+            };
+            off += len as u64;
+            src = &src[len..];
+        }
+        Ok(())
+    }
+
+    /// Internal function to get the sector size, if supported by the 
+    /// byte access functions.
+    fn checked_sector_size(&self) -> BlockIoResult<u64> {
+        if self.sector_size == 0 || self.sector_size > MAX_SECTOR_SIZE {
+            Err(BlockIoError::Unsupported)
+        } else {
+            Ok(self.sector_size)
+        }
+    }
+
+}
+
+
+/// A block device exposed in the sysfs, it's read and written at any
+/// offset, within the size of the device.
+struct BlockFile(&'static BlockDevice);
+
+impl SysFile for BlockFile {
+
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let len = buf.len().min(self.0.size().saturating_sub(offset) as usize);
+        self.0.read_bytes(&mut buf[..len], offset).map_err(io_error)?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let len = buf.len().min(self.0.size().saturating_sub(offset) as usize);
+        if len == 0 && !buf.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        self.0.write_bytes(&buf[..len], offset).map_err(io_error)?;
+        Ok(len)
+    }
+
+}
+
+/// Internal function to convert a block device error.
+fn io_error(e: BlockIoError) -> FsError {
+    match e {
+        BlockIoError::ReadOnly => FsError::ReadOnly,
+        BlockIoError::Unsupported => FsError::Unsupported,
+        _ => FsError::Io,
+    }
 }
 
 
@@ -159,6 +274,12 @@ pub enum BlockIoError {
     ReadOnly,
     /// The given offset is not aligned to a sector of the block device.
     UnalignedOffset,
+    /// The given buffer length is not a multiple of the sector size.
+    UnalignedLength,
+    /// The device reported an I/O error for the operation.
+    Device,
+    /// The operation is not supported by the device.
+    Unsupported,
     /// Internal error of the backend of the block device.
     Internal,
 }
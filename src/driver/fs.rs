@@ -0,0 +1,109 @@
+//! Core filesystem driver.
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::filesystem::{FileSystem, FsError, FsResult, mount};
+use crate::sync::Mutex;
+use crate::println;
+
+use super::block::BlockDevice;
+use super::{BlockDriver, Driver};
+
+
+/// Directory where block devices are automatically mounted, each one
+/// in a directory named after the device.
+pub const AUTO_MOUNT_DIR: &str = "/mnt";
+
+
+/// A type of filesystem stored on block devices.
+pub struct FileSystemType {
+    /// Name of the type, like `fat` or `ext2`.
+    pub name: &'static str,
+    /// Create the filesystem stored on the given block device, none if
+    /// the device doesn't contain this type of filesystem.
+    pub probe: fn(dev: &'static BlockDevice) -> FsResult<Option<Arc<dyn FileSystem>>>,
+}
+
+
+/// This driver must be used by filesystem drivers to register the types
+/// of filesystems they support. The block devices of the block driver
+/// are probed with each type registered, and automatically mounted in
+/// [`AUTO_MOUNT_DIR`] if recognized.
+pub struct FsDriver {
+    /// Registered filesystem types.
+    types: Mutex<Vec<&'static FileSystemType>>,
+    /// If the block driver is specified, its devices will be mounted.
+    block_driver: Option<&'static BlockDriver>,
+}
+
+impl FsDriver {
+
+    pub const fn new() -> Self {
+        Self {
+            types: Mutex::new(Vec::new()),
+            block_driver: None,
+        }
+    }
+
+    /// Enable automatic mount of the block devices of the given driver.
+    pub const fn with_block(mut self, block_driver: &'static BlockDriver) -> Self {
+        self.block_driver = Some(block_driver);
+        self
+    }
+
+    /// Register a new filesystem type, the block devices that are not
+    /// yet mounted are probed with it.
+    pub fn register(&self, typ: &'static FileSystemType) {
+
+        self.types.lock().push(typ);
+
+        let Some(block_driver) = self.block_driver else { return };
+        for dev in block_driver.devices() {
+            if !mount::is_mounted(dev.name()) {
+                let path = format!("{}/{}", AUTO_MOUNT_DIR, dev.name());
+                match self.mount_with(dev, typ, &path) {
+                    Ok(true) => println!(" = Mounted {} ({}) at {}", dev.name(), typ.name, path),
+                    Ok(false) => {}
+                    Err(e) => println!(" = Failed to mount {} ({}): {:?}", dev.name(), typ.name, e),
+                }
+            }
+        }
+
+    }
+
+    /// Mount the given block device at the given path, the filesystem
+    /// type is the given one or the first one recognizing the device.
+    pub fn mount(&self, dev: &'static BlockDevice, path: &str, type_name: Option<&str>) -> FsResult<()> {
+        let types = self.types.lock().clone();
+        for typ in types {
+            if type_name.map_or(true, |name| name == typ.name) && self.mount_with(dev, typ, path)? {
+                return Ok(());
+            }
+        }
+        Err(FsError::Unsupported)
+    }
+
+    /// Internal function to mount the block device if recognized by the
+    /// given type, return false if not recognized.
+    fn mount_with(&self, dev: &'static BlockDevice, typ: &FileSystemType, path: &str) -> FsResult<bool> {
+        match (typ.probe)(dev)? {
+            Some(fs) => mount::mount(path, dev.name(), fs).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+}
+
+impl Driver for FsDriver {
+
+    fn load(&'static self) {
+
+    }
+
+    fn unload(&self) {
+
+    }
+
+}
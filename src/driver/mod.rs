@@ -2,16 +2,21 @@
 
 pub mod virtio;
 pub mod block;
+pub mod fs;
 
 pub use virtio::VirtioDriver;
 pub use block::BlockDriver;
+pub use fs::FsDriver;
+pub use crate::filesystem::fat::FatDriver;
+pub use crate::filesystem::ext2::Ext2Driver;
 
 
 /// Definition of a driver and it's callbacks.
 pub trait Driver: Sync {
     
-    /// Called once when the driver is loaded.
-    fn load(&self);
+    /// Called once when the driver is loaded. Drivers are static,
+    /// so they can register references to themselves.
+    fn load(&'static self);
 
     /// Called once when the driver is unloaded.
     fn unload(&self);
@@ -4,16 +4,23 @@
 //! 
 //! [`official specification`]: https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.pdf
 
-use core::ptr::{NonNull, addr_of};
-use core::cell::{RefCell, Cell};
+use core::ptr::{NonNull, addr_of, addr_of_mut};
+use core::sync::atomic::{fence, Ordering};
 use core::num::NonZeroUsize;
 use core::mem::size_of;
 
+use alloc::format;
+use alloc::sync::Arc;
+
 use bitflags::bitflags;
 
-use crate::memory::page::{PAGE_SIZE, alloc_zeroed, alloc};
-use crate::{println, print, write_slice, mmio_struct};
-use crate::sync::Mutex;
+use crate::filesystem::sysfs::{self, TextFile};
+use crate::memory::page::{PAGE_SIZE, alloc_zeroed};
+use crate::{println, print, write_slice, mmio_struct, devicetree};
+use crate::cpu::mstatus;
+use crate::interrupt::plic;
+use crate::sync::{Mutex, RwLock};
+use crate::process;
 
 use super::{Driver, BlockDriver};
 use super::block::{BlockDevice, BlockIoResult, BlockIoError};
@@ -28,25 +35,42 @@ const VIRTIO_QUEUE_SIZE: u32 = 1 << 7;
 /// Sector size for virtio block devices.
 const VIRTIO_BLOCK_SECTOR_SIZE: u64 = 512;
 
+/// Compatible string of virtio devices in the device tree.
+const VIRTIO_COMPATIBLE: &str = "virtio,mmio";
+
+/// Address of the first port of QEMU's virt machine, used if there is
+/// no device tree to discover ports.
+const VIRTIO_DEFAULT_ADDR: usize = 0x1000_1000;
+
+/// Stride between ports of QEMU's virt machine.
+const VIRTIO_DEFAULT_STRIDE: usize = 0x1000;
+
+/// Interrupt source id of the first port of QEMU's virt machine, the 
+/// next ports use consecutive ids.
+const VIRTIO_DEFAULT_IRQ: u8 = 1;
+
 
 /// Use this driver to provide virtio discovery capabilities.
-/// The address, stride and number of ports must be know at
-/// compile-time.
-pub struct VirtioDriver<const ADDR: usize, const STRIDE: usize, const COUNT: usize> {
+/// The ports are discovered from the device tree, the maximum
+/// number of ports must be know at compile-time. Information on
+/// connected devices is exposed in the sysfs, in `/sys/virtio/<name>`.
+pub struct VirtioDriver<const COUNT: usize> {
     /// Exhaustive list of all devices for all ports (connected or not).
-    devices: RefCell<[Option<Device>; COUNT]>,
+    devices: RwLock<[Option<Device>; COUNT]>,
+    /// Data of the loaded block devices, for all ports.
+    blocks: [BlockDeviceSlot; COUNT],
     /// If the block driver is specified, block devices will be initialized.
     block_driver: Option<&'static BlockDriver>,
 }
 
-unsafe impl<const ADDR: usize, const STRIDE: usize, const COUNT: usize> Sync for VirtioDriver<ADDR, STRIDE, COUNT> {}
-
-impl<const ADDR: usize, const STRIDE: usize, const COUNT: usize> VirtioDriver<ADDR, STRIDE, COUNT> {
+impl<const COUNT: usize> VirtioDriver<COUNT> {
     
     /// Create the virtio driver.
     pub const fn new() -> Self {
+        const EMPTY_BLOCK: BlockDeviceSlot = Mutex::new(None);
         Self {
-            devices: RefCell::new([None; COUNT]),
+            devices: RwLock::new([None; COUNT]),
+            blocks: [EMPTY_BLOCK; COUNT],
             block_driver: None,
         }
     }
@@ -60,7 +84,7 @@ impl<const ADDR: usize, const STRIDE: usize, const COUNT: usize> VirtioDriver<AD
     
     /// Iterate over connected devices.
     pub fn iter(&self) -> impl Iterator<Item = Device> + '_ {
-        let devices = self.devices.borrow();
+        let devices = self.devices.read();
         (0..COUNT).filter_map(move |idx| devices[idx])
     }
 
@@ -69,17 +93,50 @@ impl<const ADDR: usize, const STRIDE: usize, const COUNT: usize> VirtioDriver<AD
         self.iter().filter(move |dev| dev.typ == typ)
     }
 
+    /// Discover the address and interrupt source id of each port, the
+    /// ports are sorted by address so indices are stable across boots.
+    fn discover_ports() -> [Option<(usize, u8)>; COUNT] {
+
+        let mut ports = [None; COUNT];
+
+        if let Some(tree) = devicetree::get() {
+            let mut len = 0;
+            for node in tree.find_compatible(VIRTIO_COMPATIBLE) {
+                if len == COUNT {
+                    println!(" = Too many ports, only {} are supported", COUNT);
+                    break;
+                }
+                if let (Some((addr, _)), Some(irq)) = (node.reg().next(), node.interrupts().next()) {
+                    ports[len] = Some((addr as usize, irq as u8));
+                    len += 1;
+                }
+            }
+            ports[..len].sort_unstable_by_key(|port| port.map(|(addr, _)| addr));
+        } else {
+            for (idx, port) in ports.iter_mut().enumerate() {
+                *port = Some((VIRTIO_DEFAULT_ADDR + idx * VIRTIO_DEFAULT_STRIDE, VIRTIO_DEFAULT_IRQ + idx as u8));
+            }
+        }
+
+        ports
+
+    }
+
 }
 
-impl<const ADDR: usize, const STRIDE: usize, const COUNT: usize> Driver for VirtioDriver<ADDR, STRIDE, COUNT> {
+impl<const COUNT: usize> Driver for VirtioDriver<COUNT> {
 
-    fn load(&self) {
+    fn load(&'static self) {
 
         println!("== Loading VirtIO");
         
-        for idx in 0..COUNT {
+        for (idx, port) in Self::discover_ports().into_iter().enumerate() {
+
+            let (addr, irq) = match port {
+                Some(port) => port,
+                None => break,
+            };
 
-            let addr = ADDR + idx * STRIDE;
             print!(" = Probing device #{} at {:08X}: ", idx, addr);
 
             let dev = MmioDevice(addr as _);
@@ -110,19 +167,21 @@ impl<const ADDR: usize, const STRIDE: usize, const COUNT: usize> Driver for Virt
             let dev = Device {
                 idx,
                 mmio: dev,
+                irq,
                 typ,
             };
 
             match typ {
                 DeviceType::Block => {
                     if let Some(block_driver) = self.block_driver {
-                        load_block_device(block_driver, &dev);
+                        load_block_device(block_driver, &dev, &self.blocks[idx]);
                     }
                 }
                 _ => {}
             }
 
-            self.devices.borrow_mut()[idx] = Some(dev);
+            expose_device(&dev, addr);
+            self.devices.write()[idx] = Some(dev);
             
         }
 
@@ -135,6 +194,25 @@ impl<const ADDR: usize, const STRIDE: usize, const COUNT: usize> Driver for Virt
 }
 
 
+/// Expose information on the device in the sysfs, in a directory named
+/// after its index, with a file for each information.
+fn expose_device(dev: &Device, addr: usize) {
+    let infos = [
+        ("type", format!("{:?}\n", dev.typ)),
+        ("version", format!("{}\n", dev.mmio.version())),
+        ("vendor", format!("0x{:08X}\n", dev.mmio.vendor_id())),
+        ("addr", format!("0x{:08X}\n", addr)),
+        ("irq", format!("{}\n", dev.irq)),
+    ];
+    for (name, content) in infos {
+        let path = format!("virtio/virtio{:02}/{}", dev.idx, name);
+        if let Err(e) = sysfs::register_file(&path, Arc::new(TextFile::new(content))) {
+            println!("   Failed to expose {}: {:?}", path, e);
+        }
+    }
+}
+
+
 /// Enumeration of some of the possible device types.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceType {
@@ -155,6 +233,8 @@ pub struct Device {
     pub idx: usize,
     /// Memory-mapped I/O registers of the device.
     pub mmio: MmioDevice,
+    /// Interrupt source id of the device.
+    pub irq: u8,
     /// Device type.
     pub typ: DeviceType,
 }
@@ -223,6 +303,7 @@ pub struct QueueUsed<const SIZE: usize> {
 }
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct QueueUsedElement {
     /// Index of start of used descriptor chain.
     pub id: u32,
@@ -232,13 +313,15 @@ pub struct QueueUsedElement {
 }
 
 
-/// Request structure for block device.
+/// Request structure for block device, the data buffer is given
+/// by the caller in its own descriptor.
 #[repr(C)]
 pub struct BlockRequest {
     pub header: BlockRequestHeader,
-    pub data: *mut u8,
     /// Written by the device, must be interpreted with [`BlockRequestStatus`].
     pub status: u8,
+    /// Set by the driver when the device has used the request.
+    pub done: bool,
 }
 
 #[repr(C)]
@@ -459,8 +542,9 @@ mmio_struct! {
 }
 
 
-/// This structure handles a virtio queue (allocated in pages) 
-/// and tracks the index of the last item appended to the queue.
+/// This structure handles a virtio queue (allocated in pages),
+/// it tracks the free descriptors in a free list and the index
+/// of the last used element consumed.
 /// 
 /// This structure is intentionnaly not thread-safe (Sync), 
 /// therefore you must use it through a mutex, do all the
@@ -469,8 +553,13 @@ mmio_struct! {
 pub struct QueueHandler<const SIZE: usize = {VIRTIO_QUEUE_SIZE as usize}> {
     /// The actual queue pointer.
     queue: NonNull<Queue<SIZE>>,
-    /// The index of the last inserted item.
-    index: u16,
+    /// Index of the first free descriptor, the free descriptors are
+    /// linked together through their `next` field.
+    free_head: u16,
+    /// Number of free descriptors in the free list.
+    free_count: u16,
+    /// The index of the next used element to consume in the used ring.
+    used_index: u16,
 }
 
 impl<const SIZE: usize> QueueHandler<SIZE> {
@@ -489,9 +578,16 @@ impl<const SIZE: usize> QueueHandler<SIZE> {
             }
         };
 
+        // Initially, all descriptors are linked in the free list.
+        for (index, descriptor) in unsafe { (*queue.as_ptr()).descriptor.iter_mut().enumerate() } {
+            descriptor.next = (index + 1) as u16;
+        }
+
         Ok(Self {
             queue,
-            index: 0,
+            free_head: 0,
+            free_count: SIZE as u16,
+            used_index: 0,
         })
 
     }
@@ -511,13 +607,34 @@ impl<const SIZE: usize> QueueHandler<SIZE> {
         (self.queue.addr().get() / PAGE_SIZE) as u32
     }
 
+    /// Return the number of free descriptors, the caller must check 
+    /// that there is enough free descriptors before appending a chain.
+    #[inline]
+    pub fn free_count(&self) -> usize {
+        self.free_count as usize
+    }
+
+    /// Return the index of the descriptor that will be used by the 
+    /// next call to [`append`](Self::append), if any descriptor is free.
+    #[inline]
+    pub fn free_head(&self) -> Option<u16> {
+        (self.free_count != 0).then_some(self.free_head)
+    }
+
+    /// Take a free descriptor and start a new descriptor chain with it.
+    /// 
+    /// **This function panics if there is no free descriptor.**
     pub fn append<'a, 'b: 'a>(&'a mut self, descriptor: QueueDescriptor) -> QueueHandlerNext<'a, 'b, SIZE> {
         
-        let index = ((self.index as u32 + 1) % SIZE as u32) as u16;
-        self.index = index;
+        assert_ne!(self.free_count, 0, "no free descriptor in the queue");
 
         // Note: the reference here has an unbound lifetime.
         let queue = unsafe { self.queue.as_mut() };
+
+        let index = self.free_head;
+        self.free_head = queue.descriptor[index as usize].next;
+        self.free_count -= 1;
+
         queue.descriptor[index as usize] = descriptor;
 
         let queue = &mut queue.descriptor[index as usize];
@@ -537,10 +654,54 @@ impl<const SIZE: usize> QueueHandler<SIZE> {
 
     /// Mark the given descriptor index has available for the device.
     pub fn mark_available(&mut self, head_index: u16) {
+        let queue = self.queue.as_ptr();
+        unsafe {
+            // The available index is free-running, the device takes it
+            // modulo the queue size.
+            let index = addr_of!((*queue).available.index).read_volatile();
+            (*queue).available.ring[index as usize % SIZE] = head_index;
+            // The ring entry must be visible before the index update.
+            fence(Ordering::SeqCst);
+            addr_of_mut!((*queue).available.index).write_volatile(index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Put back all the descriptors of the chain starting at the given
+    /// head index in the free list. This must be called once the chain 
+    /// has been used by the device and its buffers are no longer needed.
+    pub fn free_chain(&mut self, head_index: u16) {
         let queue = unsafe { self.queue.as_mut() };
-        let index = queue.available.index;
-        queue.available.ring[index as usize] = head_index;
-        queue.available.index = ((index as u32 + 1) % SIZE as u32) as u16;
+        let mut index = head_index;
+        loop {
+            let descriptor = &mut queue.descriptor[index as usize];
+            let has_next = descriptor.flags & QueueDescriptorFlag::NEXT.bits() != 0;
+            let next = descriptor.next;
+            descriptor.flags = 0;
+            descriptor.next = self.free_head;
+            self.free_head = index;
+            self.free_count += 1;
+            if !has_next {
+                break;
+            }
+            index = next;
+        }
+    }
+
+    /// Consume the next element of the used ring, if the device has 
+    /// used any new descriptor chain since the last call.
+    pub fn pop_used(&mut self) -> Option<QueueUsedElement> {
+        let queue = self.queue.as_ptr();
+        unsafe {
+            if addr_of!((*queue).used.index).read_volatile() == self.used_index {
+                return None;
+            }
+            // The used element must be read after the index.
+            fence(Ordering::SeqCst);
+            let element = addr_of!((*queue).used.ring[self.used_index as usize % SIZE]).read_volatile();
+            self.used_index = self.used_index.wrapping_add(1);
+            Some(element)
+        }
     }
 
 }
@@ -561,12 +722,12 @@ pub struct QueueHandlerNext<'a, 'b: 'a, const SIZE: usize> {
 
 impl<'a, 'b: 'a, const SIZE: usize> QueueHandlerNext<'a, 'b, SIZE> {
 
-    pub fn next<'b_: 'a>(mut self, descriptor: QueueDescriptor) -> QueueHandlerNext<'a, 'b_, SIZE> {
+    pub fn next<'b_: 'a>(self, descriptor: QueueDescriptor) -> QueueHandlerNext<'a, 'b_, SIZE> {
         let mut next = self.handler.append(descriptor);
         // The head index should not change over calls to 'next'.
         next.head_index = self.head_index;
         self.prev.flags |= QueueDescriptorFlag::NEXT.bits();
-        self.prev.next = self.index;
+        self.prev.next = next.index;
         next
     }
 
@@ -593,11 +754,36 @@ impl<'a, 'b: 'a, const SIZE: usize> QueueHandlerNext<'a, 'b, SIZE> {
 pub struct BlockDeviceData {
     pub mmio: MmioLegacyDevice,
     pub queue: QueueHandler,
+    /// Preallocated pool of requests, indexed by the head index of their
+    /// descriptor chain. Each descriptor chain owns its request until freed.
+    pub requests: NonNull<[BlockRequest; VIRTIO_QUEUE_SIZE as usize]>,
+}
+
+impl BlockDeviceData {
+
+    /// Pages needed to store the requests pool.
+    const REQUESTS_PAGES_COUNT: usize = (size_of::<[BlockRequest; VIRTIO_QUEUE_SIZE as usize]>() + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    /// Get a pointer to the request owned by the given head index.
+    #[inline]
+    fn request(&self, head_index: u16) -> *mut BlockRequest {
+        unsafe { addr_of_mut!((*self.requests.as_ptr())[head_index as usize]) }
+    }
+
 }
 
+/// The slot for a block device, stored by the [`VirtioDriver`] for each
+/// port. We put the device data in a mutex because we need to access it 
+/// safely accross threads and from the interrupt handler.
+type BlockDeviceSlot = Mutex<Option<BlockDeviceData>>;
+
+/// Number of descriptors needed for a block request: header, data
+/// buffer and status.
+const BLOCK_REQUEST_DESCRIPTORS: usize = 3;
+
 
 /// Called to load a block device.
-fn load_block_device(block_driver: &BlockDriver, dev: &Device) {
+fn load_block_device(block_driver: &BlockDriver, dev: &Device, slot: &'static BlockDeviceSlot) {
 
     let dev_version = dev.mmio.version();
     if dev_version != 1 {
@@ -651,76 +837,178 @@ fn load_block_device(block_driver: &BlockDriver, dev: &Device) {
         }
     };
 
+    // SAFETY: Same as the queue allocation above.
+    let requests = unsafe {
+        match alloc_zeroed(NonZeroUsize::new_unchecked(BlockDeviceData::REQUESTS_PAGES_COUNT)) {
+            Ok(ptr) => ptr.cast(),
+            Err(_) => {
+                println!("   Failed requests allocation");
+                return;
+            }
+        }
+    };
+
     mmio.set_queue_num(queue.size());
     mmio.set_guest_page_size(queue.page_size());
     mmio.set_queue_physical_page_number(queue.page_number());
 
+    // Construct our block device data, the interrupt handler is
+    // registered before the driver is marked as operational.
+    *slot.spin_lock() = Some(BlockDeviceData {
+        mmio,
+        queue,
+        requests,
+    });
+
+    unsafe { plic::register(dev.irq, 1, handle_block_interrupt, slot); }
+
     // 8. Our driver is operationnal!
     status |= DeviceStatus::DRIVER_OK;
     mmio.set_status(status.bits());
 
     // Note that capacity is expressed in number of 512-bytes sectors.
-    println!("   Capacity of {} bytes", config.capacity() * VIRTIO_BLOCK_SECTOR_SIZE);
-    
-    // Construct our block device data for registering it to the block driver.
-    // We put the device data in a mutex because we need to access it safely
-    // accross threads.
-    let dev_data = Mutex::new(BlockDeviceData {
-        mmio,
-        queue,
-    });
+    let size = config.capacity() * VIRTIO_BLOCK_SECTOR_SIZE;
+    println!("   Capacity of {} bytes", size);
 
-    fn do_read(data: &Mutex<BlockDeviceData>, dst: &mut [u8], off: u64) -> BlockIoResult<()> {
-        do_block_operation(data, dst.as_mut_ptr(), dst.len(), off, false)
+    fn do_read(slot: &&'static BlockDeviceSlot, dst: &mut [u8], off: u64) -> BlockIoResult<()> {
+        do_block_operation(*slot, dst.as_mut_ptr(), dst.len(), off, false)
     }
 
-    fn do_write(data: &Mutex<BlockDeviceData>, src: &[u8], off: u64) -> BlockIoResult<()> {
-        do_block_operation(data, src.as_ptr() as _, src.len(), off, true)
+    fn do_write(slot: &&'static BlockDeviceSlot, src: &[u8], off: u64) -> BlockIoResult<()> {
+        do_block_operation(*slot, src.as_ptr() as _, src.len(), off, true)
     }
 
-    let mut block_dev = BlockDevice::new(dev_data, do_read, (!read_only).then_some(do_write), VIRTIO_BLOCK_SECTOR_SIZE);
+    let mut block_dev = BlockDevice::new(slot, do_read, (!read_only).then_some(do_write), VIRTIO_BLOCK_SECTOR_SIZE, size);
     write_slice!(block_dev.raw_name_mut(), "virtio{:02}", dev.idx).unwrap();
     block_driver.register(block_dev);
 
 }
 
 
-fn do_block_operation(data: &Mutex<BlockDeviceData>, buf: *mut u8, len: usize, off: u64, write: bool) -> BlockIoResult<()> {
+/// Interrupt handler for block devices, pending requests that have
+/// been completed by the device are marked as done and the processes
+/// waiting for them are woken up. The descriptors are freed by the 
+/// waiting processes once they have read the status.
+fn handle_block_interrupt(slot: &'static BlockDeviceSlot, _id: u8) {
+
+    // Processes are woken up once the lock is released, because the
+    // lock is also taken by the wait conditions of the processes.
+    let mut completed = [0usize; VIRTIO_QUEUE_SIZE as usize];
+    let mut completed_count = 0;
+
+    {
+        // We are in interrupt context, so the lock is never held by 
+        // the interrupted code (interrupts are disabled while held).
+        let mut data = slot.spin_lock();
+        let data = match data.as_mut() {
+            Some(data) => data,
+            None => return,
+        };
+
+        // Acknowledge the interrupt before consuming the used ring, so
+        // any completion after this point will raise a new interrupt.
+        data.mmio.set_interrupt_ack(data.mmio.interrupt_status());
+
+        while let Some(element) = data.queue.pop_used() {
+            if element.id < VIRTIO_QUEUE_SIZE {
+                let request = data.request(element.id as u16);
+                unsafe { addr_of_mut!((*request).done).write_volatile(true); }
+                completed[completed_count] = request.addr();
+                completed_count += 1;
+            }
+        }
+    }
+
+    for &chan in &completed[..completed_count] {
+        process::wake(chan);
+    }
+
+}
+
+
+fn do_block_operation(slot: &'static BlockDeviceSlot, buf: *mut u8, len: usize, off: u64, write: bool) -> BlockIoResult<()> {
 
-    // Lock for the whole operation.
-    let mut data = data.spin_lock();
+    if off % VIRTIO_BLOCK_SECTOR_SIZE != 0 {
+        return Err(BlockIoError::UnalignedOffset);
+    } else if len as u64 % VIRTIO_BLOCK_SECTOR_SIZE != 0 {
+        return Err(BlockIoError::UnalignedLength);
+    }
 
     // Sectors are 512 bytes 
     let sector = off / VIRTIO_BLOCK_SECTOR_SIZE;
 
-    // Allocate a temporary request structure that take an entire page.
-    // FIXME: In the future, improve the allocation strategy.
-    let mut block_request: NonNull<BlockRequest> = unsafe {
-        alloc(NonZeroUsize::new_unchecked(size_of::<BlockRequest>())).map_err(|_| BlockIoError::Internal)?.cast()
-    };
+    // The lock is also taken by the interrupt handler, so interrupts
+    // are disabled while we hold it, only for submitting the request.
+    let submit = || mstatus::without_interrupts(|| {
 
-    // SAFETY: We own the only pointer to request, so the following mut ref
-    // is legal for the rest of the function.
-    let block_request = unsafe { block_request.as_mut() };
+        let mut data = slot.spin_lock();
+        let data = data.as_mut().ok_or(BlockIoError::Internal)?;
 
-    // Fill 
-    block_request.header.sector = sector;
-    block_request.header.typ = if write { BlockRequestType::Out } else { BlockRequestType::In } as _;
-    block_request.header.reserved = 0;
-    block_request.data = buf;
-    block_request.status = 111;
+        if data.queue.free_count() < BLOCK_REQUEST_DESCRIPTORS {
+            return Ok(None);
+        }
 
-    let head_index = data.queue
-        .append(QueueDescriptor::new(addr_of!(block_request.header).addr() as u64, size_of::<BlockRequestHeader>() as u32, false))
-        .next(QueueDescriptor::new(buf.addr() as u64, len as u32, !write)) // TODO: Check !write
-        .next(QueueDescriptor::new(addr_of!(block_request.status).addr() as u64, 1, true))
-        .head_index();
+        // The request used is the one of the head descriptor of the chain.
+        let index = data.queue.free_head().ok_or(BlockIoError::Internal)?;
+        let request = data.request(index);
+
+        // SAFETY: The descriptor is free, so we own the request until it
+        // is submitted, the following mut ref is legal.
+        let block_request = unsafe { &mut *request };
+
+        // Fill 
+        block_request.header.sector = sector;
+        block_request.header.typ = if write { BlockRequestType::Out } else { BlockRequestType::In } as _;
+        block_request.header.reserved = 0;
+        block_request.status = 111;
+        block_request.done = false;
+
+        let head_index = data.queue
+            .append(QueueDescriptor::new(addr_of!(block_request.header).addr() as u64, size_of::<BlockRequestHeader>() as u32, false))
+            .next(QueueDescriptor::new(buf.addr() as u64, len as u32, !write))
+            .next(QueueDescriptor::new(addr_of!(block_request.status).addr() as u64, 1, true))
+            .head_index();
+
+        debug_assert_eq!(head_index, index);
+        data.queue.mark_available(head_index);
+        
+        // Notify the queue 0 as it is the only one used.
+        data.mmio.set_queue_notify(0);
 
-    data.queue.mark_available(head_index);
-    
-    // Notify the queue 0 as it is the only one used.
-    data.mmio.set_queue_notify(0);
+        Ok(Some((head_index, request)))
 
-    Ok(())
+    });
+
+    // If the queue is full, wait for another operation to free descriptors.
+    let (head_index, request) = loop {
+        if let Some(submitted) = submit()? {
+            break submitted;
+        }
+        process::sleep_while(slot as *const BlockDeviceSlot as usize, || {
+            slot.spin_lock().as_ref().map_or(false, |data| data.queue.free_count() < BLOCK_REQUEST_DESCRIPTORS)
+        });
+    };
+
+    // Block until the interrupt handler marks the request as done.
+    process::sleep_while(request.addr(), || unsafe {
+        !addr_of!((*request).done).read_volatile()
+    });
+
+    let status = unsafe { addr_of!((*request).status).read_volatile() };
+
+    // Now that the status is read, the descriptors (and the request) can be reused.
+    mstatus::without_interrupts(|| {
+        if let Some(data) = slot.spin_lock().as_mut() {
+            data.queue.free_chain(head_index);
+        }
+    });
+    process::wake(slot as *const BlockDeviceSlot as usize);
+
+    match status {
+        s if s == BlockRequestStatus::Ok as u8 => Ok(()),
+        s if s == BlockRequestStatus::IoError as u8 => Err(BlockIoError::Device),
+        s if s == BlockRequestStatus::Unsupported as u8 => Err(BlockIoError::Unsupported),
+        _ => Err(BlockIoError::Internal),
+    }
 
 }
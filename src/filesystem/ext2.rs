@@ -0,0 +1,1329 @@
+//! ext2 filesystem driver, over block devices.
+//!
+//! Files, directories, symbolic links and hard links are supported,
+//! with their permissions. Blocks are addressed through the direct and
+//! indirect blocks of inodes, and allocated with the bitmaps of block
+//! groups. All modifications are directly written to the device.
+//!
+//! Filesystems with incompatible features are not mounted, and the ones
+//! with unknown read-only compatible features are mounted read-only.
+//! There is no clock in the kernel, so timestamps are not updated.
+//!
+//! Specification: https://www.nongnu.org/ext2-doc/ext2.html
+
+use core::any::Any;
+
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use alloc::vec;
+
+use crate::driver::block::{BlockDevice, BlockIoError};
+use crate::driver::fs::{FileSystemType, FsDriver};
+use crate::driver::Driver;
+use crate::sync::Mutex;
+use crate::println;
+
+use super::{DirEntry, FileSystem, FsError, FsResult, Inode, InodeKind, Metadata};
+
+
+/// Offset of the superblock from the start of the device.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+/// Size of the superblock.
+const SUPERBLOCK_SIZE: usize = 1024;
+/// Magic signature of the superblock.
+const MAGIC: u16 = 0xEF53;
+
+/// Size of block group descriptors.
+const GROUP_DESC_SIZE: u64 = 32;
+/// Size of the part of inodes that is used, larger inodes have extra
+/// fields that are kept as-is.
+const INODE_SIZE: usize = 128;
+
+/// Inode of the root directory.
+const ROOT_INO: u32 = 2;
+/// First inode that is not reserved, for revision 0.
+const GOOD_OLD_FIRST_INO: u32 = 11;
+
+/// Number of direct blocks of inodes, followed by the indirect, doubly
+/// indirect and triply indirect blocks.
+const DIRECT_BLOCKS: u64 = 12;
+
+/// Incompatible feature: directory entries store the type of inodes.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Read-only compatible features: sparse superblocks, large files and
+/// B-tree directories, which are readable as linear directories.
+const RO_COMPAT_SUPPORTED: u32 = 0x0001 | 0x0002 | 0x0004;
+/// Read-only compatible feature: large files.
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+/// Flag of inodes of directories indexed with a hash tree, it's removed
+/// when the directory is modified because the index is not updated.
+const INDEX_FL: u32 = 0x1000;
+
+/// Types of inodes, in their mode.
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+
+/// Types of inodes, in directory entries.
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+/// Size of the block array of inodes, symbolic links with a shorter
+/// target are stored in it.
+const FAST_SYMLINK_SIZE: usize = 60;
+
+
+/// The ext2 filesystem type, registered by the [`Ext2Driver`].
+static EXT2_TYPE: FileSystemType = FileSystemType {
+    name: "ext2",
+    probe,
+};
+
+
+/// This driver registers the ext2 filesystem type, block devices with
+/// an ext2 filesystem are then mounted by the filesystem driver.
+pub struct Ext2Driver {
+    fs_driver: &'static FsDriver,
+}
+
+impl Ext2Driver {
+
+    pub const fn new(fs_driver: &'static FsDriver) -> Self {
+        Self { fs_driver }
+    }
+
+}
+
+impl Driver for Ext2Driver {
+
+    fn load(&'static self) {
+        println!("== Loading ext2");
+        self.fs_driver.register(&EXT2_TYPE);
+    }
+
+    fn unload(&self) {
+
+    }
+
+}
+
+
+/// A mounted ext2 filesystem.
+pub struct Ext2Fs {
+    dev: &'static BlockDevice,
+    block_size: u64,
+    inode_size: u64,
+    blocks_count: u32,
+    inodes_count: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    first_data_block: u32,
+    first_ino: u32,
+    /// Last write time of the superblock, used as deletion time.
+    write_time: u32,
+    /// Directory entries store the type of inodes.
+    filetype: bool,
+    /// Files larger than 2 GiB are supported.
+    large_file: bool,
+    /// The filesystem can't be modified.
+    read_only: bool,
+    /// Used to give a strong reference to the nodes.
+    weak: Weak<Ext2Fs>,
+    /// The state is locked for all operations.
+    state: Mutex<Ext2State>,
+}
+
+/// Mutable state of the filesystem.
+struct Ext2State {
+    free_blocks: u32,
+    free_inodes: u32,
+    groups: Vec<Group>,
+}
+
+/// A block group descriptor.
+#[derive(Debug, Clone, Copy)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+/// An inode of the filesystem, its content is read from the device for
+/// each operation.
+pub struct Ext2Node {
+    fs: Arc<Ext2Fs>,
+    ino: u32,
+}
+
+/// The used part of an inode, as stored on the device.
+#[derive(Clone)]
+struct RawInode([u8; INODE_SIZE]);
+
+/// An entry of a directory block.
+#[derive(Debug, Clone, Copy)]
+struct RawDirEntry {
+    /// Offset of the entry in its block.
+    offset: usize,
+    ino: u32,
+    rec_len: usize,
+    name_len: usize,
+    file_type: u8,
+}
+
+/// A used entry found in a directory, with its location.
+#[derive(Debug)]
+struct Found {
+    /// Offset of the block containing the entry on the device.
+    block_off: u64,
+    entry: RawDirEntry,
+    /// Offset of the previous entry in the block, if any.
+    prev: Option<usize>,
+}
+
+
+/// Internal function called by the filesystem driver to mount a block
+/// device, if it contains an ext2 filesystem.
+fn probe(dev: &'static BlockDevice) -> FsResult<Option<Arc<dyn FileSystem>>> {
+
+    let mut sb = [0; SUPERBLOCK_SIZE];
+    dev.read_bytes(&mut sb, SUPERBLOCK_OFFSET).map_err(io_error)?;
+    if le16(&sb, 56) != MAGIC {
+        return Ok(None);
+    }
+
+    let log_block_size = le32(&sb, 24);
+    let blocks_per_group = le32(&sb, 32);
+    let inodes_per_group = le32(&sb, 40);
+    // Blocks of 64 KiB are not supported, because the record length of
+    // an entry filling a whole block doesn't fit its 16 bits field.
+    if log_block_size > 5 || blocks_per_group == 0 || inodes_per_group == 0 {
+        return Ok(None);
+    }
+
+    let revision = le32(&sb, 76);
+    let (first_ino, inode_size, incompat, ro_compat) = if revision == 0 {
+        (GOOD_OLD_FIRST_INO, INODE_SIZE as u64, 0, 0)
+    } else {
+        (le32(&sb, 84), le16(&sb, 88) as u64, le32(&sb, 96), le32(&sb, 100))
+    };
+
+    if inode_size < INODE_SIZE as u64 || !inode_size.is_power_of_two() {
+        return Ok(None);
+    } else if incompat & !INCOMPAT_FILETYPE != 0 {
+        println!(" = Unsupported ext2 features on {}: {:#x}", dev.name(), incompat);
+        return Ok(None);
+    }
+
+    let block_size = 1024 << log_block_size;
+    let blocks_count = le32(&sb, 4);
+    let first_data_block = le32(&sb, 20);
+    let groups_count = (blocks_count.saturating_sub(first_data_block) + blocks_per_group - 1) / blocks_per_group;
+
+    let mut descs = vec![0; (groups_count as u64 * GROUP_DESC_SIZE) as usize];
+    dev.read_bytes(&mut descs, (first_data_block as u64 + 1) * block_size).map_err(io_error)?;
+    let groups = descs.chunks_exact(GROUP_DESC_SIZE as usize).map(|desc| Group {
+        block_bitmap: le32(desc, 0),
+        inode_bitmap: le32(desc, 4),
+        inode_table: le32(desc, 8),
+        free_blocks: le16(desc, 12),
+        free_inodes: le16(desc, 14),
+        used_dirs: le16(desc, 16),
+    }).collect();
+
+    let fs = Arc::new_cyclic(|weak| Ext2Fs {
+        dev,
+        block_size,
+        inode_size,
+        blocks_count,
+        inodes_count: le32(&sb, 0),
+        blocks_per_group,
+        inodes_per_group,
+        first_data_block,
+        first_ino,
+        write_time: le32(&sb, 48),
+        filetype: incompat & INCOMPAT_FILETYPE != 0,
+        large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+        read_only: dev.read_only() || ro_compat & !RO_COMPAT_SUPPORTED != 0,
+        weak: weak.clone(),
+        state: Mutex::new(Ext2State {
+            free_blocks: le32(&sb, 12),
+            free_inodes: le32(&sb, 16),
+            groups,
+        }),
+    });
+
+    Ok(Some(fs))
+
+}
+
+
+impl FileSystem for Ext2Fs {
+
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> FsResult<Arc<dyn Inode>> {
+        Ok(self.node(ROOT_INO))
+    }
+
+}
+
+impl Ext2Fs {
+
+    /// Get the node of an inode.
+    fn node(&self, ino: u32) -> Arc<Ext2Node> {
+        Arc::new(Ext2Node { fs: self.weak.upgrade().unwrap(), ino })
+    }
+
+    fn read(&self, dst: &mut [u8], off: u64) -> FsResult<()> {
+        self.dev.read_bytes(dst, off).map_err(io_error)
+    }
+
+    fn write(&self, src: &[u8], off: u64) -> FsResult<()> {
+        self.dev.write_bytes(src, off).map_err(io_error)
+    }
+
+    fn read_u32(&self, off: u64) -> FsResult<u32> {
+        let mut buf = [0; 4];
+        self.read(&mut buf, off)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn write_u32(&self, off: u64, value: u32) -> FsResult<()> {
+        self.write(&value.to_le_bytes(), off)
+    }
+
+    /// Get the offset of a block on the device.
+    #[inline]
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size
+    }
+
+    /// Number of block numbers in an indirect block.
+    #[inline]
+    fn per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    // Superblock and groups
+
+    /// Write the free counts of the superblock and of a group.
+    fn write_counts(&self, state: &Ext2State, group: usize) -> FsResult<()> {
+        let mut counts = [0; 8];
+        counts[0..4].copy_from_slice(&state.free_blocks.to_le_bytes());
+        counts[4..8].copy_from_slice(&state.free_inodes.to_le_bytes());
+        self.write(&counts, SUPERBLOCK_OFFSET + 12)?;
+        let desc = &state.groups[group];
+        let mut counts = [0; 6];
+        counts[0..2].copy_from_slice(&desc.free_blocks.to_le_bytes());
+        counts[2..4].copy_from_slice(&desc.free_inodes.to_le_bytes());
+        counts[4..6].copy_from_slice(&desc.used_dirs.to_le_bytes());
+        let table = self.block_offset(self.first_data_block + 1);
+        self.write(&counts, table + group as u64 * GROUP_DESC_SIZE + 12)
+    }
+
+    /// Find and set the first clear bit of a bitmap block, among the
+    /// given number of bits.
+    fn alloc_bit(&self, bitmap: u32, count: u32) -> FsResult<Option<u32>> {
+        let mut data = vec![0u8; self.block_size as usize];
+        let off = self.block_offset(bitmap);
+        self.read(&mut data, off)?;
+        for (i, byte) in data.iter().enumerate().take((count as usize + 7) / 8) {
+            if *byte == 0xFF {
+                continue;
+            }
+            let bit = byte.trailing_ones();
+            let index = i as u32 * 8 + bit;
+            if index >= count {
+                break;
+            }
+            self.write(&[byte | (1 << bit)], off + i as u64)?;
+            return Ok(Some(index));
+        }
+        Ok(None)
+    }
+
+    /// Clear a bit of a bitmap block.
+    fn free_bit(&self, bitmap: u32, index: u32) -> FsResult<()> {
+        let off = self.block_offset(bitmap) + index as u64 / 8;
+        let mut byte = [0];
+        self.read(&mut byte, off)?;
+        if byte[0] & (1 << (index % 8)) == 0 {
+            return Err(FsError::Corrupted);
+        }
+        self.write(&[byte[0] & !(1 << (index % 8))], off)
+    }
+
+    /// Allocate a zeroed block, preferably in the group of the given
+    /// inode.
+    fn alloc_block(&self, state: &mut Ext2State, ino: u32) -> FsResult<u32> {
+        let groups_count = state.groups.len();
+        let goal = ((ino - 1) / self.inodes_per_group) as usize;
+        for i in 0..groups_count {
+            let group = (goal + i) % groups_count;
+            if state.groups[group].free_blocks == 0 {
+                continue;
+            }
+            let first = self.first_data_block + group as u32 * self.blocks_per_group;
+            let count = self.blocks_per_group.min(self.blocks_count - first);
+            if let Some(index) = self.alloc_bit(state.groups[group].block_bitmap, count)? {
+                state.groups[group].free_blocks -= 1;
+                state.free_blocks = state.free_blocks.saturating_sub(1);
+                self.write_counts(state, group)?;
+                let block = first + index;
+                self.write(&vec![0; self.block_size as usize], self.block_offset(block))?;
+                return Ok(block);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Free a block.
+    fn free_block(&self, state: &mut Ext2State, block: u32) -> FsResult<()> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        self.free_bit(state.groups[group].block_bitmap, (block - self.first_data_block) % self.blocks_per_group)?;
+        state.groups[group].free_blocks += 1;
+        state.free_blocks += 1;
+        self.write_counts(state, group)
+    }
+
+    /// Allocate an inode, preferably in the group of the given inode.
+    fn alloc_inode(&self, state: &mut Ext2State, parent: u32, dir: bool) -> FsResult<u32> {
+        let groups_count = state.groups.len();
+        let goal = ((parent - 1) / self.inodes_per_group) as usize;
+        for i in 0..groups_count {
+            let group = (goal + i) % groups_count;
+            if state.groups[group].free_inodes == 0 {
+                continue;
+            }
+            if let Some(index) = self.alloc_bit(state.groups[group].inode_bitmap, self.inodes_per_group)? {
+                let ino = group as u32 * self.inodes_per_group + index + 1;
+                if ino < self.first_ino {
+                    // Reserved inodes should always be marked used.
+                    return Err(FsError::Corrupted);
+                }
+                let desc = &mut state.groups[group];
+                desc.free_inodes -= 1;
+                if dir {
+                    desc.used_dirs += 1;
+                }
+                state.free_inodes = state.free_inodes.saturating_sub(1);
+                self.write_counts(state, group)?;
+                return Ok(ino);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Free an inode.
+    fn free_inode(&self, state: &mut Ext2State, ino: u32, dir: bool) -> FsResult<()> {
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        self.free_bit(state.groups[group].inode_bitmap, (ino - 1) % self.inodes_per_group)?;
+        let desc = &mut state.groups[group];
+        desc.free_inodes += 1;
+        if dir {
+            desc.used_dirs = desc.used_dirs.saturating_sub(1);
+        }
+        state.free_inodes += 1;
+        self.write_counts(state, group)
+    }
+
+    // Inodes
+
+    /// Get the offset of an inode on the device.
+    fn inode_offset(&self, state: &Ext2State, ino: u32) -> FsResult<u64> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Corrupted);
+        }
+        let group = &state.groups[((ino - 1) / self.inodes_per_group) as usize];
+        let index = (ino - 1) % self.inodes_per_group;
+        Ok(self.block_offset(group.inode_table) + index as u64 * self.inode_size)
+    }
+
+    fn read_inode(&self, state: &Ext2State, ino: u32) -> FsResult<RawInode> {
+        let mut inode = RawInode([0; INODE_SIZE]);
+        self.read(&mut inode.0, self.inode_offset(state, ino)?)?;
+        Ok(inode)
+    }
+
+    fn write_inode(&self, state: &Ext2State, ino: u32, inode: &RawInode) -> FsResult<()> {
+        self.write(&inode.0, self.inode_offset(state, ino)?)
+    }
+
+    /// Initialize a newly allocated inode, the extra part of large
+    /// inodes is cleared.
+    fn init_inode(&self, state: &Ext2State, ino: u32, mode: u16, links: u16) -> FsResult<RawInode> {
+        let mut inode = RawInode([0; INODE_SIZE]);
+        inode.set_mode(mode);
+        inode.set_links(links);
+        let extra = vec![0; self.inode_size as usize];
+        self.write(&extra, self.inode_offset(state, ino)?)?;
+        self.write_inode(state, ino, &inode)?;
+        Ok(inode)
+    }
+
+    /// Maximum size of files.
+    fn max_size(&self) -> u64 {
+        let per = self.per_block();
+        let blocks = DIRECT_BLOCKS + per + per * per + per * per * per;
+        let max = if self.large_file { u64::MAX } else { i32::MAX as u64 };
+        (blocks * self.block_size).min(max)
+    }
+
+    /// Get the block of an inode at the given index, none if it's not
+    /// allocated. Blocks are allocated if requested, the inode is then
+    /// modified and must be written by the caller.
+    fn map_block(&self, state: &mut Ext2State, ino: u32, inode: &mut RawInode, index: u64, alloc: bool) -> FsResult<Option<u32>> {
+
+        let per = self.per_block();
+        let (slot, levels, mut index) = if index < DIRECT_BLOCKS {
+            (index as usize, 0, 0)
+        } else if index - DIRECT_BLOCKS < per {
+            (12, 1, index - DIRECT_BLOCKS)
+        } else if index - DIRECT_BLOCKS - per < per * per {
+            (13, 2, index - DIRECT_BLOCKS - per)
+        } else if index - DIRECT_BLOCKS - per - per * per < per * per * per {
+            (14, 3, index - DIRECT_BLOCKS - per - per * per)
+        } else {
+            return Err(FsError::NoSpace);
+        };
+
+        let mut block = inode.block(slot);
+        if block == 0 {
+            if !alloc {
+                return Ok(None);
+            }
+            block = self.alloc_block(state, ino)?;
+            inode.set_block(slot, block);
+            inode.add_blocks(self.block_size);
+        }
+
+        for level in (0..levels).rev() {
+            let span = per.pow(level);
+            let entry_off = self.block_offset(block) + (index / span) * 4;
+            index %= span;
+            let mut next = self.read_u32(entry_off)?;
+            if next == 0 {
+                if !alloc {
+                    return Ok(None);
+                }
+                next = self.alloc_block(state, ino)?;
+                self.write_u32(entry_off, next)?;
+                inode.add_blocks(self.block_size);
+            }
+            block = next;
+        }
+
+        Ok(Some(block))
+
+    }
+
+    /// Free the blocks of an inode from the given index, the inode is
+    /// modified and must be written by the caller.
+    fn free_blocks_from(&self, state: &mut Ext2State, inode: &mut RawInode, start: u64) -> FsResult<()> {
+
+        for slot in start.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            let block = inode.block(slot as usize);
+            if block != 0 {
+                self.free_block(state, block)?;
+                inode.sub_blocks(self.block_size);
+                inode.set_block(slot as usize, 0);
+            }
+        }
+
+        let per = self.per_block();
+        let mut base = DIRECT_BLOCKS;
+        for level in 1..=3 {
+            let span = per.pow(level);
+            let slot = 11 + level as usize;
+            if start < base + span {
+                let block = inode.block(slot);
+                if self.free_tree(state, inode, block, level, start.saturating_sub(base))? {
+                    inode.set_block(slot, 0);
+                }
+            }
+            base += span;
+        }
+
+        Ok(())
+
+    }
+
+    /// Free the blocks of a tree of indirect blocks from the given index,
+    /// return true if the root block of the tree has been freed.
+    fn free_tree(&self, state: &mut Ext2State, inode: &mut RawInode, block: u32, level: u32, start: u64) -> FsResult<bool> {
+
+        if block == 0 {
+            return Ok(true);
+        } else if level == 0 {
+            self.free_block(state, block)?;
+            inode.sub_blocks(self.block_size);
+            return Ok(true);
+        }
+
+        let span = self.per_block().pow(level - 1);
+        let mut data = vec![0; self.block_size as usize];
+        self.read(&mut data, self.block_offset(block))?;
+
+        let first = (start / span) as usize;
+        let mut modified = false;
+        for i in first..self.per_block() as usize {
+            let child = le32(&data, i * 4);
+            let child_start = if i == first { start % span } else { 0 };
+            if child != 0 && self.free_tree(state, inode, child, level - 1, child_start)? {
+                data[i * 4..i * 4 + 4].fill(0);
+                modified = true;
+            }
+        }
+
+        if start == 0 {
+            self.free_block(state, block)?;
+            inode.sub_blocks(self.block_size);
+            Ok(true)
+        } else {
+            if modified {
+                self.write(&data, self.block_offset(block))?;
+            }
+            Ok(false)
+        }
+
+    }
+
+    /// Read the data of an inode, holes are read as zeros.
+    fn read_data(&self, state: &mut Ext2State, ino: u32, inode: &RawInode, off: u64, mut dst: &mut [u8]) -> FsResult<()> {
+        let mut inode = inode.clone();
+        let mut pos = off;
+        while !dst.is_empty() {
+            let block_off = pos % self.block_size;
+            let len = ((self.block_size - block_off) as usize).min(dst.len());
+            match self.map_block(state, ino, &mut inode, pos / self.block_size, false)? {
+                Some(block) => self.read(&mut dst[..len], self.block_offset(block) + block_off)?,
+                None => dst[..len].fill(0),
+            }
+            dst = &mut dst[len..];
+            pos += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Write the data of an inode, blocks are allocated if needed. The
+    /// inode is modified and must be written by the caller.
+    fn write_data(&self, state: &mut Ext2State, ino: u32, inode: &mut RawInode, off: u64, mut src: &[u8]) -> FsResult<()> {
+        let mut pos = off;
+        while !src.is_empty() {
+            let block_off = pos % self.block_size;
+            let len = ((self.block_size - block_off) as usize).min(src.len());
+            let block = self.map_block(state, ino, inode, pos / self.block_size, true)?.unwrap();
+            self.write(&src[..len], self.block_offset(block) + block_off)?;
+            src = &src[len..];
+            pos += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Change the size of an inode, the blocks after the new size are
+    /// freed. The inode is modified and must be written by the caller.
+    fn resize(&self, state: &mut Ext2State, ino: u32, inode: &mut RawInode, size: u64) -> FsResult<()> {
+        let old_size = inode.size();
+        if size < old_size {
+            let blocks = (size + self.block_size - 1) / self.block_size;
+            self.free_blocks_from(state, inode, blocks)?;
+            // The rest of the last block is cleared, so it reads as
+            // zeros if the file is extended again.
+            let block_off = size % self.block_size;
+            if block_off != 0 {
+                if let Some(block) = self.map_block(state, ino, inode, size / self.block_size, false)? {
+                    let zeros = vec![0; (self.block_size - block_off) as usize];
+                    self.write(&zeros, self.block_offset(block) + block_off)?;
+                }
+            }
+        }
+        inode.set_size(size);
+        Ok(())
+    }
+
+    /// Release an inode that is no longer linked, with its blocks.
+    fn release_inode(&self, state: &mut Ext2State, ino: u32, inode: &mut RawInode) -> FsResult<()> {
+        if !inode.is_fast_symlink(self.block_size) {
+            self.free_blocks_from(state, inode, 0)?;
+        }
+        let dir = inode.mode() & S_IFMT == S_IFDIR;
+        inode.set_links(0);
+        inode.set_size(0);
+        // Without a clock, the last write time is used, it must not be
+        // a small value that would be read as a link of the orphan list.
+        inode.set_dtime(self.write_time.max(self.inodes_count + 1));
+        self.write_inode(state, ino, inode)?;
+        self.free_inode(state, ino, dir)
+    }
+
+    // Directories
+
+    /// Read a block of a directory, none after the last one. The offset
+    /// of the block on the device is returned with its content.
+    fn read_dir_block(&self, state: &mut Ext2State, ino: u32, inode: &RawInode, index: u64) -> FsResult<Option<(u64, Vec<u8>)>> {
+        if index >= inode.size() / self.block_size {
+            return Ok(None);
+        }
+        let block = self.map_block(state, ino, &mut inode.clone(), index, false)?.ok_or(FsError::Corrupted)?;
+        let mut data = vec![0; self.block_size as usize];
+        self.read(&mut data, self.block_offset(block))?;
+        Ok(Some((self.block_offset(block), data)))
+    }
+
+    /// Parse the entries of a directory block, including unused ones.
+    fn parse_dir_block(&self, data: &[u8]) -> FsResult<Vec<RawDirEntry>> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            if offset + 8 > data.len() {
+                return Err(FsError::Corrupted);
+            }
+            let rec_len = le16(data, offset + 4) as usize;
+            let name_len = data[offset + 6] as usize;
+            if rec_len < 8 || rec_len % 4 != 0 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+                return Err(FsError::Corrupted);
+            }
+            entries.push(RawDirEntry {
+                offset,
+                ino: le32(data, offset),
+                rec_len,
+                name_len,
+                file_type: if self.filetype { data[offset + 7] } else { 0 },
+            });
+            offset += rec_len;
+        }
+        Ok(entries)
+    }
+
+    /// Call the given function with each used entry of a directory and
+    /// its name, until it returns false.
+    fn for_each_dir_entry(&self, state: &mut Ext2State, ino: u32, inode: &RawInode, mut func: impl FnMut(&RawDirEntry, &[u8]) -> bool) -> FsResult<()> {
+        let mut index = 0;
+        while let Some((_, data)) = self.read_dir_block(state, ino, inode, index)? {
+            for entry in self.parse_dir_block(&data)? {
+                let name = &data[entry.offset + 8..entry.offset + 8 + entry.name_len];
+                if entry.ino != 0 && !func(&entry, name) {
+                    return Ok(());
+                }
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Find the used entry of a directory with the given name.
+    fn find_dir_entry(&self, state: &mut Ext2State, ino: u32, inode: &RawInode, name: &str) -> FsResult<Option<Found>> {
+        let mut index = 0;
+        while let Some((block_off, data)) = self.read_dir_block(state, ino, inode, index)? {
+            let mut prev = None;
+            for entry in self.parse_dir_block(&data)? {
+                let entry_name = &data[entry.offset + 8..entry.offset + 8 + entry.name_len];
+                if entry.ino != 0 && entry_name == name.as_bytes() {
+                    return Ok(Some(Found { block_off, entry, prev }));
+                }
+                prev = Some(entry.offset);
+            }
+            index += 1;
+        }
+        Ok(None)
+    }
+
+    /// Return true if the directory only contains `.` and `..`.
+    fn is_dir_empty(&self, state: &mut Ext2State, ino: u32, inode: &RawInode) -> FsResult<bool> {
+        let mut empty = true;
+        self.for_each_dir_entry(state, ino, inode, |_, name| {
+            empty = name == b"." || name == b"..";
+            empty
+        })?;
+        Ok(empty)
+    }
+
+    /// Add an entry to a directory, it's extended if needed. The inode
+    /// of the directory is written.
+    fn add_dir_entry(&self, state: &mut Ext2State, dir_ino: u32, dir: &mut RawInode, name: &str, ino: u32, file_type: u8) -> FsResult<()> {
+
+        let needed = dir_entry_size(name.len());
+        dir.set_flags(dir.flags() & !INDEX_FL);
+
+        let mut index = 0;
+        while let Some((block_off, data)) = self.read_dir_block(state, dir_ino, dir, index)? {
+            for entry in self.parse_dir_block(&data)? {
+                let used = if entry.ino == 0 { 0 } else { dir_entry_size(entry.name_len) };
+                if entry.rec_len - used >= needed {
+                    if used != 0 {
+                        let rec_len = (used as u16).to_le_bytes();
+                        self.write(&rec_len, block_off + entry.offset as u64 + 4)?;
+                    }
+                    let new_entry = self.dir_entry(ino, entry.rec_len - used, name, file_type);
+                    self.write(&new_entry, block_off + (entry.offset + used) as u64)?;
+                    return self.write_inode(state, dir_ino, dir);
+                }
+            }
+            index += 1;
+        }
+
+        // No entry has enough free space, a block is added.
+        let size = dir.size();
+        let block = self.map_block(state, dir_ino, dir, size / self.block_size, true)?.unwrap();
+        let new_entry = self.dir_entry(ino, self.block_size as usize, name, file_type);
+        self.write(&new_entry, self.block_offset(block))?;
+        dir.set_size(size + self.block_size);
+        self.write_inode(state, dir_ino, dir)
+
+    }
+
+    /// Remove a found entry of a directory, the previous entry of its
+    /// block is extended over it.
+    fn remove_dir_entry(&self, state: &Ext2State, dir_ino: u32, dir: &mut RawInode, found: &Found) -> FsResult<()> {
+        match found.prev {
+            Some(prev) => {
+                let mut rec_len = [0; 2];
+                let prev_off = found.block_off + prev as u64 + 4;
+                self.read(&mut rec_len, prev_off)?;
+                let rec_len = u16::from_le_bytes(rec_len) as usize + found.entry.rec_len;
+                self.write(&(rec_len as u16).to_le_bytes(), prev_off)?;
+            }
+            None => self.write_u32(found.block_off + found.entry.offset as u64, 0)?,
+        }
+        dir.set_flags(dir.flags() & !INDEX_FL);
+        self.write_inode(state, dir_ino, dir)
+    }
+
+    /// Build a directory entry.
+    fn dir_entry(&self, ino: u32, rec_len: usize, name: &str, file_type: u8) -> Vec<u8> {
+        let mut entry = vec![0; dir_entry_size(name.len())];
+        entry[0..4].copy_from_slice(&ino.to_le_bytes());
+        entry[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+        entry[6] = name.len() as u8;
+        entry[7] = if self.filetype { file_type } else { 0 };
+        entry[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    /// Set the inode of the `..` entry of a directory.
+    fn set_dot_dot(&self, state: &mut Ext2State, ino: u32, inode: &RawInode, parent: u32) -> FsResult<()> {
+        let found = self.find_dir_entry(state, ino, inode, "..")?.ok_or(FsError::Corrupted)?;
+        self.write_u32(found.block_off + found.entry.offset as u64, parent)
+    }
+
+    /// Get the inode of the `..` entry of a directory.
+    fn dot_dot(&self, state: &mut Ext2State, ino: u32) -> FsResult<u32> {
+        let inode = self.read_inode(state, ino)?;
+        let found = self.find_dir_entry(state, ino, &inode, "..")?.ok_or(FsError::Corrupted)?;
+        Ok(found.entry.ino)
+    }
+
+    /// Get the kind of an entry, from its type or its inode.
+    fn entry_kind(&self, state: &Ext2State, entry: &RawDirEntry) -> FsResult<InodeKind> {
+        Ok(match entry.file_type {
+            FT_REG_FILE => InodeKind::File,
+            FT_DIR => InodeKind::Directory,
+            FT_SYMLINK => InodeKind::Symlink,
+            0 => self.read_inode(state, entry.ino)?.kind(),
+            _ => InodeKind::Device,
+        })
+    }
+
+}
+
+
+impl Ext2Node {
+
+    /// Read the inode of the node, it must still be linked.
+    fn inode(&self, state: &Ext2State) -> FsResult<RawInode> {
+        let inode = self.fs.read_inode(state, self.ino)?;
+        if inode.links() == 0 {
+            Err(FsError::NotFound)
+        } else {
+            Ok(inode)
+        }
+    }
+
+    /// Read the inode of the node, it must be a directory.
+    fn dir_inode(&self, state: &Ext2State) -> FsResult<RawInode> {
+        let inode = self.inode(state)?;
+        if inode.kind() != InodeKind::Directory {
+            Err(FsError::NotDirectory)
+        } else {
+            Ok(inode)
+        }
+    }
+
+    /// Check that the filesystem can be modified.
+    fn check_writable(&self) -> FsResult<()> {
+        if self.fs.read_only {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Create a new inode and link it in this directory.
+    fn create_inode(&self, name: &str, mode: u16, data: &[u8]) -> FsResult<Arc<Ext2Node>> {
+
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.dir_inode(&state)?;
+        if fs.find_dir_entry(&mut state, self.ino, &dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let ino = fs.alloc_inode(&mut state, self.ino, is_dir)?;
+        let mut inode = fs.init_inode(&state, ino, mode, if is_dir { 2 } else { 1 })?;
+
+        let ret = if is_dir {
+            // The directory is initialized with its '.' and '..' entries.
+            let mut block = fs.dir_entry(ino, 12, ".", FT_DIR);
+            block.extend_from_slice(&fs.dir_entry(self.ino, fs.block_size as usize - 12, "..", FT_DIR));
+            fs.write_data(&mut state, ino, &mut inode, 0, &block).map(|_| inode.set_size(fs.block_size))
+        } else if mode & S_IFMT == S_IFLNK && data.len() < FAST_SYMLINK_SIZE {
+            inode.0[40..40 + data.len()].copy_from_slice(data);
+            inode.set_size(data.len() as u64);
+            Ok(())
+        } else {
+            fs.write_data(&mut state, ino, &mut inode, 0, data).map(|_| inode.set_size(data.len() as u64))
+        };
+
+        let ret = ret
+            .and_then(|_| fs.write_inode(&state, ino, &inode))
+            .and_then(|_| fs.add_dir_entry(&mut state, self.ino, &mut dir, name, ino, file_type(mode)));
+
+        if let Err(e) = ret {
+            fs.release_inode(&mut state, ino, &mut inode)?;
+            return Err(e);
+        }
+
+        if is_dir {
+            dir.set_links(dir.links() + 1);
+            fs.write_inode(&state, self.ino, &dir)?;
+        }
+
+        Ok(fs.node(ino))
+
+    }
+
+}
+
+impl Inode for Ext2Node {
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FsResult<Metadata> {
+        let state = self.fs.state.lock();
+        let inode = self.inode(&state)?;
+        Ok(Metadata {
+            kind: inode.kind(),
+            size: inode.size(),
+            ino: self.ino as u64,
+            mode: inode.mode() & 0o7777,
+            links: inode.links() as u32,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let inode = self.inode(&state)?;
+        match inode.kind() {
+            InodeKind::File => {}
+            InodeKind::Directory => return Err(FsError::IsDirectory),
+            _ => return Err(FsError::Unsupported),
+        }
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = ((size - offset) as usize).min(buf.len());
+        fs.read_data(&mut state, self.ino, &inode, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut inode = self.inode(&state)?;
+        match inode.kind() {
+            InodeKind::File => {}
+            InodeKind::Directory => return Err(FsError::IsDirectory),
+            _ => return Err(FsError::Unsupported),
+        }
+        let end = offset.checked_add(buf.len() as u64)
+            .filter(|&end| end <= fs.max_size())
+            .ok_or(FsError::NoSpace)?;
+        let ret = fs.write_data(&mut state, self.ino, &mut inode, offset, buf);
+        if ret.is_ok() && end > inode.size() {
+            inode.set_size(end);
+        }
+        // The inode is written even on failure, for allocated blocks.
+        fs.write_inode(&state, self.ino, &inode)?;
+        ret.map(|_| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut inode = self.inode(&state)?;
+        match inode.kind() {
+            InodeKind::File => {}
+            InodeKind::Directory => return Err(FsError::IsDirectory),
+            _ => return Err(FsError::Unsupported),
+        }
+        if size > fs.max_size() {
+            return Err(FsError::NoSpace);
+        }
+        let ret = fs.resize(&mut state, self.ino, &mut inode, size);
+        fs.write_inode(&state, self.ino, &inode)?;
+        ret
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let dir = self.dir_inode(&state)?;
+        let found = fs.find_dir_entry(&mut state, self.ino, &dir, name)?.ok_or(FsError::NotFound)?;
+        Ok(fs.node(found.entry.ino))
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let dir = self.dir_inode(&state)?;
+        let mut current = 0;
+        let mut found = None;
+        fs.for_each_dir_entry(&mut state, self.ino, &dir, |entry, name| {
+            if name == b"." || name == b".." {
+                true
+            } else if current == index {
+                found = Some((*entry, String::from_utf8_lossy(name).into_owned()));
+                false
+            } else {
+                current += 1;
+                true
+            }
+        })?;
+        match found {
+            Some((entry, name)) => Ok(Some(DirEntry {
+                name,
+                kind: fs.entry_kind(&state, &entry)?,
+                ino: entry.ino as u64,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn create(&self, name: &str, kind: InodeKind) -> FsResult<Arc<dyn Inode>> {
+        match kind {
+            InodeKind::File => Ok(self.create_inode(name, S_IFREG | 0o644, &[])?),
+            InodeKind::Directory => Ok(self.create_inode(name, S_IFDIR | 0o755, &[])?),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FsResult<()> {
+        if target.is_empty() || target.len() as u64 > self.fs.block_size {
+            return Err(FsError::InvalidArgument);
+        }
+        self.create_inode(name, S_IFLNK | 0o777, target.as_bytes()).map(|_| ())
+    }
+
+    fn link(&self, name: &str, inode: &dyn Inode) -> FsResult<()> {
+
+        self.check_writable()?;
+        let target = inode.as_any().downcast_ref::<Ext2Node>().ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::CrossDevice);
+        }
+
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.dir_inode(&state)?;
+        let mut inode = target.inode(&state)?;
+        if inode.kind() == InodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        } else if inode.links() == u16::MAX {
+            return Err(FsError::NoSpace);
+        } else if fs.find_dir_entry(&mut state, self.ino, &dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        fs.add_dir_entry(&mut state, self.ino, &mut dir, name, target.ino, file_type(inode.mode()))?;
+        inode.set_links(inode.links() + 1);
+        fs.write_inode(&state, target.ino, &inode)
+
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.dir_inode(&state)?;
+
+        let found = fs.find_dir_entry(&mut state, self.ino, &dir, name)?.ok_or(FsError::NotFound)?;
+        let ino = found.entry.ino;
+        let mut inode = fs.read_inode(&state, ino)?;
+        let is_dir = inode.kind() == InodeKind::Directory;
+        if is_dir && !fs.is_dir_empty(&mut state, ino, &inode)? {
+            return Err(FsError::NotEmpty);
+        }
+
+        fs.remove_dir_entry(&state, self.ino, &mut dir, &found)?;
+
+        if is_dir {
+            // The '..' entry of the removed directory linked this one.
+            dir.set_links(dir.links().saturating_sub(1));
+            fs.write_inode(&state, self.ino, &dir)?;
+            fs.release_inode(&mut state, ino, &mut inode)
+        } else if inode.links() <= 1 {
+            fs.release_inode(&mut state, ino, &mut inode)
+        } else {
+            inode.set_links(inode.links() - 1);
+            fs.write_inode(&state, ino, &inode)
+        }
+
+    }
+
+    fn rename(&self, name: &str, new_parent: &dyn Inode, new_name: &str) -> FsResult<()> {
+
+        self.check_writable()?;
+        let new_parent = new_parent.as_any().downcast_ref::<Ext2Node>().ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.fs, &new_parent.fs) {
+            return Err(FsError::CrossDevice);
+        }
+
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.dir_inode(&state)?;
+        let mut new_dir = new_parent.dir_inode(&state)?;
+        let same_parent = self.ino == new_parent.ino;
+
+        let ino = fs.find_dir_entry(&mut state, self.ino, &dir, name)?.ok_or(FsError::NotFound)?.entry.ino;
+        let inode = fs.read_inode(&state, ino)?;
+        let is_dir = inode.kind() == InodeKind::Directory;
+
+        if is_dir && !same_parent {
+            // A directory can't be moved into itself.
+            let mut current = new_parent.ino;
+            while current != ROOT_INO {
+                if current == ino {
+                    return Err(FsError::InvalidArgument);
+                }
+                current = fs.dot_dot(&mut state, current)?;
+            }
+        }
+
+        if let Some(existing) = fs.find_dir_entry(&mut state, new_parent.ino, &new_dir, new_name)? {
+            let existing_ino = existing.entry.ino;
+            if existing_ino == ino {
+                // Both names are links to the same inode.
+                return Ok(());
+            }
+            let mut existing_inode = fs.read_inode(&state, existing_ino)?;
+            let existing_dir = existing_inode.kind() == InodeKind::Directory;
+            if existing_dir != is_dir {
+                return Err(if existing_dir { FsError::IsDirectory } else { FsError::NotDirectory });
+            } else if existing_dir && !fs.is_dir_empty(&mut state, existing_ino, &existing_inode)? {
+                return Err(FsError::NotEmpty);
+            }
+            fs.remove_dir_entry(&state, new_parent.ino, &mut new_dir, &existing)?;
+            if existing_dir {
+                new_dir.set_links(new_dir.links().saturating_sub(1));
+                fs.write_inode(&state, new_parent.ino, &new_dir)?;
+                fs.release_inode(&mut state, existing_ino, &mut existing_inode)?;
+            } else if existing_inode.links() <= 1 {
+                fs.release_inode(&mut state, existing_ino, &mut existing_inode)?;
+            } else {
+                existing_inode.set_links(existing_inode.links() - 1);
+                fs.write_inode(&state, existing_ino, &existing_inode)?;
+            }
+        }
+
+        // The new entry is added first, so the inode is never unlinked,
+        // the old entry is then found again because entries moved.
+        fs.add_dir_entry(&mut state, new_parent.ino, &mut new_dir, new_name, ino, file_type(inode.mode()))?;
+        if same_parent {
+            dir = new_dir.clone();
+        }
+        let found = fs.find_dir_entry(&mut state, self.ino, &dir, name)?.ok_or(FsError::Corrupted)?;
+        fs.remove_dir_entry(&state, self.ino, &mut dir, &found)?;
+
+        // A directory moved to another parent updates its '..' entry and
+        // the links of both parents, nothing changes in the same parent.
+        if is_dir && !same_parent {
+            fs.set_dot_dot(&mut state, ino, &inode, new_parent.ino)?;
+            dir.set_links(dir.links().saturating_sub(1));
+            new_dir.set_links(new_dir.links() + 1);
+            fs.write_inode(&state, self.ino, &dir)?;
+            fs.write_inode(&state, new_parent.ino, &new_dir)?;
+        }
+
+        Ok(())
+
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let inode = self.inode(&state)?;
+        if inode.kind() != InodeKind::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        let size = inode.size() as usize;
+        if inode.is_fast_symlink(fs.block_size) {
+            let target = inode.0.get(40..40 + size).ok_or(FsError::Corrupted)?;
+            return Ok(String::from_utf8_lossy(target).into_owned());
+        } else if size as u64 > fs.block_size {
+            return Err(FsError::Corrupted);
+        }
+        let mut target = vec![0; size];
+        fs.read_data(&mut state, self.ino, &inode, 0, &mut target)?;
+        String::from_utf8(target).map_err(|_| FsError::Corrupted)
+    }
+
+}
+
+
+impl RawInode {
+
+    fn mode(&self) -> u16 {
+        le16(&self.0, 0)
+    }
+
+    fn set_mode(&mut self, mode: u16) {
+        self.0[0..2].copy_from_slice(&mode.to_le_bytes());
+    }
+
+    fn kind(&self) -> InodeKind {
+        match self.mode() & S_IFMT {
+            S_IFREG => InodeKind::File,
+            S_IFDIR => InodeKind::Directory,
+            S_IFLNK => InodeKind::Symlink,
+            _ => InodeKind::Device,
+        }
+    }
+
+    /// Size of the inode, the high part is only used for files.
+    fn size(&self) -> u64 {
+        let high = if self.kind() == InodeKind::File { le32(&self.0, 108) } else { 0 };
+        ((high as u64) << 32) | le32(&self.0, 4) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.0[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+        if self.kind() == InodeKind::File {
+            self.0[108..112].copy_from_slice(&((size >> 32) as u32).to_le_bytes());
+        }
+    }
+
+    fn set_dtime(&mut self, dtime: u32) {
+        self.0[20..24].copy_from_slice(&dtime.to_le_bytes());
+    }
+
+    fn links(&self) -> u16 {
+        le16(&self.0, 26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        self.0[26..28].copy_from_slice(&links.to_le_bytes());
+    }
+
+    /// Number of 512-byte sectors used by the inode.
+    fn sectors(&self) -> u32 {
+        le32(&self.0, 28)
+    }
+
+    fn add_blocks(&mut self, block_size: u64) {
+        let sectors = self.sectors() + (block_size / 512) as u32;
+        self.0[28..32].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    fn sub_blocks(&mut self, block_size: u64) {
+        let sectors = self.sectors().saturating_sub((block_size / 512) as u32);
+        self.0[28..32].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    fn flags(&self) -> u32 {
+        le32(&self.0, 32)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        self.0[32..36].copy_from_slice(&flags.to_le_bytes());
+    }
+
+    fn block(&self, slot: usize) -> u32 {
+        le32(&self.0, 40 + slot * 4)
+    }
+
+    fn set_block(&mut self, slot: usize, block: u32) {
+        self.0[40 + slot * 4..44 + slot * 4].copy_from_slice(&block.to_le_bytes());
+    }
+
+    /// Return true if this is a symbolic link with its target stored in
+    /// the block array, it has no block (except extended attributes).
+    fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let acl_sectors = if le32(&self.0, 104) != 0 { (block_size / 512) as u32 } else { 0 };
+        self.kind() == InodeKind::Symlink
+            && (self.size() as usize) < FAST_SYMLINK_SIZE
+            && self.sectors() <= acl_sectors
+    }
+
+}
+
+
+/// Internal function to get the size of a directory entry with a name
+/// of the given length.
+#[inline]
+fn dir_entry_size(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
+
+/// Internal function to get the type of a directory entry for the given
+/// mode of an inode.
+fn file_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => FT_REG_FILE,
+        S_IFDIR => FT_DIR,
+        S_IFLNK => FT_SYMLINK,
+        0x2000 => 3,
+        0x6000 => 4,
+        0x1000 => 5,
+        0xC000 => 6,
+        _ => 0,
+    }
+}
+
+/// Internal function to convert a block device error.
+fn io_error(e: BlockIoError) -> FsError {
+    match e {
+        BlockIoError::ReadOnly => FsError::ReadOnly,
+        _ => FsError::Io,
+    }
+}
+
+#[inline]
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+#[inline]
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
@@ -0,0 +1,1466 @@
+//! FAT12, FAT16 and FAT32 filesystem driver, over block devices.
+//!
+//! Long file names (VFAT) are supported, they are created when a name
+//! doesn't fit the 8.3 short name format, with a generated short name.
+//! All modifications are directly written to the device, only one
+//! sector of the allocation table is cached for reading.
+//!
+//! Specification: https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf
+
+use core::any::Any;
+
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use alloc::vec;
+
+use crate::driver::block::{BlockDevice, BlockIoError, MAX_SECTOR_SIZE};
+use crate::driver::fs::{FileSystemType, FsDriver};
+use crate::driver::Driver;
+use crate::sync::Mutex;
+use crate::println;
+
+use super::{DirEntry, FileSystem, FsError, FsResult, Inode, InodeKind, Metadata};
+
+
+/// Size of a directory entry.
+const ENTRY_SIZE: u64 = 32;
+
+/// Attributes of directory entries.
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes of long file name entries.
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First byte of the name of a deleted entry.
+const ENTRY_DELETED: u8 = 0xE5;
+/// First byte of the name of the entry after the last one.
+const ENTRY_END: u8 = 0x00;
+
+/// Flag of the order of the last long file name entry, stored first.
+const LFN_LAST: u8 = 0x40;
+/// Number of UTF-16 characters in each long file name entry.
+const LFN_CHARS: usize = 13;
+/// Offsets of the characters in a long file name entry.
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Maximum length of a long file name, in UTF-16 characters.
+const LFN_MAX_LEN: usize = 255;
+
+/// Date written in created entries, the 1st January 1980.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// Special characters allowed in short names.
+const SHORT_NAME_SPECIALS: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// Signatures of the FSInfo sector of FAT32.
+const FS_INFO_LEAD_SIG: u32 = 0x41615252;
+const FS_INFO_STRUCT_SIG: u32 = 0x61417272;
+
+
+/// The FAT filesystem type, registered by the [`FatDriver`].
+static FAT_TYPE: FileSystemType = FileSystemType {
+    name: "fat",
+    probe,
+};
+
+
+/// This driver registers the FAT filesystem type, block devices with
+/// a FAT filesystem are then mounted by the filesystem driver.
+pub struct FatDriver {
+    fs_driver: &'static FsDriver,
+}
+
+impl FatDriver {
+
+    pub const fn new(fs_driver: &'static FsDriver) -> Self {
+        Self { fs_driver }
+    }
+
+}
+
+impl Driver for FatDriver {
+
+    fn load(&'static self) {
+        println!("== Loading FAT");
+        self.fs_driver.register(&FAT_TYPE);
+    }
+
+    fn unload(&self) {
+
+    }
+
+}
+
+
+/// Kind of allocation table, defined by the number of clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatKind {
+
+    /// Value marking the end of a chain of clusters.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0xFFF,
+            Self::Fat16 => 0xFFFF,
+            Self::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+}
+
+
+/// Geometry of the filesystem, read from the boot sector. All offsets
+/// are in bytes from the start of the device.
+#[derive(Debug)]
+struct Geometry {
+    kind: FatKind,
+    sector_size: u64,
+    cluster_size: u64,
+    /// Offset of the first allocation table.
+    fat_offset: u64,
+    /// Size of each allocation table.
+    fat_size: u64,
+    /// Indices of the allocation tables that are written, the first one
+    /// is read.
+    fats: Vec<u64>,
+    /// Offset of the root directory of FAT12/16.
+    root_offset: u64,
+    /// Number of entries of the root directory of FAT12/16.
+    root_entries: u64,
+    /// First cluster of the root directory of FAT32.
+    root_cluster: u32,
+    /// Offset of the first cluster, numbered 2.
+    data_offset: u64,
+    /// Largest valid cluster number.
+    max_cluster: u32,
+    /// Offset of the FSInfo sector of FAT32.
+    fs_info_offset: Option<u64>,
+}
+
+impl Geometry {
+
+    /// Parse the boot sector, none if it's not a FAT boot sector.
+    fn parse(boot: &[u8]) -> Option<Self> {
+
+        if boot[510] != 0x55 || boot[511] != 0xAA || !matches!(boot[0], 0xEB | 0xE9) {
+            return None;
+        }
+
+        let sector_size = le16(boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = le16(boot, 14) as u64;
+        let fats_count = boot[16] as u64;
+        let root_entries = le16(boot, 17) as u64;
+        let total_sectors = match le16(boot, 19) {
+            0 => le32(boot, 32) as u64,
+            count => count as u64,
+        };
+        let fat_sectors = match le16(boot, 22) {
+            0 => le32(boot, 36) as u64,
+            count => count as u64,
+        };
+
+        if !sector_size.is_power_of_two() || !(512..=4096).contains(&sector_size)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0 || fats_count == 0 || fat_sectors == 0 {
+            return None;
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE + sector_size - 1) / sector_size;
+        let data_sector = reserved_sectors + fats_count * fat_sectors + root_sectors;
+        let clusters_count = total_sectors.checked_sub(data_sector)? / sectors_per_cluster;
+
+        let kind = if clusters_count < 4085 {
+            FatKind::Fat12
+        } else if clusters_count < 65525 {
+            FatKind::Fat16
+        } else {
+            FatKind::Fat32
+        };
+
+        let mut fats: Vec<u64> = (0..fats_count).collect();
+        let mut root_cluster = 0;
+        let mut fs_info_offset = None;
+
+        if kind == FatKind::Fat32 {
+            if root_entries != 0 {
+                return None;
+            }
+            // Mirroring is disabled, only the active table is used.
+            let ext_flags = le16(boot, 40);
+            if ext_flags & 0x80 != 0 {
+                fats = vec![(ext_flags & 0xF) as u64];
+            }
+            root_cluster = le32(boot, 44);
+            fs_info_offset = match le16(boot, 48) {
+                0 | 0xFFFF => None,
+                sector => Some(sector as u64 * sector_size),
+            };
+        }
+
+        // The table must be large enough for all clusters.
+        let fat_bits = match kind {
+            FatKind::Fat12 => 12,
+            FatKind::Fat16 => 16,
+            FatKind::Fat32 => 32,
+        };
+        if (clusters_count + 2) * fat_bits > fat_sectors * sector_size * 8 {
+            return None;
+        }
+
+        Some(Self {
+            kind,
+            sector_size,
+            cluster_size: sectors_per_cluster * sector_size,
+            fat_offset: reserved_sectors * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fats,
+            root_offset: (reserved_sectors + fats_count * fat_sectors) * sector_size,
+            root_entries,
+            root_cluster,
+            data_offset: data_sector * sector_size,
+            max_cluster: (clusters_count + 1) as u32,
+            fs_info_offset,
+        })
+
+    }
+
+    /// Get the offset of the given cluster.
+    #[inline]
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    /// Return true if the value is a valid cluster of a chain, false
+    /// for free, bad or end of chain values.
+    #[inline]
+    fn is_cluster(&self, value: u32) -> bool {
+        value >= 2 && value <= self.max_cluster
+    }
+
+}
+
+
+/// A mounted FAT filesystem.
+pub struct FatFs {
+    dev: &'static BlockDevice,
+    geometry: Geometry,
+    /// Used to give a strong reference to the nodes.
+    weak: Weak<FatFs>,
+    /// The state is locked for all operations.
+    state: Mutex<FatState>,
+}
+
+/// Mutable state of the filesystem.
+struct FatState {
+    /// The nodes in use, so only one node exists for each entry.
+    nodes: Vec<Weak<FatNode>>,
+    /// Offset and content of the cached sector of the allocation table.
+    fat_cache: Option<(u64, Vec<u8>)>,
+    /// Cluster where the search of free clusters starts.
+    next_free: u32,
+    /// True once the FSInfo sector has been invalidated, because it's
+    /// not maintained.
+    fs_info_invalidated: bool,
+}
+
+/// A file or a directory of the filesystem.
+pub struct FatNode {
+    fs: Arc<FatFs>,
+    /// Only modified with the state of the filesystem locked.
+    info: Mutex<NodeInfo>,
+}
+
+/// Information about a node, copied from its entry.
+#[derive(Debug, Clone, Copy)]
+struct NodeInfo {
+    /// Offset of the short entry of the node in its parent directory,
+    /// none for the root directory.
+    pos: Option<u64>,
+    /// First cluster, zero for empty files and the root of FAT12/16.
+    cluster: u32,
+    /// Size of files, zero for directories.
+    size: u32,
+    attr: u8,
+    /// The entry of the node has been removed.
+    removed: bool,
+}
+
+/// A directory, either the fixed root directory of FAT12/16 or a chain
+/// of clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    FixedRoot,
+    Chain(u32),
+}
+
+/// A parsed entry of a directory, with its long file name if any.
+#[derive(Debug)]
+struct Entry {
+    /// Long file name or short name.
+    name: String,
+    short: [u8; 11],
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    /// Offsets of the slots of the entry, the short entry is last.
+    slots: Vec<u64>,
+}
+
+impl Entry {
+
+    /// Offset of the short entry.
+    #[inline]
+    fn pos(&self) -> u64 {
+        self.slots[self.slots.len() - 1]
+    }
+
+    #[inline]
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Return true if this entry has the given name, long file names
+    /// and short names are compared case-insensitively.
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_display(&self.short).eq_ignore_ascii_case(name)
+    }
+
+}
+
+
+/// Internal function called by the filesystem driver to mount a block
+/// device, if it contains a FAT filesystem.
+fn probe(dev: &'static BlockDevice) -> FsResult<Option<Arc<dyn FileSystem>>> {
+
+    if dev.sector_size() > MAX_SECTOR_SIZE {
+        return Ok(None);
+    }
+
+    let mut boot = [0; 512];
+    dev.read_bytes(&mut boot, 0).map_err(io_error)?;
+    let Some(geometry) = Geometry::parse(&boot) else {
+        return Ok(None);
+    };
+
+    let fs = Arc::new_cyclic(|weak| FatFs {
+        dev,
+        geometry,
+        weak: weak.clone(),
+        state: Mutex::new(FatState {
+            nodes: Vec::new(),
+            fat_cache: None,
+            next_free: 2,
+            fs_info_invalidated: false,
+        }),
+    });
+
+    Ok(Some(fs))
+
+}
+
+
+impl FileSystem for FatFs {
+
+    fn name(&self) -> &str {
+        "fat"
+    }
+
+    fn root(&self) -> FsResult<Arc<dyn Inode>> {
+        let mut state = self.state.lock();
+        let cluster = self.geometry.root_cluster;
+        Ok(self.node(&mut state, NodeInfo { pos: None, cluster, size: 0, attr: ATTR_DIRECTORY, removed: false }))
+    }
+
+}
+
+impl FatFs {
+
+    /// Get the node of the given entry, it's created if not in use.
+    fn node(&self, state: &mut FatState, info: NodeInfo) -> Arc<FatNode> {
+        state.nodes.retain(|node| node.strong_count() != 0);
+        for node in &state.nodes {
+            if let Some(node) = node.upgrade() {
+                let node_info = *node.info.lock();
+                if !node_info.removed && node_info.pos == info.pos {
+                    return node;
+                }
+            }
+        }
+        let node = Arc::new(FatNode {
+            fs: self.weak.upgrade().unwrap(),
+            info: Mutex::new(info),
+        });
+        state.nodes.push(Arc::downgrade(&node));
+        node
+    }
+
+    /// Find the node in use of the entry at the given offset.
+    fn find_node(&self, state: &FatState, pos: u64) -> Option<Arc<FatNode>> {
+        state.nodes.iter()
+            .filter_map(Weak::upgrade)
+            .find(|node| node.info.lock().pos == Some(pos))
+    }
+
+    fn read(&self, dst: &mut [u8], off: u64) -> FsResult<()> {
+        self.dev.read_bytes(dst, off).map_err(io_error)
+    }
+
+    fn write(&self, src: &[u8], off: u64) -> FsResult<()> {
+        self.dev.write_bytes(src, off).map_err(io_error)
+    }
+
+    // Allocation table
+
+    /// Read bytes of the first allocation table through the cache.
+    fn read_fat(&self, state: &mut FatState, mut off: u64, dst: &mut [u8]) -> FsResult<()> {
+        let sector_size = self.geometry.sector_size;
+        for byte in dst {
+            let sector = off - off % sector_size;
+            let cached = matches!(&state.fat_cache, Some((cached, _)) if *cached == sector);
+            if !cached {
+                let mut data = vec![0; sector_size as usize];
+                self.read(&mut data, sector)?;
+                state.fat_cache = Some((sector, data));
+            }
+            let (_, data) = state.fat_cache.as_ref().unwrap();
+            *byte = data[(off - sector) as usize];
+            off += 1;
+        }
+        Ok(())
+    }
+
+    /// Write bytes to all the allocation tables, the cache is updated.
+    fn write_fat(&self, state: &mut FatState, off: u64, src: &[u8]) -> FsResult<()> {
+        if let Some((sector, data)) = &mut state.fat_cache {
+            for (i, &byte) in src.iter().enumerate() {
+                let byte_off = off + i as u64;
+                if byte_off >= *sector && byte_off < *sector + data.len() as u64 {
+                    data[(byte_off - *sector) as usize] = byte;
+                }
+            }
+        }
+        let relative = off - self.geometry.fat_offset - self.geometry.fats[0] * self.geometry.fat_size;
+        for &fat in &self.geometry.fats {
+            self.write(src, self.geometry.fat_offset + fat * self.geometry.fat_size + relative)?;
+        }
+        Ok(())
+    }
+
+    /// Internal function to get the offset in the first table and the
+    /// size of the entry of a cluster.
+    fn fat_entry(&self, cluster: u32) -> (u64, usize) {
+        let base = self.geometry.fat_offset + self.geometry.fats[0] * self.geometry.fat_size;
+        match self.geometry.kind {
+            FatKind::Fat12 => (base + cluster as u64 + cluster as u64 / 2, 2),
+            FatKind::Fat16 => (base + cluster as u64 * 2, 2),
+            FatKind::Fat32 => (base + cluster as u64 * 4, 4),
+        }
+    }
+
+    /// Get the value of the given cluster in the allocation table.
+    fn get_fat(&self, state: &mut FatState, cluster: u32) -> FsResult<u32> {
+        let (off, len) = self.fat_entry(cluster);
+        let mut buf = [0; 4];
+        self.read_fat(state, off, &mut buf[..len])?;
+        let raw = u32::from_le_bytes(buf);
+        Ok(match self.geometry.kind {
+            FatKind::Fat12 if cluster % 2 == 1 => raw >> 4,
+            FatKind::Fat12 => raw & 0xFFF,
+            FatKind::Fat16 => raw,
+            FatKind::Fat32 => raw & 0x0FFFFFFF,
+        })
+    }
+
+    /// Set the value of the given cluster in the allocation tables.
+    fn set_fat(&self, state: &mut FatState, cluster: u32, value: u32) -> FsResult<()> {
+
+        self.invalidate_fs_info(state)?;
+
+        let (off, len) = self.fat_entry(cluster);
+        let mut buf = [0; 4];
+        self.read_fat(state, off, &mut buf[..len])?;
+        let raw = u32::from_le_bytes(buf);
+
+        // Bits that are not part of the entry are kept.
+        let raw = match self.geometry.kind {
+            FatKind::Fat12 if cluster % 2 == 1 => (raw & 0x000F) | ((value & 0xFFF) << 4),
+            FatKind::Fat12 => (raw & 0xF000) | (value & 0xFFF),
+            FatKind::Fat16 => value & 0xFFFF,
+            FatKind::Fat32 => (raw & 0xF0000000) | (value & 0x0FFFFFFF),
+        };
+
+        self.write_fat(state, off, &raw.to_le_bytes()[..len])
+
+    }
+
+    /// The free clusters count and the next free cluster hints of the
+    /// FSInfo sector are marked unknown before the first modification
+    /// of the allocation table, because they are not maintained.
+    fn invalidate_fs_info(&self, state: &mut FatState) -> FsResult<()> {
+        if state.fs_info_invalidated {
+            return Ok(());
+        }
+        state.fs_info_invalidated = true;
+        if let Some(off) = self.geometry.fs_info_offset {
+            let mut sector = [0; 512];
+            self.read(&mut sector, off)?;
+            if le32(&sector, 0) == FS_INFO_LEAD_SIG && le32(&sector, 484) == FS_INFO_STRUCT_SIG {
+                self.write(&[0xFF; 8], off + 488)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the next cluster of a chain, none at the end.
+    fn next_cluster(&self, state: &mut FatState, cluster: u32) -> FsResult<Option<u32>> {
+        let next = self.get_fat(state, cluster)?;
+        Ok(self.geometry.is_cluster(next).then_some(next))
+    }
+
+    /// Get the cluster at the given index of a chain, none if the chain
+    /// is shorter.
+    fn cluster_at(&self, state: &mut FatState, first: u32, index: u64) -> FsResult<Option<u32>> {
+        if !self.geometry.is_cluster(first) {
+            return Ok(None);
+        }
+        let mut cluster = first;
+        for _ in 0..index {
+            match self.next_cluster(state, cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cluster))
+    }
+
+    /// Get the last cluster of a chain and the length of the chain.
+    fn last_cluster(&self, state: &mut FatState, first: u32) -> FsResult<(u32, u64)> {
+        let mut cluster = first;
+        let mut len = 1;
+        while let Some(next) = self.next_cluster(state, cluster)? {
+            cluster = next;
+            len += 1;
+            if len > self.geometry.max_cluster as u64 {
+                return Err(FsError::Corrupted);
+            }
+        }
+        Ok((cluster, len))
+    }
+
+    /// Allocate a free cluster, marked as the end of a chain, and link
+    /// it after the given cluster if any.
+    fn alloc_cluster(&self, state: &mut FatState, prev: Option<u32>) -> FsResult<u32> {
+        let count = self.geometry.max_cluster - 1;
+        for i in 0..count {
+            let cluster = 2 + (state.next_free - 2 + i) % count;
+            if self.get_fat(state, cluster)? == 0 {
+                self.set_fat(state, cluster, self.geometry.kind.end_of_chain())?;
+                if let Some(prev) = prev {
+                    self.set_fat(state, prev, cluster)?;
+                }
+                state.next_free = cluster;
+                return Ok(cluster);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Free all the clusters of a chain.
+    fn free_chain(&self, state: &mut FatState, first: u32) -> FsResult<()> {
+        let mut cluster = Some(first).filter(|&first| self.geometry.is_cluster(first));
+        let mut count = 0;
+        while let Some(current) = cluster {
+            cluster = self.next_cluster(state, current)?;
+            self.set_fat(state, current, 0)?;
+            count += 1;
+            if count > self.geometry.max_cluster {
+                return Err(FsError::Corrupted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write zeros to a whole cluster.
+    fn zero_cluster(&self, cluster: u32) -> FsResult<()> {
+        let zeros = vec![0; self.geometry.cluster_size as usize];
+        self.write(&zeros, self.geometry.cluster_offset(cluster))
+    }
+
+    // Data
+
+    /// Read the data of a chain at the given offset.
+    fn read_data(&self, state: &mut FatState, first: u32, off: u64, mut dst: &mut [u8]) -> FsResult<()> {
+        let cluster_size = self.geometry.cluster_size;
+        let mut cluster = self.cluster_at(state, first, off / cluster_size)?;
+        let mut cluster_off = off % cluster_size;
+        while !dst.is_empty() {
+            let current = cluster.ok_or(FsError::Corrupted)?;
+            let len = ((cluster_size - cluster_off) as usize).min(dst.len());
+            self.read(&mut dst[..len], self.geometry.cluster_offset(current) + cluster_off)?;
+            dst = &mut dst[len..];
+            cluster_off = 0;
+            if !dst.is_empty() {
+                cluster = self.next_cluster(state, current)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the data of a chain at the given offset, the chain is
+    /// extended if needed and the first cluster of the node is
+    /// allocated if it's empty.
+    fn write_data(&self, state: &mut FatState, info: &mut NodeInfo, off: u64, mut src: &[u8]) -> FsResult<()> {
+
+        if src.is_empty() {
+            return Ok(());
+        }
+
+        let cluster_size = self.geometry.cluster_size;
+        let end = off + src.len() as u64;
+        self.ensure_clusters(state, info, (end + cluster_size - 1) / cluster_size)?;
+
+        let mut cluster = self.cluster_at(state, info.cluster, off / cluster_size)?;
+        let mut cluster_off = off % cluster_size;
+        while !src.is_empty() {
+            let current = cluster.ok_or(FsError::Corrupted)?;
+            let len = ((cluster_size - cluster_off) as usize).min(src.len());
+            self.write(&src[..len], self.geometry.cluster_offset(current) + cluster_off)?;
+            src = &src[len..];
+            cluster_off = 0;
+            if !src.is_empty() {
+                cluster = self.next_cluster(state, current)?;
+            }
+        }
+
+        Ok(())
+
+    }
+
+    /// Write zeros to the data of a chain, between the given offsets.
+    fn zero_data(&self, state: &mut FatState, info: &mut NodeInfo, mut off: u64, end: u64) -> FsResult<()> {
+        let zeros = [0; 512];
+        while off < end {
+            let len = ((end - off) as usize).min(zeros.len());
+            self.write_data(state, info, off, &zeros[..len])?;
+            off += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Extend the chain of the node to the given number of clusters.
+    fn ensure_clusters(&self, state: &mut FatState, info: &mut NodeInfo, count: u64) -> FsResult<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let (mut last, mut len) = if self.geometry.is_cluster(info.cluster) {
+            self.last_cluster(state, info.cluster)?
+        } else {
+            let first = self.alloc_cluster(state, None)?;
+            info.cluster = first;
+            (first, 1)
+        };
+        while len < count {
+            last = self.alloc_cluster(state, Some(last))?;
+            len += 1;
+        }
+        Ok(())
+    }
+
+    /// Shrink the chain of the node to the given number of clusters.
+    fn shrink_clusters(&self, state: &mut FatState, info: &mut NodeInfo, count: u64) -> FsResult<()> {
+        if count == 0 {
+            let first = info.cluster;
+            info.cluster = 0;
+            return self.free_chain(state, first);
+        }
+        if let Some(last) = self.cluster_at(state, info.cluster, count - 1)? {
+            if let Some(next) = self.next_cluster(state, last)? {
+                self.set_fat(state, last, self.geometry.kind.end_of_chain())?;
+                self.free_chain(state, next)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Directories
+
+    /// Get the directory of a directory node.
+    fn dir(&self, info: &NodeInfo) -> Dir {
+        if info.pos.is_none() && self.geometry.kind != FatKind::Fat32 {
+            Dir::FixedRoot
+        } else {
+            Dir::Chain(info.cluster)
+        }
+    }
+
+    /// Call the given function with the offset and the content of each
+    /// slot of a directory, until it returns false. Return false if
+    /// stopped by the function.
+    fn for_each_slot(&self, state: &mut FatState, dir: Dir, mut func: impl FnMut(&mut FatState, u64, &[u8]) -> FsResult<bool>) -> FsResult<bool> {
+
+        let mut scan = |state: &mut FatState, region_off: u64, region: &[u8]| -> FsResult<bool> {
+            for (i, slot) in region.chunks_exact(ENTRY_SIZE as usize).enumerate() {
+                if !func(state, region_off + i as u64 * ENTRY_SIZE, slot)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        };
+
+        match dir {
+            Dir::FixedRoot => {
+                let mut region = vec![0; (self.geometry.root_entries * ENTRY_SIZE) as usize];
+                self.read(&mut region, self.geometry.root_offset)?;
+                scan(state, self.geometry.root_offset, &region)
+            }
+            Dir::Chain(first) => {
+                let mut region = vec![0; self.geometry.cluster_size as usize];
+                let mut cluster = Some(first).filter(|&first| self.geometry.is_cluster(first));
+                let mut count = 0;
+                while let Some(current) = cluster {
+                    let region_off = self.geometry.cluster_offset(current);
+                    self.read(&mut region, region_off)?;
+                    if !scan(state, region_off, &region)? {
+                        return Ok(false);
+                    }
+                    cluster = self.next_cluster(state, current)?;
+                    count += 1;
+                    if count > self.geometry.max_cluster {
+                        return Err(FsError::Corrupted);
+                    }
+                }
+                Ok(true)
+            }
+        }
+
+    }
+
+    /// Call the given function with each entry of a directory, until it
+    /// returns false. Volume labels and the `.` and `..` entries are
+    /// skipped.
+    fn for_each_entry(&self, state: &mut FatState, dir: Dir, mut func: impl FnMut(&mut FatState, Entry) -> FsResult<bool>) -> FsResult<()> {
+
+        // Long file name being parsed, characters are stored in order.
+        let mut lfn = [0u16; LFN_CHARS * 20];
+        let mut lfn_slots = Vec::new();
+        let mut lfn_next_ord = 0;
+        let mut lfn_checksum = 0;
+
+        self.for_each_slot(state, dir, |state, pos, slot| {
+
+            match slot[0] {
+                ENTRY_END => return Ok(false),
+                ENTRY_DELETED => {
+                    lfn_slots.clear();
+                    return Ok(true);
+                }
+                _ => {}
+            }
+
+            let attr = slot[11];
+            if attr & 0x3F == ATTR_LONG_NAME {
+                let ord = slot[0] & !LFN_LAST;
+                if slot[0] & LFN_LAST != 0 && (1..=20).contains(&ord) {
+                    lfn_slots.clear();
+                    lfn_checksum = slot[13];
+                    lfn[..].fill(0xFFFF);
+                } else if ord == 0 || lfn_slots.is_empty() || ord != lfn_next_ord || slot[13] != lfn_checksum {
+                    lfn_slots.clear();
+                    return Ok(true);
+                }
+                let start = (ord as usize - 1) * LFN_CHARS;
+                for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+                    lfn[start + i] = le16(slot, offset);
+                }
+                lfn_slots.push(pos);
+                lfn_next_ord = ord - 1;
+                return Ok(true);
+            }
+
+            let lfn_valid = lfn_next_ord == 0 && !lfn_slots.is_empty() && checksum(&slot[..11]) == lfn_checksum;
+            let lfn_len = lfn.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(lfn.len());
+            let mut slots = if lfn_valid { core::mem::take(&mut lfn_slots) } else { Vec::new() };
+            lfn_slots.clear();
+            lfn_next_ord = 0;
+
+            if attr & ATTR_VOLUME_ID != 0 || slot[0] == b'.' {
+                return Ok(true);
+            }
+
+            let mut short = [0; 11];
+            short.copy_from_slice(&slot[..11]);
+            if short[0] == 0x05 {
+                short[0] = ENTRY_DELETED;
+            }
+
+            let name = if lfn_valid {
+                char::decode_utf16(lfn[..lfn_len].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            } else {
+                short_display_with_case(&short, slot[12])
+            };
+
+            slots.push(pos);
+            let cluster = if self.geometry.kind == FatKind::Fat32 {
+                ((le16(slot, 20) as u32) << 16) | le16(slot, 26) as u32
+            } else {
+                le16(slot, 26) as u32
+            };
+
+            func(state, Entry { name, short, attr, cluster, size: le32(slot, 28), slots })
+
+        })?;
+
+        Ok(())
+
+    }
+
+    /// Find the entry of a directory with the given name.
+    fn find_entry(&self, state: &mut FatState, dir: Dir, name: &str) -> FsResult<Option<Entry>> {
+        let mut found = None;
+        self.for_each_entry(state, dir, |_, entry| {
+            if entry.matches(name) {
+                found = Some(entry);
+                Ok(false)
+            } else {
+                Ok(true)
+            }
+        })?;
+        Ok(found)
+    }
+
+    /// Return true if the directory has no entries.
+    fn is_dir_empty(&self, state: &mut FatState, dir: Dir) -> FsResult<bool> {
+        let mut empty = true;
+        self.for_each_entry(state, dir, |_, _| {
+            empty = false;
+            Ok(false)
+        })?;
+        Ok(empty)
+    }
+
+    /// Find consecutive free slots in a directory, it's extended if
+    /// needed. The node is given to update its first cluster.
+    fn alloc_slots(&self, state: &mut FatState, dir_node: &mut NodeInfo, count: usize) -> FsResult<Vec<u64>> {
+        loop {
+
+            let dir = self.dir(dir_node);
+            let mut run = Vec::new();
+            self.for_each_slot(state, dir, |_, pos, slot| {
+                if slot[0] == ENTRY_DELETED || slot[0] == ENTRY_END {
+                    run.push(pos);
+                } else {
+                    run.clear();
+                }
+                Ok(run.len() < count)
+            })?;
+
+            if run.len() >= count {
+                return Ok(run);
+            }
+
+            match dir {
+                Dir::FixedRoot => return Err(FsError::NoSpace),
+                Dir::Chain(first) => {
+                    let prev = if self.geometry.is_cluster(first) {
+                        Some(self.last_cluster(state, first)?.0)
+                    } else {
+                        None
+                    };
+                    let cluster = self.alloc_cluster(state, prev)?;
+                    self.zero_cluster(cluster)?;
+                    if prev.is_none() {
+                        dir_node.cluster = cluster;
+                    }
+                }
+            }
+
+        }
+    }
+
+    /// Write the slots of a new entry in a directory, with a long file
+    /// name if needed. Return the offset of the short entry.
+    fn add_entry(&self, state: &mut FatState, dir_node: &mut NodeInfo, name: &str, attr: u8, cluster: u32, size: u32) -> FsResult<u64> {
+
+        let dir = self.dir(dir_node);
+        if self.find_entry(state, dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short, lfn) = match exact_short_name(name) {
+            Some(short) => (short, None),
+            None => {
+                let lfn: Vec<u16> = name.encode_utf16().collect();
+                if lfn.len() > LFN_MAX_LEN {
+                    return Err(FsError::NameTooLong);
+                }
+                (self.generate_short_name(state, dir, name)?, Some(lfn))
+            }
+        };
+
+        let lfn_count = lfn.as_ref().map_or(0, |lfn| (lfn.len() + LFN_CHARS - 1) / LFN_CHARS);
+        let slots = self.alloc_slots(state, dir_node, lfn_count + 1)?;
+
+        if let Some(lfn) = &lfn {
+            let sum = checksum(&short);
+            for (i, &pos) in slots[..lfn_count].iter().enumerate() {
+                // Slots are stored from the last part of the name.
+                let ord = (lfn_count - i) as u8;
+                let mut slot = [0; ENTRY_SIZE as usize];
+                slot[0] = if i == 0 { ord | LFN_LAST } else { ord };
+                slot[11] = ATTR_LONG_NAME;
+                slot[13] = sum;
+                for (j, &offset) in LFN_OFFSETS.iter().enumerate() {
+                    let index = (ord as usize - 1) * LFN_CHARS + j;
+                    let c = match index.cmp(&lfn.len()) {
+                        core::cmp::Ordering::Less => lfn[index],
+                        core::cmp::Ordering::Equal => 0,
+                        core::cmp::Ordering::Greater => 0xFFFF,
+                    };
+                    slot[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+                }
+                self.write(&slot, pos)?;
+            }
+        }
+
+        let pos = slots[lfn_count];
+        self.write(&short_entry(&short, attr, cluster, size), pos)?;
+        Ok(pos)
+
+    }
+
+    /// Mark all the slots of an entry as deleted.
+    fn remove_entry(&self, entry: &Entry) -> FsResult<()> {
+        for &pos in &entry.slots {
+            self.write(&[ENTRY_DELETED], pos)?;
+        }
+        Ok(())
+    }
+
+    /// Generate a unique short name for a long file name, like
+    /// `LONGNA~1.TXT`.
+    fn generate_short_name(&self, state: &mut FatState, dir: Dir, name: &str) -> FsResult<[u8; 11]> {
+
+        let (base, ext) = match name.rfind('.') {
+            Some(index) if index != 0 => (&name[..index], &name[index + 1..]),
+            _ => (name, ""),
+        };
+
+        let mut basis = [b' '; 11];
+        let mut base_len = 0;
+        for c in base.chars().filter(|&c| c != ' ' && c != '.') {
+            if base_len == 8 {
+                break;
+            }
+            basis[base_len] = short_char(c);
+            base_len += 1;
+        }
+        if base_len == 0 {
+            basis[0] = b'_';
+            base_len = 1;
+        }
+        for (i, c) in ext.chars().filter(|&c| c != ' ' && c != '.').take(3).enumerate() {
+            basis[8 + i] = short_char(c);
+        }
+
+        let mut used = Vec::new();
+        self.for_each_entry(state, dir, |_, entry| {
+            used.push(entry.short);
+            Ok(true)
+        })?;
+
+        for n in 1..1_000_000u32 {
+            let mut suffix = [0; 8];
+            let suffix_len = {
+                let mut digits = [0; 7];
+                let mut len = 0;
+                let mut value = n;
+                while value != 0 {
+                    digits[len] = b'0' + (value % 10) as u8;
+                    value /= 10;
+                    len += 1;
+                }
+                suffix[0] = b'~';
+                for i in 0..len {
+                    suffix[1 + i] = digits[len - 1 - i];
+                }
+                len + 1
+            };
+            let mut short = basis;
+            let start = base_len.min(8 - suffix_len);
+            short[start..start + suffix_len].copy_from_slice(&suffix[..suffix_len]);
+            short[start + suffix_len..8].fill(b' ');
+            if !used.contains(&short) {
+                return Ok(short);
+            }
+        }
+
+        Err(FsError::NoSpace)
+
+    }
+
+    /// Update the first cluster and the size in the entry of a node.
+    fn update_entry(&self, info: &NodeInfo) -> FsResult<()> {
+        let Some(pos) = info.pos else { return Ok(()) };
+        let mut buf = [0; 12];
+        buf[0..2].copy_from_slice(&((info.cluster >> 16) as u16).to_le_bytes());
+        buf[6..8].copy_from_slice(&(info.cluster as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(&info.size.to_le_bytes());
+        if self.geometry.kind == FatKind::Fat32 {
+            self.write(&buf, pos + 20)
+        } else {
+            self.write(&buf[6..], pos + 26)
+        }
+    }
+
+    /// Get the cluster of a directory as stored in `..` entries, zero
+    /// for the root directory.
+    fn dot_dot_cluster(&self, info: &NodeInfo) -> u32 {
+        if info.pos.is_none() { 0 } else { info.cluster }
+    }
+
+    /// Get the parent directory cluster of a directory, from its `..`
+    /// entry, zero for the root directory.
+    fn parent_cluster(&self, state: &mut FatState, cluster: u32) -> FsResult<u32> {
+        let mut parent = 0;
+        self.for_each_slot(state, Dir::Chain(cluster), |_, _, slot| {
+            if &slot[..11] == b"..         " {
+                parent = if self.geometry.kind == FatKind::Fat32 {
+                    ((le16(slot, 20) as u32) << 16) | le16(slot, 26) as u32
+                } else {
+                    le16(slot, 26) as u32
+                };
+                Ok(false)
+            } else {
+                Ok(slot[0] == b'.')
+            }
+        })?;
+        Ok(parent)
+    }
+
+}
+
+
+impl FatNode {
+
+    /// Get the information of the node, if not removed.
+    fn info(&self) -> FsResult<NodeInfo> {
+        let info = *self.info.lock();
+        if info.removed {
+            Err(FsError::NotFound)
+        } else {
+            Ok(info)
+        }
+    }
+
+    /// Get the information of the node, it must be a directory.
+    fn dir_info(&self) -> FsResult<NodeInfo> {
+        let info = self.info()?;
+        if info.attr & ATTR_DIRECTORY == 0 {
+            Err(FsError::NotDirectory)
+        } else {
+            Ok(info)
+        }
+    }
+
+    /// Check that the filesystem can be modified.
+    fn check_writable(&self) -> FsResult<()> {
+        if self.fs.dev.read_only() {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Internal function to modify the information of the node, once
+    /// the operation succeeded.
+    fn set_info(&self, info: NodeInfo) {
+        *self.info.lock() = info;
+    }
+
+}
+
+impl Inode for FatNode {
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FsResult<Metadata> {
+        let info = self.info()?;
+        let dir = info.attr & ATTR_DIRECTORY != 0;
+        Ok(Metadata {
+            kind: if dir { InodeKind::Directory } else { InodeKind::File },
+            size: info.size as u64,
+            ino: info.pos.map_or(1, |pos| pos / ENTRY_SIZE + 2),
+            mode: if info.attr & ATTR_READ_ONLY != 0 { 0o555 } else { 0o777 },
+            links: 1,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let info = self.info()?;
+        if info.attr & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        let size = info.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = ((size - offset) as usize).min(buf.len());
+        fs.read_data(&mut state, info.cluster, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut info = self.info()?;
+        if info.attr & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        let end = offset.checked_add(buf.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(FsError::NoSpace)?;
+        // The gap after the end of the file is filled with zeros.
+        let size = info.size as u64;
+        let ret = fs.zero_data(&mut state, &mut info, size, offset)
+            .and_then(|_| fs.write_data(&mut state, &mut info, offset, buf));
+        info.size = info.size.max(end as u32);
+        // The entry is updated even on failure, for allocated clusters.
+        fs.update_entry(&info)?;
+        self.set_info(info);
+        ret.map(|_| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut info = self.info()?;
+        if info.attr & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDirectory);
+        } else if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let ret = if size < info.size as u64 {
+            let cluster_size = fs.geometry.cluster_size;
+            fs.shrink_clusters(&mut state, &mut info, (size + cluster_size - 1) / cluster_size)
+        } else {
+            let old_size = info.size as u64;
+            fs.zero_data(&mut state, &mut info, old_size, size)
+        };
+        if ret.is_ok() {
+            info.size = size as u32;
+        }
+        fs.update_entry(&info)?;
+        self.set_info(info);
+        ret
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let info = self.dir_info()?;
+        let entry = fs.find_entry(&mut state, fs.dir(&info), name)?.ok_or(FsError::NotFound)?;
+        Ok(fs.node(&mut state, NodeInfo {
+            pos: Some(entry.pos()),
+            cluster: entry.cluster,
+            size: if entry.is_dir() { 0 } else { entry.size },
+            attr: entry.attr,
+            removed: false,
+        }))
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let info = self.dir_info()?;
+        let mut current = 0;
+        let mut found = None;
+        fs.for_each_entry(&mut state, fs.dir(&info), |_, entry| {
+            if current == index {
+                found = Some(DirEntry {
+                    kind: if entry.is_dir() { InodeKind::Directory } else { InodeKind::File },
+                    ino: entry.pos() / ENTRY_SIZE + 2,
+                    name: entry.name,
+                });
+                Ok(false)
+            } else {
+                current += 1;
+                Ok(true)
+            }
+        })?;
+        Ok(found)
+    }
+
+    fn create(&self, name: &str, kind: InodeKind) -> FsResult<Arc<dyn Inode>> {
+
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut info = self.dir_info()?;
+
+        let (attr, cluster) = match kind {
+            InodeKind::File => (ATTR_ARCHIVE, 0),
+            InodeKind::Directory => {
+                // The directory is initialized with its '.' and '..' entries.
+                let cluster = fs.alloc_cluster(&mut state, None)?;
+                let mut data = vec![0; fs.geometry.cluster_size as usize];
+                data[..32].copy_from_slice(&short_entry(b".          ", ATTR_DIRECTORY, cluster, 0));
+                data[32..64].copy_from_slice(&short_entry(b"..         ", ATTR_DIRECTORY, fs.dot_dot_cluster(&info), 0));
+                if let Err(e) = fs.write(&data, fs.geometry.cluster_offset(cluster)) {
+                    fs.free_chain(&mut state, cluster)?;
+                    return Err(e);
+                }
+                (ATTR_DIRECTORY, cluster)
+            }
+            _ => return Err(FsError::Unsupported),
+        };
+
+        let ret = fs.add_entry(&mut state, &mut info, name, attr, cluster, 0);
+        // The directory might have been extended.
+        self.set_info(info);
+
+        let pos = match ret {
+            Ok(pos) => pos,
+            Err(e) => {
+                if cluster != 0 {
+                    fs.free_chain(&mut state, cluster)?;
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(fs.node(&mut state, NodeInfo { pos: Some(pos), cluster, size: 0, attr, removed: false }))
+
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+
+        self.check_writable()?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let info = self.dir_info()?;
+
+        let entry = fs.find_entry(&mut state, fs.dir(&info), name)?.ok_or(FsError::NotFound)?;
+        if entry.is_dir() && !fs.is_dir_empty(&mut state, Dir::Chain(entry.cluster))? {
+            return Err(FsError::NotEmpty);
+        } else if entry.attr & ATTR_READ_ONLY != 0 {
+            return Err(FsError::PermissionDenied);
+        }
+
+        fs.remove_entry(&entry)?;
+        fs.free_chain(&mut state, entry.cluster)?;
+
+        if let Some(node) = fs.find_node(&state, entry.pos()) {
+            let mut node_info = node.info.lock();
+            node_info.removed = true;
+            node_info.pos = None;
+        }
+
+        Ok(())
+
+    }
+
+    fn rename(&self, name: &str, new_parent: &dyn Inode, new_name: &str) -> FsResult<()> {
+
+        self.check_writable()?;
+        let new_parent = new_parent.as_any().downcast_ref::<FatNode>().ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.fs, &new_parent.fs) {
+            return Err(FsError::CrossDevice);
+        }
+
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let info = self.dir_info()?;
+        let mut new_info = new_parent.dir_info()?;
+        let same_parent = info.pos == new_info.pos;
+
+        let entry = fs.find_entry(&mut state, fs.dir(&info), name)?.ok_or(FsError::NotFound)?;
+
+        if entry.is_dir() && !same_parent {
+            // A directory can't be moved into itself.
+            let mut cluster = fs.dot_dot_cluster(&new_info);
+            while cluster != 0 {
+                if cluster == entry.cluster {
+                    return Err(FsError::InvalidArgument);
+                }
+                cluster = fs.parent_cluster(&mut state, cluster)?;
+            }
+        }
+
+        if let Some(existing) = fs.find_entry(&mut state, fs.dir(&new_info), new_name)? {
+            if existing.pos() == entry.pos() {
+                // Same entry, but the case of the name might change.
+                if existing.name == new_name {
+                    return Ok(());
+                }
+            } else if existing.is_dir() != entry.is_dir() {
+                return Err(if existing.is_dir() { FsError::IsDirectory } else { FsError::NotDirectory });
+            } else if existing.is_dir() && !fs.is_dir_empty(&mut state, Dir::Chain(existing.cluster))? {
+                return Err(FsError::NotEmpty);
+            } else {
+                fs.remove_entry(&existing)?;
+                fs.free_chain(&mut state, existing.cluster)?;
+                if let Some(node) = fs.find_node(&state, existing.pos()) {
+                    let mut node_info = node.info.lock();
+                    node_info.removed = true;
+                    node_info.pos = None;
+                }
+            }
+        }
+
+        // The raw slots of the old entry are saved to restore them if
+        // the new entry can't be added, the long name slots included.
+        let mut saved = vec![[0; ENTRY_SIZE as usize]; entry.slots.len()];
+        for (slot, &pos) in saved.iter_mut().zip(&entry.slots) {
+            fs.read(slot, pos)?;
+        }
+
+        // The old entry is removed first, so its slots can be reused.
+        fs.remove_entry(&entry)?;
+        let ret = fs.add_entry(&mut state, &mut new_info, new_name, entry.attr, entry.cluster, entry.size);
+        new_parent.set_info(new_info);
+        if same_parent {
+            self.set_info(new_info);
+        }
+
+        let pos = match ret {
+            Ok(pos) => pos,
+            Err(e) => {
+                // Restore the old entry, its slots might have been reused.
+                for (slot, &pos) in saved.iter().zip(&entry.slots) {
+                    fs.write(slot, pos)?;
+                }
+                return Err(e);
+            }
+        };
+
+        if entry.is_dir() && !same_parent {
+            let dot_dot = short_entry(b"..         ", ATTR_DIRECTORY, fs.dot_dot_cluster(&new_info), 0);
+            fs.write(&dot_dot, fs.geometry.cluster_offset(entry.cluster) + ENTRY_SIZE)?;
+        }
+
+        if let Some(node) = fs.find_node(&state, entry.pos()) {
+            node.info.lock().pos = Some(pos);
+        }
+
+        Ok(())
+
+    }
+
+}
+
+
+/// Internal function to convert a block device error.
+fn io_error(e: BlockIoError) -> FsError {
+    match e {
+        BlockIoError::ReadOnly => FsError::ReadOnly,
+        _ => FsError::Io,
+    }
+}
+
+#[inline]
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+#[inline]
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+
+/// Compute the checksum of a short name, stored in the long file name
+/// entries of the short entry.
+fn checksum(short: &[u8]) -> u8 {
+    short.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+
+/// Build a short entry.
+fn short_entry(short: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; ENTRY_SIZE as usize] {
+    let mut slot = [0; ENTRY_SIZE as usize];
+    slot[..11].copy_from_slice(short);
+    if slot[0] == ENTRY_DELETED {
+        slot[0] = 0x05;
+    }
+    slot[11] = attr;
+    slot[16..18].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    slot[18..20].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    slot[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    slot[24..26].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    slot[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    slot[28..32].copy_from_slice(&size.to_le_bytes());
+    slot
+}
+
+
+/// Get the displayed form of a short name, like `README.TXT`.
+fn short_display(short: &[u8; 11]) -> String {
+    short_display_with_case(short, 0)
+}
+
+/// Get the displayed form of a short name, the case flags of the entry
+/// make the base or the extension lowercase.
+fn short_display_with_case(short: &[u8; 11], case: u8) -> String {
+    let mut name = String::with_capacity(12);
+    let base = short[..8].iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+    let ext = short[8..].iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+    for &c in &short[..base] {
+        name.push(if case & 0x08 != 0 { c.to_ascii_lowercase() } else { c } as char);
+    }
+    if ext != 0 {
+        name.push('.');
+        for &c in &short[8..8 + ext] {
+            name.push(if case & 0x10 != 0 { c.to_ascii_lowercase() } else { c } as char);
+        }
+    }
+    name
+}
+
+
+/// Get the short name of a name that is a valid uppercase 8.3 name, so
+/// no long file name is needed.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+    let valid = |c: &u8| c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(c);
+    if !base.bytes().all(|c| valid(&c)) || !ext.bytes().all(|c| valid(&c)) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+
+/// Convert a character of a long file name to a character of a short
+/// name, invalid ones are replaced by an underscore.
+fn short_char(c: char) -> u8 {
+    let c = c.to_ascii_uppercase();
+    if c.is_ascii_uppercase() || c.is_ascii_digit() || (c.is_ascii() && SHORT_NAME_SPECIALS.contains(&(c as u8))) {
+        c as u8
+    } else {
+        b'_'
+    }
+}
@@ -0,0 +1,131 @@
+//! Opened files, and the table of files opened by processes.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::sync::{IrqSpinLock, Mutex};
+
+use super::{File, FsError, FsResult, Inode, InodeKind, OpenFlags, SeekFrom};
+
+
+/// Identifier of a file in the open-file table.
+pub type FileId = usize;
+
+
+/// All the files opened by processes, indexed by their identifier, the
+/// handles of the processes refer to them.
+static OPEN_FILES: IrqSpinLock<Vec<Option<Arc<dyn File>>>> = IrqSpinLock::new(Vec::new());
+
+
+/// Insert an opened file in the open-file table, return its identifier.
+pub fn insert(file: Box<dyn File>) -> FileId {
+    let file = Arc::from(file);
+    let mut files = OPEN_FILES.lock();
+    if let Some(id) = files.iter().position(Option::is_none) {
+        files[id] = Some(file);
+        id
+    } else {
+        files.push(Some(file));
+        files.len() - 1
+    }
+}
+
+
+/// Get an opened file from its identifier. The file is shared, so it
+/// can be used without keeping the table locked.
+pub fn get(id: FileId) -> Option<Arc<dyn File>> {
+    OPEN_FILES.lock().get(id)?.clone()
+}
+
+
+/// Close the file with the given identifier, it's dropped once no
+/// longer used. Return false if the file is not opened.
+///
+/// Files might be closed with the process table locked, when killing
+/// a process, so dropping a file must not block.
+pub fn close(id: FileId) -> bool {
+    let file = OPEN_FILES.lock().get_mut(id).and_then(Option::take);
+    file.is_some()
+}
+
+
+/// Generic file for inodes of filesystems, reads and writes are done at
+/// the offset of the file. Reading a directory returns the names of its
+/// entries, each one followed by a line feed, the offset is then the
+/// index of the next entry.
+pub struct InodeFile {
+    inode: Arc<dyn Inode>,
+    kind: InodeKind,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl InodeFile {
+
+    pub fn new(inode: Arc<dyn Inode>, kind: InodeKind, flags: OpenFlags) -> Self {
+        Self { inode, kind, flags, offset: Mutex::new(0) }
+    }
+
+    /// Internal function to read the names of the directory entries.
+    fn read_dir(&self, index: &mut u64, buf: &mut [u8]) -> FsResult<usize> {
+        let mut len = 0;
+        while let Some(entry) = self.inode.read_dir(*index as usize)? {
+            let name = entry.name.as_bytes();
+            if len + name.len() + 1 > buf.len() {
+                if len == 0 {
+                    return Err(FsError::NameTooLong);
+                }
+                break;
+            }
+            buf[len..len + name.len()].copy_from_slice(name);
+            buf[len + name.len()] = b'\n';
+            len += name.len() + 1;
+            *index += 1;
+        }
+        Ok(len)
+    }
+
+}
+
+impl File for InodeFile {
+
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut offset = self.offset.lock();
+        if self.kind == InodeKind::Directory {
+            return self.read_dir(&mut offset, buf);
+        }
+        let len = self.inode.read_at(*offset, buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::PermissionDenied);
+        } else if self.kind == InodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        let mut offset = self.offset.lock();
+        let len = self.inode.write_at(*offset, buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> FsResult<u64> {
+        let mut offset = self.offset.lock();
+        let (base, delta) = match pos {
+            SeekFrom::Start(new_offset) => (0, new_offset as i64),
+            SeekFrom::Current(delta) => (*offset, delta),
+            // The end of a directory is unknown without reading it.
+            SeekFrom::End(_) if self.kind == InodeKind::Directory => return Err(FsError::Unsupported),
+            SeekFrom::End(delta) => (self.inode.metadata()?.size, delta),
+        };
+        *offset = base.checked_add_signed(delta).ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+}
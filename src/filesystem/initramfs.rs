@@ -0,0 +1,345 @@
+//! Initial RAM filesystem, an archive unpacked in the root filesystem
+//! at boot. Archives are either `cpio` (newc format) or `tar` (ustar
+//! format), they are linked into the kernel with the `initramfs`
+//! feature, or given by the bootloader as the initrd. GNU long names
+//! and POSIX extended headers are supported for names of `tar` entries.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::format;
+
+use super::{FsError, FsResult, OpenFlags, path};
+
+
+/// Magic of `cpio` headers in the newc format, without and with CRC.
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+/// Size of `cpio` newc headers.
+const CPIO_HEADER_SIZE: usize = 110;
+/// Name of the last entry of `cpio` archives.
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// Size of `tar` blocks.
+const TAR_BLOCK_SIZE: usize = 512;
+/// Magic of `tar` headers in the ustar format, at offset 257, the
+/// POSIX one is followed by a nul byte and has a prefix of names.
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_POSIX_MAGIC: &[u8] = b"ustar\0";
+
+/// Types of files, in the mode of `cpio` entries.
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+
+/// The archive linked into the kernel with the `initramfs` feature.
+#[cfg(feature = "initramfs")]
+static ARCHIVE: &[u8] = include_bytes!(env!("AVES_INITRAMFS"));
+
+
+/// Unpack the archive linked into the kernel, if any, and then the
+/// initrd given by the bootloader, if any. Errors are printed.
+///
+/// *This function is unsafe because it must be called after the
+/// initialization of the memory, the initrd is read in place.*
+pub unsafe fn load(root: &str) {
+
+    #[cfg(feature = "initramfs")]
+    match unpack(ARCHIVE, root) {
+        Ok(count) => crate::println!(" = Unpacked {} entries from the kernel archive", count),
+        Err(e) => crate::println!(" = Failed to unpack the kernel archive: {:?}", e),
+    }
+
+    if let Some(region) = crate::memory::initrd() {
+        let data = core::slice::from_raw_parts(region.start as *const u8, region.size());
+        match unpack(data, root) {
+            Ok(count) => crate::println!(" = Unpacked {} entries from the initrd", count),
+            Err(e) => crate::println!(" = Failed to unpack the initrd: {:?}", e),
+        }
+    }
+
+}
+
+
+/// Unpack a `cpio` or `tar` archive in the given directory, return the
+/// number of entries unpacked. Existing directories are kept and other
+/// existing files are replaced. Entries of unsupported types are
+/// ignored.
+pub fn unpack(data: &[u8], root: &str) -> FsResult<usize> {
+    if data.starts_with(CPIO_MAGIC) || data.starts_with(CPIO_CRC_MAGIC) {
+        unpack_cpio(data, root)
+    } else if data.get(257..262) == Some(TAR_MAGIC) {
+        unpack_tar(data, root)
+    } else {
+        Err(FsError::Unsupported)
+    }
+}
+
+
+/// Internal function to unpack a `cpio` archive in the newc format.
+fn unpack_cpio(data: &[u8], root: &str) -> FsResult<usize> {
+
+    // Hard links share their inode number, only the first path of each
+    // inode is kept.
+    let mut inodes: Vec<(u32, String)> = Vec::new();
+    let mut count = 0;
+    let mut offset = 0;
+
+    loop {
+
+        let header = data.get(offset..offset + CPIO_HEADER_SIZE).ok_or(FsError::Corrupted)?;
+        if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(FsError::Corrupted);
+        }
+
+        let field = |index: usize| -> FsResult<u32> {
+            let hex = core::str::from_utf8(&header[6 + index * 8..14 + index * 8]).map_err(|_| FsError::Corrupted)?;
+            u32::from_str_radix(hex, 16).map_err(|_| FsError::Corrupted)
+        };
+
+        let ino = field(0)?;
+        let mode = field(1)?;
+        let nlink = field(4)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // The name and the data are aligned to 4 bytes.
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let data_start = align4(name_start + name_size);
+        let data_end = data_start + file_size;
+        offset = align4(data_end);
+
+        let name = data.get(name_start..name_start + name_size.saturating_sub(1)).ok_or(FsError::Corrupted)?;
+        let name = core::str::from_utf8(name).map_err(|_| FsError::Corrupted)?;
+        if name == CPIO_TRAILER {
+            return Ok(count);
+        }
+
+        let content = data.get(data_start..data_end).ok_or(FsError::Corrupted)?;
+        let Some(path) = entry_path(root, name) else { continue };
+
+        match mode & S_IFMT {
+            S_IFDIR => create_dir(&path)?,
+            S_IFLNK => {
+                let target = core::str::from_utf8(content).map_err(|_| FsError::Corrupted)?;
+                create_symlink(target, &path)?;
+            }
+            S_IFREG => {
+                let first = inodes.iter().find(|(entry_ino, _)| *entry_ino == ino);
+                match first {
+                    Some((_, first)) if nlink > 1 => {
+                        create_link(first, &path)?;
+                        // The data is stored with the last link.
+                        if !content.is_empty() {
+                            write_file(&path, content)?;
+                        }
+                    }
+                    _ => {
+                        write_file(&path, content)?;
+                        if nlink > 1 {
+                            inodes.push((ino, path));
+                        }
+                    }
+                }
+            }
+            _ => continue,
+        }
+
+        count += 1;
+
+    }
+
+}
+
+
+/// Internal function to unpack a `tar` archive in the ustar format.
+fn unpack_tar(data: &[u8], root: &str) -> FsResult<usize> {
+
+    let mut count = 0;
+    let mut offset = 0;
+    // Name and link name of the next entry, given by a GNU long name
+    // entry or a POSIX extended header.
+    let mut long_name = None;
+    let mut long_link_name = None;
+
+    while let Some(header) = data.get(offset..offset + TAR_BLOCK_SIZE) {
+
+        // The archive ends with zeroed blocks.
+        if header[0] == 0 {
+            break;
+        }
+
+        let size = octal(&header[124..136])? as usize;
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let content = data.get(data_start..data_start + size).ok_or(FsError::Corrupted)?;
+        offset = data_start + (size + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+
+        match header[156] {
+            b'L' => {
+                long_name = Some(String::from(c_str(content)?));
+                continue;
+            }
+            b'K' => {
+                long_link_name = Some(String::from(c_str(content)?));
+                continue;
+            }
+            b'x' => {
+                for (key, value) in pax_records(content)? {
+                    match key {
+                        "path" => long_name = Some(String::from(value)),
+                        "linkpath" => long_link_name = Some(String::from(value)),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = match long_name.take() {
+            Some(name) => name,
+            None if &header[257..263] == TAR_POSIX_MAGIC && header[345] != 0 => {
+                format!("{}/{}", c_str(&header[345..500])?, c_str(&header[0..100])?)
+            }
+            None => String::from(c_str(&header[0..100])?),
+        };
+        let Some(path) = entry_path(root, &name) else { continue };
+        let link_name = match long_link_name.take() {
+            Some(link_name) => link_name,
+            None => String::from(c_str(&header[157..257])?),
+        };
+
+        match header[156] {
+            b'0' | 0 => write_file(&path, content)?,
+            b'1' => match entry_path(root, &link_name) {
+                Some(existing) => create_link(&existing, &path)?,
+                None => return Err(FsError::Corrupted),
+            },
+            b'2' => create_symlink(&link_name, &path)?,
+            b'5' => create_dir(&path)?,
+            _ => continue,
+        }
+
+        count += 1;
+
+    }
+
+    Ok(count)
+
+}
+
+
+/// Internal function to get the path of an entry in the root directory,
+/// none for the root entry itself.
+fn entry_path(root: &str, name: &str) -> Option<String> {
+    let name = name.trim_start_matches("./").trim_start_matches('/').trim_end_matches('/');
+    if name.is_empty() || name == "." {
+        None
+    } else {
+        path::normalize(&path::join(root, name)).ok()
+    }
+}
+
+
+/// Internal function to create the parent directories of a path.
+fn create_parents(path: &str) -> FsResult<()> {
+    if let Some((parent, _)) = path::split_parent(path) {
+        if super::metadata(parent).is_err() {
+            create_parents(parent)?;
+            super::create_dir(parent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Internal function to create a directory, it might already exist.
+fn create_dir(path: &str) -> FsResult<()> {
+    create_parents(path)?;
+    match super::create_dir(path) {
+        Err(FsError::AlreadyExists) => Ok(()),
+        ret => ret,
+    }
+}
+
+/// Internal function to create a symbolic link, replacing the existing
+/// file.
+fn create_symlink(target: &str, path: &str) -> FsResult<()> {
+    create_parents(path)?;
+    remove_file(path)?;
+    super::symlink(target, path)
+}
+
+/// Internal function to create a hard link, replacing the existing file.
+fn create_link(existing: &str, path: &str) -> FsResult<()> {
+    create_parents(path)?;
+    remove_file(path)?;
+    super::link(existing, path)
+}
+
+/// Internal function to write a file, replacing its content.
+fn write_file(path: &str, content: &[u8]) -> FsResult<()> {
+    create_parents(path)?;
+    let file = super::open(path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+    let mut written = 0;
+    while written < content.len() {
+        match file.write(&content[written..])? {
+            0 => return Err(FsError::NoSpace),
+            len => written += len,
+        }
+    }
+    Ok(())
+}
+
+/// Internal function to remove an existing file, if any.
+fn remove_file(path: &str) -> FsResult<()> {
+    match super::remove(path) {
+        Err(FsError::NotFound) => Ok(()),
+        ret => ret,
+    }
+}
+
+
+/// Internal function to align an offset of a `cpio` archive.
+#[inline]
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Internal function to parse an octal number of a `tar` header, it's
+/// terminated by a space or a nul byte.
+fn octal(field: &[u8]) -> FsResult<u64> {
+    let digits = c_str(field)?.trim();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| FsError::Corrupted)
+}
+
+/// Internal function to parse the records of a POSIX extended header,
+/// each record is formatted as `<len> <key>=<value>\n` where the length
+/// is the one of the whole record.
+fn pax_records(mut content: &[u8]) -> FsResult<Vec<(&str, &str)>> {
+    let mut records = Vec::new();
+    while !content.is_empty() {
+        let space = content.iter().position(|&c| c == b' ').ok_or(FsError::Corrupted)?;
+        let len = core::str::from_utf8(&content[..space]).map_err(|_| FsError::Corrupted)?;
+        let len = len.parse::<usize>().map_err(|_| FsError::Corrupted)?;
+        // The record must fit in the content and end with a new line.
+        if len <= space + 1 || len > content.len() || content[len - 1] != b'\n' {
+            return Err(FsError::Corrupted);
+        }
+        let record = core::str::from_utf8(&content[space + 1..len - 1]).map_err(|_| FsError::Corrupted)?;
+        let (key, value) = record.split_once('=').ok_or(FsError::Corrupted)?;
+        records.push((key, value));
+        content = &content[len..];
+    }
+    Ok(records)
+}
+
+/// Internal function to read a nul-terminated string of a `tar` header.
+fn c_str(field: &[u8]) -> FsResult<&str> {
+    let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| FsError::Corrupted)
+}
+
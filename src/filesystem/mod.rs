@@ -1,8 +1,477 @@
 //! Kernel virtual filesystem management.
-//! 
+//!
 //! Everything in Aves is working around the filesystem.
-//! 
-//! Block devices are mounted in other block devices
-//! (except for the root block device of the rootfs).
+//!
+//! Filesystems are mounted at paths of a single tree, the filesystem
+//! of a path is the one mounted at its longest prefix. Filesystems
+//! are made of inodes (files, directories, symbolic links) and inodes
+//! are opened as files, which are stored in the open-file table while
+//! used by the handles of processes.
+//!
+//! The root filesystem is a [`TmpFs`] in memory, where the initramfs
+//! is unpacked at boot. Block devices are mounted in it, filesystem
+//! drivers register the filesystems they support to the
+//! [`FsDriver`](crate::driver::FsDriver). Devices are exposed by their
+//! drivers in the [`SysFs`], mounted at `/sys`.
 
+pub mod path;
+pub mod mount;
+pub mod file;
+pub mod tmpfs;
+pub mod sysfs;
+pub mod initramfs;
+pub mod fat;
+pub mod ext2;
 
+use core::any::Any;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use bitflags::bitflags;
+
+use crate::driver::fs::AUTO_MOUNT_DIR;
+use crate::println;
+
+pub use file::{FileId, InodeFile};
+pub use tmpfs::TmpFs;
+pub use sysfs::SysFs;
+
+
+/// Maximum number of symbolic links followed when resolving a path.
+const MAX_SYMLINKS: usize = 8;
+
+
+/// Type alias for results of filesystem operations.
+pub type FsResult<T> = Result<T, FsError>;
+
+
+/// Errors that can happen with filesystem operations.
+#[derive(Debug, Clone, Copy)]
+pub enum FsError {
+    /// The path is not absolute, or a name is invalid.
+    InvalidPath,
+    /// A name of the path is too long.
+    NameTooLong,
+    /// An argument of the operation is invalid.
+    InvalidArgument,
+    /// The path doesn't exist.
+    NotFound,
+    /// A component of the path is not a directory.
+    NotDirectory,
+    /// The operation is not allowed on a directory.
+    IsDirectory,
+    /// The path already exists.
+    AlreadyExists,
+    /// The directory to remove is not empty.
+    NotEmpty,
+    /// Too many symbolic links have been followed.
+    TooManySymlinks,
+    /// The path is used, like a mount point.
+    Busy,
+    /// The operation would move an inode to another filesystem.
+    CrossDevice,
+    /// The filesystem is read-only.
+    ReadOnly,
+    /// The file has not been opened for this operation.
+    PermissionDenied,
+    /// The operation is not supported by the filesystem or the file.
+    Unsupported,
+    /// The filesystem is full.
+    NoSpace,
+    /// The kernel is out of memory.
+    OutOfMemory,
+    /// The structures of the filesystem are invalid.
+    Corrupted,
+    /// The underlying device failed.
+    Io,
+    /// The operation would block, the process should sleep on the
+    /// given wait channel while the condition returns true, and then
+    /// retry the operation.
+    WouldBlock(usize, fn() -> bool),
+}
+
+
+/// Kind of an inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    File,
+    Directory,
+    Symlink,
+    /// A special file provided by a driver.
+    Device,
+}
+
+
+/// Metadata of an inode.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: InodeKind,
+    /// Size in bytes, for files and symbolic links.
+    pub size: u64,
+    /// Number of the inode, unique in its filesystem.
+    pub ino: u64,
+    /// Unix-like permissions of the inode, 0o777 if not supported.
+    pub mode: u16,
+    /// Number of hard links to the inode.
+    pub links: u32,
+}
+
+
+/// An entry of a directory.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: InodeKind,
+    pub ino: u64,
+}
+
+
+bitflags! {
+    /// Options given when opening a file.
+    pub struct OpenFlags: u8 {
+        const READ      = 0b0001;
+        const WRITE     = 0b0010;
+        /// Create the file if it doesn't exist.
+        const CREATE    = 0b0100;
+        /// Truncate the file to zero when opened for writing.
+        const TRUNCATE  = 0b1000;
+    }
+}
+
+
+/// The position to seek a file to.
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+
+/// A mounted filesystem.
+pub trait FileSystem: Send + Sync {
+
+    /// Name of the type of the filesystem.
+    fn name(&self) -> &str;
+
+    /// Get the root directory of the filesystem.
+    fn root(&self) -> FsResult<Arc<dyn Inode>>;
+
+    /// Write all the pending modifications to the underlying device.
+    fn sync(&self) -> FsResult<()> {
+        Ok(())
+    }
+
+}
+
+
+/// An inode of a filesystem, all operations are unsupported by default.
+/// Operations on directories take the name of an entry, it's checked
+/// to be a valid name by the caller.
+pub trait Inode: Send + Sync {
+
+    /// Get the inode as [`Any`], used by filesystems to downcast the
+    /// inodes given to [`Inode::rename`] and [`Inode::link`].
+    fn as_any(&self) -> &dyn Any;
+
+    /// Get the metadata of the inode.
+    fn metadata(&self) -> FsResult<Metadata>;
+
+    /// Open the inode, by default none is returned and the generic
+    /// [`InodeFile`] is used. Special files can return their own file.
+    fn open(&self, flags: OpenFlags) -> FsResult<Option<Box<dyn File>>> {
+        let _ = flags;
+        Ok(None)
+    }
+
+    /// Read the file at the given offset, return the length read, zero
+    /// at the end of the file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let _ = (offset, buf);
+        Err(FsError::Unsupported)
+    }
+
+    /// Write the file at the given offset, it's extended if needed.
+    /// Return the length written.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let _ = (offset, buf);
+        Err(FsError::Unsupported)
+    }
+
+    /// Change the size of the file, it's extended with zeros.
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        let _ = size;
+        Err(FsError::Unsupported)
+    }
+
+    /// Find an entry of the directory.
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let _ = name;
+        Err(FsError::NotDirectory)
+    }
+
+    /// Get the entry of the directory at the given index, none after the
+    /// last one. The `.` and `..` entries are not returned.
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let _ = index;
+        Err(FsError::NotDirectory)
+    }
+
+    /// Create an empty file or directory in the directory.
+    fn create(&self, name: &str, kind: InodeKind) -> FsResult<Arc<dyn Inode>> {
+        let _ = (name, kind);
+        Err(FsError::Unsupported)
+    }
+
+    /// Create a symbolic link to the given target in the directory.
+    fn symlink(&self, name: &str, target: &str) -> FsResult<()> {
+        let _ = (name, target);
+        Err(FsError::Unsupported)
+    }
+
+    /// Create a hard link to the given inode in the directory, the inode
+    /// is of the same filesystem.
+    fn link(&self, name: &str, inode: &dyn Inode) -> FsResult<()> {
+        let _ = (name, inode);
+        Err(FsError::Unsupported)
+    }
+
+    /// Remove an entry of the directory, directories must be empty.
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let _ = name;
+        Err(FsError::Unsupported)
+    }
+
+    /// Move an entry of the directory to the given directory of the same
+    /// filesystem, possibly this one, replacing the existing entry.
+    fn rename(&self, name: &str, new_parent: &dyn Inode, new_name: &str) -> FsResult<()> {
+        let _ = (name, new_parent, new_name);
+        Err(FsError::Unsupported)
+    }
+
+    /// Read the target of the symbolic link.
+    fn read_link(&self) -> FsResult<String> {
+        Err(FsError::InvalidArgument)
+    }
+
+}
+
+
+/// An opened file, it's shared by the handles that opened it.
+pub trait File: Send + Sync {
+
+    /// Read the file at its current offset, return the length read,
+    /// zero at the end of the file.
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize>;
+
+    /// Write the file at its current offset, return the length written.
+    fn write(&self, buf: &[u8]) -> FsResult<usize>;
+
+    /// Move the current offset, return the new offset from the start.
+    fn seek(&self, pos: SeekFrom) -> FsResult<u64> {
+        let _ = pos;
+        Err(FsError::Unsupported)
+    }
+
+}
+
+
+/// Mount a tmpfs as the root filesystem, unpack the initramfs in it
+/// and mount the sysfs. This must be done before loading drivers, so
+/// they can mount their filesystems in it.
+///
+/// *This function is unsafe because it must be called once, after the
+/// initialization of the memory.*
+pub unsafe fn init() {
+
+    if let Err(e) = mount::mount("/", "tmpfs", TmpFs::new()) {
+        println!("== Root filesystem failed: {:?}", e);
+        return;
+    }
+
+    println!("== Root filesystem mounted");
+    initramfs::load("/");
+
+    // Mount points of block devices and of the sysfs, so they can be
+    // listed.
+    let _ = create_dir(AUTO_MOUNT_DIR);
+    let _ = create_dir(sysfs::SYS_DIR);
+
+    if let Err(e) = mount::mount(sysfs::SYS_DIR, "sysfs", Arc::new(SysFs)) {
+        println!(" = Failed to mount the sysfs: {:?}", e);
+    }
+
+}
+
+
+/// Open the file at the given path.
+pub fn open(path: &str, flags: OpenFlags) -> FsResult<Box<dyn File>> {
+
+    let path = path::normalize(path)?;
+    let inode = match resolve(&path, true) {
+        Ok((_, inode)) => inode,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = path::split_parent(&path).ok_or(FsError::AlreadyExists)?;
+            resolve(parent, true)?.1.create(name, InodeKind::File)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let metadata = inode.metadata()?;
+    if metadata.kind == InodeKind::Directory && flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::IsDirectory);
+    }
+
+    if let Some(file) = inode.open(flags)? {
+        return Ok(file);
+    }
+
+    if metadata.kind == InodeKind::File && flags.contains(OpenFlags::WRITE | OpenFlags::TRUNCATE) {
+        inode.truncate(0)?;
+    }
+
+    Ok(Box::new(InodeFile::new(inode, metadata.kind, flags)))
+
+}
+
+
+/// Get the metadata of the inode at the given path, symbolic links are
+/// followed.
+pub fn metadata(path: &str) -> FsResult<Metadata> {
+    resolve(&path::normalize(path)?, true)?.1.metadata()
+}
+
+
+/// Create a directory at the given path.
+pub fn create_dir(path: &str) -> FsResult<()> {
+    let path = path::normalize(path)?;
+    let (parent, name) = path::split_parent(&path).ok_or(FsError::AlreadyExists)?;
+    resolve(parent, true)?.1.create(name, InodeKind::Directory).map(|_| ())
+}
+
+
+/// Create a symbolic link at the given path, to the given target which
+/// is not checked to exist.
+pub fn symlink(target: &str, path: &str) -> FsResult<()> {
+    let path = path::normalize(path)?;
+    let (parent, name) = path::split_parent(&path).ok_or(FsError::AlreadyExists)?;
+    resolve(parent, true)?.1.symlink(name, target)
+}
+
+
+/// Create a hard link at the given path to the inode at the existing
+/// path, both must be in the same filesystem.
+pub fn link(existing: &str, path: &str) -> FsResult<()> {
+
+    let existing = path::normalize(existing)?;
+    let path = path::normalize(path)?;
+    let (parent, name) = path::split_parent(&path).ok_or(FsError::AlreadyExists)?;
+
+    let (existing_fs, inode) = resolve(&existing, false)?;
+    let (parent_fs, parent) = resolve(parent, true)?;
+    if !same_fs(&existing_fs, &parent_fs) {
+        return Err(FsError::CrossDevice);
+    }
+
+    parent.link(name, &*inode)
+
+}
+
+
+/// Remove the file, the symbolic link or the empty directory at the
+/// given path, mount points can't be removed.
+pub fn remove(path: &str) -> FsResult<()> {
+    let path = path::normalize(path)?;
+    if mount::is_mount_point(&path) {
+        return Err(FsError::Busy);
+    }
+    let (parent, name) = path::split_parent(&path).ok_or(FsError::Busy)?;
+    resolve(parent, true)?.1.unlink(name)
+}
+
+
+/// Move the inode at the given path to a new path of the same
+/// filesystem, replacing the existing inode if any.
+pub fn rename(from: &str, to: &str) -> FsResult<()> {
+
+    let from = path::normalize(from)?;
+    let to = path::normalize(to)?;
+    if mount::is_mount_point(&from) || mount::is_mount_point(&to) {
+        return Err(FsError::Busy);
+    }
+
+    let (from_parent, from_name) = path::split_parent(&from).ok_or(FsError::Busy)?;
+    let (to_parent, to_name) = path::split_parent(&to).ok_or(FsError::Busy)?;
+
+    let (from_fs, from_parent) = resolve(from_parent, true)?;
+    let (to_fs, to_parent) = resolve(to_parent, true)?;
+    if !same_fs(&from_fs, &to_fs) {
+        return Err(FsError::CrossDevice);
+    }
+
+    from_parent.rename(from_name, &*to_parent, to_name)
+
+}
+
+
+/// Internal function to resolve a normalized path to its inode, and
+/// the filesystem containing it. Symbolic links are followed, the last
+/// component is only followed if requested.
+fn resolve(path: &str, follow: bool) -> FsResult<(Arc<dyn FileSystem>, Arc<dyn Inode>)> {
+
+    let mut path = String::from(path);
+    let mut symlinks_count = 0;
+
+    'resolve: loop {
+
+        let (fs, mount_path, rest) = mount::find(&path)?;
+        let mut inode = fs.root()?;
+        // Path of the current inode, used to resolve relative links.
+        let mut inode_path = mount_path;
+
+        let mut components = path::components_of(&rest).peekable();
+        while let Some(name) = components.next() {
+
+            let next = inode.lookup(name)?;
+            let last = components.peek().is_none();
+
+            if (follow || !last) && next.metadata()?.kind == InodeKind::Symlink {
+
+                symlinks_count += 1;
+                if symlinks_count > MAX_SYMLINKS {
+                    return Err(FsError::TooManySymlinks);
+                }
+
+                // The path is restarted from the target with the rest.
+                let mut target = path::join(&inode_path, &next.read_link()?);
+                for name in components {
+                    target.push('/');
+                    target.push_str(name);
+                }
+                path = path::normalize(&target)?;
+                continue 'resolve;
+
+            }
+
+            if !inode_path.ends_with('/') {
+                inode_path.push('/');
+            }
+            inode_path.push_str(name);
+            inode = next;
+
+        }
+
+        return Ok((fs, inode));
+
+    }
+
+}
+
+
+/// Internal function to check if two filesystems are the same.
+#[inline]
+fn same_fs(a: &Arc<dyn FileSystem>, b: &Arc<dyn FileSystem>) -> bool {
+    Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+}
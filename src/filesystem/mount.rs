@@ -0,0 +1,87 @@
+//! Mount table of the virtual filesystem.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::sync::RwLock;
+
+use super::{FileSystem, FsError, FsResult, path};
+
+
+/// All mounted filesystems.
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+
+/// A filesystem mounted at a path.
+struct Mount {
+    /// Normalized path of the mount point.
+    path: String,
+    /// Name of the source of the filesystem, usually a block device.
+    source: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+
+/// Mount a filesystem at the given path, the source is the name of what
+/// is mounted, usually a block device. The mount point doesn't need to
+/// exist in the parent filesystem, but it can't be listed otherwise.
+pub fn mount(path: &str, source: &str, fs: Arc<dyn FileSystem>) -> FsResult<()> {
+    let path = path::normalize(path)?;
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount { path, source: String::from(source), fs });
+    Ok(())
+}
+
+
+/// Unmount the filesystem mounted at the given path, it's synchronized
+/// before. This fails if other filesystems are mounted under it.
+pub fn unmount(path: &str) -> FsResult<Arc<dyn FileSystem>> {
+    let path = path::normalize(path)?;
+    let mut mounts = MOUNTS.write();
+    let index = mounts.iter().position(|mount| mount.path == path).ok_or(FsError::NotFound)?;
+    if mounts.iter().any(|mount| mount.path != path && path::strip_prefix(&mount.path, &path).is_some()) {
+        return Err(FsError::Busy);
+    }
+    mounts[index].fs.sync()?;
+    Ok(mounts.remove(index).fs)
+}
+
+
+/// Return true if a filesystem from the given source is mounted.
+pub fn is_mounted(source: &str) -> bool {
+    MOUNTS.read().iter().any(|mount| mount.source == source)
+}
+
+
+/// Return true if a filesystem is mounted exactly at the given
+/// normalized path.
+pub fn is_mount_point(path: &str) -> bool {
+    MOUNTS.read().iter().any(|mount| mount.path == path)
+}
+
+
+/// Call the given function with the path, the source and the filesystem
+/// of each mount, in mount order.
+pub fn for_each(mut func: impl FnMut(&str, &str, &Arc<dyn FileSystem>)) {
+    for mount in MOUNTS.read().iter() {
+        func(&mount.path, &mount.source, &mount.fs);
+    }
+}
+
+
+/// Find the filesystem containing the given normalized path, this is
+/// the one mounted at the longest prefix of the path. The path of the
+/// mount point and the rest of the path are returned with it.
+pub fn find(path: &str) -> FsResult<(Arc<dyn FileSystem>, String, String)> {
+    let mounts = MOUNTS.read();
+    let mount = mounts.iter()
+        .filter(|mount| path::strip_prefix(path, &mount.path).is_some())
+        .max_by_key(|mount| mount.path.len())
+        .ok_or(FsError::NotFound)?;
+    let rest = path::strip_prefix(path, &mount.path).unwrap_or("");
+    Ok((Arc::clone(&mount.fs), mount.path.clone(), String::from(rest)))
+}
@@ -0,0 +1,112 @@
+//! Paths of the virtual filesystem, they are absolute UTF-8 strings
+//! with components separated by slashes.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{FsError, FsResult};
+
+
+/// Maximum length of a component of a path.
+pub const MAX_NAME_LEN: usize = 255;
+
+
+/// Normalize an absolute path, empty and `.` components are removed
+/// and `..` components remove the previous one, the parent of the root
+/// is the root itself. The normalized path has no trailing slash,
+/// unless it's the root `/`.
+pub fn normalize(path: &str) -> FsResult<String> {
+
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    for component in components_of(path) {
+        match component {
+            "." => {}
+            ".." => { components.pop(); }
+            _ => {
+                check_name(component)?;
+                components.push(component);
+            }
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+
+    Ok(normalized)
+
+}
+
+
+/// Join the given path to a normalized base path, used to resolve the
+/// target of symbolic links. The given path is returned if absolute.
+/// The result must be normalized.
+pub fn join(base: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        String::from(path)
+    } else {
+        let mut joined = String::from(base);
+        joined.push('/');
+        joined.push_str(path);
+        joined
+    }
+}
+
+
+/// Iterate over the non-empty components of a path.
+#[inline]
+pub fn components_of(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+
+/// Split a normalized path into its parent path and its last component,
+/// none for the root.
+pub fn split_parent(path: &str) -> Option<(&str, &str)> {
+    let index = path.rfind('/')?;
+    let name = &path[index + 1..];
+    if name.is_empty() {
+        None
+    } else if index == 0 {
+        Some(("/", name))
+    } else {
+        Some((&path[..index], name))
+    }
+}
+
+
+/// Return the rest of a normalized path relative to the given normalized
+/// prefix, none if the path is not the prefix or under it. The rest has
+/// no leading slash.
+pub fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if prefix == "/" {
+        return Some(&path[1..]);
+    }
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() {
+        Some(rest)
+    } else {
+        rest.strip_prefix('/')
+    }
+}
+
+
+/// Check that a name is a valid component of a path.
+pub fn check_name(name: &str) -> FsResult<()> {
+    if name.len() > MAX_NAME_LEN {
+        Err(FsError::NameTooLong)
+    } else if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        Err(FsError::InvalidPath)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+//! System filesystem, a virtual filesystem mounted at [`SYS_DIR`] where
+//! kernel drivers expose their devices and settings as files.
+//!
+//! Drivers register virtual files ([`SysFile`]) at paths relative to
+//! the root of the filesystem, the parent directories are created when
+//! needed and are never removed. A driver can also register a whole
+//! directory ([`SysDir`]) that resolves its own entries on lookup, this
+//! is used for namespaces that can't be listed in advance, like the
+//! addresses and ports of `/sys/ip4/<addr>/tcp/<port>`.
+
+use core::any::Any;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::sync::Mutex;
+
+use super::{DirEntry, File, FileSystem, FsError, FsResult, Inode, InodeKind, Metadata, OpenFlags, path};
+
+
+/// Directory where the system filesystem is mounted.
+pub const SYS_DIR: &str = "/sys";
+
+
+/// Root of the registered directories, it's never removed.
+static ROOT: Dir = Dir::new();
+
+
+/// A virtual file registered by a driver, all operations are unsupported
+/// by default.
+pub trait SysFile: Send + Sync {
+
+    /// Size of the file, zero if the content is generated when read.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// Open the file, by default none is returned and the generic file
+    /// of the filesystem reads and writes with [`SysFile::read_at`] and
+    /// [`SysFile::write_at`]. Files that can't be read at an offset,
+    /// like streams, return their own file.
+    fn open(&self, flags: OpenFlags) -> FsResult<Option<Box<dyn File>>> {
+        let _ = flags;
+        Ok(None)
+    }
+
+    /// Read the file at the given offset, return the length read, zero
+    /// at the end of the file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let _ = (offset, buf);
+        Err(FsError::Unsupported)
+    }
+
+    /// Write the file at the given offset, return the length written.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let _ = (offset, buf);
+        Err(FsError::Unsupported)
+    }
+
+}
+
+
+/// A virtual directory registered by a driver, its entries are resolved
+/// by the driver.
+pub trait SysDir: Send + Sync {
+
+    /// Find an entry of the directory, the name is valid.
+    fn lookup(&self, name: &str) -> FsResult<SysEntry>;
+
+    /// Get the name and the entry at the given index, none after the
+    /// last one. Directories that can't be listed return none.
+    fn read_dir(&self, index: usize) -> FsResult<Option<(String, SysEntry)>> {
+        let _ = index;
+        Ok(None)
+    }
+
+}
+
+
+/// An entry of a virtual directory.
+#[derive(Clone)]
+pub enum SysEntry {
+    File(Arc<dyn SysFile>),
+    Dir(Arc<dyn SysDir>),
+}
+
+
+/// A read-only virtual file with a fixed content, like information on
+/// a device that doesn't change.
+pub struct TextFile(String);
+
+impl TextFile {
+
+    pub fn new(content: String) -> Self {
+        Self(content)
+    }
+
+}
+
+impl SysFile for TextFile {
+
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        Ok(read_content(self.0.as_bytes(), offset, buf))
+    }
+
+}
+
+
+/// The system filesystem, all instances share the registered entries.
+pub struct SysFs;
+
+impl FileSystem for SysFs {
+
+    fn name(&self) -> &str {
+        "sysfs"
+    }
+
+    fn root(&self) -> FsResult<Arc<dyn Inode>> {
+        Ok(Arc::new(SysNode(Entry::Dir(&ROOT))))
+    }
+
+}
+
+
+/// Register a virtual file at the given path, relative to the root of
+/// the system filesystem.
+pub fn register_file(path: &str, file: Arc<dyn SysFile>) -> FsResult<()> {
+    register(path, Entry::File(file))
+}
+
+
+/// Register a virtual directory at the given path, relative to the root
+/// of the system filesystem. The entries under this path are resolved
+/// by the directory.
+pub fn register_dir(path: &str, dir: Arc<dyn SysDir>) -> FsResult<()> {
+    register(path, Entry::Provided(dir))
+}
+
+
+/// Create the directory at the given path, relative to the root of the
+/// system filesystem, with its parents. It might already exist.
+pub fn create_dir(path: &str) -> FsResult<()> {
+    let path = path::normalize(&path::join("/", path))?;
+    parent_dir(&path, true).map(|_| ())
+}
+
+
+/// Unregister the virtual file or directory at the given path, relative
+/// to the root of the system filesystem. The directories created for
+/// registered entries can't be removed.
+pub fn unregister(path: &str) -> FsResult<()> {
+    let path = path::normalize(&path::join("/", path))?;
+    let (parent, name) = path::split_parent(&path).ok_or(FsError::Busy)?;
+    let parent = parent_dir(parent, false)?;
+    let mut entries = parent.entries.lock();
+    let index = entries.iter().position(|(entry_name, _)| entry_name == name).ok_or(FsError::NotFound)?;
+    if let Entry::Dir(_) = entries[index].1 {
+        return Err(FsError::IsDirectory);
+    }
+    entries.remove(index);
+    Ok(())
+}
+
+
+/// Read the given content at the given offset, this can be used by
+/// virtual files which content is generated. Return the length read.
+pub fn read_content(content: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    let start = (offset.min(content.len() as u64)) as usize;
+    let len = buf.len().min(content.len() - start);
+    buf[..len].copy_from_slice(&content[start..start + len]);
+    len
+}
+
+
+/// Internal function to register an entry at the given path.
+fn register(path: &str, entry: Entry) -> FsResult<()> {
+    let path = path::normalize(&path::join("/", path))?;
+    let (parent, name) = path::split_parent(&path).ok_or(FsError::AlreadyExists)?;
+    let parent = parent_dir(parent, true)?;
+    let mut entries = parent.entries.lock();
+    if entries.iter().any(|(entry_name, _)| entry_name == name) {
+        return Err(FsError::AlreadyExists);
+    }
+    entries.push((String::from(name), entry));
+    Ok(())
+}
+
+
+/// Internal function to get the registered directory at the given
+/// normalized path, the missing directories are created if requested.
+fn parent_dir(path: &str, create: bool) -> FsResult<&'static Dir> {
+    let mut dir = &ROOT;
+    for name in path::components_of(path) {
+        let mut entries = dir.entries.lock();
+        let next = match entries.iter().find(|(entry_name, _)| entry_name == name) {
+            Some((_, Entry::Dir(next))) => *next,
+            Some(_) => return Err(FsError::NotDirectory),
+            None if create => {
+                // Directories are never removed, so they are leaked to
+                // be shared with static lifetime.
+                let next: &'static Dir = Box::leak(Box::new(Dir::new()));
+                entries.push((String::from(name), Entry::Dir(next)));
+                next
+            }
+            None => return Err(FsError::NotFound),
+        };
+        drop(entries);
+        dir = next;
+    }
+    Ok(dir)
+}
+
+
+/// A directory created for registered entries.
+struct Dir {
+    entries: Mutex<Vec<(String, Entry)>>,
+}
+
+impl Dir {
+
+    const fn new() -> Self {
+        Self { entries: Mutex::new(Vec::new()) }
+    }
+
+}
+
+
+/// An entry of the filesystem, registered or resolved by a directory.
+#[derive(Clone)]
+enum Entry {
+    Dir(&'static Dir),
+    Provided(Arc<dyn SysDir>),
+    File(Arc<dyn SysFile>),
+}
+
+impl Entry {
+
+    fn kind(&self) -> InodeKind {
+        match self {
+            Entry::Dir(_) | Entry::Provided(_) => InodeKind::Directory,
+            Entry::File(_) => InodeKind::Device,
+        }
+    }
+
+    /// The number of the inode is the address of the entry, it's unique
+    /// while the entry is registered.
+    fn ino(&self) -> u64 {
+        let ptr = match self {
+            Entry::Dir(dir) => *dir as *const Dir as *const u8,
+            Entry::Provided(dir) => Arc::as_ptr(dir) as *const u8,
+            Entry::File(file) => Arc::as_ptr(file) as *const u8,
+        };
+        ptr.addr() as u64
+    }
+
+}
+
+impl From<SysEntry> for Entry {
+    fn from(entry: SysEntry) -> Self {
+        match entry {
+            SysEntry::File(file) => Entry::File(file),
+            SysEntry::Dir(dir) => Entry::Provided(dir),
+        }
+    }
+}
+
+
+/// The inode of an entry.
+struct SysNode(Entry);
+
+impl Inode for SysNode {
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FsResult<Metadata> {
+        let (size, links) = match &self.0 {
+            Entry::File(file) => (file.size(), 1),
+            _ => (0, 2),
+        };
+        Ok(Metadata {
+            kind: self.0.kind(),
+            size,
+            ino: self.0.ino(),
+            mode: 0o777,
+            links,
+        })
+    }
+
+    fn open(&self, flags: OpenFlags) -> FsResult<Option<Box<dyn File>>> {
+        match &self.0 {
+            Entry::File(file) => file.open(flags),
+            _ => Ok(None),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        match &self.0 {
+            Entry::File(file) => file.read_at(offset, buf),
+            _ => Err(FsError::IsDirectory),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        match &self.0 {
+            Entry::File(file) => file.write_at(offset, buf),
+            _ => Err(FsError::IsDirectory),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let entry = match &self.0 {
+            Entry::Dir(dir) => {
+                dir.entries.lock().iter()
+                    .find(|(entry_name, _)| entry_name == name)
+                    .map(|(_, entry)| entry.clone())
+                    .ok_or(FsError::NotFound)?
+            }
+            Entry::Provided(dir) => dir.lookup(name)?.into(),
+            Entry::File(_) => return Err(FsError::NotDirectory),
+        };
+        Ok(Arc::new(SysNode(entry)))
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let entry = match &self.0 {
+            Entry::Dir(dir) => dir.entries.lock().get(index).cloned(),
+            Entry::Provided(dir) => dir.read_dir(index)?.map(|(name, entry)| (name, entry.into())),
+            Entry::File(_) => return Err(FsError::NotDirectory),
+        };
+        Ok(entry.map(|(name, entry)| DirEntry {
+            name,
+            kind: entry.kind(),
+            ino: entry.ino(),
+        }))
+    }
+
+}
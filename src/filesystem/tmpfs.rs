@@ -0,0 +1,439 @@
+//! In-memory filesystem, the content of files is stored in pages of the
+//! page allocator. It's used as the root filesystem, before any block
+//! device is available.
+
+use core::any::Any;
+use core::num::NonZeroUsize;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::collections::BTreeMap;
+use alloc::collections::btree_map::Entry;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use crate::memory::page::{self, PAGE_SIZE};
+use crate::sync::Mutex;
+
+use super::{DirEntry, FileSystem, FsError, FsResult, Inode, InodeKind, Metadata};
+
+
+/// Number of the root directory.
+const ROOT_INO: u64 = 1;
+
+
+/// A RAM-backed filesystem.
+pub struct TmpFs {
+    root: Arc<TmpNode>,
+    /// Number of the next inode created.
+    next_ino: AtomicU64,
+    /// Locked by operations modifying the tree, so they are atomic
+    /// while never locking two nodes at the same time.
+    tree: Mutex<()>,
+}
+
+/// An inode of the filesystem.
+pub struct TmpNode {
+    fs: Weak<TmpFs>,
+    /// Used to insert this node in directories.
+    this: Weak<TmpNode>,
+    ino: u64,
+    inner: Mutex<TmpInner>,
+}
+
+/// Mutable part of an inode.
+struct TmpInner {
+    links: u32,
+    /// Parent directory of directories, used to prevent moving a
+    /// directory into itself.
+    parent: Weak<TmpNode>,
+    content: Content,
+}
+
+/// Content of an inode, depending on its kind.
+enum Content {
+    /// Pages of the file by index and its size, holes are read as zeros
+    /// and don't use any page.
+    File(BTreeMap<usize, Page>, u64),
+    /// Entries of the directory, in creation order.
+    Directory(Vec<(String, Arc<TmpNode>)>),
+    Symlink(String),
+}
+
+/// A page of a file, it's deallocated when dropped.
+struct Page(NonNull<u8>);
+
+// SAFETY: The page is exclusively owned.
+unsafe impl Send for Page {}
+unsafe impl Sync for Page {}
+
+
+impl TmpFs {
+
+    pub fn new() -> Arc<Self> {
+        Arc::new_cyclic(|fs: &Weak<TmpFs>| Self {
+            root: Arc::new_cyclic(|this| TmpNode {
+                fs: fs.clone(),
+                this: this.clone(),
+                ino: ROOT_INO,
+                inner: Mutex::new(TmpInner {
+                    links: 2,
+                    parent: Weak::new(),
+                    content: Content::Directory(Vec::new()),
+                }),
+            }),
+            next_ino: AtomicU64::new(ROOT_INO + 1),
+            tree: Mutex::new(()),
+        })
+    }
+
+}
+
+impl FileSystem for TmpFs {
+
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> FsResult<Arc<dyn Inode>> {
+        Ok(self.root.clone())
+    }
+
+}
+
+
+impl Page {
+
+    fn new() -> FsResult<Self> {
+        page::alloc_zeroed(NonZeroUsize::new(1).unwrap())
+            .map(Self)
+            .map_err(|_| FsError::OutOfMemory)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: The page is allocated and owned.
+        unsafe { core::slice::from_raw_parts(self.0.as_ptr(), PAGE_SIZE) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: The page is allocated and owned.
+        unsafe { core::slice::from_raw_parts_mut(self.0.as_ptr(), PAGE_SIZE) }
+    }
+
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        // SAFETY: The page is no longer used.
+        let _ = unsafe { page::dealloc(self.0) };
+    }
+}
+
+
+impl TmpNode {
+
+    /// Create a new inode of the same filesystem, linked once.
+    fn new_node(&self, parent: Weak<TmpNode>, content: Content) -> FsResult<Arc<TmpNode>> {
+        let fs = self.fs.upgrade().ok_or(FsError::NotFound)?;
+        Ok(Arc::new_cyclic(|this| TmpNode {
+            fs: self.fs.clone(),
+            this: this.clone(),
+            ino: fs.next_ino.fetch_add(1, Ordering::Relaxed),
+            inner: Mutex::new(TmpInner {
+                links: if matches!(content, Content::Directory(_)) { 2 } else { 1 },
+                parent,
+                content,
+            }),
+        }))
+    }
+
+    /// Lock the tree of the filesystem for a modification.
+    fn with_tree<T>(&self, func: impl FnOnce() -> FsResult<T>) -> FsResult<T> {
+        let fs = self.fs.upgrade().ok_or(FsError::NotFound)?;
+        let _tree = fs.tree.lock();
+        func()
+    }
+
+    /// Get the entry of this directory with the given name.
+    fn entry(&self, name: &str) -> FsResult<Option<Arc<TmpNode>>> {
+        match &self.inner.lock().content {
+            Content::Directory(entries) => Ok(entries.iter()
+                .find(|(entry_name, _)| entry_name == name)
+                .map(|(_, node)| node.clone())),
+            _ => Err(FsError::NotDirectory),
+        }
+    }
+
+    /// Insert an entry in this directory, it must not exist.
+    fn insert(&self, name: &str, node: Arc<TmpNode>) -> FsResult<()> {
+        let is_dir = node.kind() == InodeKind::Directory;
+        let mut inner = self.inner.lock();
+        let Content::Directory(entries) = &mut inner.content else {
+            return Err(FsError::NotDirectory);
+        };
+        if entries.iter().any(|(entry_name, _)| entry_name == name) {
+            return Err(FsError::AlreadyExists);
+        }
+        entries.push((String::from(name), node));
+        if is_dir {
+            inner.links += 1;
+        }
+        Ok(())
+    }
+
+    /// Remove an entry of this directory, it must exist.
+    fn remove(&self, name: &str, node: &TmpNode) {
+        let is_dir = node.kind() == InodeKind::Directory;
+        let mut inner = self.inner.lock();
+        if let Content::Directory(entries) = &mut inner.content {
+            entries.retain(|(entry_name, _)| entry_name != name);
+        }
+        if is_dir {
+            inner.links -= 1;
+        }
+    }
+
+    /// Create a new entry in this directory.
+    fn create_node(&self, name: &str, content: Content) -> FsResult<Arc<TmpNode>> {
+        self.with_tree(|| {
+            if self.entry(name)?.is_some() {
+                return Err(FsError::AlreadyExists);
+            } else if self.inner.lock().links == 0 {
+                // The directory has been removed.
+                return Err(FsError::NotFound);
+            }
+            let node = self.new_node(self.this.clone(), content)?;
+            self.insert(name, node.clone())?;
+            Ok(node)
+        })
+    }
+
+    fn kind(&self) -> InodeKind {
+        match self.inner.lock().content {
+            Content::File(..) => InodeKind::File,
+            Content::Directory(_) => InodeKind::Directory,
+            Content::Symlink(_) => InodeKind::Symlink,
+        }
+    }
+
+    /// Unlink a node removed from a directory.
+    fn unlinked(&self) {
+        let mut inner = self.inner.lock();
+        if let Content::Directory(_) = inner.content {
+            inner.links = 0;
+        } else {
+            inner.links -= 1;
+        }
+    }
+
+}
+
+impl Inode for TmpNode {
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FsResult<Metadata> {
+        let inner = self.inner.lock();
+        let (kind, size) = match &inner.content {
+            Content::File(_, size) => (InodeKind::File, *size),
+            Content::Directory(_) => (InodeKind::Directory, 0),
+            Content::Symlink(target) => (InodeKind::Symlink, target.len() as u64),
+        };
+        Ok(Metadata { kind, size, ino: self.ino, mode: 0o777, links: inner.links })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let inner = self.inner.lock();
+        let (pages, size) = match &inner.content {
+            Content::File(pages, size) => (pages, *size),
+            Content::Directory(_) => return Err(FsError::IsDirectory),
+            Content::Symlink(_) => return Err(FsError::Unsupported),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = ((size - offset) as usize).min(buf.len());
+        let mut pos = offset as usize;
+        let mut dst = &mut buf[..len];
+        while !dst.is_empty() {
+            let page_off = pos % PAGE_SIZE;
+            let chunk_len = (PAGE_SIZE - page_off).min(dst.len());
+            match pages.get(&(pos / PAGE_SIZE)) {
+                Some(page) => dst[..chunk_len].copy_from_slice(&page.as_slice()[page_off..page_off + chunk_len]),
+                None => dst[..chunk_len].fill(0),
+            }
+            dst = &mut dst[chunk_len..];
+            pos += chunk_len;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let mut inner = self.inner.lock();
+        let (pages, size) = match &mut inner.content {
+            Content::File(pages, size) => (pages, size),
+            Content::Directory(_) => return Err(FsError::IsDirectory),
+            Content::Symlink(_) => return Err(FsError::Unsupported),
+        };
+        let end = offset.checked_add(buf.len() as u64).ok_or(FsError::NoSpace)?;
+        let mut pos = offset as usize;
+        let mut src = buf;
+        while !src.is_empty() {
+            let index = pos / PAGE_SIZE;
+            let page_off = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_off).min(src.len());
+            let page = match pages.entry(index) {
+                Entry::Occupied(page) => page.into_mut(),
+                Entry::Vacant(page) => page.insert(Page::new()?),
+            };
+            page.as_mut_slice()[page_off..page_off + len].copy_from_slice(&src[..len]);
+            src = &src[len..];
+            pos += len;
+            // The size is updated for each page, in case of failure.
+            *size = (*size).max(pos as u64);
+        }
+        *size = (*size).max(end);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, new_size: u64) -> FsResult<()> {
+        let mut inner = self.inner.lock();
+        let (pages, size) = match &mut inner.content {
+            Content::File(pages, size) => (pages, size),
+            Content::Directory(_) => return Err(FsError::IsDirectory),
+            Content::Symlink(_) => return Err(FsError::Unsupported),
+        };
+        if new_size < *size {
+            let new_len = (new_size as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            drop(pages.split_off(&new_len));
+            // The rest of the last page is cleared, so it reads as zeros
+            // if the file is extended again.
+            let page_off = new_size as usize % PAGE_SIZE;
+            if let Some(page) = pages.get_mut(&(new_size as usize / PAGE_SIZE)).filter(|_| page_off != 0) {
+                page.as_mut_slice()[page_off..].fill(0);
+            }
+        }
+        *size = new_size;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        Ok(self.entry(name)?.ok_or(FsError::NotFound)?)
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let node = match &self.inner.lock().content {
+            Content::Directory(entries) => entries.get(index).cloned(),
+            _ => return Err(FsError::NotDirectory),
+        };
+        Ok(node.map(|(name, node)| DirEntry { name, kind: node.kind(), ino: node.ino }))
+    }
+
+    fn create(&self, name: &str, kind: InodeKind) -> FsResult<Arc<dyn Inode>> {
+        let content = match kind {
+            InodeKind::File => Content::File(BTreeMap::new(), 0),
+            InodeKind::Directory => Content::Directory(Vec::new()),
+            _ => return Err(FsError::Unsupported),
+        };
+        Ok(self.create_node(name, content)?)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FsResult<()> {
+        self.create_node(name, Content::Symlink(String::from(target))).map(|_| ())
+    }
+
+    fn link(&self, name: &str, inode: &dyn Inode) -> FsResult<()> {
+        let target = inode.as_any().downcast_ref::<TmpNode>().ok_or(FsError::CrossDevice)?;
+        if !Weak::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::CrossDevice);
+        } else if target.kind() == InodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        self.with_tree(|| {
+            if self.entry(name)?.is_some() {
+                return Err(FsError::AlreadyExists);
+            }
+            let node = target.this.upgrade().ok_or(FsError::NotFound)?;
+            if node.inner.lock().links == 0 {
+                return Err(FsError::NotFound);
+            }
+            self.insert(name, node.clone())?;
+            node.inner.lock().links += 1;
+            Ok(())
+        })
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        self.with_tree(|| {
+            let node = self.entry(name)?.ok_or(FsError::NotFound)?;
+            if let Content::Directory(entries) = &node.inner.lock().content {
+                if !entries.is_empty() {
+                    return Err(FsError::NotEmpty);
+                }
+            }
+            self.remove(name, &node);
+            node.unlinked();
+            Ok(())
+        })
+    }
+
+    fn rename(&self, name: &str, new_parent: &dyn Inode, new_name: &str) -> FsResult<()> {
+
+        let new_parent = new_parent.as_any().downcast_ref::<TmpNode>().ok_or(FsError::CrossDevice)?;
+        if !Weak::ptr_eq(&self.fs, &new_parent.fs) {
+            return Err(FsError::CrossDevice);
+        }
+
+        self.with_tree(|| {
+
+            let node = self.entry(name)?.ok_or(FsError::NotFound)?;
+            let is_dir = node.kind() == InodeKind::Directory;
+
+            if is_dir {
+                // A directory can't be moved into itself.
+                let mut current = new_parent.this.upgrade();
+                while let Some(dir) = current {
+                    if Arc::ptr_eq(&dir, &node) {
+                        return Err(FsError::InvalidArgument);
+                    }
+                    current = dir.inner.lock().parent.upgrade();
+                }
+            }
+
+            if let Some(existing) = new_parent.entry(new_name)? {
+                if Arc::ptr_eq(&existing, &node) {
+                    return Ok(());
+                }
+                match &existing.inner.lock().content {
+                    Content::Directory(_) if !is_dir => return Err(FsError::IsDirectory),
+                    Content::Directory(entries) if !entries.is_empty() => return Err(FsError::NotEmpty),
+                    Content::Directory(_) => {}
+                    _ if is_dir => return Err(FsError::NotDirectory),
+                    _ => {}
+                }
+                new_parent.remove(new_name, &existing);
+                existing.unlinked();
+            }
+
+            self.remove(name, &node);
+            new_parent.insert(new_name, node.clone())?;
+            if is_dir {
+                node.inner.lock().parent = new_parent.this.clone();
+            }
+            Ok(())
+
+        })
+
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        match &self.inner.lock().content {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+}
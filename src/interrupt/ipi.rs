@@ -0,0 +1,292 @@
+//! Inter-processor interrupts, built on the machine software interrupts
+//! of the CLINT.
+//!
+//! Each hart has a mailbox where other harts can post a function to
+//! call, the hart is then interrupted and calls it from its trap
+//! handler. Harts waiting for the completion of a call keep handling
+//! the calls posted to them, so two harts calling each other can't
+//! deadlock.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::{forget, transmute};
+use core::arch::asm;
+
+use crate::cpu::mstatus;
+use crate::cpu::mie::{self, MieFlags};
+use crate::cpu::{mhardid, mscratch};
+use crate::sync::{Mutex, MutexGuard};
+use crate::smp::HART_MAX_COUNT;
+use crate::trap::TrapFrame;
+use crate::{asm, println};
+
+use super::clint;
+
+
+/// Maximum number of `mtime` ticks to wait for each hart to be halted
+/// when the kernel panics, 100 ms on QEMU's virt machine.
+const HALT_TIMEOUT: u64 = 1_000_000;
+
+
+/// Type of the functions called on other harts.
+pub type IpiFunc = dyn Fn() + Sync;
+
+
+/// The mailboxes of all harts, indexed by hart id.
+static MAILBOXES: [Mailbox; HART_MAX_COUNT] = {
+    const MAILBOX: Mailbox = Mailbox::new();
+    [MAILBOX; HART_MAX_COUNT]
+};
+
+/// Bit mask of the harts that can receive inter-processor interrupts.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of harts halted by [`halt_others`].
+static HALTED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+
+/// The mailbox of a hart.
+struct Mailbox {
+    /// Held by the hart posting a call, until it is completed.
+    lock: Mutex<()>,
+    /// The function to call, only relevant while pending.
+    func: UnsafeCell<Option<&'static IpiFunc>>,
+    /// True while a call is posted and not yet completed.
+    pending: AtomicBool,
+    /// True when the hart is asked to call the scheduler.
+    reschedule: AtomicBool,
+}
+
+unsafe impl Sync for Mailbox {}
+
+impl Mailbox {
+
+    const fn new() -> Self {
+        Self {
+            lock: Mutex::new(()),
+            func: UnsafeCell::new(None),
+            pending: AtomicBool::new(false),
+            reschedule: AtomicBool::new(false),
+        }
+    }
+
+}
+
+
+/// Errors that can happen when interrupting another hart.
+#[derive(Debug, Clone, Copy)]
+pub enum IpiError {
+    /// The hart is not started or can't receive interrupts.
+    Offline,
+}
+
+
+/// Enable inter-processor interrupts for the calling hart, other harts
+/// can then call functions on it.
+///
+/// *This function is unsafe because it must be called once per hart,
+/// after the initialization of its trap frame.*
+pub unsafe fn init_hart() {
+    ONLINE.fetch_or(1 << mhardid::get(), Ordering::AcqRel);
+    mie::set(mie::get() | MieFlags::MSIE);
+}
+
+
+/// Return true if the given hart can receive inter-processor interrupts.
+#[inline]
+pub fn is_online(hartid: usize) -> bool {
+    hartid < HART_MAX_COUNT && ONLINE.load(Ordering::Acquire) & (1 << hartid) != 0
+}
+
+
+/// Call the given function on the given hart and wait for it to
+/// return. The function is called from the trap handler of the hart,
+/// with interrupts disabled, it is directly called if the given hart
+/// is the calling one.
+pub fn call_on_hart(hartid: usize, func: &IpiFunc) -> Result<(), IpiError> {
+    mstatus::without_interrupts(|| {
+        if hartid == mhardid::get() {
+            func();
+            return Ok(());
+        }
+        // SAFETY: We wait for the call to complete, so the function
+        // outlives its use by the other hart.
+        let _guard = post(hartid, unsafe { transmute::<&IpiFunc, &'static IpiFunc>(func) })?;
+        wait(hartid);
+        Ok(())
+    })
+}
+
+
+/// Call the given function on all the other online harts and wait for
+/// all of them to return, the harts run the function concurrently.
+pub fn broadcast(func: &IpiFunc) {
+    mstatus::without_interrupts(|| {
+
+        // SAFETY: We wait for all calls to complete, see 'call_on_hart'.
+        let func = unsafe { transmute::<&IpiFunc, &'static IpiFunc>(func) };
+        let current_hartid = mhardid::get();
+
+        let mut guards: [Option<MutexGuard<'static, ()>>; HART_MAX_COUNT] = Default::default();
+        for (hartid, guard) in guards.iter_mut().enumerate() {
+            if hartid != current_hartid {
+                *guard = post(hartid, func).ok();
+            }
+        }
+
+        for (hartid, guard) in guards.iter().enumerate() {
+            if guard.is_some() {
+                wait(hartid);
+            }
+        }
+
+    })
+}
+
+
+/// Call the given function on all the online harts, including the
+/// calling one, and wait for all of them to return.
+pub fn broadcast_all(func: &IpiFunc) {
+    broadcast(func);
+    mstatus::without_interrupts(func);
+}
+
+
+/// Ask the given hart to call the scheduler, this doesn't wait.
+pub fn reschedule(hartid: usize) -> Result<(), IpiError> {
+    if !is_online(hartid) {
+        return Err(IpiError::Offline);
+    }
+    MAILBOXES[hartid].reschedule.store(true, Ordering::Release);
+    unsafe {
+        asm!("fence w, o");
+        clint::set_msip(hartid);
+    }
+    Ok(())
+}
+
+
+/// Halt all the other online harts, this is used when the kernel
+/// panics. Each hart prints the registers saved by its last trap,
+/// usually the interrupt itself, and then aborts. Harts are halted one
+/// after the other, and at most [`HALT_TIMEOUT`] ticks are waited for
+/// each one because it might be stuck with interrupts disabled.
+pub fn halt_others() {
+
+    let current_hartid = mhardid::get();
+
+    for (hartid, mailbox) in MAILBOXES.iter().enumerate() {
+
+        if hartid == current_hartid || !is_online(hartid) {
+            continue;
+        }
+
+        // The lock is not waited for, its owner might be halted. It's
+        // never released, so no other call can be posted.
+        let Some(guard) = mailbox.lock.try_lock() else { continue };
+        if mailbox.pending.load(Ordering::Acquire) {
+            continue;
+        }
+        forget(guard);
+
+        let halted_count = HALTED_COUNT.load(Ordering::Acquire);
+        unsafe { *mailbox.func.get() = Some(&halt); }
+        mailbox.pending.store(true, Ordering::Release);
+
+        unsafe {
+            asm!("fence w, o");
+            clint::set_msip(hartid);
+            let deadline = clint::get_mtime() + HALT_TIMEOUT;
+            while HALTED_COUNT.load(Ordering::Acquire) == halted_count && clint::get_mtime() < deadline {
+                spin_loop();
+            }
+        }
+
+    }
+
+}
+
+
+/// Handle the inter-processor interrupt of the calling hart, the
+/// posted function is called if any. Return true if the hart has been
+/// asked to call the scheduler.
+///
+/// *This function is unsafe because it must be called from the trap
+/// handler, on machine software interrupts.*
+pub unsafe fn handle() -> bool {
+    let hartid = mhardid::get();
+    // Cleared before handling, so any call posted after the check will
+    // raise a new interrupt.
+    clint::clear_msip(hartid);
+    asm!("fence");
+    handle_call();
+    MAILBOXES[hartid].reschedule.swap(false, Ordering::AcqRel)
+}
+
+
+/// Internal function to post a call to the mailbox of the given hart
+/// and interrupt it, the returned guard must be kept until completion.
+/// This must be called with interrupts disabled.
+fn post(hartid: usize, func: &'static IpiFunc) -> Result<MutexGuard<'static, ()>, IpiError> {
+
+    if !is_online(hartid) {
+        return Err(IpiError::Offline);
+    }
+
+    let mailbox = &MAILBOXES[hartid];
+    let guard = loop {
+        if let Some(guard) = mailbox.lock.try_lock() {
+            break guard;
+        }
+        handle_call();
+        spin_loop();
+    };
+
+    // SAFETY: The lock is held and no call is pending, so the function
+    // is not accessed by the other hart.
+    unsafe { *mailbox.func.get() = Some(func); }
+    mailbox.pending.store(true, Ordering::Release);
+
+    unsafe {
+        asm!("fence w, o");
+        clint::set_msip(hartid);
+    }
+
+    Ok(guard)
+
+}
+
+
+/// Internal function to wait for the call posted to the given hart to
+/// complete, calls posted to the calling hart are handled meanwhile.
+fn wait(hartid: usize) {
+    while MAILBOXES[hartid].pending.load(Ordering::Acquire) {
+        handle_call();
+        spin_loop();
+    }
+}
+
+
+/// Internal function to call the function posted to the calling hart,
+/// if any. This must be called with interrupts disabled.
+fn handle_call() {
+    let mailbox = &MAILBOXES[mhardid::get()];
+    if mailbox.pending.load(Ordering::Acquire) {
+        // SAFETY: The function is not modified while pending.
+        if let Some(func) = unsafe { (*mailbox.func.get()).take() } {
+            func();
+        }
+        mailbox.pending.store(false, Ordering::Release);
+    }
+}
+
+
+/// Internal function called on the harts halted by [`halt_others`].
+fn halt() {
+    println!("== The hart #{} has been halted", mhardid::get());
+    // SAFETY: The scratch register points to the trap frame of the hart.
+    unsafe { (*(mscratch::get() as *const TrapFrame)).dump(); }
+    HALTED_COUNT.fetch_add(1, Ordering::AcqRel);
+    unsafe { asm::asm_abort() }
+}
@@ -1,55 +1,159 @@
 //! Management of the Platform-Level Interrupt Controller.
 
 use core::num::NonZeroU8;
+use core::mem::transmute;
 
+use crate::cpu::mstatus;
+use crate::{devicetree, println};
 
-const PLIC_PRIORITY: *mut u32           = 0x0C00_0000 as *mut u32;
-const PLIC_ENABLE: *mut u32             = 0x0C00_2000 as *mut u32;
-const PLIC_THRESHOLD: *mut u32          = 0x0C20_0000 as *mut u32;
-const PLIC_CLAIM_AND_COMPLETE: *mut u32 = 0x0C20_0004 as *mut u32;
+
+/// Compatible strings of the PLIC in the device tree.
+const PLIC_COMPATIBLE: [&str; 2] = ["riscv,plic0", "sifive,plic-1.0.0"];
+
+const PLIC_PRIORITY: usize           = 0x00_0000;
+const PLIC_ENABLE: usize             = 0x00_2000;
+const PLIC_THRESHOLD: usize          = 0x20_0000;
+const PLIC_CLAIM_AND_COMPLETE: usize = 0x20_0004;
+
+/// Number of interrupt sources that can be enabled (0 is reserved).
+const PLIC_SOURCE_COUNT: usize = 32;
+
+
+/// Base address of the PLIC, defaults to the one of QEMU's virt machine.
+static mut PLIC_BASE: usize = 0x0C00_0000;
+
+/// Handlers registered for each interrupt source.
+static mut HANDLERS: [Option<Handler>; PLIC_SOURCE_COUNT] = [None; PLIC_SOURCE_COUNT];
+
+
+/// A type-erased handler for an interrupt source.
+#[derive(Clone, Copy)]
+struct Handler {
+    /// The handler function, the first argument is the data pointer.
+    func: fn(data: *const u8, id: u8),
+    /// Pointer to the data given to the handler.
+    data: *const u8,
+}
+
+
+/// Initialize the PLIC base address from the device tree, if found.
+/// 
+/// *This function is unsafe because it must be called on boot, before
+/// any other function of this module.*
+pub unsafe fn init() {
+    if let Some(node) = devicetree::find_compatible(&PLIC_COMPATIBLE) {
+        if let Some((addr, _)) = node.reg().next() {
+            PLIC_BASE = addr as usize;
+        }
+    }
+}
+
+/// Return the base address of the PLIC.
+#[inline]
+pub fn base() -> usize {
+    unsafe { PLIC_BASE }
+}
+
+#[inline]
+unsafe fn reg(offset: usize) -> *mut u32 {
+    (PLIC_BASE + offset) as *mut u32
+}
 
 
 /// Unable an interrupt given its id (1..=31).
 pub unsafe fn enable(id: u8) {
-    PLIC_ENABLE.write_volatile(PLIC_ENABLE.read_volatile() | (1 << id));
+    reg(PLIC_ENABLE).write_volatile(reg(PLIC_ENABLE).read_volatile() | (1 << id));
 }
 
 /// Disable an interrupt given its id (1..=31).
 pub unsafe fn disable(id: u8) {
-    PLIC_ENABLE.write_volatile(PLIC_ENABLE.read_volatile() & !(1 << id));
+    reg(PLIC_ENABLE).write_volatile(reg(PLIC_ENABLE).read_volatile() & !(1 << id));
 }
 
 /// Return true if the given interrupt is enabled.
 pub unsafe fn is_enabled(id: u8) -> bool {
-    PLIC_ENABLE.read_volatile() & (1 << id) != 0
+    reg(PLIC_ENABLE).read_volatile() & (1 << id) != 0
 }
 
 /// Set the priority (0..=7) of the given interrupt.
 pub unsafe fn set_priority(id: u8, priority: u8) {
-    PLIC_PRIORITY.add(id as usize).write_volatile(priority as u32 & 0b111);
+    reg(PLIC_PRIORITY).add(id as usize).write_volatile(priority as u32 & 0b111);
 }
 
 /// Get the priority (0..=7) of the given interrupt.
 pub unsafe fn get_priority(id: u8) -> u8 {
-    PLIC_PRIORITY.add(id as usize).read_volatile() as u8 & 0b111
+    reg(PLIC_PRIORITY).add(id as usize).read_volatile() as u8 & 0b111
 }
 
 /// Set the global threshold (0..=7). 
 pub unsafe fn set_threshold(global_threshold: u8) {
-    PLIC_THRESHOLD.write_volatile(global_threshold as u32 & 0b111);
+    reg(PLIC_THRESHOLD).write_volatile(global_threshold as u32 & 0b111);
 }
 
 /// Get the global threshold (0..=7). 
 pub unsafe fn get_threshold() -> u8 {
-    PLIC_THRESHOLD.read_volatile() as u8 & 0b111
+    reg(PLIC_THRESHOLD).read_volatile() as u8 & 0b111
 }
 
 /// Claim the next available interrupt.
 pub unsafe fn claim() -> Option<NonZeroU8> {
-    NonZeroU8::new(PLIC_CLAIM_AND_COMPLETE.read_volatile() as u8)
+    NonZeroU8::new(reg(PLIC_CLAIM_AND_COMPLETE).read_volatile() as u8)
 }
 
 /// Mark the previously claimed interrupt as completed.
 pub unsafe fn complete(id: u8) {
-    PLIC_CLAIM_AND_COMPLETE.write_volatile(id as u32);
+    reg(PLIC_CLAIM_AND_COMPLETE).write_volatile(id as u32);
+}
+
+
+/// Register a handler for the given interrupt source id (1..=31) and 
+/// enable it with the given priority (1..=7). The handler is called
+/// from the trap handler with the given data each time the source
+/// interrupts, the interrupt is claimed before and completed after.
+/// 
+/// The data must be synchronizable between threads because the
+/// handler is called from interrupt context.
+/// 
+/// *This function is unsafe because the PLIC is directly accessed,
+/// any handler previously registered for the source is replaced.*
+pub unsafe fn register<D: Sync>(id: u8, priority: u8, handler: fn(data: &'static D, id: u8), data: &'static D) {
+    debug_assert!(id != 0 && (id as usize) < PLIC_SOURCE_COUNT, "invalid interrupt source id");
+    // SAFETY: Here the transmutation is safe because &D as the same
+    // layout as *const u8.
+    let handler = Handler {
+        func: transmute(handler),
+        data: data as *const D as *const u8,
+    };
+    mstatus::without_interrupts(|| {
+        HANDLERS[id as usize] = Some(handler);
+        set_priority(id, priority);
+        enable(id);
+    });
+}
+
+/// Disable the given interrupt source and remove its handler.
+/// 
+/// *This function is unsafe because the PLIC is directly accessed.*
+pub unsafe fn unregister(id: u8) {
+    mstatus::without_interrupts(|| {
+        disable(id);
+        HANDLERS[id as usize] = None;
+    });
+}
+
+/// Claim all pending interrupts, call their handler and complete
+/// them. This is called by the trap handler on machine external
+/// interrupts.
+/// 
+/// *This function is unsafe because it must be called from the 
+/// trap handler.*
+pub unsafe fn dispatch() {
+    while let Some(id) = claim() {
+        let id = id.get();
+        match HANDLERS.get(id as usize).copied().flatten() {
+            Some(handler) => (handler.func)(handler.data, id),
+            None => println!("== Unhandled external interrupt #{}", id),
+        }
+        complete(id);
+    }
 }
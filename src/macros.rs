@@ -7,6 +7,8 @@ macro_rules! print {
         #[allow(unused_unsafe)]
         {
             use core::fmt::Write;
+            // The lock is bypassed on panic, the hart might hold it.
+            let _guard = if crate::uart::is_panicking() { None } else { Some(crate::uart::PRINT_LOCK.lock()) };
             let _ = write!(unsafe { &mut crate::uart::DEFAULT }, $($arg)+);
         }
     };
@@ -38,7 +40,10 @@ macro_rules! write_slice {
                 if s.len() > self.0.len() {
                     Err(core::fmt::Error)
                 } else {
-                    self.0[..s.len()].copy_from_slice(s.as_bytes());
+                    // Advance the cursor after the written string.
+                    let (head, tail) = core::mem::take(&mut self.0).split_at_mut(s.len());
+                    head.copy_from_slice(s.as_bytes());
+                    self.0 = tail;
                     Ok(())
                 }
             }
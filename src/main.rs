@@ -11,6 +11,8 @@
 #![feature(panic_info_message)]
 #![feature(concat_idents)]
 
+extern crate alloc;
+
 
 pub mod asm;
 pub mod cpu;
@@ -22,9 +24,11 @@ pub mod process;
 pub mod filesystem;
 
 pub mod uart;
+pub mod tty;
 pub mod trap;
 
 pub mod sync;
+pub mod smp;
 
 pub mod macros;
 
@@ -32,96 +36,222 @@ pub mod driver;
 
 pub mod conf;
 
+pub mod devicetree;
+
 pub mod util;
 
-// Internal for now, used to prototype the API.
-// mod api;
+pub mod api;
+pub mod syscall;
 
 
 
 /// The main entry point of the kernel, called from `boot.asm`.
-/// The kernel will run only on the hart #0.
+/// The kernel is initialized by the hart #0, which then wakes up the
+/// other harts. The device tree pointer is given by the bootloader.
 #[no_mangle]
-extern "C" fn kmain() {
+extern "C" fn kmain(_hartid: usize, fdt: *const u8) {
     
+    // The device tree is parsed first because it's used to discover
+    // the UART, errors are printed once it's initialized.
+    let fdt_result = unsafe { devicetree::init(fdt) };
+
     unsafe { uart::init(); }
     println!("== Starting Aves 0.1.0");
     println!("== UART initialized");
     println!("== On hart #{}", cpu::mhardid::get());
 
+    match fdt_result {
+        Ok(()) => {
+            let tree = devicetree::get().unwrap();
+            println!("== Device tree at 0x{:08X} ({} bytes)", tree.addr(), tree.size());
+        }
+        Err(e) => println!("== Device tree not found: {:?}", e),
+    }
+
+    unsafe {
+        interrupt::clint::init();
+        interrupt::plic::init();
+    }
+    println!(" = CLINT: 0x{:08X}", interrupt::clint::base());
+    println!(" = PLIC: 0x{:08X}", interrupt::plic::base());
+
     if let Some(isa) = cpu::misa::get() {
         println!("== Extensions: {:?}", isa.extensions);
     }
 
-    unsafe { memory::page::init(); }
-    let info = unsafe { memory::page::info() };
+    let (_, reserved) = unsafe { memory::init() };
+    let info = memory::page::info();
     println!("== Page allocator initialized");
-    println!(" = Meta: 0x{:08X} -> 0x{:08X} ({})", info.metadata_pages_start, info.metadata_pages_end, info.metadata_pages_count);
-    println!(" = Usbl: 0x{:08X} -> 0x{:08X} ({})", info.usable_pages_start, info.usable_pages_end, info.usable_pages_count);
+    for region in reserved.iter() {
+        println!(" = Rsvd: 0x{:08X} -> 0x{:08X}", region.start, region.end);
+    }
+    for zone_info in (0..memory::page::zones_count()).filter_map(memory::page::zone_info) {
+        println!(" = Meta: 0x{:08X} -> 0x{:08X} ({})", zone_info.metadata_pages_start, zone_info.metadata_pages_end, zone_info.metadata_pages_count);
+        println!(" = Usbl: 0x{:08X} -> 0x{:08X} ({})", zone_info.usable_pages_start, zone_info.usable_pages_end, zone_info.usable_pages_count);
+    }
+    println!(" = Total: {} usable pages", info.usable_pages_count);
+
+    #[cfg(feature = "bench")]
+    unsafe { memory::bench::run(); }
+
+    match unsafe { memory::paging::init_kernel() } {
+        Ok(table) => {
+            unsafe { memory::paging::activate(table, 0); }
+            println!("== Kernel address space initialized");
+            println!(" = Root: 0x{:08X}", table.addr());
+        }
+        Err(e) => println!("== Kernel address space failed: {:?}", e),
+    }
+
+    unsafe {
+        trap::init_hart();
+        interrupt::ipi::init_hart();
+    }
+    println!("== Interrupt trap initialized");
 
     unsafe {
         interrupt::plic::set_threshold(0);
-        interrupt::plic::enable(10);
-        interrupt::plic::set_priority(10, 1);
+        interrupt::plic::register(uart::DEFAULT_IRQ, 1, tty::handle_interrupt, &());
     }
     println!("== PLIC Initialized");
 
+    unsafe { filesystem::init(); }
+    tty::register_files();
+
     for driver in conf::DRIVERS {
         driver.load();
     }
 
-    unsafe { trap::init_hart_zero(); }
-    println!("== Interrupt trap initialized");
-
     unsafe { process::init(); }
     println!("== Process manager initialized");
 
     process::spawn(process::builtin::init, "[init]");
 
+    #[cfg(feature = "selftest")]
+    process::spawn(sync::selftest::run, "[selftest]");
+
+    unsafe { smp::start_harts(); }
+    println!("== Harts started: {}", smp::hart_count());
+
     println!("== Start scheduling processes");
     unsafe { process::start_schedule(); }
 
 }
 
 
-/// Called from `trap.asm` when a fatal exception was trapped.
-/// 
+/// The entry point of secondary harts, called from `boot.asm` once
+/// woken up by the hart #0 in [`smp::start_harts`]. The hart just
+/// initializes its own state and starts scheduling processes.
 #[no_mangle]
-extern "C" fn kpanic(code: usize) -> ! {
-    
-    fn get_code(code: usize) -> Option<&'static str> {
-        Some(match code {
-            0 => "Instruction address misaligned",
-            1 => "Instruction access fault",
-            2 => "Illegal instruction",
-            3 => "Breakpoint",
-            4 => "Load address misaligned",
-            5 => "Load access fault",
-            6 => "Store/AMO address misaligned",
-            7 => "Store/AMO access fault",
-            12 => "Instruction page fault",
-            13 => "Load page fault",
-            15 => "Store/AMO page fault",
-            _ => return None
-        })
+extern "C" fn kmain_hart(hartid: usize) {
+
+    unsafe {
+        interrupt::clint::clear_msip(hartid);
+        memory::paging::activate_kernel();
+        trap::init_hart();
+        interrupt::ipi::init_hart();
     }
 
+    println!("== Hart #{} started", hartid);
+    unsafe { process::start_schedule(); }
+
+}
+
+
+/// Called from `trap.asm` when a trap (exception or interrupt) is
+/// taken by the hart. The frame contains the saved state of the
+/// interrupted code and will be restored when this returns.
+#[no_mangle]
+extern "C" fn ktrap(frame: &mut trap::TrapFrame) {
+
+    if frame.trap.interrupt() {
+        match frame.trap.code() {
+            // Machine software interrupt, sent by another hart.
+            3 => unsafe {
+                if interrupt::ipi::handle() || process::is_idle() {
+                    process::schedule(frame);
+                }
+            }
+            // Machine timer interrupt, the time slice of the process has elapsed.
+            7 => unsafe { process::schedule(frame) },
+            // Machine external interrupt, dispatched by the PLIC.
+            11 => unsafe {
+                interrupt::plic::dispatch();
+                // A process might have been woken up by the handler.
+                if process::is_idle() {
+                    process::schedule(frame);
+                }
+            }
+            code => println!("== Unhandled interrupt #{} on hart #{}", code, cpu::mhardid::get()),
+        }
+    } else {
+        match frame.trap.code() {
+            // Breakpoint, just resume after it. The process is killed if
+            // its program counter can't be translated.
+            3 => unsafe {
+                if !frame.skip_instruction() {
+                    process::kill(frame, "Breakpoint at an unmapped address");
+                }
+            }
+            // Environment call from U-mode or M-mode, system call.
+            8 | 11 => unsafe { syscall::dispatch(frame) },
+            // Faults from user code only kill the faulty process.
+            code if frame.from_user() => unsafe {
+                process::kill(frame, exception_name(code).unwrap_or("Unknown exception"));
+            }
+            code => kpanic(code, frame),
+        }
+    }
+
+}
+
+
+/// Get the name of an exception from its code.
+fn exception_name(code: usize) -> Option<&'static str> {
+    Some(match code {
+        0 => "Instruction address misaligned",
+        1 => "Instruction access fault",
+        2 => "Illegal instruction",
+        3 => "Breakpoint",
+        4 => "Load address misaligned",
+        5 => "Load access fault",
+        6 => "Store/AMO address misaligned",
+        7 => "Store/AMO access fault",
+        8 => "Environment call from U-mode",
+        9 => "Environment call from S-mode",
+        11 => "Environment call from M-mode",
+        12 => "Instruction page fault",
+        13 => "Load page fault",
+        15 => "Store/AMO page fault",
+        _ => return None
+    })
+}
+
+
+/// Called from `ktrap` when a fatal exception was trapped.
+fn kpanic(code: usize, frame: &trap::TrapFrame) -> ! {
+    
+    uart::set_panicking();
     println!("== The hart #{} encountered a fatal exception...", cpu::mhardid::get());
     
-    if let Some(code_name) = get_code(code) {
+    if let Some(code_name) = exception_name(code) {
         println!(" = Code: {}", code_name);
     } else {
         println!(" = Unknown code: {:02X}", code);
     }
 
+    frame.dump();
+
+    interrupt::ipi::halt_others();
     unsafe { asm::asm_abort() }
 
 }
 
 
-/// Panic handler will only abort.
+/// Panic handler, all the harts are halted.
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    uart::set_panicking();
     println!("== The hart #{} panicked...", cpu::mhardid::get());
     if let Some(loc) = info.location() {
         println!(" = At: {}", loc);
@@ -129,5 +259,6 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     if let Some(msg) = info.message() {
         println!(" = Message: {}", msg);
     }
+    interrupt::ipi::halt_others();
     unsafe { asm::asm_abort() }
 }
@@ -0,0 +1,183 @@
+//! Kernel benchmarks of the memory allocators, only compiled with the
+//! `bench` feature and run at boot.
+//!
+//! The page allocator is compared against a reference implementation
+//! of the previous first-fit linear scan allocator, both run the same
+//! workload of mixed-size allocations on a fragmented memory.
+
+use core::num::NonZeroUsize;
+use core::ptr::NonNull;
+
+use crate::cpu::mcycle;
+use crate::println;
+
+use super::page::{self, PAGE_SIZE};
+
+
+/// Number of allocations live at the same time in the workload.
+const LIVE_COUNT: usize = 256;
+
+/// Number of measured allocations.
+const ROUNDS: usize = 4096;
+
+
+/// Run all the benchmarks.
+///
+/// *This function is unsafe because it must be called after the
+/// initialization of the page allocator, without concurrent usage.*
+pub unsafe fn run() {
+
+    println!("== Benchmark: page allocator ({} rounds, {} live allocations)", ROUNDS, LIVE_COUNT);
+
+    let usable_pages_count = page::info().free_pages_count;
+
+    let (alloc_cycles, dealloc_cycles) = bench_pages(Buddy);
+    println!(" = Buddy:  alloc {} cycles, dealloc {} cycles", alloc_cycles / ROUNDS as u64, dealloc_cycles / ROUNDS as u64);
+
+    // The metadata of the linear allocator is one byte per page.
+    let meta_pages_count = (usable_pages_count + PAGE_SIZE - 1) / PAGE_SIZE;
+    let meta = page::alloc_zeroed(NonZeroUsize::new_unchecked(meta_pages_count)).expect("failed to allocate linear metadata");
+    let linear = Linear {
+        pages: core::slice::from_raw_parts_mut(meta.as_ptr(), usable_pages_count - meta_pages_count),
+    };
+
+    let (alloc_cycles, dealloc_cycles) = bench_pages(linear);
+    println!(" = Linear: alloc {} cycles, dealloc {} cycles", alloc_cycles / ROUNDS as u64, dealloc_cycles / ROUNDS as u64);
+
+    page::dealloc(meta).unwrap();
+
+}
+
+
+/// Common interface of the benchmarked page allocators.
+trait PageAllocator {
+    unsafe fn alloc(&mut self, pages_count: usize) -> Option<usize>;
+    unsafe fn dealloc(&mut self, addr: usize);
+}
+
+
+/// Internal function to run the workload on the given allocator, the
+/// total cycles spent in allocations and deallocations are returned.
+unsafe fn bench_pages(mut allocator: impl PageAllocator) -> (u64, u64) {
+
+    let mut live = [None; LIVE_COUNT];
+    let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+    let mut alloc_cycles = 0;
+    let mut dealloc_cycles = 0;
+
+    // Fill the live set to fragment the memory.
+    for slot in &mut live {
+        *slot = allocator.alloc(rng.pages_count());
+    }
+
+    for _ in 0..ROUNDS {
+
+        let slot = &mut live[rng.next() as usize % LIVE_COUNT];
+
+        if let Some(addr) = slot.take() {
+            let start = mcycle::get();
+            allocator.dealloc(addr);
+            dealloc_cycles += mcycle::get() - start;
+        }
+
+        let pages_count = rng.pages_count();
+        let start = mcycle::get();
+        *slot = allocator.alloc(pages_count);
+        alloc_cycles += mcycle::get() - start;
+
+    }
+
+    for addr in live.into_iter().flatten() {
+        allocator.dealloc(addr);
+    }
+
+    (alloc_cycles, dealloc_cycles)
+
+}
+
+
+/// The kernel page allocator.
+struct Buddy;
+
+impl PageAllocator for Buddy {
+
+    unsafe fn alloc(&mut self, pages_count: usize) -> Option<usize> {
+        page::alloc(NonZeroUsize::new_unchecked(pages_count)).ok().map(|ptr| ptr.addr().get())
+    }
+
+    unsafe fn dealloc(&mut self, addr: usize) {
+        page::dealloc(NonNull::new_unchecked(addr as *mut u8)).unwrap();
+    }
+
+}
+
+
+/// Reference first-fit allocator scanning one metadata byte per page,
+/// returning page indices instead of addresses.
+struct Linear {
+    pages: &'static mut [u8],
+}
+
+impl Linear {
+    const TAKEN: u8 = 0b01;
+    const FIRST: u8 = 0b10;
+}
+
+impl PageAllocator for Linear {
+
+    unsafe fn alloc(&mut self, pages_count: usize) -> Option<usize> {
+        let mut first_page = 0;
+        let mut free_count = 0;
+        for (i, &page) in self.pages.iter().enumerate() {
+            if page & Self::TAKEN == 0 {
+                if free_count == 0 {
+                    first_page = i;
+                }
+                free_count += 1;
+                if free_count == pages_count {
+                    self.pages[first_page] = Self::TAKEN | Self::FIRST;
+                    self.pages[first_page + 1..=i].fill(Self::TAKEN);
+                    return Some(first_page);
+                }
+            } else {
+                free_count = 0;
+            }
+        }
+        None
+    }
+
+    unsafe fn dealloc(&mut self, index: usize) {
+        self.pages[index] = 0;
+        for page in &mut self.pages[index + 1..] {
+            if *page != Self::TAKEN {
+                break;
+            }
+            *page = 0;
+        }
+    }
+
+}
+
+
+/// Simple pseudo-random generator, for reproducible workloads.
+struct XorShift(u64);
+
+impl XorShift {
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Mostly single pages, sometimes up to 16 pages.
+    fn pages_count(&mut self) -> usize {
+        match self.next() % 8 {
+            0 => 1 + (self.next() % 16) as usize,
+            1 | 2 => 2,
+            _ => 1,
+        }
+    }
+
+}
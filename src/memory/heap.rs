@@ -0,0 +1,162 @@
+//! Kernel heap allocator, built on top of the page allocator.
+//!
+//! Small allocations are served from slabs of fixed-size blocks, one
+//! free list per size class, slab pages are taken from the page
+//! allocator when a class is exhausted and never given back. Large
+//! allocations directly take whole pages, aligned as requested.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::num::NonZeroUsize;
+use core::ptr::{self, NonNull};
+
+use crate::sync::IrqSpinLock;
+
+use super::page::{PAGE_SIZE, alloc, alloc_aligned, dealloc};
+
+
+/// Block sizes of the size classes, each one is also its alignment.
+const CLASSES_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of size classes.
+const CLASSES_COUNT: usize = CLASSES_SIZES.len();
+
+
+/// The kernel heap allocator.
+#[global_allocator]
+static HEAP: Heap = Heap::new();
+
+
+/// The heap allocator, made of a free list for each size class.
+pub struct Heap {
+    classes: IrqSpinLock<[FreeList; CLASSES_COUNT]>,
+}
+
+/// A free block, it stores the next free block of the same class.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// Free list of a size class, with some statistics.
+#[derive(Clone, Copy)]
+struct FreeList {
+    head: Option<NonNull<FreeBlock>>,
+    /// Number of slab pages allocated for this class.
+    pages_count: usize,
+    /// Number of blocks currently allocated.
+    allocated_count: usize,
+}
+
+impl Heap {
+
+    pub const fn new() -> Self {
+        Self {
+            classes: IrqSpinLock::new([FreeList {
+                head: None,
+                pages_count: 0,
+                allocated_count: 0,
+            }; CLASSES_COUNT]),
+        }
+    }
+
+}
+
+impl FreeList {
+
+    /// Pop a free block, a new slab page is allocated if needed.
+    unsafe fn pop(&mut self, block_size: usize) -> *mut u8 {
+
+        if self.head.is_none() {
+
+            let page = match alloc(NonZeroUsize::new_unchecked(1)) {
+                Ok(page) => page.as_ptr(),
+                Err(_) => return ptr::null_mut(),
+            };
+
+            // Link all the blocks of the page, in address order.
+            for offset in (0..PAGE_SIZE).step_by(block_size).rev() {
+                let block = page.add(offset) as *mut FreeBlock;
+                block.write(FreeBlock { next: self.head });
+                self.head = Some(NonNull::new_unchecked(block));
+            }
+
+            self.pages_count += 1;
+
+        }
+
+        let block = self.head.unwrap_unchecked();
+        self.head = (*block.as_ptr()).next;
+        self.allocated_count += 1;
+        block.as_ptr() as *mut u8
+
+    }
+
+    /// Push a block back in the free list.
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        let block = ptr as *mut FreeBlock;
+        block.write(FreeBlock { next: self.head });
+        self.head = Some(NonNull::new_unchecked(block));
+        self.allocated_count -= 1;
+    }
+
+}
+
+// SAFETY: Free lists are only accessed through the lock.
+unsafe impl Send for FreeList {}
+
+unsafe impl GlobalAlloc for Heap {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = size_class(layout) {
+            self.classes.lock()[class].pop(CLASSES_SIZES[class])
+        } else {
+            // Large alignments are served by the page allocator, which
+            // aligns blocks to their size.
+            let pages_count = (layout.size() + PAGE_SIZE - 1) / PAGE_SIZE;
+            match alloc_aligned(NonZeroUsize::new_unchecked(pages_count), layout.align().max(PAGE_SIZE)) {
+                Ok(page) => page.as_ptr(),
+                Err(_) => ptr::null_mut(),
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = size_class(layout) {
+            self.classes.lock()[class].push(ptr);
+        } else {
+            // Aligned or not, the pages are freed from their first one.
+            dealloc(NonNull::new_unchecked(ptr)).expect("invalid large heap deallocation");
+        }
+    }
+
+}
+
+
+/// Statistics about the kernel heap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapInfo {
+    /// Number of pages used by slabs.
+    pub slab_pages_count: usize,
+    /// Number of bytes of allocated small blocks.
+    pub allocated_size: usize,
+}
+
+
+/// Get statistics about the small allocations of the kernel heap.
+pub fn info() -> HeapInfo {
+    let classes = HEAP.classes.lock();
+    let mut info = HeapInfo::default();
+    for (class, list) in classes.iter().enumerate() {
+        info.slab_pages_count += list.pages_count;
+        info.allocated_size += list.allocated_count * CLASSES_SIZES[class];
+    }
+    info
+}
+
+
+/// Internal function to get the smallest size class that fits the
+/// size and alignment of the layout, none for large allocations.
+#[inline]
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    CLASSES_SIZES.iter().position(|&class_size| size <= class_size)
+}
@@ -1,3 +1,87 @@
 //! Memory-related modules.
 
+pub mod region;
 pub mod page;
+pub mod paging;
+pub mod heap;
+
+#[cfg(feature = "bench")]
+pub mod bench;
+
+use crate::asm::{LD_MEMORY_START, LD_MEMORY_END, LD_KSTACK_END};
+use crate::devicetree;
+
+use region::{Region, RegionList};
+
+
+/// Discover the usable memory regions and the reserved ones, and
+/// initialize the page allocator with them. The usable memory is 
+/// given by the device tree's memory nodes, or by the linker script
+/// if there is no device tree. Reserved ranges are the kernel image
+/// and its stack, the device tree blob, its memory reservations and
+/// reserved memory nodes, and the initrd if any.
+/// 
+/// The returned lists are respectively the usable regions given to
+/// the page allocator and the reserved ones.
+/// 
+/// *This function is unsafe because it must be called once, after
+/// the initialization of the device tree.*
+pub unsafe fn init() -> (RegionList, RegionList) {
+
+    let mut usable = RegionList::new();
+    let mut reserved = RegionList::new();
+
+    // The kernel image starts at the beginning of the memory and ends
+    // with the kernel stack.
+    reserved.add(Region::new(LD_MEMORY_START.addr(), LD_KSTACK_END.addr()));
+
+    if let Some(tree) = devicetree::get() {
+
+        for node in tree.nodes().filter(|node| node.device_type() == Some("memory")) {
+            for (addr, size) in node.reg() {
+                usable.add(Region::with_size(addr as usize, size as usize));
+            }
+        }
+
+        reserved.add(Region::with_size(tree.addr(), tree.size()));
+
+        for (addr, size) in tree.reservations() {
+            reserved.add(Region::with_size(addr as usize, size as usize));
+        }
+
+        if let Some(reserved_memory) = tree.find_path("/reserved-memory") {
+            for node in reserved_memory.descendants() {
+                for (addr, size) in node.reg() {
+                    reserved.add(Region::with_size(addr as usize, size as usize));
+                }
+            }
+        }
+
+        if let Some(initrd) = initrd() {
+            reserved.add(initrd);
+        }
+
+    }
+
+    if usable.is_empty() {
+        usable.add(Region::new(LD_MEMORY_START.addr(), LD_MEMORY_END.addr()));
+    }
+
+    for region in reserved.iter() {
+        usable.remove(region);
+    }
+
+    page::init(&usable);
+    (usable, reserved)
+
+}
+
+
+/// Return the memory region of the initial ramdisk given by the 
+/// bootloader through the `/chosen` node of the device tree.
+pub fn initrd() -> Option<Region> {
+    let chosen = devicetree::get()?.find_path("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.u64()?;
+    let end = chosen.property("linux,initrd-end")?.u64()?;
+    Some(Region::new(start as usize, end as usize))
+}
@@ -1,4 +1,14 @@
 //! Paged memory allocation.
+//!
+//! The allocator manages a list of zones, each zone being a contiguous
+//! usable memory region. The first pages of each zone are used to store
+//! the metadata of all pages of the zone.
+//!
+//! Free pages are managed by a buddy allocator, free blocks of `2^order`
+//! pages are kept in one free list per order, and are always aligned to
+//! their size in the physical address space. Allocations split larger
+//! blocks and the pages in excess are given back, freed blocks are merged
+//! with their buddy when it's also free.
 
 use core::num::NonZeroUsize;
 use core::ptr::NonNull;
@@ -6,18 +16,28 @@ use core::mem::size_of;
 
 use bitflags::bitflags;
 
-use crate::asm::{LD_HEAP_START, LD_HEAP_SIZE};
+use crate::sync::IrqSpinLock;
+
+use super::region::{RegionList, MAX_REGIONS};
 
 
 /// Size of an allocated page.
 pub const PAGE_SIZE: usize = 4096;
 
-/// The index of the first allocable page. Previous pages are
-/// used to store metadata about allocated pages.
-/// 
-/// Once set, this value should be at least 1, because we will always 
-/// have a page at least for metadata itself.
-static mut PAGE_START: usize = 0;
+/// Maximum order of free blocks, the largest blocks are 4 MiB. Larger
+/// allocations are served from runs of contiguous blocks.
+pub const MAX_ORDER: usize = 10;
+
+/// Maximum number of zones, one per usable region.
+const MAX_ZONES: usize = MAX_REGIONS;
+
+
+/// The zones of usable memory, the lock disables interrupts so the
+/// allocator can be used from interrupt handlers.
+static ZONES: IrqSpinLock<Zones> = IrqSpinLock::new(Zones {
+    zones: [Zone::EMPTY; MAX_ZONES],
+    count: 0,
+});
 
 
 bitflags! {
@@ -29,6 +49,9 @@ bitflags! {
         const TAKEN     = 0b0000_0001;
         /// The page is the first of an allocation.
         const FIRST     = 0b0000_0010;
+        /// The page is the first of a free block, the order of the
+        /// block is stored in the metadata.
+        const FREE      = 0b0000_0100;
         /// The page is already allocated for metadata usage.
         const METADATA  = 0b1000_0000;
     }
@@ -37,21 +60,13 @@ bitflags! {
 /// Represent a metadata for a single page.
 #[repr(C)]
 struct PageMetadata {
-    flags: PageFlags
+    flags: PageFlags,
+    /// Order of the free block, only relevant for `FREE` pages.
+    order: u8,
 }
 
 impl PageMetadata {
 
-    #[inline]
-    pub fn is_taken(&self) -> bool {
-        self.flags.contains(PageFlags::TAKEN)
-    }
-
-    #[inline]
-    pub fn is_first(&self) -> bool {
-        self.flags.contains(PageFlags::FIRST)
-    }
-
     #[inline]
     pub fn is_taken_and_first(&self) -> bool {
         self.flags.contains(PageFlags::TAKEN | PageFlags::FIRST)
@@ -62,164 +77,419 @@ impl PageMetadata {
         self.flags & (PageFlags::TAKEN | PageFlags::FIRST) == PageFlags::TAKEN
     }
 
+    #[inline]
+    pub fn is_free_block(&self, order: usize) -> bool {
+        self.flags.contains(PageFlags::FREE) && self.order as usize == order
+    }
+
 }
 
 
-/// Get a slice of all pages metadata. 
-/// 
-/// *This function is unsafe, because caller must ensure that 
-/// no concurrent access to the metadata is made.*
-#[inline(always)]
-unsafe fn get_pages() -> &'static mut [PageMetadata] {
-    let len = LD_HEAP_SIZE / PAGE_SIZE;
-    let ptr = LD_HEAP_START as *mut PageMetadata;
-    core::slice::from_raw_parts_mut(ptr, len)
+/// Header stored in the first page of each free block, linking the
+/// blocks of the same order.
+struct FreeBlock {
+    prev: Option<NonNull<FreeBlock>>,
+    next: Option<NonNull<FreeBlock>>,
 }
 
 
-/// Initialize the page system.
-/// 
-/// *This function is unsafe because it must be called before
-/// any other page allocation function.*
-pub unsafe fn init() {
+/// The zones, only the first `count` are valid.
+struct Zones {
+    zones: [Zone; MAX_ZONES],
+    count: usize,
+}
 
-    let pages = get_pages();
+impl Zones {
 
-    // Here we compute the number of reserved pages, used only for pages metadata.
-    let pages_meta_total_size = pages.len() * size_of::<PageMetadata>();
-    PAGE_START = (pages_meta_total_size + PAGE_SIZE - 1) / PAGE_SIZE;
+    /// Get a slice of all initialized zones.
+    #[inline(always)]
+    fn get(&mut self) -> &mut [Zone] {
+        &mut self.zones[..self.count]
+    }
 
-    for (i, page) in pages.iter_mut().enumerate() {
-        if i < PAGE_START {
-            // The flags for metadata-reserved pages should not change afterward.
-            page.flags = PageFlags::TAKEN | PageFlags::METADATA;
-        } else {
-            page.flags = PageFlags::EMPTY;
+}
+
+// SAFETY: Zones only point to the memory they manage.
+unsafe impl Send for Zones {}
+
+
+/// A contiguous range of pages, the metadata of all pages is stored
+/// at the beginning of the zone.
+#[derive(Clone, Copy)]
+struct Zone {
+    /// Address of the first page of the zone, aligned to [`PAGE_SIZE`].
+    start: usize,
+    /// Number of pages in the zone, counting metadata ones.
+    pages_count: usize,
+    /// The index of the first allocable page. Previous pages are
+    /// used to store metadata about allocated pages.
+    ///
+    /// This value should be at least 1, because we will always
+    /// have a page at least for metadata itself.
+    page_start: usize,
+    /// Head of the free list of each order.
+    free_lists: [Option<NonNull<FreeBlock>>; MAX_ORDER + 1],
+    /// Number of allocated pages.
+    allocated_pages_count: usize,
+    /// Number of allocations.
+    allocations_count: usize,
+}
+
+impl Zone {
+
+    const EMPTY: Self = Self {
+        start: 0,
+        pages_count: 0,
+        page_start: 0,
+        free_lists: [None; MAX_ORDER + 1],
+        allocated_pages_count: 0,
+        allocations_count: 0,
+    };
+
+    /// Address of the first usable page of the zone.
+    #[inline]
+    fn usable_start(&self) -> usize {
+        self.start + self.page_start * PAGE_SIZE
+    }
+
+    /// Address of the end of the zone (exclusive).
+    #[inline]
+    fn end(&self) -> usize {
+        self.start + self.pages_count * PAGE_SIZE
+    }
+
+    /// Get a slice of all pages metadata.
+    ///
+    /// *This function is unsafe, because caller must ensure that
+    /// no concurrent access to the metadata is made.*
+    #[inline(always)]
+    unsafe fn pages(&self) -> &'static mut [PageMetadata] {
+        core::slice::from_raw_parts_mut(self.start as *mut PageMetadata, self.pages_count)
+    }
+
+    /// Get the metadata of the page at the given address.
+    #[inline(always)]
+    unsafe fn page(&self, addr: usize) -> &'static mut PageMetadata {
+        &mut self.pages()[(addr - self.start) / PAGE_SIZE]
+    }
+
+    /// Allocate the given number of pages from a block of at least the
+    /// given order, the returned address is aligned to the block size.
+    /// Allocations larger than the largest block are taken from a run
+    /// of contiguous free blocks, see [`Zone::alloc_run`].
+    unsafe fn alloc(&mut self, pages_count: usize, min_order: usize) -> Option<usize> {
+
+        let order = order_of(pages_count).max(min_order);
+        if order > MAX_ORDER {
+            return self.alloc_run(pages_count, PAGE_SIZE << min_order);
+        }
+
+        // Find the smallest free block that is large enough.
+        let mut block_order = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop_free(block_order);
+
+        // Split the block, the upper halves are given back.
+        while block_order > order {
+            block_order -= 1;
+            self.push_free(addr + (PAGE_SIZE << block_order), block_order);
         }
+
+        self.take(addr, pages_count, addr + (PAGE_SIZE << order));
+        Some(addr)
+
     }
 
-}
+    /// Allocate the given number of pages from a run of contiguous free
+    /// blocks starting at the given alignment. Free blocks are found by
+    /// scanning the metadata of the whole zone, so this is slower than
+    /// the free lists and only used for the largest allocations.
+    unsafe fn alloc_run(&mut self, pages_count: usize, align: usize) -> Option<usize> {
 
+        let size = pages_count.checked_mul(PAGE_SIZE)?;
+        let mut run_start = None;
+        let mut addr = self.usable_start();
 
-/// Allocate the given number of pages.
-/// The returned pointer is aligned to `PAGE_SIZE` (4096).
-/// 
-/// *This function is unsafe because caller must ensure
-/// that no concurrent call is made to this function or
-/// other allocation-related functions.*
-pub unsafe fn alloc(pages_count: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
-
-    let pages_count = pages_count.get();
-    let pages = get_pages();
-
-    let mut first_page = 0;
-    let mut found = false;
-    let mut pages_needed = 0;
-
-    for (i, page) in pages.iter().enumerate().skip(PAGE_START) {
-
-        if !page.is_taken() {
-            
-            if pages_needed == 0 {
-                // If pages needed equals 0, this is a sentinel that means
-                // we are starting a new free-pages sequence.
-                pages_needed = pages_count - 1;
-                first_page = i;
-            } else {
-                // Here we decrement pages needed and if we reach 0, this
-                // means that we found anough pages for our allocation.
-                pages_needed -= 1;
+        while addr < self.end() {
+
+            let page = self.page(addr);
+            if !page.flags.contains(PageFlags::FREE) {
+                run_start = None;
+                addr += PAGE_SIZE;
+                continue;
             }
 
-            // This branch is in common because in case of pages count 1,
-            // we directly reach this condition.
-            if pages_needed == 0 {
-                found = true;
-                break;
+            // Blocks are aligned to their size, so a block contains an
+            // aligned address only if its start is aligned.
+            if run_start.is_none() && addr % align == 0 {
+                run_start = Some(addr);
+            }
+
+            addr += PAGE_SIZE << page.order;
+
+            if let Some(start) = run_start {
+                if addr - start >= size {
+                    let mut block_addr = start;
+                    while block_addr < addr {
+                        let order = self.page(block_addr).order as usize;
+                        self.remove_free(block_addr, order);
+                        block_addr += PAGE_SIZE << order;
+                    }
+                    self.take(start, pages_count, addr);
+                    return Some(start);
+                }
             }
 
-        } else {
-            // If the page is taken, just reset the counter and start searching again.
-            pages_needed = 0;
         }
 
+        None
+
     }
 
-    if found {
+    /// Mark the given number of pages as allocated, from the start of a
+    /// range removed from the free lists, the pages in excess up to the
+    /// end of the range are given back.
+    unsafe fn take(&mut self, addr: usize, pages_count: usize, end: usize) {
 
-        for page in &mut pages[(first_page + 1)..(first_page + pages_count)] {
+        let pages = &mut self.pages()[(addr - self.start) / PAGE_SIZE..][..pages_count];
+        pages[0].flags = PageFlags::TAKEN | PageFlags::FIRST;
+        for page in &mut pages[1..] {
             page.flags = PageFlags::TAKEN;
         }
 
-        pages[first_page].flags = PageFlags::TAKEN | PageFlags::FIRST;
+        self.free_range(addr + pages_count * PAGE_SIZE, end);
+
+        self.allocated_pages_count += pages_count;
+        self.allocations_count += 1;
+
+    }
+
+    /// Free the allocation starting at the given address.
+    unsafe fn dealloc(&mut self, addr: usize) -> Result<(), DeallocError> {
+
+        if addr < self.usable_start() {
+            return Err(DeallocError::InvalidPointer);
+        }
+
+        let pages = &mut self.pages()[(addr - self.start) / PAGE_SIZE..];
+        if !pages[0].is_taken_and_first() {
+            return Err(DeallocError::InvalidPointer);
+        }
+
+        // Here we take all subsequent pages that a both taken
+        // and not a first one. Encountering a first page would
+        // mean that we are on another allocation.
+        let pages_count = 1 + pages[1..].iter()
+            .take_while(|page| page.is_taken_and_not_first())
+            .count();
+
+        self.free_range(addr, addr + pages_count * PAGE_SIZE);
+        self.allocated_pages_count -= pages_count;
+        self.allocations_count -= 1;
+        Ok(())
+
+    }
+
+    /// Free a range of pages, the range is split in the largest aligned
+    /// blocks possible, each one being merged with its buddies.
+    unsafe fn free_range(&mut self, mut addr: usize, end: usize) {
+
+        for page in &mut self.pages()[(addr - self.start) / PAGE_SIZE..(end - self.start) / PAGE_SIZE] {
+            page.flags = PageFlags::EMPTY;
+        }
+
+        while addr < end {
+            let mut order = 0;
+            while order < MAX_ORDER
+                && addr % (PAGE_SIZE << (order + 1)) == 0
+                && addr + (PAGE_SIZE << (order + 1)) <= end {
+                order += 1;
+            }
+            self.free_block(addr, order);
+            addr += PAGE_SIZE << order;
+        }
+
+    }
+
+    /// Free a single block of the given order, merged with its buddy
+    /// while possible.
+    unsafe fn free_block(&mut self, mut addr: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = addr ^ (PAGE_SIZE << order);
+            if buddy < self.usable_start() || buddy + (PAGE_SIZE << order) > self.end() {
+                break;
+            } else if !self.page(buddy).is_free_block(order) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push_free(addr, order);
+    }
+
+    /// Push a block in the free list of its order.
+    unsafe fn push_free(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let next = self.free_lists[order];
+        block.write(FreeBlock { prev: None, next });
+        if let Some(next) = next {
+            (*next.as_ptr()).prev = Some(NonNull::new_unchecked(block));
+        }
+        self.free_lists[order] = Some(NonNull::new_unchecked(block));
+        let page = self.page(addr);
+        page.flags = PageFlags::FREE;
+        page.order = order as u8;
+    }
+
+    /// Pop the first block of the free list of the given order, which
+    /// must not be empty.
+    unsafe fn pop_free(&mut self, order: usize) -> usize {
+        let addr = self.free_lists[order].unwrap_unchecked().addr().get();
+        self.remove_free(addr, order);
+        addr
+    }
+
+    /// Remove the given block from the free list of its order.
+    unsafe fn remove_free(&mut self, addr: usize, order: usize) {
+        let block = &mut *(addr as *mut FreeBlock);
+        match block.prev {
+            Some(prev) => (*prev.as_ptr()).next = block.next,
+            None => self.free_lists[order] = block.next,
+        }
+        if let Some(next) = block.next {
+            (*next.as_ptr()).prev = block.prev;
+        }
+        self.page(addr).flags = PageFlags::EMPTY;
+    }
+
+}
+
+
+/// Get the order of the smallest block that can hold the given number
+/// of pages.
+#[inline]
+fn order_of(pages_count: usize) -> usize {
+    pages_count.next_power_of_two().trailing_zeros() as usize
+}
+
+
+/// Initialize the page system from the given usable memory regions,
+/// the regions are aligned to pages and too small ones are ignored.
+///
+/// *This function is unsafe because it must be called before
+/// any other page allocation function, and the given regions must
+/// not overlap with any used memory (kernel, device tree...).*
+pub unsafe fn init(regions: &RegionList) {
+
+    let mut zones = ZONES.lock();
+    zones.count = 0;
+
+    for region in regions.iter() {
+
+        let region = region.align_inward(PAGE_SIZE);
+        let pages_count = region.size() / PAGE_SIZE;
+
+        // Here we compute the number of reserved pages, used only for pages metadata.
+        let pages_meta_total_size = pages_count * size_of::<PageMetadata>();
+        let page_start = (pages_meta_total_size + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        // The zone must at least have one usable page.
+        if pages_count <= page_start {
+            continue;
+        }
+
+        let mut zone = Zone {
+            start: region.start,
+            pages_count,
+            page_start,
+            ..Zone::EMPTY
+        };
+
+        for page in &mut zone.pages()[..page_start] {
+            // The flags for metadata-reserved pages should not change afterward.
+            page.flags = PageFlags::TAKEN | PageFlags::METADATA;
+        }
+
+        zone.free_range(zone.usable_start(), zone.end());
 
-        Ok(NonNull::new_unchecked(LD_HEAP_START.add(first_page * PAGE_SIZE) as _))
+        let index = zones.count;
+        zones.zones[index] = zone;
+        zones.count += 1;
 
-    } else {
-        Err(AllocError)
     }
 
 }
 
 
+/// Allocate the given number of pages.
+/// The returned pointer is aligned to `PAGE_SIZE` (4096).
+///
+/// Allocations up to the largest block size (`PAGE_SIZE << MAX_ORDER`)
+/// are served in logarithmic time. Larger ones scan the zones for
+/// contiguous free blocks, they are only limited by the contiguous
+/// free memory of a zone.
+pub fn alloc(pages_count: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+    alloc_aligned(pages_count, PAGE_SIZE)
+}
+
+
+/// Allocate the given number of pages, the returned pointer is aligned
+/// to the given alignment, which must be a power of two. Like sizes,
+/// alignments larger than the largest block size (`PAGE_SIZE <<
+/// MAX_ORDER`) are supported but slower, see [`alloc`].
+pub fn alloc_aligned(pages_count: NonZeroUsize, align: usize) -> Result<NonNull<u8>, AllocError> {
+
+    debug_assert!(align.is_power_of_two());
+    let min_order = order_of((align / PAGE_SIZE).max(1));
+
+    for zone in ZONES.lock().get() {
+        // SAFETY: The zone is locked.
+        if let Some(addr) = unsafe { zone.alloc(pages_count.get(), min_order) } {
+            return Ok(unsafe { NonNull::new_unchecked(addr as _) });
+        }
+    }
+
+    Err(AllocError)
+
+}
+
+
 /// Allocate the given number of pages and set all the data to 0.
 /// The returned pointer is aligned to `PAGE_SIZE` (4096).
-/// 
-/// *This function is unsafe because caller must ensure
-/// that no concurrent call is made to this function or
-/// other allocation-related functions.*
-pub unsafe fn alloc_zeroed(pages_count: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+pub fn alloc_zeroed(pages_count: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
     alloc(pages_count).map(|nnptr| {
         // Since the returned data is a multiple of PAGE_SIZE (4096),
         // it's safe and valid to fill it 8 by 8 bytes.
         let mut ptr = nnptr.as_ptr() as *mut u64;
         let len = pages_count.get() * PAGE_SIZE / 8;
         for _ in 0..len {
-            *ptr = 0;
-            ptr = ptr.add(1);
+            // SAFETY: The pages have just been allocated.
+            unsafe {
+                *ptr = 0;
+                ptr = ptr.add(1);
+            }
         }
         nnptr
     })
 }
 
 
-/// Deallocate previsouly allocated pages with [`alloc_raw`]. 
+/// Deallocate previsouly allocated pages with [`alloc`].
 /// The given address is aligned to [`PAGE_SIZE`] anyway.
-/// 
-/// *This function is unsafe because caller must ensure
-/// that no concurrent call is made to this function or
-/// other allocation-related functions.*
+///
+/// *This function is unsafe because the pages must not be
+/// used after being deallocated.*
 pub unsafe fn dealloc(page: NonNull<u8>) -> Result<(), DeallocError> {
 
-    // Compute the page index (this inerently align the pointer).
-    let page_index = page.as_ptr().offset_from(LD_HEAP_START) as usize / PAGE_SIZE;
-    
-    let pages = get_pages();
-    
-    if let Some(pages) = pages.get_mut(page_index..) {
-
-        if !pages[0].is_taken_and_first() {
-            Err(DeallocError::InvalidPointer)
-        } else {
-
-            pages[0].flags = PageFlags::EMPTY;
-
-            // Here we take all subsequent pages that a both taken 
-            // and not a first one. Encountering a first page would
-            // mean that we are on another allocation.
-            pages[1..]
-                .iter_mut()
-                .take_while(|page| page.is_taken_and_not_first())
-                .for_each(|page| page.flags = PageFlags::EMPTY);
+    // Align the pointer to its page.
+    let addr = page.addr().get() & !(PAGE_SIZE - 1);
+    let mut zones = ZONES.lock();
+    let zone = zones.get().iter_mut()
+        .find(|zone| addr >= zone.start && addr < zone.end())
+        .ok_or(DeallocError::OutOfRangePointer)?;
 
-            Ok(())
+    zone.dealloc(addr)
 
-        }
-
-    } else {
-        Err(DeallocError::OutOfRangePointer)
-    }
-    
 }
 
 
@@ -239,35 +509,65 @@ pub enum DeallocError {
 }
 
 
-/// Compute an information structure.
-pub unsafe fn info() -> PageMemoryInfo {
+/// Compute an information structure for the given zone index.
+pub fn zone_info(index: usize) -> Option<PageMemoryInfo> {
 
-    let pages = get_pages();
-    let metadata_usage_split_addr = LD_HEAP_START.add(PAGE_START * PAGE_SIZE).addr();
-    let pages_count = pages.len();
+    let mut zones = ZONES.lock();
+    let zone = zones.get().get(index)?;
+    let metadata_usage_split_addr = zone.usable_start();
+    let usable_pages_count = zone.pages_count - zone.page_start;
 
-    let mut info = PageMemoryInfo {
-        metadata_pages_start: LD_HEAP_START.addr(),
+    Some(PageMemoryInfo {
+        metadata_pages_start: zone.start,
         metadata_pages_end: metadata_usage_split_addr,
         usable_pages_start: metadata_usage_split_addr,
-        usable_pages_end: LD_HEAP_START.add(pages_count * PAGE_SIZE).addr(),
-        metadata_pages_count: PAGE_START,
-        usable_pages_count: pages_count - PAGE_START,
-        total_pages_count: pages_count,
+        usable_pages_end: zone.end(),
+        metadata_pages_count: zone.page_start,
+        usable_pages_count,
+        total_pages_count: zone.pages_count,
+        allocated_pages_count: zone.allocated_pages_count,
+        free_pages_count: usable_pages_count - zone.allocated_pages_count,
+        allocations_count: zone.allocations_count,
+    })
+
+}
+
+
+/// Return the number of zones managed by the allocator.
+#[inline]
+pub fn zones_count() -> usize {
+    ZONES.lock().count
+}
+
+
+/// Compute an information structure for all zones, the addresses
+/// are the lowest start and highest end of all zones.
+pub fn info() -> PageMemoryInfo {
+
+    let mut info = PageMemoryInfo {
+        metadata_pages_start: usize::MAX,
+        metadata_pages_end: 0,
+        usable_pages_start: usize::MAX,
+        usable_pages_end: 0,
+        metadata_pages_count: 0,
+        usable_pages_count: 0,
+        total_pages_count: 0,
         allocated_pages_count: 0,
         free_pages_count: 0,
         allocations_count: 0,
     };
 
-    for page in &pages[PAGE_START..] {
-        if page.is_taken() {
-            if page.is_first() {
-                info.allocations_count += 1;
-            }
-            info.allocated_pages_count += 1;
-        } else {
-            info.free_pages_count += 1;
-        }
+    for zone in (0..zones_count()).filter_map(|index| zone_info(index)) {
+        info.metadata_pages_start = info.metadata_pages_start.min(zone.metadata_pages_start);
+        info.metadata_pages_end = info.metadata_pages_end.max(zone.metadata_pages_end);
+        info.usable_pages_start = info.usable_pages_start.min(zone.usable_pages_start);
+        info.usable_pages_end = info.usable_pages_end.max(zone.usable_pages_end);
+        info.metadata_pages_count += zone.metadata_pages_count;
+        info.usable_pages_count += zone.usable_pages_count;
+        info.total_pages_count += zone.total_pages_count;
+        info.allocated_pages_count += zone.allocated_pages_count;
+        info.free_pages_count += zone.free_pages_count;
+        info.allocations_count += zone.allocations_count;
     }
 
     info
@@ -300,175 +600,3 @@ pub struct PageMemoryInfo {
     /// Number of allocations.
     pub allocations_count: usize,
 }
-
-
-/*bitflags! {
-    /// Metadata flags for a single page.
-    pub struct EntryFlags: u8 {
-
-        /// Special constant for empty entry.
-        const EMPTY     = 0b0000_0000;
-        /// The entry is valid.
-        const VALID     = 0b0000_0001;
-        /// The entry maps to a readable memory.
-        const READ      = 0b0000_0010;
-        /// The entry maps to a writable memory.
-        const WRITE     = 0b0000_0100;
-        /// The entry maps to an executable memory.
-        const EXECUTE   = 0b0000_1000;
-        /// The page is accessible to user mode.
-        const USER      = 0b0001_0000;
-        /// This mapping exists in all address spaces.
-        const GLOBAL    = 0b0010_0000;
-        /// Set to 1 if the page has been read/written or fetched since last bit clear.
-        const ACCESSED  = 0b0100_0000;
-        /// The page has been written since last bit clear.
-        const DIRTY     = 0b1000_0000;
-
-        const READ_WRITE = Self::READ.bits | Self::WRITE.bits;
-        const READ_EXECUTE = Self::READ.bits | Self::EXECUTE.bits;
-        const READ_WRITE_EXECUTE = Self::READ.bits | Self::WRITE.bits | Self::EXECUTE.bits;
-
-    }
-}
-
-
-#[repr(C)]
-pub union Entry {
-    raw: u64,
-    flags: EntryFlags,
-}
-
-impl Entry {
-
-    #[inline]
-    pub fn get_flags(&self) -> EntryFlags {
-        unsafe { self.flags }
-    }
-    
-    #[inline]
-    pub fn is_valid(&self) -> bool {
-        self.get_flags().contains(EntryFlags::VALID)
-    }
-
-    /// Return true if the entry maps to a physical address.
-    #[inline]
-    pub fn is_leaf(&self) -> bool {
-        self.get_flags() & EntryFlags::READ_WRITE_EXECUTE != EntryFlags::EMPTY
-    }
-
-    #[inline]
-    pub fn set_leaf(&mut self, paddr: usize, flags: EntryFlags) {
-        // Physical address are limited to 44 bits and we remove the 12 least bits.
-        self.raw = ((paddr & 0xFFF_FFFF_F000) >> 2) as u64;
-        self.flags = flags | EntryFlags::VALID | EntryFlags::DIRTY | EntryFlags::ACCESSED;
-    }
-
-    /// Return true if the entry points to the next level of page table.
-    #[inline]
-    pub fn is_branch(&self) -> bool {
-        !self.is_leaf()
-    }
-
-    /// Set this entry has a branch to a next level of page table.
-    #[inline]
-    pub fn set_branch(&mut self, table: NonNull<Table>) {
-        // >> 2 because >> 12 << 10
-        self.raw = ((table.addr().get() & !0x3FF) >> 2) as u64;
-        self.flags = EntryFlags::VALID;
-    }
-
-    #[inline]
-    pub fn get_branch(&self) -> Option<NonNull<Table>> {
-        unsafe {
-            if self.flags & (EntryFlags::READ_WRITE_EXECUTE | EntryFlags::VALID) == EntryFlags::VALID {
-                // >> 2 because >> 12 << 10
-                let ppn = (self.raw & 0x003F_FFFF_FFFF_FC00) << 2;
-                NonNull::new(ppn as *mut Table)
-            } else {
-                None
-            }
-        }
-    }
-
-}
-
-
-/// Represent a RAII paging table used by the MMU.
-pub struct Table {
-    entries: NonNull<TableEntries>
-}
-
-/// Type alias for table entries.
-pub type TableEntries = [Entry; 512];
-
-impl Table {
-
-    /// Allocate a new paging table.
-    /// 
-    /// *This function is unsafe because caller must ensure
-    /// that no concurrent call is made to this function or
-    /// other allocation-related functions.*
-    pub unsafe fn new() -> Result<Self, AllocError> {
-        // We allocate one page, it's enough for the whole table structure.
-        debug_assert_eq!(size_of::<Table>(), PAGE_SIZE);
-        alloc_zeroed(NonZeroUsize::new_unchecked(1)).map(|p| Table { 
-            entries: p.cast() 
-        })
-    }
-
-    /// Map a virtual address to a physical address for this table.
-    /// This function might fail if a sub-table allocation fails.
-    /// 
-    /// *This function is unsafe because caller must ensure
-    /// that no concurrent call is made to this function or
-    /// other allocation-related functions.*
-    pub unsafe fn map(&mut self, vaddr: usize, paddr: usize, flags: EntryFlags) -> Result<(), AllocError> {
-
-        let vpn = [
-            (vaddr >> 12) & 0x1FF,
-            (vaddr >> 21) & 0x1FF,
-            (vaddr >> 30) & 0x1FF,
-        ];
-
-        let mut entry = &mut self.entries[vpn[2]];
-
-        for i in (0..2).rev() {
-
-            let level_table;
-            if let Some(table) = entry.get_branch() {
-                level_table = table;
-            } else {
-                level_table = Self::new()?;
-                entry.set_branch(level_table);
-            };
-
-            entry = &mut (*level_table.as_ptr()).entries[vpn[i]];
-
-        }
-
-        entry.set_leaf(paddr, flags);
-        Ok(())
-
-    }
-
-    /// Unmap all branches tables, this doesn't free the 'self' one.
-    pub unsafe fn unmap(&mut self) {
-        self.unmap_internal(true);
-    }
-
-    unsafe fn unmap_internal(&mut self, empty: bool) {
-        for entry in &mut self.entries {
-            if let Some(table) = entry.get_branch() {
-                // Next level tables don't need to empty entries.
-                (*table.as_ptr()).unmap_internal(false);
-                let _ = dealloc(table.cast());
-            }
-            if empty {
-                entry.raw = 0;
-            }
-        }
-    }
-
-}
-*/
\ No newline at end of file
@@ -0,0 +1,449 @@
+//! Sv39 virtual memory paging.
+//!
+//! Virtual addresses are 39 bits wide and translated through three
+//! levels of page tables, each table is exactly one page of 512
+//! entries. Leaf entries can be found at any level, mapping 4 KiB
+//! pages, 2 MiB mega pages or 1 GiB giga pages.
+//!
+//! The kernel address space identity maps the kernel image, the
+//! memory managed by the page allocator and the MMIO regions. It is
+//! active whenever the hart doesn't run a user process, so code running
+//! in supervisor mode is translated through it. Note that the kernel
+//! itself runs in machine mode, where translation is never applied.
+
+use core::num::NonZeroUsize;
+use core::ptr::NonNull;
+use core::mem::size_of;
+
+use bitflags::bitflags;
+
+use crate::asm::{LD_TEXT_START, LD_RODATA_START, LD_RODATA_END, LD_DATA_START, LD_KSTACK_END};
+use crate::cpu::satp::{self, Mode};
+use crate::interrupt::{clint, plic, ipi};
+use crate::devicetree;
+
+use super::page::{self, PAGE_SIZE, AllocError, alloc_zeroed, dealloc};
+use super::region::Region;
+
+
+/// Size of a mega page, mapped by a leaf of a level 1 table.
+pub const MEGA_PAGE_SIZE: usize = PAGE_SIZE << 9;
+
+/// Size of a giga page, mapped by a leaf of a level 2 (root) table.
+pub const GIGA_PAGE_SIZE: usize = MEGA_PAGE_SIZE << 9;
+
+/// Number of entries in a table.
+const TABLE_ENTRIES_COUNT: usize = 512;
+
+
+/// The root table of the kernel address space, once built.
+static mut KERNEL_TABLE: Option<NonNull<Table>> = None;
+
+
+bitflags! {
+    /// Flags of a page table entry.
+    pub struct EntryFlags: u64 {
+
+        /// Special constant for empty entry.
+        const EMPTY     = 0b0000_0000;
+        /// The entry is valid.
+        const VALID     = 0b0000_0001;
+        /// The entry maps to a readable memory.
+        const READ      = 0b0000_0010;
+        /// The entry maps to a writable memory.
+        const WRITE     = 0b0000_0100;
+        /// The entry maps to an executable memory.
+        const EXECUTE   = 0b0000_1000;
+        /// The page is accessible to user mode.
+        const USER      = 0b0001_0000;
+        /// This mapping exists in all address spaces.
+        const GLOBAL    = 0b0010_0000;
+        /// Set to 1 if the page has been read/written or fetched since last bit clear.
+        const ACCESSED  = 0b0100_0000;
+        /// The page has been written since last bit clear.
+        const DIRTY     = 0b1000_0000;
+
+        const READ_WRITE = Self::READ.bits | Self::WRITE.bits;
+        const READ_EXECUTE = Self::READ.bits | Self::EXECUTE.bits;
+        const READ_WRITE_EXECUTE = Self::READ.bits | Self::WRITE.bits | Self::EXECUTE.bits;
+
+    }
+}
+
+
+/// A page table entry.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy)]
+pub struct Entry(u64);
+
+impl Entry {
+
+    #[inline]
+    pub fn flags(&self) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.0 & 0xFF)
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.flags().contains(EntryFlags::VALID)
+    }
+
+    /// Return true if the entry maps to a physical address.
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.flags().intersects(EntryFlags::READ_WRITE_EXECUTE)
+    }
+
+    /// Return true if the entry points to the next level of page table.
+    #[inline]
+    pub fn is_branch(&self) -> bool {
+        self.is_valid() && !self.is_leaf()
+    }
+
+    /// Return the physical address of the page or table pointed by this entry.
+    #[inline]
+    pub fn paddr(&self) -> usize {
+        // >> 10 << 12 because the PPN starts at bit 10.
+        ((self.0 & 0x003F_FFFF_FFFF_FC00) << 2) as usize
+    }
+
+    #[inline]
+    pub fn set_leaf(&mut self, paddr: usize, flags: EntryFlags) {
+        // Physical address are limited to 56 bits and we remove the 12 least bits.
+        let flags = flags | EntryFlags::VALID | EntryFlags::DIRTY | EntryFlags::ACCESSED;
+        self.0 = ((paddr as u64 & 0x00FF_FFFF_FFFF_F000) >> 2) | flags.bits;
+    }
+
+    /// Set this entry has a branch to a next level of page table.
+    #[inline]
+    pub fn set_branch(&mut self, table: NonNull<Table>) {
+        // >> 2 because >> 12 << 10
+        self.0 = ((table.addr().get() as u64 & 0x00FF_FFFF_FFFF_F000) >> 2) | EntryFlags::VALID.bits;
+    }
+
+    #[inline]
+    pub fn get_branch(&self) -> Option<NonNull<Table>> {
+        if self.is_branch() {
+            NonNull::new(self.paddr() as *mut Table)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+
+}
+
+
+/// A page table used by the MMU, it takes exactly one page.
+#[repr(C, align(4096))]
+pub struct Table {
+    pub entries: [Entry; TABLE_ENTRIES_COUNT],
+}
+
+impl Table {
+
+    /// Allocate a new, empty, paging table.
+    pub fn new() -> Result<NonNull<Self>, AllocError> {
+        // We allocate one page, it's enough for the whole table structure.
+        debug_assert_eq!(size_of::<Table>(), PAGE_SIZE);
+        alloc_zeroed(unsafe { NonZeroUsize::new_unchecked(1) }).map(NonNull::cast)
+    }
+
+    /// Map a virtual address to a physical address for this table, the
+    /// level is the level of the leaf entry: 0 for a 4 KiB page, 1 for a
+    /// mega page and 2 for a giga page. Both addresses must be aligned
+    /// to the size of the page. This function might fail if a sub-table
+    /// allocation fails.
+    ///
+    /// Mapping again a page to the same physical address only update
+    /// its flags.
+    ///
+    /// *This function is unsafe because the table and its branches 
+    /// must not be concurrently modified.*
+    pub unsafe fn map(&mut self, vaddr: usize, paddr: usize, flags: EntryFlags, level: usize) -> Result<(), MapError> {
+
+        debug_assert!(level <= 2);
+        debug_assert!(flags.intersects(EntryFlags::READ_WRITE_EXECUTE), "leaf entry must be readable, writable or executable");
+
+        let page_size = PAGE_SIZE << (9 * level);
+        if vaddr % page_size != 0 || paddr % page_size != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        let vpn = vpn(vaddr);
+        let mut entry = &mut self.entries[vpn[2]];
+
+        for i in (level..2).rev() {
+
+            let level_table;
+            if let Some(table) = entry.get_branch() {
+                level_table = table;
+            } else if entry.is_valid() {
+                return Err(MapError::AlreadyMapped);
+            } else {
+                level_table = Self::new().map_err(|_| MapError::OutOfMemory)?;
+                entry.set_branch(level_table);
+            };
+
+            entry = &mut (*level_table.as_ptr()).entries[vpn[i]];
+
+        }
+
+        if entry.is_valid() && (!entry.is_leaf() || entry.paddr() != paddr) {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        entry.set_leaf(paddr, flags);
+        Ok(())
+
+    }
+
+    /// Map a whole range of virtual addresses to the physical range of
+    /// the same size, mega pages are used where alignment allows it.
+    /// Both addresses and the size must be aligned to [`PAGE_SIZE`].
+    ///
+    /// *This function is unsafe for the same reasons as [`map`](Self::map).*
+    pub unsafe fn map_range(&mut self, vaddr: usize, paddr: usize, size: usize, flags: EntryFlags) -> Result<(), MapError> {
+
+        if vaddr % PAGE_SIZE != 0 || paddr % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let (vaddr, paddr) = (vaddr + offset, paddr + offset);
+            if vaddr % MEGA_PAGE_SIZE == 0 && paddr % MEGA_PAGE_SIZE == 0 && size - offset >= MEGA_PAGE_SIZE {
+                self.map(vaddr, paddr, flags, 1)?;
+                offset += MEGA_PAGE_SIZE;
+            } else {
+                self.map(vaddr, paddr, flags, 0)?;
+                offset += PAGE_SIZE;
+            }
+        }
+
+        Ok(())
+
+    }
+
+    /// Translate the given virtual address to its physical address, the
+    /// flags of the leaf entry are also returned.
+    pub fn translate(&self, vaddr: usize) -> Option<(usize, EntryFlags)> {
+
+        let vpn = vpn(vaddr);
+        let mut table = self;
+
+        for level in (0..3).rev() {
+            let entry = &table.entries[vpn[level]];
+            if !entry.is_valid() {
+                return None;
+            } else if entry.is_leaf() {
+                let page_offset = vaddr & ((PAGE_SIZE << (9 * level)) - 1);
+                return Some((entry.paddr() + page_offset, entry.flags()));
+            } else {
+                // SAFETY: Branches always point to valid tables.
+                table = unsafe { &*entry.get_branch()?.as_ptr() };
+            }
+        }
+
+        None
+
+    }
+
+    /// Remove the mapping of the leaf containing the given virtual
+    /// address, the physical address of the leaf is returned. Tables
+    /// are not freed even if they become empty.
+    pub fn unmap_page(&mut self, vaddr: usize) -> Option<usize> {
+
+        let vpn = vpn(vaddr);
+        let mut table: &mut Table = self;
+
+        for level in (0..3).rev() {
+            let entry = &mut table.entries[vpn[level]];
+            if !entry.is_valid() {
+                return None;
+            } else if entry.is_leaf() {
+                let paddr = entry.paddr();
+                entry.clear();
+                return Some(paddr);
+            } else {
+                // SAFETY: Branches always point to valid tables.
+                table = unsafe { &mut *entry.get_branch()?.as_ptr() };
+            }
+        }
+
+        None
+
+    }
+
+    /// Iterate over all leaf entries of this table, recursively, with
+    /// their virtual address, physical address, size and flags.
+    pub fn for_each_leaf(&self, mut func: impl FnMut(usize, usize, usize, EntryFlags)) {
+        self.for_each_leaf_internal(0, 2, &mut func);
+    }
+
+    fn for_each_leaf_internal(&self, vaddr_base: usize, level: usize, func: &mut impl FnMut(usize, usize, usize, EntryFlags)) {
+        let page_size = PAGE_SIZE << (9 * level);
+        for (i, entry) in self.entries.iter().enumerate() {
+            let vaddr = vaddr_base + i * page_size;
+            if let Some(table) = entry.get_branch() {
+                // SAFETY: Branches always point to valid tables.
+                unsafe { (*table.as_ptr()).for_each_leaf_internal(vaddr, level - 1, func); }
+            } else if entry.is_valid() {
+                func(vaddr, entry.paddr(), page_size, entry.flags());
+            }
+        }
+    }
+
+    /// Unmap all branches tables, this doesn't free the 'self' one.
+    /// Note that physical pages mapped by leaves are not freed.
+    ///
+    /// *This function is unsafe because the branches tables must
+    /// not be used anymore, by any hart or reference.*
+    pub unsafe fn unmap(&mut self) {
+        self.unmap_internal(true);
+    }
+
+    unsafe fn unmap_internal(&mut self, empty: bool) {
+        for entry in &mut self.entries {
+            if let Some(table) = entry.get_branch() {
+                // Next level tables don't need to empty entries.
+                (*table.as_ptr()).unmap_internal(false);
+                let _ = dealloc(table.cast());
+            }
+            if empty {
+                entry.clear();
+            }
+        }
+    }
+
+}
+
+
+/// Errors that can happen when mapping pages.
+#[derive(Debug, Clone, Copy)]
+pub enum MapError {
+    /// A page table allocation failed.
+    OutOfMemory,
+    /// The given addresses are not aligned to the page size.
+    Unaligned,
+    /// The virtual address is already mapped to another physical address.
+    AlreadyMapped,
+}
+
+
+/// Internal function to get the virtual page numbers of each level.
+#[inline]
+fn vpn(vaddr: usize) -> [usize; 3] {
+    [
+        (vaddr >> 12) & 0x1FF,
+        (vaddr >> 21) & 0x1FF,
+        (vaddr >> 30) & 0x1FF,
+    ]
+}
+
+
+/// Build the kernel address space, this must be called after the
+/// initialization of the page allocator and of the interrupt
+/// controllers.
+///
+/// *This function is unsafe because it must be called once, 
+/// before other harts are started.*
+pub unsafe fn init_kernel() -> Result<NonNull<Table>, MapError> {
+
+    let table_ptr = Table::new().map_err(|_| MapError::OutOfMemory)?;
+    let table = &mut *table_ptr.as_ptr();
+
+    let global = EntryFlags::GLOBAL;
+
+    // Kernel image, the text and read-only data are page-aligned by
+    // the linker script, the data, bss and stack are writable.
+    let text = Region::new(LD_TEXT_START.addr(), LD_RODATA_START.addr());
+    let rodata = Region::new(LD_RODATA_START.addr(), LD_RODATA_END.addr());
+    let data = Region::new(LD_DATA_START.addr(), LD_KSTACK_END.addr());
+    map_identity(table, text, EntryFlags::READ_EXECUTE | global)?;
+    map_identity(table, rodata, EntryFlags::READ | global)?;
+    map_identity(table, data, EntryFlags::READ_WRITE | global)?;
+
+    // All the memory managed by the page allocator, metadata included.
+    for zone in (0..page::zones_count()).filter_map(page::zone_info) {
+        map_identity(table, Region::new(zone.metadata_pages_start, zone.usable_pages_end), EntryFlags::READ_WRITE | global)?;
+    }
+
+    // The device tree and its MMIO regions, devices are children of the
+    // '/soc' node. Without a device tree, map the default devices.
+    if let Some(tree) = devicetree::get() {
+        map_identity(table, Region::with_size(tree.addr(), tree.size()), EntryFlags::READ | global)?;
+        if let Some(soc) = tree.find_path("/soc") {
+            for node in soc.descendants() {
+                for (addr, size) in node.reg() {
+                    map_identity(table, Region::with_size(addr as usize, size as usize), EntryFlags::READ_WRITE | global)?;
+                }
+            }
+        }
+        if let Some(initrd) = super::initrd() {
+            map_identity(table, initrd, EntryFlags::READ | global)?;
+        }
+    } else {
+        map_identity(table, Region::with_size(clint::base(), 0x1_0000), EntryFlags::READ_WRITE | global)?;
+        map_identity(table, Region::with_size(plic::base(), 0x400_0000), EntryFlags::READ_WRITE | global)?;
+        map_identity(table, Region::with_size(0x1000_0000, 0x9000), EntryFlags::READ_WRITE | global)?;
+    }
+
+    KERNEL_TABLE = Some(table_ptr);
+    Ok(table_ptr)
+
+}
+
+
+/// Get the root table of the kernel address space, if built.
+#[inline]
+pub fn kernel_table() -> Option<NonNull<Table>> {
+    unsafe { KERNEL_TABLE }
+}
+
+
+/// Flush the address translation caches of all the harts, this must be
+/// called after modifying a table that might be active on other harts.
+pub fn flush_all_harts() {
+    ipi::broadcast_all(&satp::sfence_vma);
+}
+
+
+/// Set the given table as the current address space of the hart for
+/// supervisor and user modes, with the given address space identifier.
+///
+/// *This function is unsafe because the table must stay valid while
+/// it's active and must map the code being executed, if executed in
+/// supervisor or user mode.*
+pub unsafe fn activate(table: NonNull<Table>, asid: u16) {
+    satp::set(Mode::Sv39, asid, table.addr().get() / PAGE_SIZE);
+    satp::sfence_vma();
+}
+
+
+/// Set the kernel address space as the current address space of the
+/// hart, if built, with the address space identifier zero.
+///
+/// *This function is unsafe for the same reasons as [`activate`].*
+pub unsafe fn activate_kernel() {
+    if let Some(table) = kernel_table() {
+        activate(table, 0);
+    }
+}
+
+
+/// Internal function to identity map the given region, the region is
+/// extended to page boundaries.
+unsafe fn map_identity(table: &mut Table, region: Region, flags: EntryFlags) -> Result<(), MapError> {
+    let start = region.start & !(PAGE_SIZE - 1);
+    let end = (region.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    if end > start {
+        table.map_range(start, start, end - start, flags)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Physical memory regions, used to describe usable and reserved
+//! memory before the page allocator is initialized.
+
+
+/// Maximum number of regions in a [`RegionList`].
+pub const MAX_REGIONS: usize = 16;
+
+
+/// A range of physical memory, the end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Region {
+
+    #[inline]
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    #[inline]
+    pub const fn with_size(start: usize, size: usize) -> Self {
+        Self { start, end: start.saturating_add(size) }
+    }
+
+    #[inline]
+    pub const fn size(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    /// Return the largest region contained in this one with its start and
+    /// end aligned to the given power of two.
+    pub const fn align_inward(&self, align: usize) -> Self {
+        let start = (self.start + align - 1) & !(align - 1);
+        let end = self.end & !(align - 1);
+        Self { start, end }
+    }
+
+}
+
+
+/// A fixed-size list of non-overlapping regions, sorted by address.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionList {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+}
+
+impl RegionList {
+
+    pub const fn new() -> Self {
+        Self {
+            regions: [Region::new(0, 0); MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = Region> + '_ {
+        self.as_slice().iter().copied()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a region to the list, the part of the region that overlaps
+    /// with existing regions is ignored. Return false if the list is
+    /// full, in such case the region is not added.
+    pub fn add(&mut self, region: Region) -> bool {
+        // Removing the region first ensures that regions don't overlap.
+        self.remove(region);
+        if region.is_empty() {
+            true
+        } else if self.len == MAX_REGIONS {
+            false
+        } else {
+            let index = self.as_slice().iter().position(|r| r.start > region.start).unwrap_or(self.len);
+            self.regions.copy_within(index..self.len, index + 1);
+            self.regions[index] = region;
+            self.len += 1;
+            true
+        }
+    }
+
+    /// Remove the given region from all the regions of the list, this
+    /// might split an existing region in two. Return false if the list
+    /// is full and a split region has been lost.
+    pub fn remove(&mut self, region: Region) -> bool {
+
+        if region.is_empty() {
+            return true;
+        }
+
+        let mut result = Self::new();
+        let mut complete = true;
+
+        for current in self.iter() {
+            if current.end <= region.start || current.start >= region.end {
+                complete &= result.push(current);
+            } else {
+                // Parts before and after the removed region, ignored if empty.
+                complete &= result.push(Region::new(current.start, region.start));
+                complete &= result.push(Region::new(region.end, current.end));
+            }
+        }
+
+        *self = result;
+        complete
+
+    }
+
+    /// Internal function to push a region at the end, ignored if empty.
+    fn push(&mut self, region: Region) -> bool {
+        if region.is_empty() {
+            true
+        } else if self.len == MAX_REGIONS {
+            false
+        } else {
+            self.regions[self.len] = region;
+            self.len += 1;
+            true
+        }
+    }
+
+}
@@ -1,20 +1,98 @@
 //! Definition of built-in processes.
 
-use crate::process::{spawn, wait};
+use core::fmt::{self, Write};
+
+use crate::asm::{USER_HELLO_START, USER_HELLO_END};
+use crate::process::{spawn_user_image, wait};
+use crate::{api, println};
+
+
+/// Built-in machine processes that can be spawned by their name.
+static BUILTINS: [(&str, extern "C" fn()); 2] = [
+    ("init", init),
+    ("shell", shell),
+];
+
+
+/// Find a built-in process from its name.
+pub fn find(name: &str) -> Option<extern "C" fn()> {
+    BUILTINS.iter().find(|(builtin_name, _)| *builtin_name == name).map(|(_, entry)| *entry)
+}
 
 
 /// The 'init' builtin process.
 pub extern "C" fn init() {
-    spawn(shell, "[shell]");
+    if let Err(e) = api::spawn("shell") {
+        println!("== Failed to spawn shell: {:?}", e);
+    }
+    // SAFETY: Both symbols are defined in the same section.
+    let hello = unsafe {
+        let start = &USER_HELLO_START as *const u8;
+        core::slice::from_raw_parts(start, (&USER_HELLO_END as *const u8).offset_from(start) as usize)
+    };
+    if let Err(e) = spawn_user_image(hello, "[user-hello]") {
+        println!("== Failed to spawn user process: {:?}", e);
+    }
     loop {
         wait();
     }
 }
 
 
-/// The 'sh' builtin process.
+/// The 'sh' builtin process, it reads lines from the TTY and spawns
+/// the built-in processes or the ELF executables (absolute paths)
+/// named by them, until the end of input.
 pub extern "C" fn shell() {
 
-    
+    let tty = match api::open("/sys/tty0", "rw") {
+        Ok(tty) => tty,
+        Err(e) => {
+            println!("== Shell failed to open the TTY: {:?}", e);
+            return;
+        }
+    };
+
+    let mut out = HandleWriter(tty);
+    let mut line = [0; 256];
+
+    loop {
+
+        let _ = write!(out, "> ");
+        let len = match api::read(tty, &mut line) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => {
+                let _ = writeln!(out, "read error: {:?}", e);
+                break;
+            }
+        };
+
+        let Ok(line) = core::str::from_utf8(&line[..len]) else {
+            let _ = writeln!(out, "invalid utf-8");
+            continue;
+        };
+
+        match line.trim() {
+            "" => {}
+            "exit" => break,
+            name => match api::spawn(name) {
+                Ok(pid) => { let _ = writeln!(out, "spawned #{}", pid); }
+                Err(e) => { let _ = writeln!(out, "{}: {:?}", name, e); }
+            }
+        }
+
+    }
+
+    let _ = api::free(tty);
 
 }
+
+
+/// Internal formatting writer to a handle.
+struct HandleWriter(usize);
+
+impl Write for HandleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        api::write(self.0, s.as_bytes()).map(|_| ()).map_err(|_| fmt::Error)
+    }
+}
@@ -0,0 +1,317 @@
+//! ELF64 program loader, used to spawn user processes from RISC-V
+//! executables.
+//!
+//! ELF specification: https://refspecs.linuxfoundation.org/elf/gabi4+/contents.html
+
+use core::mem::{size_of, MaybeUninit};
+use core::num::NonZeroUsize;
+use core::ptr::NonNull;
+
+use crate::driver::block::{BlockDevice, BlockIoError};
+use crate::filesystem::{self, File, FsError, OpenFlags, SeekFrom};
+use crate::memory::page::{PAGE_SIZE, alloc_zeroed, dealloc};
+use crate::memory::paging::{Table, EntryFlags};
+
+use super::{Pid, SpawnError, USER_STACK_END, USER_STACK_PAGES, spawn_user, free_address_space};
+
+
+/// Magic bytes at the start of ELF files.
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+/// 64-bit class.
+const ELF_CLASS_64: u8 = 2;
+/// Little endian data encoding.
+const ELF_DATA_LSB: u8 = 1;
+/// Executable file type.
+const ELF_TYPE_EXEC: u16 = 2;
+/// RISC-V machine.
+const ELF_MACHINE_RISCV: u16 = 243;
+
+/// Loadable segment type.
+const PT_LOAD: u32 = 1;
+
+/// Segment flags.
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Maximum number of loadable segments.
+const MAX_SEGMENTS: usize = 16;
+
+
+/// The file header, at the start of the file.
+///
+/// Size of: 64
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FileHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+/// A program header, describing a segment.
+///
+/// Size of: 56
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+
+/// A source of bytes for the ELF loader.
+pub trait ElfSource {
+
+    /// Read exactly the length of the buffer at the given offset.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), ElfError>;
+
+}
+
+impl ElfSource for [u8] {
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), ElfError> {
+        let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+        let end = start.checked_add(buf.len()).ok_or(ElfError::Truncated)?;
+        buf.copy_from_slice(self.get(start..end).ok_or(ElfError::Truncated)?);
+        Ok(())
+    }
+
+}
+
+
+/// A file stored as a contiguous range of bytes on a block device.
+pub struct BlockFile<'a> {
+    device: &'a BlockDevice,
+    offset: u64,
+    size: u64,
+}
+
+impl<'a> BlockFile<'a> {
+
+    pub fn new(device: &'a BlockDevice, offset: u64, size: u64) -> Self {
+        Self { device, offset, size }
+    }
+
+}
+
+impl ElfSource for BlockFile<'_> {
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), ElfError> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(ElfError::Truncated)?;
+        if end > self.size {
+            return Err(ElfError::Truncated);
+        }
+        let offset = self.offset.checked_add(offset).ok_or(ElfError::Truncated)?;
+        self.device.read_bytes(buf, offset).map_err(ElfError::Io)
+    }
+
+}
+
+
+/// A file opened through the virtual filesystem, like an executable of
+/// a filesystem on a block device, or a whole block device through the
+/// sysfs. Reads move the offset of the file, so it must not be
+/// shared while loading.
+impl ElfSource for dyn File {
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), ElfError> {
+        self.seek(SeekFrom::Start(offset)).map_err(ElfError::Fs)?;
+        let mut len = 0;
+        while len < buf.len() {
+            match self.read(&mut buf[len..]).map_err(ElfError::Fs)? {
+                0 => return Err(ElfError::Truncated),
+                read_len => len += read_len,
+            }
+        }
+        Ok(())
+    }
+
+}
+
+
+/// Errors that can happen when loading an ELF file.
+#[derive(Debug)]
+pub enum ElfError {
+    /// The source is too short for the headers or segments.
+    Truncated,
+    /// The block device backing the file failed.
+    Io(BlockIoError),
+    /// The file can't be opened or read through the filesystem.
+    Fs(FsError),
+    /// The file doesn't start with the ELF magic.
+    BadMagic,
+    /// The file is not a 64-bit little endian ELF.
+    UnsupportedClass,
+    /// The file is not an executable.
+    UnsupportedType,
+    /// The file is not for the RISC-V machine.
+    WrongMachine,
+    /// The program headers are malformed.
+    InvalidHeader,
+    /// A segment is malformed or outside of the user address space.
+    InvalidSegment,
+    /// Two loadable segments overlap in the same pages.
+    OverlappingSegments,
+    /// The process can't be spawned.
+    Spawn(SpawnError),
+}
+
+
+/// Load the given ELF executable in a new address space and spawn it
+/// as a user process, with the given arguments and environment.
+pub fn spawn_elf<S: ElfSource + ?Sized>(source: &S, argv: &[&str], envp: &[&str], name: &str) -> Result<Pid, ElfError> {
+
+    let header: FileHeader = read_struct(source, 0)?;
+
+    if header.ident[..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    } else if header.ident[4] != ELF_CLASS_64 || header.ident[5] != ELF_DATA_LSB {
+        return Err(ElfError::UnsupportedClass);
+    } else if header.kind != ELF_TYPE_EXEC {
+        return Err(ElfError::UnsupportedType);
+    } else if header.machine != ELF_MACHINE_RISCV {
+        return Err(ElfError::WrongMachine);
+    } else if header.phentsize as usize != size_of::<ProgramHeader>() {
+        return Err(ElfError::InvalidHeader);
+    }
+
+    // Loadable segments are first collected to check them before
+    // allocating anything.
+    let mut segments = [MaybeUninit::<ProgramHeader>::uninit(); MAX_SEGMENTS];
+    let mut segments_count = 0;
+
+    for i in 0..header.phnum as u64 {
+
+        let ph_offset = i.checked_mul(size_of::<ProgramHeader>() as u64)
+            .and_then(|offset| offset.checked_add(header.phoff))
+            .ok_or(ElfError::Truncated)?;
+        let ph: ProgramHeader = read_struct(source, ph_offset)?;
+        if ph.kind != PT_LOAD || ph.memsz == 0 {
+            continue;
+        }
+
+        let (start, end) = segment_pages(&ph)?;
+        if ph.filesz > ph.memsz || end > (USER_STACK_END - USER_STACK_PAGES * PAGE_SIZE) as u64 {
+            return Err(ElfError::InvalidSegment);
+        } else if ph.offset.checked_add(ph.filesz).is_none() {
+            return Err(ElfError::Truncated);
+        } else if segments_count == MAX_SEGMENTS {
+            return Err(ElfError::InvalidHeader);
+        }
+
+        for other in &segments[..segments_count] {
+            let (other_start, other_end) = segment_pages(unsafe { other.assume_init_ref() })?;
+            if start < other_end && other_start < end {
+                return Err(ElfError::OverlappingSegments);
+            }
+        }
+
+        segments[segments_count] = MaybeUninit::new(ph);
+        segments_count += 1;
+
+    }
+
+    let root = Table::new().map_err(|_| ElfError::Spawn(SpawnError::OutOfMemory))?;
+
+    for ph in &segments[..segments_count] {
+        // SAFETY: The root table is owned by this function until spawned.
+        if let Err(e) = unsafe { load_segment(source, root, ph.assume_init_ref()) } {
+            unsafe { free_address_space(root); }
+            return Err(e);
+        }
+    }
+
+    spawn_user(root, header.entry as usize, argv, envp, name).map_err(ElfError::Spawn)
+
+}
+
+
+/// Open the ELF executable at the given path of the virtual filesystem
+/// and spawn it like [`spawn_elf`], the process is named after the path.
+pub fn spawn_elf_path(path: &str, argv: &[&str], envp: &[&str]) -> Result<Pid, ElfError> {
+    let file = filesystem::open(path, OpenFlags::READ).map_err(ElfError::Fs)?;
+    spawn_elf(&*file, argv, envp, path)
+}
+
+
+/// Internal function to load a segment in the given address space, a
+/// zeroed page is allocated for each page of the segment.
+unsafe fn load_segment<S: ElfSource + ?Sized>(source: &S, root: NonNull<Table>, ph: &ProgramHeader) -> Result<(), ElfError> {
+
+    let mut flags = EntryFlags::USER;
+    if ph.flags & PF_R != 0 { flags |= EntryFlags::READ; }
+    if ph.flags & PF_W != 0 { flags |= EntryFlags::WRITE; }
+    if ph.flags & PF_X != 0 { flags |= EntryFlags::EXECUTE; }
+
+    if !flags.intersects(EntryFlags::READ_WRITE_EXECUTE) {
+        return Err(ElfError::InvalidSegment);
+    }
+
+    let (start, end) = segment_pages(ph)?;
+    let file_start = ph.vaddr;
+    let file_end = ph.vaddr.checked_add(ph.filesz).ok_or(ElfError::InvalidSegment)?;
+
+    for page_vaddr in (start..end).step_by(PAGE_SIZE) {
+
+        let page = alloc_zeroed(NonZeroUsize::new_unchecked(1))
+            .map_err(|_| ElfError::Spawn(SpawnError::OutOfMemory))?;
+        if let Err(e) = (*root.as_ptr()).map(page_vaddr as usize, page.addr().get(), flags, 0) {
+            let _ = dealloc(page);
+            return Err(ElfError::Spawn(SpawnError::Map(e)));
+        }
+
+        // Part of the page that is backed by the file, the rest is zero.
+        let copy_start = file_start.max(page_vaddr);
+        let copy_end = file_end.min(page_vaddr + PAGE_SIZE as u64);
+        if copy_start < copy_end {
+            let dst = core::slice::from_raw_parts_mut(
+                page.as_ptr().add((copy_start - page_vaddr) as usize),
+                (copy_end - copy_start) as usize);
+            let offset = ph.offset.checked_add(copy_start - file_start).ok_or(ElfError::Truncated)?;
+            source.read_at(offset, dst)?;
+        }
+
+    }
+
+    Ok(())
+
+}
+
+
+/// Internal function to get the page-aligned range of virtual addresses
+/// of a segment.
+fn segment_pages(ph: &ProgramHeader) -> Result<(u64, u64), ElfError> {
+    let page_mask = PAGE_SIZE as u64 - 1;
+    let end = ph.vaddr.checked_add(ph.memsz)
+        .and_then(|end| end.checked_add(page_mask))
+        .ok_or(ElfError::InvalidSegment)?;
+    Ok((ph.vaddr & !page_mask, end & !page_mask))
+}
+
+
+/// Internal function to read a plain structure from the source.
+fn read_struct<T: Copy, S: ElfSource + ?Sized>(source: &S, offset: u64) -> Result<T, ElfError> {
+    let mut value = MaybeUninit::<T>::zeroed();
+    // SAFETY: Structures read are only made of integers, valid for any bytes.
+    let buf = unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    source.read_at(offset, buf)?;
+    Ok(unsafe { value.assume_init() })
+}
@@ -0,0 +1,73 @@
+//! Per-process table of opened handles.
+
+use bitflags::bitflags;
+
+use crate::filesystem::file::{self, FileId};
+
+
+/// Maximum number of handles a process can open at the same time.
+pub const MAX_HANDLES: usize = 16;
+
+
+bitflags! {
+    /// Access rights given when opening a handle.
+    pub struct HandleFlags: u8 {
+        const READ  = 0b01;
+        const WRITE = 0b10;
+    }
+}
+
+
+/// An opened handle of a process.
+#[derive(Debug, Clone, Copy)]
+pub struct Handle {
+    /// The opened file, in the open-file table.
+    pub file: FileId,
+    /// Access rights of the handle.
+    pub flags: HandleFlags,
+}
+
+
+/// Fixed-size table of handles, the index of the handle in the
+/// table is the number given to the process.
+#[derive(Debug)]
+pub struct HandleTable {
+    handles: [Option<Handle>; MAX_HANDLES],
+}
+
+impl HandleTable {
+
+    pub const fn new() -> Self {
+        Self { handles: [None; MAX_HANDLES] }
+    }
+
+    /// Insert a new handle in the first free slot, return its number
+    /// or none if the table is full.
+    pub fn insert(&mut self, handle: Handle) -> Option<usize> {
+        let index = self.handles.iter().position(Option::is_none)?;
+        self.handles[index] = Some(handle);
+        Some(index)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Handle> {
+        self.handles.get_mut(index)?.as_mut()
+    }
+
+    /// Remove the handle with the given number and close its file,
+    /// return it if existing.
+    #[inline]
+    pub fn remove(&mut self, index: usize) -> Option<Handle> {
+        let handle = self.handles.get_mut(index)?.take()?;
+        file::close(handle.file);
+        Some(handle)
+    }
+
+    /// Remove all the handles and close their files.
+    pub fn clear(&mut self) {
+        for handle in self.handles.iter_mut().filter_map(Option::take) {
+            file::close(handle.file);
+        }
+    }
+
+}
@@ -1,78 +1,159 @@
 //! Process-related structures and functions.
 
 pub mod builtin;
+pub mod handle;
+pub mod elf;
+
+use handle::HandleTable;
 
-use core::marker::PhantomData;
 use core::num::NonZeroUsize;
 use core::ptr::NonNull;
 use core::mem::size_of;
-
-use crate::memory::page::{PAGE_SIZE, alloc, dealloc};
+use core::arch::asm;
+
+use crate::memory::page::{PAGE_SIZE, AllocError, alloc, alloc_zeroed, dealloc};
+use crate::memory::paging::{self, Table, EntryFlags, MapError};
+use crate::cpu::mstatus::{self, MstatusFlags};
+use crate::cpu::mie::{self, MieFlags};
+use crate::cpu::{mscratch, mhardid};
+use crate::interrupt::{clint, ipi};
+use crate::trap::{self, TrapFrame};
+use crate::smp::HART_MAX_COUNT;
+use crate::sync::IrqSpinLock;
+use crate::asm::LD_GLOBAL_POINTER;
+use crate::api;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use alloc::string::String;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crate::println;
 
 
-/// Maximum number of processes a memory page can hold.
-/// This is used to reallocate a new page that will be linked
-/// at the end of the previous page.
-const PROCESS_COUNT_PER_PAGE: usize = PAGE_SIZE / size_of::<ProcessEntry>() - 1;
+/// All the processes that are not yet freed, sorted by PID. Processes
+/// are boxed so they never move when the table changes, their entry is
+/// removed once dead and freed. The lock must be held to change the
+/// state of any process.
+static PROCESSES: IrqSpinLock<Vec<Box<Process>>> = IrqSpinLock::new(Vec::new());
+
+/// The PID of the next spawned process, PIDs are never reused.
+static NEXT_PID: AtomicUsize = AtomicUsize::new(0);
+
+/// Address space identifiers of user processes, allocated when spawned
+/// and freed with the process. This lock is taken after the process 
+/// table lock.
+static ASIDS: IrqSpinLock<AsidAllocator> = IrqSpinLock::new(AsidAllocator::new());
+
+/// The scheduling state of each hart, indexed by hart id, a hart only
+/// accesses its own state with interrupts disabled.
+static mut HARTS: [HartState; HART_MAX_COUNT] = [HartState { running: None }; HART_MAX_COUNT];
+
+/// Bit mask of the harts that are idle, waiting for a process to be 
+/// woken up.
+static IDLE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Indices of some registers in the saved context.
+const REG_RA: usize = 1;
+const REG_SP: usize = 2;
+const REG_GP: usize = 3;
+const REG_A0: usize = 10;
+const REG_A1: usize = 11;
+const REG_A2: usize = 12;
 
-/// Maximum length of the process' name.
-const PROCESS_NAME_MAX_LEN: usize = 128;
 
-/// A pointer to the first process page.
-static mut FIRST_PROCESS_PAGE: NonNull<ProcessEntry> = NonNull::dangling();
+/// Number of pages of the stack of user processes.
+const USER_STACK_PAGES: usize = 4;
 
-/// A pointer to the last process page.
-static mut LAST_PROCESS_PAGE: NonNull<ProcessEntry> = NonNull::dangling();
+/// Number of pages of the kernel stack of processes, a guard page is
+/// also allocated below it. Machine processes run on it, and user
+/// processes use it to execute their system calls.
+const KERNEL_STACK_PAGES: usize = 4;
 
-/// Current number of process pages.
-static mut PROCESS_PAGE_COUNT: usize = 0;
+/// Value filling the guard page below the kernel stack of processes.
+/// Machine mode has no address translation, so the guard page can't be
+/// unmapped, it is instead checked by the scheduler.
+const STACK_GUARD: usize = 0x5A5A_5A5A_5A5A_5A5A;
 
-/// Current number of allocated processes (dead or not).
-static mut PROCESS_COUNT: usize = 0;
+/// Virtual address of the end of the stack of user processes, this
+/// is the end of the lower half of the Sv39 address space.
+pub const USER_STACK_END: usize = 0x40_0000_0000;
 
-static mut RUNNING_PROCESS: Option<NonNull<Process>> = None;
+/// Virtual address where flat user images are loaded, the first 
+/// pages are left unmapped to catch null pointers.
+pub const USER_IMAGE_START: usize = 0x1_0000;
+
+
+/// Default time slice given to processes, in `mtime` ticks. QEMU's
+/// virt machine timer runs at 10 MHz, so this is 10 ms.
+pub const DEFAULT_TIME_SLICE: u64 = 100_000;
 
 
 /// Type alias for a Process ID, returned upon process spawn.
 pub type Pid = usize;
 
 
-/// Size of: 289
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 struct Process {
-    /// Process ID of the process. [offset 0]
+    /// Process ID of the process.
     pid: Pid,
-    /// Process ID of the parent process. [offset 8]
+    /// Process ID of the parent process.
     parent_pid: Pid,
-    /// Start of the stack. [offset 16]
+    /// Start of the kernel stack, the guard page is just below it.
     stack_start: usize,
-    /// End of the stack (biggest address, where sp starts). [offset 24]
+    /// End of the kernel stack (biggest address, where sp starts).
     stack_end: usize,
-    /// Saved context. [offset 32]
+    /// Root page table of user processes, the process owns all the
+    /// user pages mapped in it. None for machine processes, which 
+    /// share the physical memory with the kernel.
+    root: Option<NonNull<Table>>,
+    /// Address space identifier of user processes.
+    asid: u16,
+    /// Handles opened by the process.
+    handles: HandleTable,
+    /// Saved context.
     context: Context,
-    /// Length of the name of the process. [offset 152]
-    name_len: usize,
-    /// Name of the process. Guaranteed to be UTF-8 until length is reached. [offset 160]
-    name: [u8; PROCESS_NAME_MAX_LEN],
-    /// State of the process, if dead, the entry should be ignored. [offset 288]
+    /// Context of the caller while the process executes a system call
+    /// in machine mode, restored when the system call returns.
+    syscall: Option<Context>,
+    /// Set when the process is killed while executing a system call, or
+    /// for machine processes, it is then dead once the system call 
+    /// returns or at the next system call.
+    killed: AtomicBool,
+    /// Number of `mtime` ticks this process can run before being preempted.
+    time_slice: u64,
+    /// The channel this process is sleeping on, only relevant if sleeping.
+    wait_chan: usize,
+    /// True while the process is running on a hart, until its context
+    /// is saved by the scheduler. It must not be resumed by another
+    /// hart meanwhile, even if woken up.
+    on_hart: bool,
+    /// Name of the process.
+    name: String,
+    /// State of the process, if dead, the entry should be ignored.
     state: ProcessState,
 }
 
 
-/// Size of: 120
+/// The scheduling state of a hart.
+#[derive(Debug, Clone, Copy)]
+struct HartState {
+    /// The process currently running on the hart, none if idle.
+    running: Option<NonNull<Process>>,
+}
+
+
+/// The full context of a process, saved from and restored to
+/// the trap frame of the hart when switching processes.
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 struct Context {
+    /// General purpose registers x0-x31.
+    regs: [usize; 32],
     /// Program counter.
     pc: usize,
-    /// Stack pointer.
-    sp: usize,
-    /// Unused
-    _unused: usize,
-    /// Saved registers (s0-s11).
-    sx: [usize; 12],
+    /// Machine status to restore, this defines the privilege mode.
+    mstatus: usize,
 }
 
 impl Process {
@@ -80,7 +161,27 @@ impl Process {
     /// Get the name of the process.
     #[inline]
     fn name(&self) -> &str {
-        unsafe { core::str::from_utf8_unchecked(&self.name[..self.name_len]) }
+        &self.name
+    }
+
+}
+
+impl Context {
+
+    /// Save the state of the given trap frame into this context.
+    #[inline]
+    fn save(&mut self, frame: &TrapFrame) {
+        self.regs = frame.regs;
+        self.pc = frame.trap.mepc;
+        self.mstatus = frame.mstatus;
+    }
+
+    /// Restore this context into the given trap frame.
+    #[inline]
+    fn restore(&self, frame: &mut TrapFrame) {
+        frame.regs = self.regs;
+        frame.trap.mepc = self.pc;
+        frame.mstatus = self.mstatus;
     }
 
 }
@@ -99,94 +200,318 @@ enum ProcessState {
     Running     = 0x3,
     /// A process that returned from its entry point.
     Dead        = 0x4,
-}
-
-#[repr(C)]
-#[derive(Clone, Copy)]
-union ProcessEntry {
-    /// Interpret the entry as a process.
-    process: Process,
-    /// Interpret the entry as the next process page.
-    next_page: NonNull<ProcessEntry>,
+    /// The process is sleeping on a wait channel until woken up.
+    Sleeping    = 0x5,
 }
 
 
-
 /// Initialize the process manager.
 /// 
 /// *This function is unsafe because you must ensure that
 /// it is called once and after the initialization of the
 /// page memory allocator.*
 pub unsafe fn init() {
-    FIRST_PROCESS_PAGE = alloc(NonZeroUsize::new_unchecked(1)).unwrap().cast();
-    LAST_PROCESS_PAGE = FIRST_PROCESS_PAGE;
-    PROCESS_PAGE_COUNT = 1;
+    PROCESSES.lock().reserve(16);
 }
 
 
-/// Spawn a new process.
+/// Spawn a new machine process, running a kernel function with
+/// full access to the kernel memory.
 pub fn spawn(entry_point: extern "C" fn(), name: &str) -> Pid {
+    unsafe {
+
+        let (stack_start, stack_end) = alloc_stack().unwrap();
+        let mut processes = PROCESSES.lock();
+        let process = new_process(&mut processes, name);
+
+        process.stack_start = stack_start;
+        process.stack_end = stack_end;
 
-    debug_assert!(name.len() <= PROCESS_NAME_MAX_LEN);
+        // The process starts at its entry point and returns to 'exit'.
+        // The global pointer is shared with the kernel because the
+        // entry point is a kernel function.
+        let global_pointer: usize;
+        asm!("mv {0}, gp", out(reg) global_pointer);
 
+        process.context.regs[REG_RA] = (exit as *const u8).addr();
+        process.context.regs[REG_SP] = process.stack_end;
+        process.context.regs[REG_GP] = global_pointer;
+        process.context.pc = (entry_point as *mut u8).addr();
+        process.context.mstatus = (MstatusFlags::MPP_MACHINE | MstatusFlags::MPIE).bits();
+
+        process.state = ProcessState::Spawned;
+        process.pid
+
+    }
+}
+
+
+/// Spawn a new user process in the address space of the given root 
+/// table, starting at the given virtual entry point. The process 
+/// owns the table and all the user pages mapped in it, even if the 
+/// spawn fails, these are freed once the process is dead. A stack 
+/// is mapped below [`USER_STACK_END`].
+/// 
+/// The arguments and environment strings are copied on the stack
+/// following the System V ABI: `sp` points to `argc`, followed by the
+/// null-terminated `argv` and `envp` arrays and an empty auxiliary 
+/// vector. For convenience, `a0`, `a1` and `a2` are also set to 
+/// `argc`, `argv` and `envp`.
+pub fn spawn_user(root: NonNull<Table>, entry_point: usize, argv: &[&str], envp: &[&str], name: &str) -> Result<Pid, SpawnError> {
     unsafe {
 
-        if PROCESS_COUNT > 0 && PROCESS_COUNT % PROCESS_COUNT_PER_PAGE == 0 {
+        let table = &mut *root.as_ptr();
 
-            let new_process_page = alloc(NonZeroUsize::new_unchecked(1)).unwrap().cast();
+        for i in 1..=USER_STACK_PAGES {
+            let page = match alloc_zeroed(NonZeroUsize::new_unchecked(1)) {
+                Ok(page) => page,
+                Err(_) => {
+                    free_address_space(root);
+                    return Err(SpawnError::OutOfMemory);
+                }
+            };
+            if let Err(e) = table.map(USER_STACK_END - i * PAGE_SIZE, page.addr().get(), EntryFlags::USER | EntryFlags::READ_WRITE, 0) {
+                let _ = dealloc(page);
+                free_address_space(root);
+                return Err(SpawnError::Map(e));
+            }
+        }
 
-            let next_page_ptr = LAST_PROCESS_PAGE.as_ptr().add(PROCESS_COUNT_PER_PAGE);
-            let next_page = &mut (*next_page_ptr).next_page;
+        let (sp, argv_ptr, envp_ptr) = match setup_user_stack(table, argv, envp) {
+            Ok(ret) => ret,
+            Err(e) => {
+                free_address_space(root);
+                return Err(e);
+            }
+        };
+
+        let Ok((stack_start, stack_end)) = alloc_stack() else {
+            free_address_space(root);
+            return Err(SpawnError::OutOfMemory);
+        };
+
+        let mut processes = PROCESSES.lock();
+        let process = new_process(&mut processes, name);
+        process.root = Some(root);
+        process.asid = ASIDS.lock().alloc();
+        process.stack_start = stack_start;
+        process.stack_end = stack_end;
+
+        // Returning from the entry point will fault, user processes 
+        // must exit by themselves.
+        process.context.regs[REG_SP] = sp;
+        process.context.regs[REG_A0] = argv.len();
+        process.context.regs[REG_A1] = argv_ptr;
+        process.context.regs[REG_A2] = envp_ptr;
+        process.context.pc = entry_point;
+        // Previous privilege is user (zero).
+        process.context.mstatus = MstatusFlags::MPIE.bits();
 
-            *next_page = new_process_page;
+        process.state = ProcessState::Spawned;
+        Ok(process.pid)
+
+    }
+}
+
+
+/// Spawn a new user process from a flat image, the image is copied 
+/// at [`USER_IMAGE_START`] in a new address space, where it is 
+/// readable, writable and executable, and starts at its first byte.
+pub fn spawn_user_image(image: &[u8], name: &str) -> Result<Pid, SpawnError> {
+
+    let root = Table::new().map_err(|_| SpawnError::OutOfMemory)?;
+
+    // SAFETY: The table is owned by this function until spawned.
+    unsafe {
 
-            LAST_PROCESS_PAGE = new_process_page;
-            PROCESS_PAGE_COUNT += 1;
+        let table = &mut *root.as_ptr();
 
+        for (i, chunk) in image.chunks(PAGE_SIZE).enumerate() {
+            let page = match alloc_zeroed(NonZeroUsize::new_unchecked(1)) {
+                Ok(page) => page,
+                Err(_) => {
+                    free_address_space(root);
+                    return Err(SpawnError::OutOfMemory);
+                }
+            };
+            page.as_ptr().copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+            if let Err(e) = table.map(USER_IMAGE_START + i * PAGE_SIZE, page.addr().get(), EntryFlags::USER | EntryFlags::READ_WRITE_EXECUTE, 0) {
+                let _ = dealloc(page);
+                free_address_space(root);
+                return Err(SpawnError::Map(e));
+            }
         }
 
-        let stack_ptr = alloc(NonZeroUsize::new_unchecked(1)).unwrap();
+    }
 
-        // In the future, we might reuse old processes, but not for now.
-        let pid = PROCESS_COUNT;
-        
-        // Index of the process in the last page.
-        let process_index = pid - (PROCESS_PAGE_COUNT - 1) * PROCESS_COUNT_PER_PAGE;
-        
-        let process_ptr = LAST_PROCESS_PAGE.as_ptr().add(process_index);
-        let process = &mut (*process_ptr).process;
+    spawn_user(root, USER_IMAGE_START, &[name], &[], name)
 
-        process.state = ProcessState::Spawned;
-        process.pid = pid;
-        process.parent_pid = 0;
-        process.stack_start = stack_ptr.as_ptr().addr();
-        process.stack_end = stack_ptr.as_ptr().add(PAGE_SIZE).addr();
+}
 
-        process.context.pc = (entry_point as *mut u8).addr();
-        process.context.sp = process.stack_end;
-        process.context.sx.fill(0);
 
-        process.name_len = name.len();
-        process.name[..name.len()].clone_from_slice(name.as_bytes());
+/// Errors that can happen when spawning a user process.
+#[derive(Debug, Clone, Copy)]
+pub enum SpawnError {
+    /// A page allocation failed.
+    OutOfMemory,
+    /// A page can't be mapped in the address space.
+    Map(MapError),
+    /// The arguments and environment don't fit in the stack.
+    ArgumentsTooLong,
+}
+
 
-        PROCESS_COUNT += 1;
-        pid
+/// Internal function to allocate a new process entry in the table,
+/// the process is left in invalid state with an empty context, the
+/// caller must set its stack and context before making it spawned.
+/// 
+/// *This function is unsafe because it must be called with the process
+/// table locked and after the initialization of the process manager.*
+unsafe fn new_process<'a>(processes: &'a mut Vec<Box<Process>>, name: &str) -> &'a mut Process {
+
+    // PIDs are increasing, so the table stays sorted.
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+
+    processes.push(Box::new(Process {
+        pid,
+        parent_pid: hart().running.map(|p| (*p.as_ptr()).pid).unwrap_or(0),
+        stack_start: 0,
+        stack_end: 0,
+        root: None,
+        asid: 0,
+        handles: HandleTable::new(),
+        context: Context {
+            regs: [0; 32],
+            pc: 0,
+            mstatus: 0,
+        },
+        syscall: None,
+        killed: AtomicBool::new(false),
+        time_slice: DEFAULT_TIME_SLICE,
+        wait_chan: 0,
+        on_hart: false,
+        name: String::from(name),
+        state: ProcessState::Invalid,
+    }));
+
+    processes.last_mut().unwrap()
 
+}
+
+
+/// Internal function to allocate a kernel stack with its guard page,
+/// return the start and end of the stack.
+fn alloc_stack() -> Result<(usize, usize), AllocError> {
+    // SAFETY: The count is not zero and the guard page is allocated.
+    unsafe {
+        let guard_ptr = alloc(NonZeroUsize::new_unchecked(KERNEL_STACK_PAGES + 1))?;
+        core::slice::from_raw_parts_mut(guard_ptr.as_ptr().cast::<usize>(), PAGE_SIZE / size_of::<usize>()).fill(STACK_GUARD);
+        Ok((guard_ptr.as_ptr().add(PAGE_SIZE).addr(), guard_ptr.as_ptr().add((KERNEL_STACK_PAGES + 1) * PAGE_SIZE).addr()))
     }
+}
+
+
+/// Internal function to copy the arguments and environment strings on
+/// the stack of a user address space, return the stack pointer and the
+/// addresses of the `argv` and `envp` arrays.
+unsafe fn setup_user_stack(table: &Table, argv: &[&str], envp: &[&str]) -> Result<(usize, usize, usize), SpawnError> {
+
+    // argc, argv, null, envp, null, auxv (AT_NULL, 0)
+    let pointers_size = (1 + argv.len() + 1 + envp.len() + 1 + 2) * size_of::<usize>();
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+
+    // Half of the stack is kept for the process itself.
+    if pointers_size + strings_size + 16 > USER_STACK_PAGES * PAGE_SIZE / 2 {
+        return Err(SpawnError::ArgumentsTooLong);
+    }
+
+    // Strings are at the top of the stack, the stack pointer must be
+    // aligned to 16 bytes.
+    let mut string_ptr = USER_STACK_END - strings_size;
+    let sp = (string_ptr - pointers_size) & !15;
+    let mut ptr = sp;
+
+    let mut write_usize = |value: usize| {
+        write_user(table, ptr, &value.to_ne_bytes());
+        ptr += size_of::<usize>();
+    };
+
+    write_usize(argv.len());
+    let argv_ptr = sp + size_of::<usize>();
+    let envp_ptr = argv_ptr + (argv.len() + 1) * size_of::<usize>();
+
+    for strings in [argv, envp] {
+        for s in strings {
+            write_user(table, string_ptr, s.as_bytes());
+            write_user(table, string_ptr + s.len(), &[0]);
+            write_usize(string_ptr);
+            string_ptr += s.len() + 1;
+        }
+        write_usize(0);
+    }
+
+    // Auxiliary vector only contains AT_NULL.
+    write_usize(0);
+    write_usize(0);
+
+    Ok((sp, argv_ptr, envp_ptr))
 
 }
 
 
+/// Internal function to write data at a virtual address of a user 
+/// address space, the pages must be mapped.
+unsafe fn write_user(table: &Table, mut vaddr: usize, mut data: &[u8]) {
+    while !data.is_empty() {
+        let len = (PAGE_SIZE - vaddr % PAGE_SIZE).min(data.len());
+        let (paddr, _) = table.translate(vaddr).expect("user page is not mapped");
+        (paddr as *mut u8).copy_from_nonoverlapping(data.as_ptr(), len);
+        vaddr += len;
+        data = &data[len..];
+    }
+}
+
+
+/// Internal function to free a user address space, all the user
+/// pages mapped in it are freed with the tables.
+unsafe fn free_address_space(root: NonNull<Table>) {
+    let table = &mut *root.as_ptr();
+    table.for_each_leaf(|_, paddr, _, flags| {
+        if flags.contains(EntryFlags::USER) {
+            let _ = dealloc(NonNull::new_unchecked(paddr as *mut u8));
+        }
+    });
+    table.unmap();
+    let _ = dealloc(root.cast());
+}
+
+
+/// Set the time slice, in `mtime` ticks, given to the process each 
+/// time it is scheduled. Return false if the process doesn't exist.
+pub fn set_time_slice(pid: Pid, time_slice: u64) -> bool {
+    let mut processes = PROCESSES.lock();
+    let Some(process) = iter(&mut processes).find(|process| process.pid == pid) else {
+        return false;
+    };
+    if let ProcessState::Invalid | ProcessState::Dead = process.state {
+        false
+    } else {
+        process.time_slice = time_slice;
+        true
+    }
+}
+
+
 extern "C" {
-    
-    /// This function is defined in `proc.asm` and will switch from
-    /// one process to another. The context will be saved in the 
-    /// `from` process and restored for `to`.
-    fn asm_process_switch(to: *mut Process, exit_fn: extern "C" fn() -> !, from: *mut Process);
-    
-    /// This function is defined in `proc.asm` and will switch to a
-    /// given process. The context of the target process will be restored.
-    fn asm_process_switch_noreturn(to: *mut Process, exit_fn: extern "C" fn() -> !);
+
+    /// This function is defined in `trap.asm` and will restore the
+    /// state saved in the trap frame of the hart and `mret` to it.
+    fn asm_trap_return() -> !;
+
+    /// This function is defined in `boot.asm` and is used as the 
+    /// program counter of the hart when no process can run, it 
+    /// waits for interrupts indefinitely.
+    fn asm_idle() -> !;
 
 }
 
@@ -194,148 +519,556 @@ extern "C" {
 /// Put the calling process in waiting state. This will switch to 
 /// another waiting process or do nothing if no process is running.
 /// 
-/// This function is guaranteed to return at some point when another 
-/// process will decides to wait. The registers that are marked as
-/// `preserved` in the [`RISC-V programmer's manual`] are saved.
-/// 
-/// [`RISC-V programmer's manual`]: https://github.com/riscv-non-isa/riscv-asm-manual/blob/master/riscv-asm.md
+/// This function is guaranteed to return at some point, because 
+/// processes are also preempted by the timer interrupt. All the
+/// registers are saved by the trap handler.
 pub fn wait() {
-    unsafe {
-        if let Some(process) = RUNNING_PROCESS {
-            let current_process = &mut *process.as_ptr();
-            if let Some(next_process) = get_next_process(current_process.pid) {
-                current_process.state = ProcessState::Waiting;
-                RUNNING_PROCESS = Some(next_process.into());
-                asm_process_switch(next_process, exit, current_process);
+    api::yield_now();
+}
+
+
+/// Block the calling process on the given wait channel while the 
+/// given condition returns true. The process is put in sleeping
+/// state and will not be scheduled until [`wake`] is called with
+/// the same channel, the condition is then checked again.
+/// 
+/// The condition is checked with the process table locked, so a wake
+/// up from an interrupt handler or another hart can't be missed 
+/// between the check and the sleep, the condition must not block or
+/// use the process manager. If no process is running (kernel 
+/// initialization), this waits for interrupts instead.
+/// 
+/// The channel is an arbitrary value, usually the address of the
+/// object being waited for.
+/// 
+/// The trap handler can't switch processes, so this must not be called
+/// from it, system calls are executed by the process itself (see 
+/// [`enter_syscall`]) and can sleep.
+pub fn sleep_while(chan: usize, mut cond: impl FnMut() -> bool) {
+    assert!(!trap::in_trap(), "sleeping in the trap handler");
+    loop {
+        let sleep = mstatus::without_interrupts(|| unsafe {
+            let processes = PROCESSES.lock();
+            if !cond() {
+                return false;
+            }
+            if let Some(process) = hart().running {
+                let current_process = &mut *process.as_ptr();
+                // A killed process is freed when switched.
+                if current_process.state != ProcessState::Dead {
+                    current_process.state = ProcessState::Sleeping;
+                    current_process.wait_chan = chan;
+                }
+                // The lock is released before trapping in the scheduler,
+                // we are resumed with interrupts still disabled.
+                drop(processes);
+                wait();
+            } else {
+                drop(processes);
+                // The interrupt will be taken once enabled again.
+                asm!("wfi");
             }
+            true
+        });
+        if !sleep {
+            break;
         }
     }
 }
 
 
+/// Wake up all processes sleeping on the given wait channel.
+/// This can be called from interrupt handlers. Other harts that are
+/// idle are asked to reschedule, so they can resume the processes.
+pub fn wake(chan: usize) {
+
+    let mut woken = false;
+    for process in iter(&mut PROCESSES.lock()) {
+        if process.state == ProcessState::Sleeping && process.wait_chan == chan {
+            process.state = ProcessState::Waiting;
+            woken = true;
+        }
+    }
+
+    if woken {
+        wake_idle_harts();
+    }
+
+}
+
+
+/// Wake up the first process sleeping on the given wait channel, if
+/// any. This can be called from interrupt handlers.
+pub fn wake_one(chan: usize) {
+
+    let mut processes = PROCESSES.lock();
+    let sleeping = iter(&mut processes)
+        .find(|process| process.state == ProcessState::Sleeping && process.wait_chan == chan);
+
+    if let Some(process) = sleeping {
+        process.state = ProcessState::Waiting;
+        drop(processes);
+        wake_idle_harts();
+    }
+
+}
+
+
+/// Internal function to ask the other idle harts to reschedule, after
+/// some processes have been woken up.
+fn wake_idle_harts() {
+    let idle_harts = IDLE_HARTS.load(Ordering::Acquire) & !(1 << mhardid::get());
+    for hartid in (0..HART_MAX_COUNT).filter(|hartid| idle_harts & (1 << hartid) != 0) {
+        let _ = ipi::reschedule(hartid);
+    }
+}
+
+
 /// Exit from the current process and resume other awaiting processes.
 pub extern "C" fn exit() -> ! {
     unsafe {
+        let _processes = PROCESSES.lock();
+        if let Some(process) = hart().running {
+            // The stack will be freed by the scheduler once switched to 
+            // another process, because we are still running on it.
+            (*process.as_ptr()).state = ProcessState::Dead;
+        }
+    }
+    // Because the process is marked 'Dead', we should never get back here.
+    loop {
+        wait();
+    }
+}
 
-        if let Some(process) = RUNNING_PROCESS {
-
-            let current_process = &mut *process.as_ptr();
-            current_process.state = ProcessState::Dead;
 
-            // Free the stack page.
-            dealloc(NonNull::new_unchecked(current_process.stack_start as *mut u8)).unwrap();
+/// Kill the running process after a fatal exception from user mode,
+/// and switch to the next process.
+/// 
+/// *This function is unsafe because it must be called from the trap 
+/// handler of the hart.*
+pub unsafe fn kill(frame: &mut TrapFrame, reason: &str) {
+    if let Some(process) = hart().running {
+        let _processes = PROCESSES.lock();
+        let process = &mut *process.as_ptr();
+        println!("== Process #{} {} (parent #{}) killed: {} (pc: 0x{:08X}, val: 0x{:08X})", 
+            process.pid, process.name(), process.parent_pid, reason, frame.trap.pc(), frame.trap.val());
+        process.state = ProcessState::Dead;
+    }
+    schedule(frame);
+}
 
-            current_process.context.pc = 0;
-            current_process.context.sp = 0;
 
-            if let Some(next_process) = get_next_process(current_process.pid) {
-                RUNNING_PROCESS = Some(next_process.into());
-                asm_process_switch_noreturn(next_process, exit);
-                // We should never get here even if the method has not explicitly
-                // the '!' never return type. Because the process is marked 'Dead',
-                // we should never get back here.
-            }
+/// Kill the process with the given PID, return false if the process
+/// doesn't exist or is already dead. The resources of the process are
+/// freed immediately, unless it is running on a hart, which is then
+/// asked to reschedule. A process executing a system call might hold
+/// kernel locks, it is woken up if sleeping and dies when the system
+/// call returns. Machine processes might hold kernel locks at any time,
+/// so they are only marked and die at their next system call (yielding
+/// excluded, it's used while sleeping on locks), or when their current
+/// one returns.
+pub fn kill_pid(pid: Pid) -> bool {
+
+    let mut processes = PROCESSES.lock();
+    let Some(process) = iter(&mut processes).find(|process| process.pid == pid) else {
+        return false;
+    };
+
+    if let ProcessState::Dead = process.state {
+        return false;
+    }
 
+    if process.syscall.is_some() || process.root.is_none() {
+        process.killed.store(true, Ordering::Release);
+        if process.syscall.is_some() && process.state == ProcessState::Sleeping {
+            process.state = ProcessState::Waiting;
+            drop(processes);
+            wake_idle_harts();
         }
+        return true;
+    }
 
-        // If no process is running or if there is no next process,
-        // just abort the hart to avoid returning from the function.
-        println!("== Last process exited on hart #{}, aborting...", crate::cpu::mhardid::get());
-        crate::asm::asm_abort();
+    process.state = ProcessState::Dead;
+
+    if !process.on_hart {
+        // SAFETY: The process is not running and was alive.
+        unsafe { free_process(&mut processes, pid); }
+        return true;
+    }
+
+    let process_ptr = NonNull::from(process);
+    drop(processes);
+
+    // The process is freed by the scheduler of its hart, this might be
+    // the calling hart if interrupted while running the process.
+    // SAFETY: Running processes of other harts are only compared.
+    let running_hartid = (0..HART_MAX_COUNT)
+        .find(|&hartid| unsafe { HARTS[hartid].running } == Some(process_ptr));
+    if let Some(hartid) = running_hartid {
+        let _ = ipi::reschedule(hartid);
+    }
+
+    true
+
+}
+
+
+/// Exit the running process from the trap handler, and switch to the
+/// next process.
+/// 
+/// *This function is unsafe because it must be called from the trap 
+/// handler of the hart.*
+pub unsafe fn exit_trap(frame: &mut TrapFrame) {
+    if let Some(process) = hart().running {
+        let _processes = PROCESSES.lock();
+        (*process.as_ptr()).state = ProcessState::Dead;
+    }
+    schedule(frame);
+}
 
+
+/// Enter the system call of the running process from the trap handler,
+/// the context of the caller is saved from the given frame and the 
+/// process continues in machine mode at the given entry point, with
+/// the arguments of the system call unchanged in its registers. User
+/// processes use their kernel stack, and machine processes continue
+/// below their stack pointer. The process can then sleep and be 
+/// preempted while executing the system call, until it returns to the
+/// caller with [`leave_syscall`]. Return false if no process is running
+/// or if it's already executing a system call.
+/// 
+/// *This function is unsafe because it must be called from the trap
+/// handler of the hart, after an environment call, and the entry point
+/// must never return.*
+pub unsafe fn enter_syscall(frame: &mut TrapFrame, entry: usize) -> bool {
+
+    let processes = PROCESSES.lock();
+    let Some(process) = hart().running else {
+        return false;
+    };
+
+    let process = &mut *process.as_ptr();
+    if process.syscall.is_some() {
+        return false;
+    } else if process.state == ProcessState::Dead || process.killed.load(Ordering::Acquire) {
+        // Killed just before trapping, or a killed machine process.
+        process.state = ProcessState::Dead;
+        drop(processes);
+        schedule(frame);
+        return true;
     }
+
+    let mut caller = process.context;
+    caller.save(frame);
+    process.syscall = Some(caller);
+
+    frame.regs[REG_SP] = if frame.from_user() {
+        process.stack_end
+    } else {
+        frame.regs[REG_SP] & !15
+    };
+    frame.regs[REG_RA] = 0;
+    frame.regs[REG_GP] = LD_GLOBAL_POINTER.addr();
+    frame.trap.mepc = entry;
+    // Interrupts are enabled like in the caller.
+    frame.mstatus = (frame.mstatus & !MstatusFlags::MPP.bits()) | MstatusFlags::MPP_MACHINE.bits();
+    true
+
+}
+
+
+/// Return from the system call of the running process with the given
+/// value, the context of the caller is restored in the given frame. If
+/// the process has been killed meanwhile, it is switched and freed.
+/// Return false if the process is not executing a system call.
+/// 
+/// *This function is unsafe because it must be called from the trap
+/// handler of the hart.*
+pub unsafe fn leave_syscall(frame: &mut TrapFrame, ret: usize) -> bool {
+
+    let processes = PROCESSES.lock();
+    let Some(process) = hart().running else {
+        return false;
+    };
+
+    let process = &mut *process.as_ptr();
+    let Some(caller) = process.syscall.take() else {
+        return false;
+    };
+
+    caller.restore(frame);
+    frame.regs[REG_A0] = ret;
+
+    if process.killed.load(Ordering::Acquire) {
+        process.state = ProcessState::Dead;
+        drop(processes);
+        schedule(frame);
+    }
+
+    true
+
+}
+
+
+/// Return true if the running process has been killed while executing
+/// a system call, or if it's a killed machine process, it should then
+/// return as soon as possible. This can
+/// be called with the process table locked.
+pub fn is_killed() -> bool {
+    mstatus::without_interrupts(|| unsafe {
+        hart().running.map_or(false, |process| (*process.as_ptr()).killed.load(Ordering::Acquire))
+    })
+}
+
+
+/// Run the given function with the handle table of the running
+/// process, none if no process is running.
+pub fn with_handles<R>(func: impl FnOnce(&mut HandleTable) -> R) -> Option<R> {
+    mstatus::without_interrupts(|| unsafe {
+        hart().running.map(|process| func(&mut (*process.as_ptr()).handles))
+    })
+}
+
+
+/// Translate a virtual address of the running process to the physical
+/// address, only if the page is accessible to the process with the 
+/// given flags. Machine processes share the physical memory with the
+/// kernel, their addresses are returned unchanged.
+pub fn translate(vaddr: usize, flags: EntryFlags) -> Option<usize> {
+    mstatus::without_interrupts(|| unsafe {
+        let process = &*hart().running?.as_ptr();
+        if let Some(root) = process.root {
+            let (paddr, entry_flags) = (*root.as_ptr()).translate(vaddr)?;
+            if entry_flags.contains(flags | EntryFlags::USER) {
+                Some(paddr)
+            } else {
+                None
+            }
+        } else {
+            Some(vaddr)
+        }
+    })
 }
 
 
 /// Get the PID of the current process.
 #[inline]
 pub fn pid() -> Pid {
-    unsafe { (*RUNNING_PROCESS.unwrap().as_ptr()).pid }
+    try_pid().expect("no running process")
+}
+
+/// Get the PID of the current process, none if no process is running,
+/// like during the kernel initialization.
+#[inline]
+pub fn try_pid() -> Option<Pid> {
+    mstatus::without_interrupts(|| unsafe { hart().running.map(|process| (*process.as_ptr()).pid) })
 }
 
 
-/// Start the schedule process, *this should be called once when starting
-/// the kernel*.
+/// Start the schedule process, *this should be called once by each hart
+/// when starting the kernel*.
 pub unsafe fn start_schedule() -> ! {
-    debug_assert!(RUNNING_PROCESS.is_none());
-    if let Some(process) = iter().next() {
-        RUNNING_PROCESS = Some(process.into());
-        asm_process_switch_noreturn(process, exit);
+    debug_assert!(hart().running.is_none());
+    // Interrupts are disabled until we return to the first process,
+    // the trap frame must not be modified by any trap meanwhile.
+    mstatus::clear(MstatusFlags::MIE);
+    mie::set(mie::get() | MieFlags::MTIE);
+    schedule(&mut *(mscratch::get() as *mut TrapFrame));
+    asm_trap_return();
+}
+
+
+/// Called by the trap handler on timer interrupts or when the current 
+/// process yields, the context of the running process is saved from
+/// the given trap frame and the next process to run is restored into
+/// it. The timer of the hart is rearmed with the time slice of the
+/// next process. Processes running on other harts are never selected.
+/// 
+/// *This function is unsafe because it must be called from the trap 
+/// handler of the hart, or with interrupts disabled.*
+pub unsafe fn schedule(frame: &mut TrapFrame) {
+
+    let hartid = mhardid::get();
+    let hart = &mut HARTS[hartid];
+    let mut processes = PROCESSES.lock();
+    let mut current_pid = None;
+
+    if let Some(process) = hart.running.take() {
+        let current_process = &mut *process.as_ptr();
+        current_process.on_hart = false;
+        current_pid = Some(current_process.pid);
+        if current_process.state == ProcessState::Dead {
+            // We are running on the trap stack, so the stack of the
+            // process can be freed.
+            free_process(&mut processes, current_process.pid);
+        } else {
+            current_process.context.save(frame);
+            check_stack(current_process);
+            if current_process.state == ProcessState::Running {
+                current_process.state = ProcessState::Waiting;
+            }
+        }
+    }
+
+    IDLE_HARTS.fetch_and(!(1 << hartid), Ordering::AcqRel);
+
+    if let Some(next_process) = get_next_process(&mut processes, current_pid) {
+        next_process.context.restore(frame);
+        if let Some(root) = next_process.root {
+            paging::activate(root, next_process.asid);
+        } else {
+            paging::activate_kernel();
+        }
+        next_process.state = ProcessState::Running;
+        next_process.on_hart = true;
+        hart.running = Some(next_process.into());
+        clint::set_mtimecmp(hartid, clint::get_mtime() + next_process.time_slice);
+    } else if iter(&mut processes).any(|process| process.state != ProcessState::Dead) {
+        // Some processes are alive but can't run, so we wait for interrupts.
+        IDLE_HARTS.fetch_or(1 << hartid, Ordering::AcqRel);
+        paging::activate_kernel();
+        frame.trap.mepc = (asm_idle as *const u8).addr();
+        frame.mstatus = (MstatusFlags::MPP_MACHINE | MstatusFlags::MPIE).bits();
+        clint::set_mtimecmp(hartid, clint::get_mtime() + DEFAULT_TIME_SLICE);
+    } else {
+        // If there is no process left, just abort the hart.
+        drop(processes);
+        println!("== Last process exited on hart #{}, aborting...", hartid);
+        crate::asm::asm_abort();
     }
-    println!("== No process to start scheduling on hart #{}, aborting...", crate::cpu::mhardid::get());
-    crate::asm::asm_abort();
+
+}
+
+
+/// Return true if the hart is idle because no process can run, in this
+/// case the trap handler should call [`schedule`] after an interrupt 
+/// that might have woken up a process.
+#[inline]
+pub fn is_idle() -> bool {
+    IDLE_HARTS.load(Ordering::Acquire) & (1 << mhardid::get()) != 0
 }
 
 
 /// Internal function to get the next process to run regarding the
-/// current one.
-unsafe fn get_next_process<'a>(current_pid: Pid) -> Option<&'a mut Process> {
+/// current one, the current process is selected last if it can run.
+/// The table is sorted by PID, so the processes after the current one
+/// are found even if it has been removed.
+fn get_next_process(processes: &mut [Box<Process>], current_pid: Option<Pid>) -> Option<&mut Process> {
 
     let mut first_process = None;
-    let mut passed_current = false;
+    let mut current_process = None;
 
-    for process in iter() {
-        if process.pid == current_pid {
-            passed_current = true;
+    for process in iter(processes) {
+        if process.on_hart {
+            continue;
         } else if let ProcessState::Spawned | ProcessState::Waiting = process.state {
-            if passed_current {
-                return Some(process);
-            } else if first_process.is_none() {
-                first_process = Some(process);
+            match current_pid {
+                Some(current_pid) if process.pid == current_pid => current_process = Some(process),
+                Some(current_pid) if process.pid < current_pid => {
+                    if first_process.is_none() {
+                        first_process = Some(process);
+                    }
+                }
+                _ => return Some(process),
             }
         }
     }
 
-    first_process
+    first_process.or(current_process)
 
 }
 
 
-/// Internal function to get a process from its PID.
-unsafe fn by_pid(pid: Pid) -> *mut Process {
-    let process_idx = pid % PROCESS_COUNT_PER_PAGE;
-    let mut page_idx = pid / PROCESS_COUNT_PER_PAGE;
-    let mut process_page = FIRST_PROCESS_PAGE.as_ptr();
-    while page_idx > 0 {
-        process_page = (*process_page.add(PROCESS_COUNT_PER_PAGE)).next_page.as_ptr();
-        page_idx -= 1;
+/// Internal function to check that a process didn't overflow its 
+/// kernel stack, from its saved context, the kernel panics otherwise
+/// because its memory might be corrupted. Overflows are only detected
+/// when the process is switched, if its guard page has been written or
+/// if its stack pointer is out of its stack while in machine mode.
+fn check_stack(process: &Process) {
+    let sp = process.context.regs[REG_SP];
+    let machine = process.context.mstatus & MstatusFlags::MPP.bits() != 0;
+    // SAFETY: The guard page is allocated with the stack, just below.
+    let guard = unsafe {
+        core::slice::from_raw_parts((process.stack_start - PAGE_SIZE) as *const usize, PAGE_SIZE / size_of::<usize>())
+    };
+    if (machine && (sp < process.stack_start || sp > process.stack_end)) || guard.iter().any(|&value| value != STACK_GUARD) {
+        panic!("stack overflow of process #{} {} (sp: 0x{:08X})", process.pid, process.name(), sp);
     }
-    &mut (*process_page.add(process_idx)).process
 }
 
 
-/// Internal function to iterate over running processes.
-/// This function should not be 
-unsafe fn iter<'a>() -> ProcessIter<'a> {
-    ProcessIter {
-        page: FIRST_PROCESS_PAGE.as_ptr(),
-        index: 0,
-        _phantom: PhantomData
+/// Internal function to free the handles, the kernel stack and the
+/// address space of a dead process, and remove it from the table.
+/// 
+/// *This function is unsafe because the process must not be running,
+/// and no reference to it must be used after this call.*
+unsafe fn free_process(processes: &mut Vec<Box<Process>>, pid: Pid) {
+    let Ok(index) = processes.binary_search_by_key(&pid, |process| process.pid) else {
+        return;
+    };
+    let mut process = processes.remove(index);
+    process.handles.clear();
+    if let Some(root) = process.root.take() {
+        free_address_space(root);
+        ASIDS.lock().free(process.asid);
     }
+    // The guard page is the start of the allocation.
+    dealloc(NonNull::new_unchecked((process.stack_start - PAGE_SIZE) as *mut u8)).unwrap();
 }
 
-struct ProcessIter<'a> {
-    page: *mut ProcessEntry,
-    index: usize,
-    _phantom: PhantomData<&'a mut Process>,
+
+/// Allocator of address space identifiers, the identifier zero is
+/// shared by user processes spawned once all others are used. This
+/// is safe because the address translation cache of the hart is
+/// flushed each time an address space is activated.
+struct AsidAllocator {
+    /// The next identifier never allocated, zero once all are used.
+    next: u16,
+    /// Identifiers freed by dead processes.
+    free: Vec<u16>,
 }
 
-impl<'a> Iterator for ProcessIter<'a> {
-    type Item = &'a mut Process;
-    fn next(&mut self) -> Option<Self::Item> {
-        unsafe {
-            while self.index < PROCESS_COUNT {
-                if self.index > 0 && self.index % PROCESS_COUNT_PER_PAGE == 0 {
-                    self.page = (*self.page).next_page.as_ptr();
-                }
-                let process_ptr = &mut (*self.page).process;
-                self.index += 1;
-                self.page = self.page.add(1);
-                if process_ptr.state != ProcessState::Invalid {
-                    return Some(process_ptr);
-                }
-            }
-            None
+impl AsidAllocator {
+
+    const fn new() -> Self {
+        Self { next: 1, free: Vec::new() }
+    }
+
+    fn alloc(&mut self) -> u16 {
+        if let Some(asid) = self.free.pop() {
+            asid
+        } else if self.next != 0 {
+            let asid = self.next;
+            self.next = self.next.wrapping_add(1);
+            asid
+        } else {
+            0
+        }
+    }
+
+    fn free(&mut self, asid: u16) {
+        if asid != 0 {
+            self.free.push(asid);
         }
     }
+
+}
+
+
+/// Internal function to iterate over valid processes of the table.
+fn iter(processes: &mut [Box<Process>]) -> impl Iterator<Item = &mut Process> {
+    processes.iter_mut()
+        .map(|process| &mut **process)
+        .filter(|process| process.state != ProcessState::Invalid)
+}
+
+
+/// Internal function to get the scheduling state of the calling hart.
+/// 
+/// *This function is unsafe because it must be called with interrupts
+/// disabled, the running process could be moved to another hart
+/// otherwise.*
+#[inline]
+unsafe fn hart<'a>() -> &'a mut HartState {
+    &mut HARTS[mhardid::get()]
 }
@@ -0,0 +1,62 @@
+//! Symmetric multiprocessing, the secondary harts are parked by
+//! `boot.asm` until the hart #0 wakes them up with a software
+//! interrupt, once the kernel is initialized.
+
+use core::arch::asm;
+
+use crate::interrupt::clint;
+use crate::devicetree;
+
+
+/// Maximum number of harts supported by the kernel, harts with a
+/// greater id are never started. This must be the same as in
+/// `boot.asm`.
+pub const HART_MAX_COUNT: usize = 8;
+
+
+/// Number of harts started, including the hart #0.
+static mut HART_COUNT: usize = 1;
+
+
+/// Wake up all the secondary harts declared in the device tree, they
+/// enter `kmain_hart` and start scheduling processes. Without device
+/// tree, only the hart #0 is used.
+/// 
+/// *This function is unsafe because it must be called once by the 
+/// hart #0, after the initialization of the trap handler and of the 
+/// process manager.*
+pub unsafe fn start_harts() {
+
+    // Everything initialized so far must be visible to the other
+    // harts before they are woken up by the memory-mapped register.
+    asm!("fence");
+
+    for hartid in harts() {
+        if hartid != 0 && hartid < HART_MAX_COUNT {
+            clint::set_msip(hartid);
+            HART_COUNT += 1;
+        }
+    }
+
+}
+
+
+/// Return the number of harts started, including the hart #0.
+#[inline]
+pub fn hart_count() -> usize {
+    unsafe { HART_COUNT }
+}
+
+
+/// Internal function to iterate over the ids of the available harts,
+/// from the `/cpus` node of the device tree.
+fn harts() -> impl Iterator<Item = usize> {
+    devicetree::get()
+        .and_then(|tree| tree.find_path("/cpus"))
+        .into_iter()
+        .flat_map(|cpus| cpus.descendants())
+        .filter(|node| node.device_type() == Some("cpu"))
+        .filter(|node| node.property("status").and_then(|prop| prop.str()) != Some("disabled"))
+        .filter_map(|node| node.reg().next())
+        .map(|(hartid, _)| hartid as usize)
+}
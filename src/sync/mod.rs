@@ -0,0 +1,538 @@
+//! Synchronization primitives for the kernel-side.
+//!
+//! Blocking primitives put the calling process to sleep on a wait 
+//! channel, which is the address of the primitive, and the processes
+//! are woken up when it is released. These can't be used in interrupt
+//! context, where only the spinning variants and [`IrqSpinLock`] are
+//! allowed.
+
+#[cfg(feature = "selftest")]
+pub mod selftest;
+
+use core::arch::asm;
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU8, AtomicU32, AtomicUsize, Ordering, fence};
+
+use crate::cpu::mstatus::{self, MstatusFlags};
+use crate::process;
+
+
+#[repr(u32)]
+enum MutexState {
+    Released = 0,
+    Acquired = 1,
+}
+
+const STATE_RELEASED: u32 = MutexState::Released as u32;
+const STATE_ACQUIRED: u32 = MutexState::Acquired as u32;
+
+
+/// A mutual exclusion lock, it can be acquired by blocking the calling
+/// process with [`Mutex::lock`], or by spinning with [`Mutex::spin_lock`]
+/// in interrupt context.
+#[repr(C)]
+pub struct Mutex<T: ?Sized> {
+    state: MutexState,
+    /// Number of processes sleeping until the mutex is released.
+    waiters: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+
+    pub const fn new(data: T) -> Self {
+        Self { 
+            state: MutexState::Released, 
+            waiters: AtomicU32::new(0),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+}
+
+impl<T: ?Sized> Mutex<T> {
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let state: u32;
+        unsafe {
+            asm!("amoswap.w.aq {0}, {1}, ({2})", out(reg) state, in(reg) STATE_ACQUIRED, in(reg) &self.state);
+        }
+        match state {
+            STATE_RELEASED => Some(MutexGuard { mutex: self }),
+            _ => None
+        }
+    }
+
+    /// Acquire the lock, the calling process sleeps until the mutex is
+    /// released if it's already acquired, and is woken up on unlock.
+    /// 
+    /// This must not be used inside interrupt context, or with the
+    /// process table locked, use [`Mutex::spin_lock`] instead.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        sleep_until(&self.waiters, chan(self), || self.try_lock())
+    }
+
+    /// To use inside interrupt context.
+    pub fn spin_lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+        }
+    }
+
+    /// Internal function used by guard to unlock the lock.
+    fn unlock(&self) {
+        unsafe {
+			asm!(
+                "amoswap.w.rl zero, zero, ({0})",
+                in(reg) &self.state
+            );
+		}
+        notify(&self.waiters, chan(self), false);
+    }
+
+}
+
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.data.get() } 
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.data.get() } 
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+
+/// A spin lock that disables machine interrupts while held, it can be
+/// shared between processes and interrupt handlers of the same hart
+/// without deadlock. The previous interrupt enable state is restored
+/// when the guard is dropped.
+pub struct IrqSpinLock<T: ?Sized> {
+    mutex: Mutex<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+
+    pub const fn new(data: T) -> Self {
+        Self { mutex: Mutex::new(data) }
+    }
+
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let enabled = mstatus::get().contains(MstatusFlags::MIE);
+        mstatus::clear(MstatusFlags::MIE);
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.mutex.spin_lock()),
+            enabled,
+        }
+    }
+
+}
+
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// True if interrupts were enabled before locking.
+    enabled: bool,
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // The lock must be released before enabling interrupts again.
+        unsafe { ManuallyDrop::drop(&mut self.guard); }
+        if self.enabled {
+            mstatus::set(MstatusFlags::MIE);
+        }
+    }
+}
+
+
+/// State of a [`RwLock`] acquired for writing, other states are the
+/// number of readers.
+const RW_WRITER: u32 = u32::MAX;
+
+
+/// A reader-writer lock, allowing many readers or one writer at the
+/// same time. Processes sleep until the lock can be acquired.
+pub struct RwLock<T: ?Sized> {
+    /// Number of readers, or [`RW_WRITER`] if acquired for writing.
+    state: AtomicU32,
+    /// Number of processes sleeping until the lock is released.
+    waiters: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+}
+
+impl<T: ?Sized> RwLock<T> {
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state >= RW_WRITER - 1 {
+                return None;
+            }
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state.compare_exchange(0, RW_WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    /// Acquire the lock for reading, the calling process sleeps while
+    /// the lock is acquired for writing.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        sleep_until(&self.waiters, chan(self), || self.try_read())
+    }
+
+    /// Acquire the lock for writing, the calling process sleeps while
+    /// the lock is acquired by readers or by a writer.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        sleep_until(&self.waiters, chan(self), || self.try_write())
+    }
+
+}
+
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        // Only the last reader can let a writer in.
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            notify(&self.lock.waiters, chan(self.lock), true);
+        }
+    }
+}
+
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        // All readers can be let in.
+        self.lock.state.store(0, Ordering::Release);
+        notify(&self.lock.waiters, chan(self.lock), true);
+    }
+}
+
+
+/// A counting semaphore, processes sleep until a permit is available.
+pub struct Semaphore {
+    /// Number of available permits.
+    permits: AtomicUsize,
+    /// Number of processes sleeping until a permit is released.
+    waiters: AtomicU32,
+}
+
+impl Semaphore {
+
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// Return the number of available permits.
+    #[inline]
+    pub fn permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+
+    /// Take a permit if one is available, return true if taken.
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        while permits != 0 {
+            match self.permits.compare_exchange_weak(permits, permits - 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(actual) => permits = actual,
+            }
+        }
+        false
+    }
+
+    /// Take a permit, the calling process sleeps until one is available.
+    pub fn acquire(&self) {
+        sleep_until(&self.waiters, chan(self), || self.try_acquire().then_some(()))
+    }
+
+    /// Give back a permit, a sleeping process is woken up to take it.
+    /// This can be called from interrupt context.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        notify(&self.waiters, chan(self), false);
+    }
+
+}
+
+
+/// A condition variable, processes sleep until notified while the
+/// associated mutex is released.
+pub struct Condvar {
+    /// Incremented on each notification, so a notification between the
+    /// release of the mutex and the sleep is not missed.
+    seq: AtomicU32,
+}
+
+impl Condvar {
+
+    pub const fn new() -> Self {
+        Self { seq: AtomicU32::new(0) }
+    }
+
+    /// Release the mutex of the given guard and sleep until notified,
+    /// the mutex is acquired again before returning. Like any condition
+    /// variable, spurious wake ups can happen, so the condition should
+    /// be checked again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let seq = self.seq.load(Ordering::Acquire);
+        drop(guard);
+        process::sleep_while(chan(self), || self.seq.load(Ordering::Acquire) == seq);
+        mutex.lock()
+    }
+
+    /// Sleep until notified while the given condition returns true, the
+    /// condition is checked with the mutex acquired.
+    pub fn wait_while<'a, T: ?Sized>(&self, mut guard: MutexGuard<'a, T>, mut cond: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while cond(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake up one process waiting on this condition variable. This can
+    /// be called from interrupt context.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        process::wake_one(chan(self));
+    }
+
+    /// Wake up all processes waiting on this condition variable. This
+    /// can be called from interrupt context.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        process::wake(chan(self));
+    }
+
+}
+
+
+/// A value initialized once, the first caller of [`Once::call_once`]
+/// runs the initialization while others spin until it's done, so it can
+/// be used from any context.
+pub struct Once<T = ()> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+
+    const INCOMPLETE: u8 = 0;
+    const RUNNING: u8 = 1;
+    const COMPLETE: u8 = 2;
+
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(Self::INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Initialize the value with the given function if not yet done, 
+    /// and return it.
+    pub fn call_once(&self, func: impl FnOnce() -> T) -> &T {
+        if self.state.compare_exchange(Self::INCOMPLETE, Self::RUNNING, Ordering::Acquire, Ordering::Acquire).is_ok() {
+            unsafe { (*self.value.get()).write(func()); }
+            self.state.store(Self::COMPLETE, Ordering::Release);
+        } else {
+            while self.state.load(Ordering::Acquire) != Self::COMPLETE {
+                spin_loop();
+            }
+        }
+        // SAFETY: The state is complete, so the value is initialized.
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// Get the value, if initialized.
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Return true if the value is initialized.
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == Self::COMPLETE
+    }
+
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == Self::COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop(); }
+        }
+    }
+}
+
+
+/// A value initialized on first access with the given function.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F> Lazy<T, F> {
+
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.once.call_once(|| match self.init.take() {
+            Some(init) => init(),
+            None => unreachable!("lazy value initialized twice"),
+        })
+    }
+}
+
+
+/// Internal function to get the wait channel of a primitive, this is
+/// its address.
+#[inline]
+fn chan<T: ?Sized>(primitive: &T) -> usize {
+    (primitive as *const T).addr()
+}
+
+/// Internal function to sleep until the given function acquires the
+/// primitive, the process is counted in the waiters meanwhile.
+fn sleep_until<R>(waiters: &AtomicU32, chan: usize, mut acquire: impl FnMut() -> Option<R>) -> R {
+
+    if let Some(ret) = acquire() {
+        return ret;
+    }
+
+    // The waiter is counted before trying again, so the release can't
+    // miss it (see 'notify').
+    waiters.fetch_add(1, Ordering::SeqCst);
+    let mut ret = None;
+    process::sleep_while(chan, || {
+        ret = acquire();
+        ret.is_none()
+    });
+    waiters.fetch_sub(1, Ordering::SeqCst);
+
+    // SAFETY: The condition returned false, so it has been acquired.
+    unsafe { ret.unwrap_unchecked() }
+
+}
+
+/// Internal function to wake up the processes sleeping in [`sleep_until`]
+/// after the primitive has been released, only one if not `all`.
+fn notify(waiters: &AtomicU32, chan: usize, all: bool) {
+    // The release must be ordered before reading the waiters, a 
+    // process counted after this will see the primitive released.
+    fence(Ordering::SeqCst);
+    if waiters.load(Ordering::SeqCst) != 0 {
+        if all {
+            process::wake(chan);
+        } else {
+            process::wake_one(chan);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Self-tests of the synchronization primitives, only compiled with the
+//! `selftest` feature and run by a machine process spawned at boot.
+//!
+//! Each test spawns worker processes contending on a primitive, these
+//! regularly yield while holding it, so the other workers have to sleep
+//! and be woken up when it's released. The runner waits for the workers
+//! of a test to be done before checking its results, a lost wake up
+//! hangs the last test printed.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cpu::mstatus::{self, MstatusFlags};
+use crate::{println, process};
+
+use super::{Condvar, IrqSpinLock, Lazy, Mutex, Once, RwLock, Semaphore};
+
+
+/// Number of worker processes spawned by each test.
+const WORKERS: usize = 4;
+
+/// Number of times each worker acquires the tested primitive.
+const ROUNDS: usize = 256;
+
+/// A test function, returning true if passed.
+type Test = fn() -> bool;
+
+/// All the tests, with their names.
+static TESTS: [(&str, Test); 7] = [
+    ("Mutex", test_mutex),
+    ("RwLock", test_rwlock),
+    ("Semaphore", test_semaphore),
+    ("Condvar", test_condvar),
+    ("Once", test_once),
+    ("Lazy", test_lazy),
+    ("IrqSpinLock", test_irq_spin_lock),
+];
+
+/// Released by each worker once done.
+static DONE: Semaphore = Semaphore::new(0);
+
+
+/// The entry point of the self-test process, run all the tests.
+pub extern "C" fn run() {
+
+    println!("== Self-test: synchronization ({} workers, {} rounds)", WORKERS, ROUNDS);
+
+    let mut failed_count = 0;
+    for (name, test) in &TESTS {
+        if test() {
+            println!(" = {}: ok", name);
+        } else {
+            println!(" = {}: FAILED", name);
+            failed_count += 1;
+        }
+    }
+
+    println!(" = {} tests, {} failed", TESTS.len(), failed_count);
+
+}
+
+
+/// Internal function to spawn the given workers and wait for all of them
+/// to be done.
+fn run_workers(workers: &[extern "C" fn()]) {
+    for &worker in workers {
+        process::spawn(worker, "[selftest-worker]");
+    }
+    for _ in workers {
+        DONE.acquire();
+    }
+}
+
+/// Internal function to yield every few rounds, called while holding a
+/// primitive so that other workers contend on it.
+fn contend(round: usize) {
+    if round % 8 == 0 {
+        process::wait();
+    }
+}
+
+
+static MUTEX_COUNTER: Mutex<usize> = Mutex::new(0);
+
+/// The counter is incremented in two steps, an increment is lost if the
+/// mutex is acquired by two workers at the same time.
+fn test_mutex() -> bool {
+    run_workers(&[mutex_worker as extern "C" fn(); WORKERS]);
+    *MUTEX_COUNTER.lock() == WORKERS * ROUNDS
+}
+
+extern "C" fn mutex_worker() {
+    for round in 0..ROUNDS {
+        let mut counter = MUTEX_COUNTER.lock();
+        let value = *counter;
+        contend(round);
+        *counter = value + 1;
+    }
+    DONE.release();
+}
+
+
+static RW_PAIR: RwLock<(usize, usize)> = RwLock::new((0, 0));
+static RW_TORN: AtomicUsize = AtomicUsize::new(0);
+
+/// Writers increment both values of the pair in two steps, readers must
+/// never see them different.
+fn test_rwlock() -> bool {
+    run_workers(&[rwlock_writer, rwlock_reader, rwlock_writer, rwlock_reader]);
+    *RW_PAIR.read() == (ROUNDS * 2, ROUNDS * 2) && RW_TORN.load(Ordering::Relaxed) == 0
+}
+
+extern "C" fn rwlock_writer() {
+    for round in 0..ROUNDS {
+        let mut pair = RW_PAIR.write();
+        pair.0 += 1;
+        contend(round);
+        pair.1 += 1;
+    }
+    DONE.release();
+}
+
+extern "C" fn rwlock_reader() {
+    for round in 0..ROUNDS {
+        let pair = RW_PAIR.read();
+        let first = pair.0;
+        contend(round);
+        if pair.1 != first {
+            RW_TORN.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    DONE.release();
+}
+
+
+const SEMAPHORE_PERMITS: usize = 2;
+
+static SEMAPHORE: Semaphore = Semaphore::new(SEMAPHORE_PERMITS);
+static SEMAPHORE_INSIDE: AtomicUsize = AtomicUsize::new(0);
+static SEMAPHORE_MAX_INSIDE: AtomicUsize = AtomicUsize::new(0);
+
+/// More workers than permits, no more workers than permits can hold one
+/// at the same time, and all permits are given back.
+fn test_semaphore() -> bool {
+    run_workers(&[semaphore_worker as extern "C" fn(); WORKERS]);
+    SEMAPHORE_MAX_INSIDE.load(Ordering::Relaxed) <= SEMAPHORE_PERMITS && SEMAPHORE.permits() == SEMAPHORE_PERMITS
+}
+
+extern "C" fn semaphore_worker() {
+    for round in 0..ROUNDS {
+        SEMAPHORE.acquire();
+        let inside = SEMAPHORE_INSIDE.fetch_add(1, Ordering::Relaxed) + 1;
+        SEMAPHORE_MAX_INSIDE.fetch_max(inside, Ordering::Relaxed);
+        contend(round);
+        SEMAPHORE_INSIDE.fetch_sub(1, Ordering::Relaxed);
+        SEMAPHORE.release();
+    }
+    DONE.release();
+}
+
+
+/// Number of items pushed by the two producers of the condition
+/// variable test.
+const QUEUE_TOTAL: usize = 2 * ROUNDS;
+
+struct Queue {
+    /// Number of items pushed but not yet taken.
+    items: usize,
+    /// Number of items taken.
+    taken: usize,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue { items: 0, taken: 0 });
+static QUEUE_CONDVAR: Condvar = Condvar::new();
+
+/// Producers push items and notify one consumer, consumers sleep until
+/// an item is available. All consumers return once all items are taken.
+fn test_condvar() -> bool {
+    run_workers(&[condvar_producer, condvar_consumer, condvar_producer, condvar_consumer]);
+    let queue = QUEUE.lock();
+    queue.items == 0 && queue.taken == QUEUE_TOTAL
+}
+
+extern "C" fn condvar_producer() {
+    for round in 0..ROUNDS {
+        QUEUE.lock().items += 1;
+        QUEUE_CONDVAR.notify_one();
+        contend(round);
+    }
+    DONE.release();
+}
+
+extern "C" fn condvar_consumer() {
+    loop {
+        let mut queue = QUEUE_CONDVAR.wait_while(QUEUE.lock(), |queue| {
+            queue.items == 0 && queue.taken < QUEUE_TOTAL
+        });
+        if queue.taken == QUEUE_TOTAL {
+            break;
+        }
+        queue.items -= 1;
+        queue.taken += 1;
+        if queue.taken == QUEUE_TOTAL {
+            // Other consumers are waiting for an item that will never come.
+            drop(queue);
+            QUEUE_CONDVAR.notify_all();
+        }
+    }
+    DONE.release();
+}
+
+
+const INIT_VALUE: usize = 0xA5E5;
+
+static ONCE: Once<usize> = Once::new();
+static ONCE_INITS: AtomicUsize = AtomicUsize::new(0);
+static ONCE_WRONG: AtomicUsize = AtomicUsize::new(0);
+
+/// All workers race to initialize the value, the initialization yields
+/// so other workers see it running. It must run exactly once and all
+/// workers must get the initialized value.
+fn test_once() -> bool {
+    run_workers(&[once_worker as extern "C" fn(); WORKERS]);
+    ONCE_INITS.load(Ordering::Relaxed) == 1 && ONCE_WRONG.load(Ordering::Relaxed) == 0
+}
+
+extern "C" fn once_worker() {
+    let value = *ONCE.call_once(|| {
+        ONCE_INITS.fetch_add(1, Ordering::Relaxed);
+        process::wait();
+        INIT_VALUE
+    });
+    if value != INIT_VALUE {
+        ONCE_WRONG.fetch_add(1, Ordering::Relaxed);
+    }
+    DONE.release();
+}
+
+
+static LAZY: Lazy<usize> = Lazy::new(lazy_init);
+static LAZY_INITS: AtomicUsize = AtomicUsize::new(0);
+static LAZY_WRONG: AtomicUsize = AtomicUsize::new(0);
+
+/// Same as the [`Once`] test, through the first access of the value.
+fn test_lazy() -> bool {
+    run_workers(&[lazy_worker as extern "C" fn(); WORKERS]);
+    LAZY_INITS.load(Ordering::Relaxed) == 1 && LAZY_WRONG.load(Ordering::Relaxed) == 0
+}
+
+fn lazy_init() -> usize {
+    LAZY_INITS.fetch_add(1, Ordering::Relaxed);
+    process::wait();
+    INIT_VALUE
+}
+
+extern "C" fn lazy_worker() {
+    if *LAZY != INIT_VALUE {
+        LAZY_WRONG.fetch_add(1, Ordering::Relaxed);
+    }
+    DONE.release();
+}
+
+
+static IRQ_COUNTER: IrqSpinLock<usize> = IrqSpinLock::new(0);
+static IRQ_NESTED: IrqSpinLock<()> = IrqSpinLock::new(());
+static IRQ_BROKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The counter is incremented in two steps like the [`Mutex`] test, the
+/// workers can't yield because interrupts are disabled, so they contend
+/// from different harts. Interrupts must stay disabled while a lock is
+/// held, even after releasing a nested lock, and be enabled again once
+/// all are released.
+fn test_irq_spin_lock() -> bool {
+    run_workers(&[irq_spin_lock_worker as extern "C" fn(); WORKERS]);
+    *IRQ_COUNTER.lock() == WORKERS * ROUNDS && IRQ_BROKEN.load(Ordering::Relaxed) == 0
+}
+
+extern "C" fn irq_spin_lock_worker() {
+    for _ in 0..ROUNDS {
+        let mut counter = IRQ_COUNTER.lock();
+        drop(IRQ_NESTED.lock());
+        if interrupts_enabled() {
+            IRQ_BROKEN.fetch_add(1, Ordering::Relaxed);
+        }
+        let value = *counter;
+        for _ in 0..64 {
+            spin_loop();
+        }
+        *counter = value + 1;
+        drop(counter);
+        if !interrupts_enabled() {
+            IRQ_BROKEN.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    DONE.release();
+}
+
+fn interrupts_enabled() -> bool {
+    mstatus::get().contains(MstatusFlags::MIE)
+}
@@ -0,0 +1,383 @@
+//! Dispatching of system calls made with `ecall` by processes, see
+//! the [`api`](crate::api) module for the calling convention.
+
+use core::arch::asm;
+
+use alloc::sync::Arc;
+
+use crate::api::{self, Error};
+use crate::filesystem::{self, File, FsError, OpenFlags, SeekFrom, file};
+use crate::memory::page::PAGE_SIZE;
+use crate::memory::paging::{EntryFlags, MapError};
+use crate::process::handle::{Handle, HandleFlags};
+use crate::process::elf::{self, ElfError};
+use crate::process::{self, builtin, SpawnError};
+use crate::trap::TrapFrame;
+
+
+/// Indices of the registers used by the system calls.
+const REG_A0: usize = 10;
+const REG_A7: usize = 17;
+
+/// Internal system call used by processes to return from the system
+/// call they execute, with the value in `a0`. Only allowed in machine
+/// mode, it's never part of the API.
+const SYS_RETURN: usize = usize::MAX;
+
+/// Maximum length of string arguments, like paths.
+const MAX_STRING_LEN: usize = 256;
+
+
+/// Internal error of system calls that can block.
+enum SysError {
+    /// The system call failed.
+    Error(Error),
+    /// The process must sleep on the given wait channel while the 
+    /// condition is true, the system call is then retried.
+    Sleep(usize, fn() -> bool),
+}
+
+impl From<Error> for SysError {
+    fn from(e: Error) -> Self {
+        Self::Error(e)
+    }
+}
+
+impl From<FsError> for SysError {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::WouldBlock(chan, cond) => Self::Sleep(chan, cond),
+            e => Self::Error(fs_error(e)),
+        }
+    }
+}
+
+
+/// Handle the system call of the process that trapped with the given
+/// frame. Exiting and yielding are handled by the trap handler, other
+/// system calls are executed by the process itself in machine mode, so
+/// they can sleep, the result is then written in `a0` of the caller.
+/// The process might have been switched on return.
+///
+/// *This function is unsafe because it must be called from the trap
+/// handler of the hart, after an environment call.*
+pub unsafe fn dispatch(frame: &mut TrapFrame) {
+
+    // The process must resume after the 'ecall' instruction. The
+    // instruction is never compressed and the program counter is a
+    // virtual address of the process, so it is not read.
+    frame.trap.mepc += 4;
+
+    match frame.regs[REG_A7] {
+        api::SYS_EXIT => process::exit_trap(frame),
+        api::SYS_YIELD => {
+            frame.regs[REG_A0] = 0;
+            process::schedule(frame);
+        }
+        SYS_RETURN if !frame.from_user() => {
+            let ret = frame.regs[REG_A0];
+            if !process::leave_syscall(frame, ret) {
+                frame.regs[REG_A0] = error_code(Error::InvalidSyscall);
+            }
+        }
+        _ => {
+            if !process::enter_syscall(frame, (syscall_entry as *const u8).addr()) {
+                frame.regs[REG_A0] = error_code(Error::InvalidSyscall);
+            }
+        }
+    }
+
+}
+
+
+/// Entry point of the system calls executed by the process, the
+/// arguments are still in the registers of the caller, `a0` to `a5`
+/// and the number in `a7`. The result is returned to the caller with
+/// the internal [`SYS_RETURN`] system call.
+extern "C" fn syscall_entry(a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, _a6: usize, num: usize) -> ! {
+
+    let args = [a0, a1, a2, a3, a4, a5];
+    let ret = match num {
+        api::SYS_OPEN => sys_open(args[0], args[1], args[2], args[3]),
+        api::SYS_FREE => sys_free(args[0]),
+        api::SYS_READ => retry(|| sys_read(args[0], args[1], args[2])),
+        api::SYS_WRITE => retry(|| sys_write(args[0], args[1], args[2])),
+        api::SYS_SEEK => sys_seek(args[0], args[1] as isize, args[2]),
+        api::SYS_SPAWN => sys_spawn(args[0], args[1]),
+        api::SYS_GETPID => Ok(process::pid()),
+        _ => Err(Error::InvalidSyscall),
+    };
+
+    let ret = match ret {
+        Ok(value) => value,
+        Err(e) => error_code(e),
+    };
+
+    // SAFETY: The process is executing a system call, it returns to
+    // the caller and this stack is no longer used.
+    unsafe {
+        asm!("ecall", in("a0") ret, in("a7") SYS_RETURN, options(noreturn));
+    }
+
+}
+
+
+/// Internal function to call a system call until it doesn't block, the
+/// process sleeps while it would block. If the process is killed 
+/// meanwhile, the system call is not retried.
+fn retry(mut func: impl FnMut() -> Result<usize, SysError>) -> api::Result<usize> {
+    loop {
+        match func() {
+            Ok(value) => return Ok(value),
+            Err(SysError::Error(e)) => return Err(e),
+            Err(SysError::Sleep(chan, cond)) => {
+                process::sleep_while(chan, || cond() && !process::is_killed());
+                if process::is_killed() {
+                    return Err(Error::Busy);
+                }
+            }
+        }
+    }
+}
+
+
+/// Internal function to get the value returned in `a0` for an error.
+#[inline]
+fn error_code(e: Error) -> usize {
+    (-(e as isize)) as usize
+}
+
+
+fn sys_open(path_ptr: usize, path_len: usize, options_ptr: usize, options_len: usize) -> api::Result<usize> {
+
+    let mut path_buf = [0; MAX_STRING_LEN];
+    let mut options_buf = [0; MAX_STRING_LEN];
+    let path = user_str(path_ptr, path_len, &mut path_buf)?;
+    let options = user_str(options_ptr, options_len, &mut options_buf)?;
+
+    let mut flags = HandleFlags::empty();
+    let mut open_flags = OpenFlags::empty();
+    for c in options.chars() {
+        match c {
+            'r' => flags |= HandleFlags::READ,
+            'w' => flags |= HandleFlags::WRITE,
+            'c' => open_flags |= OpenFlags::CREATE,
+            't' => open_flags |= OpenFlags::TRUNCATE,
+            _ => return Err(Error::InvalidArgument),
+        }
+    }
+
+    if flags.contains(HandleFlags::READ) { open_flags |= OpenFlags::READ; }
+    if flags.contains(HandleFlags::WRITE) { open_flags |= OpenFlags::WRITE; }
+
+    let file = filesystem::open(path, open_flags).map_err(fs_error)?;
+    let file = file::insert(file);
+    let handle = Handle { file, flags };
+    match process::with_handles(|handles| handles.insert(handle)) {
+        Some(Some(handle)) => Ok(handle),
+        ret => {
+            file::close(file);
+            Err(if ret.is_none() { Error::InvalidHandle } else { Error::TooManyHandles })
+        }
+    }
+
+}
+
+
+fn sys_free(handle: usize) -> api::Result<usize> {
+    process::with_handles(|handles| handles.remove(handle))
+        .flatten()
+        .map(|_| 0)
+        .ok_or(Error::InvalidHandle)
+}
+
+
+fn sys_read(handle: usize, buf_ptr: usize, buf_len: usize) -> Result<usize, SysError> {
+    let file = get_file(handle, HandleFlags::READ)?;
+    user_check(buf_ptr, buf_len, EntryFlags::WRITE)?;
+    let mut len = 0;
+    let mut ret = Ok(());
+    // Each chunk is read until a short read, which might be the end 
+    // of a line, so the following chunks are not read.
+    user_chunks_mut(buf_ptr, buf_len, |chunk| {
+        if ret.is_err() {
+            return;
+        }
+        match file.read(chunk) {
+            Ok(chunk_len) if chunk_len == chunk.len() => len += chunk_len,
+            Ok(chunk_len) => {
+                len += chunk_len;
+                ret = Err(None);
+            }
+            Err(e) => ret = Err(Some(e)),
+        }
+    });
+    match ret {
+        // Errors are ignored if some data has been read.
+        Err(Some(e)) if len == 0 => Err(e.into()),
+        _ => Ok(len),
+    }
+}
+
+
+fn sys_write(handle: usize, buf_ptr: usize, buf_len: usize) -> Result<usize, SysError> {
+    let file = get_file(handle, HandleFlags::WRITE)?;
+    user_check(buf_ptr, buf_len, EntryFlags::READ)?;
+    let mut len = 0;
+    let mut ret = Ok(());
+    user_chunks(buf_ptr, buf_len, |chunk| {
+        if ret.is_err() {
+            return;
+        }
+        match file.write(chunk) {
+            Ok(chunk_len) if chunk_len == chunk.len() => len += chunk_len,
+            Ok(chunk_len) => {
+                len += chunk_len;
+                ret = Err(None);
+            }
+            Err(e) => ret = Err(Some(e)),
+        }
+    });
+    match ret {
+        Err(Some(e)) if len == 0 => Err(e.into()),
+        _ => Ok(len),
+    }
+}
+
+
+fn sys_seek(handle: usize, offset: isize, whence: usize) -> api::Result<usize> {
+    let file = get_file(handle, HandleFlags::empty())?;
+    let pos = match whence {
+        api::SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| Error::InvalidArgument)?),
+        api::SEEK_CUR => SeekFrom::Current(offset as i64),
+        api::SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Error::InvalidArgument),
+    };
+    file.seek(pos).map(|offset| offset as usize).map_err(fs_error)
+}
+
+
+fn sys_spawn(name_ptr: usize, name_len: usize) -> api::Result<usize> {
+    let mut name_buf = [0; MAX_STRING_LEN];
+    let name = user_str(name_ptr, name_len, &mut name_buf)?;
+    if name.starts_with('/') {
+        return elf::spawn_elf_path(name, &[name], &[]).map_err(elf_error);
+    }
+    let entry_point = builtin::find(name).ok_or(Error::NotFound)?;
+    Ok(process::spawn(entry_point, name))
+}
+
+
+/// Internal function to get the file of a handle of the running
+/// process, checking that it has been opened with the given flags.
+fn get_file(handle: usize, flags: HandleFlags) -> api::Result<Arc<dyn File>> {
+    let handle = process::with_handles(|handles| handles.get_mut(handle).copied())
+        .flatten()
+        .ok_or(Error::InvalidHandle)?;
+    if handle.flags.contains(flags) {
+        file::get(handle.file).ok_or(Error::InvalidHandle)
+    } else {
+        Err(Error::PermissionDenied)
+    }
+}
+
+
+/// Internal function to convert an error of the ELF loader to a system
+/// call error, malformed executables are invalid arguments.
+fn elf_error(e: ElfError) -> Error {
+    match e {
+        ElfError::Fs(e) => fs_error(e),
+        ElfError::Io(_) => Error::Io,
+        ElfError::Spawn(SpawnError::OutOfMemory) |
+        ElfError::Spawn(SpawnError::Map(MapError::OutOfMemory)) => Error::OutOfMemory,
+        _ => Error::InvalidArgument,
+    }
+}
+
+
+/// Internal function to convert a filesystem error to a system call
+/// error, blocking is not expected.
+fn fs_error(e: FsError) -> Error {
+    match e {
+        FsError::InvalidPath |
+        FsError::NameTooLong |
+        FsError::InvalidArgument |
+        FsError::TooManySymlinks => Error::InvalidArgument,
+        FsError::NotFound => Error::NotFound,
+        FsError::NotDirectory => Error::NotDirectory,
+        FsError::IsDirectory => Error::IsDirectory,
+        FsError::AlreadyExists => Error::AlreadyExists,
+        FsError::NotEmpty => Error::NotEmpty,
+        FsError::Busy => Error::Busy,
+        FsError::ReadOnly => Error::ReadOnly,
+        FsError::PermissionDenied => Error::PermissionDenied,
+        FsError::CrossDevice |
+        FsError::Unsupported |
+        FsError::WouldBlock(..) => Error::Unsupported,
+        FsError::NoSpace => Error::NoSpace,
+        FsError::OutOfMemory => Error::OutOfMemory,
+        FsError::Corrupted |
+        FsError::Io => Error::Io,
+    }
+}
+
+
+/// Internal function to check that a buffer of the running process is
+/// accessible with the given flags, each of its pages is checked.
+fn user_check(ptr: usize, len: usize, flags: EntryFlags) -> api::Result<()> {
+    let end = ptr.checked_add(len).ok_or(Error::InvalidPointer)?;
+    let mut page = ptr & !(PAGE_SIZE - 1);
+    while page < end {
+        process::translate(page, flags).ok_or(Error::InvalidPointer)?;
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+
+/// Internal function to call the given function on each physically
+/// contiguous chunk of a buffer of the running process, the buffer
+/// must have been checked with [`user_check`] before.
+fn user_chunks(ptr: usize, len: usize, mut func: impl FnMut(&[u8])) {
+    let end = ptr + len;
+    let mut vaddr = ptr;
+    while vaddr < end {
+        let chunk_end = ((vaddr & !(PAGE_SIZE - 1)) + PAGE_SIZE).min(end);
+        let paddr = process::translate(vaddr, EntryFlags::empty()).unwrap();
+        // SAFETY: The page has been checked to be mapped for the process.
+        func(unsafe { core::slice::from_raw_parts(paddr as *const u8, chunk_end - vaddr) });
+        vaddr = chunk_end;
+    }
+}
+
+
+/// Internal function to call the given function on each physically
+/// contiguous chunk of a buffer of the running process, to write it.
+/// The buffer must have been checked with [`user_check`] before.
+fn user_chunks_mut(ptr: usize, len: usize, mut func: impl FnMut(&mut [u8])) {
+    let end = ptr + len;
+    let mut vaddr = ptr;
+    while vaddr < end {
+        let chunk_end = ((vaddr & !(PAGE_SIZE - 1)) + PAGE_SIZE).min(end);
+        let paddr = process::translate(vaddr, EntryFlags::empty()).unwrap();
+        // SAFETY: The page has been checked to be mapped for the process.
+        func(unsafe { core::slice::from_raw_parts_mut(paddr as *mut u8, chunk_end - vaddr) });
+        vaddr = chunk_end;
+    }
+}
+
+
+/// Internal function to copy a string of the running process into the
+/// given buffer, the string must be valid UTF-8.
+fn user_str(ptr: usize, len: usize, buf: &mut [u8; MAX_STRING_LEN]) -> api::Result<&str> {
+    if len > MAX_STRING_LEN {
+        return Err(Error::InvalidArgument);
+    }
+    user_check(ptr, len, EntryFlags::READ)?;
+    let mut offset = 0;
+    user_chunks(ptr, len, |chunk| {
+        buf[offset..offset + chunk.len()].copy_from_slice(chunk);
+        offset += chunk.len();
+    });
+    core::str::from_utf8(&buf[..len]).map_err(|_| Error::InvalidArgument)
+}
@@ -1,32 +1,56 @@
 //! Machine trap management, this module is coupled with
 //! `trap.asm` as it defines the trap frame and the data
 //! structure should be exactly following in the assembly.
-//! 
+//!
 //! CLINT: Core-Local Interruptor
 //! PLIC:  Platform-Level Interrupt Controller
 
-use core::mem::MaybeUninit;
+use core::num::NonZeroUsize;
 
+use crate::memory::page::{PAGE_SIZE, alloc};
+use crate::memory::paging::EntryFlags;
+use crate::smp::HART_MAX_COUNT;
+use crate::cpu::mstatus::MstatusFlags;
 use crate::cpu::mie::MieFlags;
-use crate::cpu;
+use crate::{cpu, println, process};
 
 
-const MAX_TRAPS_COUNT: usize = 256;
+/// Number of pages allocated for the stack used by the trap handler.
+const TRAP_STACK_PAGES: usize = 4;
 
+/// ABI names of the general purpose registers, used when dumping.
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
 
-/// Structure used to temporarily save registers that 
-/// might be used in the trap handler.
+
+/// Structure used to save the whole state of the hart when
+/// a trap occurs. The `mscratch` register of each hart must
+/// point to its own frame.
+///
+/// Size of: 296
 #[repr(C)]
 pub struct TrapFrame {
-    pub t0: usize,
-    pub t1: usize,
-    pub t2: usize,
-    pub traps_count: usize,
-    pub traps: [MaybeUninit<Trap>; MAX_TRAPS_COUNT],
+    /// General purpose registers x0-x31, x0 is never written. [offset 0]
+    pub regs: [usize; 32],
+    /// The trap that caused the handler to be called, the `mepc`
+    /// field is restored when returning from the trap. [offset 256]
+    pub trap: Trap,
+    /// The machine status register, restored when returning
+    /// from the trap. [offset 280]
+    pub mstatus: usize,
+    /// Address of the end of the stack used by the trap handler. [offset 288]
+    pub trap_stack: usize,
 }
 
 /// Represent a trap
+///
+/// Size of: 24
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct Trap {
     pub mcause: usize,
     pub mtval: usize,
@@ -34,14 +58,62 @@ pub struct Trap {
 }
 
 impl TrapFrame {
-    
+
     pub const fn new() -> Self {
         Self {
-            t0: 0,
-            t1: 0,
-            t2: 0,
-            traps_count: 0,
-            traps: unsafe { MaybeUninit::uninit().assume_init() }
+            regs: [0; 32],
+            trap: Trap {
+                mcause: 0,
+                mtval: 0,
+                mepc: 0,
+            },
+            mstatus: 0,
+            trap_stack: 0,
+        }
+    }
+
+    /// Move the program counter to restore after the instruction
+    /// that caused the trap, this is used when the exception has
+    /// been handled and should not be repeated. The program counter
+    /// of user processes is translated through their page table to
+    /// read the instruction, false is returned if it isn't mapped as
+    /// executable and the program counter is left unchanged.
+    pub fn skip_instruction(&mut self) -> bool {
+        let addr = if self.from_user() {
+            match process::translate(self.trap.mepc, EntryFlags::EXECUTE) {
+                Some(paddr) => paddr,
+                None => return false,
+            }
+        } else {
+            self.trap.mepc
+        };
+        // SAFETY: The instruction has just been executed, so its
+        // address is valid and aligned to 2 bytes, the two bytes
+        // read never cross a page.
+        let low = unsafe { (addr as *const u16).read_volatile() };
+        // Compressed instructions are the only ones with their two
+        // lowest bits not set.
+        self.trap.mepc += if low & 0b11 == 0b11 { 4 } else { 2 };
+        true
+    }
+
+    /// Return true if the trap was taken from user mode, the
+    /// previous privilege is then zero.
+    #[inline]
+    pub fn from_user(&self) -> bool {
+        self.mstatus & MstatusFlags::MPP.bits() == 0
+    }
+
+    /// Print all the saved registers.
+    pub fn dump(&self) {
+        println!(" = mcause: {:016X}  mtval: {:016X}  mepc: {:016X}", self.trap.mcause, self.trap.mtval, self.trap.mepc);
+        println!(" = mstatus: {:016X}", self.mstatus);
+        for (i, chunk) in self.regs.chunks(4).enumerate() {
+            println!(" = {:>4}: {:016X}  {:>4}: {:016X}  {:>4}: {:016X}  {:>4}: {:016X}",
+                REGISTER_NAMES[i * 4], chunk[0],
+                REGISTER_NAMES[i * 4 + 1], chunk[1],
+                REGISTER_NAMES[i * 4 + 2], chunk[2],
+                REGISTER_NAMES[i * 4 + 3], chunk[3]);
         }
     }
 
@@ -72,14 +144,39 @@ impl Trap {
 }
 
 
-/// The frame used to save the context when hart #0 is interrupted.
-static mut HART_ZERO_FRAME: TrapFrame = TrapFrame::new();
-
-
-/// Initialize trap (for hart #0 only).
-pub unsafe fn init_hart_zero() {
+/// The frames used to save the context when a hart is interrupted,
+/// indexed by hart id.
+static mut HART_FRAMES: [TrapFrame; HART_MAX_COUNT] = {
+    const FRAME: TrapFrame = TrapFrame::new();
+    [FRAME; HART_MAX_COUNT]
+};
+
+
+/// Initialize trap for the calling hart, with its own frame and
+/// trap stack.
+///
+/// *This function is unsafe because it must be called once per
+/// hart, after the initialization of the page allocator.*
+pub unsafe fn init_hart() {
+    let frame = &mut HART_FRAMES[cpu::mhardid::get()];
+    let stack = alloc(NonZeroUsize::new_unchecked(TRAP_STACK_PAGES)).expect("failed to allocate trap stack");
+    frame.trap_stack = stack.as_ptr().add(TRAP_STACK_PAGES * PAGE_SIZE).addr();
+    cpu::mscratch::set((frame as *mut TrapFrame).addr());
     cpu::mie::set(MieFlags::MEIE);
-    cpu::mscratch::set((&mut HART_ZERO_FRAME as *mut TrapFrame).addr());
 }
 
 
+/// Return true if the calling code runs in the trap handler of the
+/// hart, on its trap stack. The trap handler can't switch processes,
+/// so it must not sleep.
+pub fn in_trap() -> bool {
+    let frame = cpu::mscratch::get() as *const TrapFrame;
+    if frame.is_null() {
+        return false;
+    }
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {0}, sp", out(reg) sp); }
+    // SAFETY: The scratch register points to the trap frame of the hart.
+    let stack_end = unsafe { (*frame).trap_stack };
+    sp < stack_end && sp >= stack_end - TRAP_STACK_PAGES * PAGE_SIZE
+}
@@ -0,0 +1,457 @@
+//! Console TTY on top of the default UART interface, with a line
+//! discipline.
+//!
+//! In canonical mode, the input is edited line by line and is only
+//! readable once a line is completed with enter: backspace erases the
+//! last character, Ctrl-U erases the line, Ctrl-C kills the foreground
+//! process and Ctrl-D ends the input (a read returns zero). In raw
+//! mode, each received character is directly readable.
+//!
+//! A foreground machine process, like the builtin shell, dies at its
+//! next system call or when its current one returns, a blocking read
+//! of the TTY is interrupted (see [`process::kill_pid`]).
+//!
+//! The TTY is exposed to processes in the sysfs as `/sys/tty0`, or
+//! `/sys/console`, opened as a [`TtyFile`], and its mode can be read
+//! or changed through `/sys/tty0.mode` ([`TtyModeFile`]), with 
+//! space-separated words like `raw echo crlf` or `canonical noecho
+//! nocrlf`.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use bitflags::bitflags;
+
+use crate::filesystem::{File, FsError, FsResult, OpenFlags};
+use crate::filesystem::sysfs::{self, SysFile};
+use crate::process::{self, Pid};
+use crate::sync::IrqSpinLock;
+use crate::util::RingBuffer;
+use crate::{uart, println};
+
+
+/// Maximum length of a line being edited in canonical mode.
+const LINE_SIZE: usize = 256;
+
+/// Size of the buffer of input ready to be read.
+const INPUT_SIZE: usize = 1024;
+
+/// Maximum length of the mode, as written by [`mode`].
+pub const MODE_SIZE: usize = 32;
+
+/// Control characters.
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+
+bitflags! {
+    /// Mode of the TTY.
+    pub struct TtyFlags: u8 {
+        /// Input is edited and read line by line.
+        const CANONICAL = 0b001;
+        /// Received characters are echoed back.
+        const ECHO      = 0b010;
+        /// Carriage returns are received as line feeds, and line feeds
+        /// are written as carriage return plus line feed.
+        const CRLF      = 0b100;
+    }
+}
+
+
+/// The console TTY.
+static TTY: IrqSpinLock<Tty> = IrqSpinLock::new(Tty::new());
+
+
+struct Tty {
+    flags: TtyFlags,
+    /// Line being edited in canonical mode.
+    line: [u8; LINE_SIZE],
+    line_len: usize,
+    /// Input ready to be read, completed lines in canonical mode.
+    input: RingBuffer<INPUT_SIZE>,
+    /// Number of end of input (Ctrl-D) to return to readers, each one
+    /// is returned once all the input before it has been read.
+    eof_count: usize,
+    /// The process killed on Ctrl-C.
+    foreground: Option<Pid>,
+    /// The foreground process to kill after a Ctrl-C, this is done by
+    /// the interrupt handler once the TTY is unlocked.
+    interrupted: Option<Pid>,
+}
+
+impl Tty {
+
+    const fn new() -> Self {
+        Self {
+            flags: TtyFlags::all(),
+            line: [0; LINE_SIZE],
+            line_len: 0,
+            input: RingBuffer::new(),
+            eof_count: 0,
+            foreground: None,
+            interrupted: None,
+        }
+    }
+
+    /// Handle a received character, return true if some input is now
+    /// readable.
+    fn receive(&mut self, mut value: u8) -> bool {
+
+        if self.flags.contains(TtyFlags::CRLF) && value == b'\r' {
+            value = b'\n';
+        }
+
+        if !self.flags.contains(TtyFlags::CANONICAL) {
+            self.echo(value);
+            return self.input.push(value);
+        }
+
+        match value {
+            b'\n' => {
+                self.echo(b'\n');
+                self.push_line(true)
+            }
+            BACKSPACE | DELETE => {
+                if self.line_len != 0 {
+                    self.line_len -= 1;
+                    self.erase(1);
+                }
+                false
+            }
+            CTRL_U => {
+                self.erase(self.line_len);
+                self.line_len = 0;
+                false
+            }
+            CTRL_C => {
+                self.echo_str("^C\n");
+                self.line_len = 0;
+                self.interrupted = self.foreground;
+                false
+            }
+            CTRL_D => {
+                // Flush the line being edited, or end the input if empty.
+                if self.line_len == 0 {
+                    self.eof_count += 1;
+                    true
+                } else {
+                    self.push_line(false)
+                }
+            }
+            _ => {
+                // The last byte is kept for the line feed.
+                if self.line_len < LINE_SIZE - 1 {
+                    self.line[self.line_len] = value;
+                    self.line_len += 1;
+                    self.echo(value);
+                }
+                false
+            }
+        }
+
+    }
+
+    /// Move the line being edited to the input, with a line feed if
+    /// requested, the line is truncated if the input is full.
+    fn push_line(&mut self, line_feed: bool) -> bool {
+        if line_feed {
+            self.line[self.line_len] = b'\n';
+            self.line_len += 1;
+        }
+        for &value in &self.line[..self.line_len] {
+            self.input.push(value);
+        }
+        self.line_len = 0;
+        true
+    }
+
+    /// Return true if a read would return something, input or end.
+    fn is_readable(&self) -> bool {
+        !self.input.is_empty() || self.eof_count != 0
+    }
+
+    /// Read the input into the buffer, at most one line in canonical
+    /// mode. Return none if nothing is readable.
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.input.is_empty() {
+            if self.eof_count == 0 {
+                return None;
+            }
+            self.eof_count -= 1;
+            return Some(0);
+        }
+        let mut len = 0;
+        while len < buf.len() {
+            let Some(value) = self.input.pop() else { break };
+            buf[len] = value;
+            len += 1;
+            if value == b'\n' && self.flags.contains(TtyFlags::CANONICAL) {
+                break;
+            }
+        }
+        Some(len)
+    }
+
+    fn echo(&self, value: u8) {
+        if self.flags.contains(TtyFlags::ECHO) {
+            self.write(&[value]);
+        }
+    }
+
+    fn echo_str(&self, s: &str) {
+        if self.flags.contains(TtyFlags::ECHO) {
+            self.write(s.as_bytes());
+        }
+    }
+
+    /// Erase the given number of characters before the cursor.
+    fn erase(&self, count: usize) {
+        for _ in 0..count {
+            self.echo_str("\x08 \x08");
+        }
+    }
+
+    fn write(&self, data: &[u8]) {
+        for &value in data {
+            unsafe {
+                if value == b'\n' && self.flags.contains(TtyFlags::CRLF) {
+                    uart::put(b'\r');
+                }
+                uart::put(value);
+            }
+        }
+    }
+
+}
+
+
+/// Interrupt handler of the default UART interface, the received
+/// characters are given to the line discipline and processes waiting
+/// for input are woken up.
+pub fn handle_interrupt(data: &(), id: u8) {
+
+    uart::handle_interrupt(data, id);
+
+    let mut readable = false;
+    let mut buf = [0; 32];
+    let mut tty = TTY.lock();
+    loop {
+        let len = uart::read(&mut buf);
+        if len == 0 {
+            break;
+        }
+        for &value in &buf[..len] {
+            readable |= tty.receive(value);
+        }
+    }
+    let interrupted = tty.interrupted.take();
+    drop(tty);
+
+    // The process table is locked while checking if a reader would
+    // block, so it must not be locked with the TTY locked.
+    if let Some(pid) = interrupted {
+        if process::kill_pid(pid) {
+            let mut tty = TTY.lock();
+            if tty.foreground == Some(pid) {
+                tty.foreground = None;
+            }
+        }
+    }
+
+    if readable {
+        process::wake(chan());
+    }
+
+}
+
+
+/// Read the input of the TTY into the given buffer, return the number
+/// of bytes read, at most one line in canonical mode. Zero is returned
+/// at the end of the input, or if the buffer is empty. None is returned
+/// if nothing can be read yet, this doesn't block.
+pub fn read(buf: &mut [u8]) -> Option<usize> {
+    if buf.is_empty() {
+        return Some(0);
+    }
+    TTY.lock().read(buf)
+}
+
+/// Read the input of the TTY like [`read`], but the calling process
+/// sleeps until something can be read.
+///
+/// This must not be used in interrupt context.
+pub fn read_blocking(buf: &mut [u8]) -> usize {
+    loop {
+        process::sleep_while(chan(), would_block);
+        if let Some(len) = read(buf) {
+            return len;
+        }
+    }
+}
+
+/// Return true if a read would block because nothing can be read.
+#[inline]
+pub fn would_block() -> bool {
+    !TTY.lock().is_readable()
+}
+
+/// Return the wait channel of the processes waiting for input, they
+/// are woken up by the interrupt handler.
+#[inline]
+pub fn chan() -> usize {
+    (&TTY as *const IrqSpinLock<Tty>).addr()
+}
+
+
+/// Write the given data to the TTY, with line feed translation if
+/// enabled.
+pub fn write(data: &[u8]) {
+    TTY.lock().write(data);
+}
+
+
+/// Get the mode of the TTY.
+pub fn flags() -> TtyFlags {
+    TTY.lock().flags
+}
+
+/// Set the mode of the TTY, the line being edited is moved to the
+/// input when leaving the canonical mode.
+pub fn set_flags(flags: TtyFlags) {
+    let mut tty = TTY.lock();
+    if tty.flags.contains(TtyFlags::CANONICAL) && !flags.contains(TtyFlags::CANONICAL) {
+        tty.push_line(false);
+    }
+    tty.flags = flags;
+}
+
+/// Change the mode of the TTY from the given space-separated words,
+/// each one sets or clears a flag: `canonical` or `raw`, `echo` or
+/// `noecho`, `crlf` or `nocrlf`. Return false, without changing the
+/// mode, if a word is unknown.
+pub fn set_mode(mode: &str) -> bool {
+    let mut flags = flags();
+    for word in mode.split_ascii_whitespace() {
+        match word {
+            "canonical" => flags.insert(TtyFlags::CANONICAL),
+            "raw" => flags.remove(TtyFlags::CANONICAL),
+            "echo" => flags.insert(TtyFlags::ECHO),
+            "noecho" => flags.remove(TtyFlags::ECHO),
+            "crlf" => flags.insert(TtyFlags::CRLF),
+            "nocrlf" => flags.remove(TtyFlags::CRLF),
+            _ => return false,
+        }
+    }
+    set_flags(flags);
+    true
+}
+
+/// Write the mode of the TTY into the given buffer, as words understood
+/// by [`set_mode`] followed by a line feed. Return the length written.
+pub fn mode(buf: &mut [u8; MODE_SIZE]) -> usize {
+    let flags = flags();
+    let words = [
+        if flags.contains(TtyFlags::CANONICAL) { "canonical" } else { "raw" },
+        if flags.contains(TtyFlags::ECHO) { "echo" } else { "noecho" },
+        if flags.contains(TtyFlags::CRLF) { "crlf" } else { "nocrlf" },
+    ];
+    let mut len = 0;
+    for (i, word) in words.iter().enumerate() {
+        buf[len..len + word.len()].copy_from_slice(word.as_bytes());
+        len += word.len();
+        buf[len] = if i == words.len() - 1 { b'\n' } else { b' ' };
+        len += 1;
+    }
+    len
+}
+
+
+/// Set the foreground process of the TTY, killed on Ctrl-C.
+pub fn set_foreground(pid: Pid) {
+    TTY.lock().foreground = Some(pid);
+}
+
+
+/// Register the files of the TTY in the sysfs, `tty0` and its alias
+/// `console`, and `tty0.mode`.
+pub fn register_files() {
+    let tty: Arc<dyn SysFile> = Arc::new(TtySysFile);
+    let files: [(&str, Arc<dyn SysFile>); 3] = [
+        ("tty0", Arc::clone(&tty)),
+        ("console", tty),
+        ("tty0.mode", Arc::new(TtyModeFile)),
+    ];
+    for (path, file) in files {
+        if let Err(e) = sysfs::register_file(path, file) {
+            println!(" = Failed to register the TTY file {}: {:?}", path, e);
+        }
+    }
+}
+
+
+/// The TTY opened as a file, reads would block until some input is
+/// available.
+pub struct TtyFile;
+
+impl File for TtyFile {
+
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        read(buf).ok_or(FsError::WouldBlock(chan(), would_block))
+    }
+
+    fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        write(buf);
+        Ok(buf.len())
+    }
+
+}
+
+
+/// The TTY in the sysfs, the process opening it becomes the foreground
+/// process, killed on Ctrl-C. Opened by the kernel without process, the
+/// foreground is unchanged.
+struct TtySysFile;
+
+impl SysFile for TtySysFile {
+
+    fn open(&self, flags: OpenFlags) -> FsResult<Option<Box<dyn File>>> {
+        let _ = flags;
+        if let Some(pid) = process::try_pid() {
+            set_foreground(pid);
+        }
+        Ok(Some(Box::new(TtyFile)))
+    }
+
+}
+
+
+/// The mode of the TTY in the sysfs, the mode is read from the offset
+/// of the file and written at once.
+pub struct TtyModeFile;
+
+impl SysFile for TtyModeFile {
+
+    fn size(&self) -> u64 {
+        mode(&mut [0; MODE_SIZE]) as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let mut data = [0; MODE_SIZE];
+        let data_len = mode(&mut data);
+        Ok(sysfs::read_content(&data[..data_len], offset, buf))
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let _ = offset;
+        let mode = core::str::from_utf8(buf).map_err(|_| FsError::InvalidArgument)?;
+        if set_mode(mode) {
+            Ok(buf.len())
+        } else {
+            Err(FsError::InvalidArgument)
+        }
+    }
+
+}
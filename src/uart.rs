@@ -1,13 +1,60 @@
 use core::fmt::Write;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::devicetree::{self, Node};
+use crate::sync::IrqSpinLock;
+use crate::util::RingBuffer;
+use crate::process;
+
+
+/// Compatible strings of the UART in the device tree.
+const UART_COMPATIBLE: [&str; 1] = ["ns16550a"];
+
+/// Size of the buffer of received characters.
+const RX_BUFFER_SIZE: usize = 256;
+
+/// Line Status Register, Data Ready bit.
+const LSR_DR: u8 = 1 << 0;
+/// Line Status Register, Transmitter Holding Register Empty bit.
+const LSR_THRE: u8 = 1 << 5;
 
 
 /// The default UART interface.
 pub static mut DEFAULT: Uart = Uart::new(0x1000_0000);
 
+/// Lock taken by the print macros, so lines printed by different
+/// harts are not interleaved.
+pub static PRINT_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// Set when the kernel panics, the print macros then bypass the print
+/// lock because the panicking hart might already hold it.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// The PLIC interrupt source id of the default UART interface.
+pub static mut DEFAULT_IRQ: u8 = 10;
 
-/// Initialize the default UART interface.
+/// Characters received by the default UART interface, pushed by the
+/// interrupt handler. 
+static RX_BUFFER: RingBuffer<RX_BUFFER_SIZE> = RingBuffer::new();
+
+/// Lock taken by readers of the receive buffer, there must be only one
+/// consumer at the same time.
+static RX_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+
+/// Initialize the default UART interface, discovered from the device
+/// tree if possible, or the one of QEMU's virt machine.
 #[inline]
 pub unsafe fn init() {
+    if let Some(node) = devicetree::find_compatible(&UART_COMPATIBLE) {
+        if let Some(uart) = Uart::from_node(&node) {
+            DEFAULT = uart;
+        }
+        if let Some(irq) = node.interrupts().next() {
+            DEFAULT_IRQ = irq as u8;
+        }
+    }
     DEFAULT.init();
 }
 
@@ -24,6 +71,72 @@ pub unsafe fn put(value: u8) {
 }
 
 
+/// Interrupt handler of the default UART interface, the received
+/// characters are drained from the FIFO into the receive buffer, 
+/// processes waiting for them are woken up. Characters are dropped if
+/// the buffer is full. Echo is left to the [TTY](crate::tty).
+pub fn handle_interrupt(_: &(), _id: u8) {
+
+    let mut received = false;
+
+    unsafe {
+        while let Some(value) = get() {
+            received |= RX_BUFFER.push(value);
+        }
+    }
+
+    if received {
+        process::wake(rx_chan());
+    }
+
+}
+
+
+/// Read the characters received by the default UART interface into the
+/// given buffer, return the number of characters read, zero if none 
+/// are available. This doesn't block.
+pub fn read(buf: &mut [u8]) -> usize {
+    let _guard = RX_LOCK.lock();
+    let mut len = 0;
+    while len < buf.len() {
+        match RX_BUFFER.pop() {
+            Some(value) => buf[len] = value,
+            None => break,
+        }
+        len += 1;
+    }
+    len
+}
+
+/// Read the characters received by the default UART interface into the
+/// given buffer, the calling process sleeps until at least one is
+/// available. Return the number of characters read.
+/// 
+/// This must not be used in interrupt context, see [`read`].
+pub fn read_blocking(buf: &mut [u8]) -> usize {
+    loop {
+        process::sleep_while(rx_chan(), is_rx_empty);
+        let len = read(buf);
+        if len != 0 || buf.is_empty() {
+            return len;
+        }
+    }
+}
+
+/// Return true if no received character is waiting to be read.
+#[inline]
+pub fn is_rx_empty() -> bool {
+    RX_BUFFER.is_empty()
+}
+
+/// Return the wait channel of the processes waiting for received
+/// characters, they are woken up by the interrupt handler.
+#[inline]
+pub fn rx_chan() -> usize {
+    (&RX_BUFFER as *const RingBuffer<RX_BUFFER_SIZE>).addr()
+}
+
+
 /// UART 16550 datasheet: http://caro.su/msx/ocm_de1/16550.pdf
 pub struct Uart {
     base_addr: *mut u8
@@ -37,6 +150,11 @@ impl Uart {
         Self { base_addr: base_addr as *mut u8 }
     }
 
+    /// Create the UART interface from its device tree node.
+    pub fn from_node(node: &Node) -> Option<Self> {
+        node.reg().next().map(|(addr, _)| Self::new(addr as usize))
+    }
+
     pub unsafe fn init(&mut self) {
 
         let ptr = self.base_addr;
@@ -70,16 +188,22 @@ impl Uart {
     #[inline]
     pub unsafe fn get(&self) -> Option<u8> {
         let ptr = self.base_addr;
-        if ptr.add(5).read_volatile() & 1 == 0 {
+        if ptr.add(5).read_volatile() & LSR_DR == 0 {
             None
         } else {
             Some(ptr.add(0).read_volatile())
         }
     }
 
+    /// Put a character, waiting for the transmitter holding register
+    /// to be empty.
     #[inline]
     pub unsafe fn put(&mut self, value: u8) {
-        self.base_addr.add(0).write_volatile(value);
+        let ptr = self.base_addr;
+        while ptr.add(5).read_volatile() & LSR_THRE == 0 {
+            spin_loop();
+        }
+        ptr.add(0).write_volatile(value);
     }
 
 }
@@ -92,3 +216,18 @@ impl Write for Uart {
         Ok(())
     }
 }
+
+
+/// Make the print macros bypass the print lock, this is used by the
+/// panic and fatal exception handlers, so they never deadlock if the
+/// hart (or a halted one) was printing.
+pub fn set_panicking() {
+    PANICKING.store(true, Ordering::Release);
+}
+
+
+/// Return true if the print lock must be bypassed.
+#[inline]
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Acquire)
+}
@@ -3,6 +3,9 @@
 mod cell;
 pub use cell::OpaqueCell;
 
+mod ring;
+pub use ring::RingBuffer;
+
 
 
 
@@ -0,0 +1,70 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// A fixed-size lock-free ring buffer of bytes, with a single producer
+/// and a single consumer.
+/// 
+/// *The producer and the consumer can run concurrently, on different
+/// harts or in interrupt context, but the callers must ensure that
+/// there is only one of each at the same time.*
+pub struct RingBuffer<const SIZE: usize> {
+    data: UnsafeCell<[u8; SIZE]>,
+    /// Index of the next byte to pop, only modified by the consumer.
+    head: AtomicUsize,
+    /// Index of the next byte to push, only modified by the producer.
+    tail: AtomicUsize,
+}
+
+unsafe impl<const SIZE: usize> Sync for RingBuffer<SIZE> {}
+
+impl<const SIZE: usize> RingBuffer<SIZE> {
+
+    pub const fn new() -> Self {
+        Self {
+            data: UnsafeCell::new([0; SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the number of bytes in the buffer.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push a byte at the end of the buffer, return false if full.
+    /// 
+    /// *Must only be called by the producer.*
+    pub fn push(&self, value: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == SIZE {
+            return false;
+        }
+        // SAFETY: The slot is free, so it's not read by the consumer.
+        unsafe { (*self.data.get())[tail % SIZE] = value; }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pop the byte at the start of the buffer, if not empty.
+    /// 
+    /// *Must only be called by the consumer.*
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: The slot is filled, so it's not written by the producer.
+        let value = unsafe { (*self.data.get())[head % SIZE] };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+}